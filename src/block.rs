@@ -0,0 +1,268 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Block device access shared by partition tables and filesystems
+//!
+//! Devices are read in whole blocks, [`BlockDevice::read_at`] takes care of
+//! reads that do not start or end on a block boundary.
+
+use alloc::vec;
+
+use crate::io::{Instance, Read, Seek};
+use crate::PROM;
+
+/// Block size assumed when the device does not implement ```block-size```
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Random access device read in fixed size blocks
+pub trait BlockDevice {
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks in the device
+    fn block_count(&self) -> u64;
+
+    /// Reads whole blocks
+    ///
+    /// # Arguments
+    ///
+    /// ```lba```: index of the first block
+    /// ```buf```: destination, its length must be a multiple of the block size
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Size of the device in bytes
+    fn size(&self) -> u64 {
+        self.block_count().saturating_mul(self.block_size() as u64)
+    }
+
+    /// Reads bytes at any offset of the device
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or("Read beyond the end of the device")?;
+        if end > self.size() {
+            return Err("Read beyond the end of the device");
+        }
+
+        let block_size = self.block_size() as u64;
+        let head = (offset % block_size) as usize;
+        if head == 0 && (buf.len() as u64).is_multiple_of(block_size) {
+            return self.read_blocks(offset / block_size, buf);
+        }
+
+        let first = offset / block_size;
+        let last = end.div_ceil(block_size);
+        let mut blocks = vec![0u8; ((last - first) * block_size) as usize];
+        self.read_blocks(first, &mut blocks)?;
+        buf.copy_from_slice(&blocks[head..head + buf.len()]);
+        Ok(())
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        (**self).read_blocks(lba, buf)
+    }
+}
+
+// Number of blocks covered by a read, checking it is within the device
+fn check_read(
+    block_size: usize,
+    block_count: u64,
+    lba: u64,
+    buf: &[u8],
+) -> Result<u64, &'static str> {
+    if !buf.len().is_multiple_of(block_size) {
+        return Err("Buffer is not a multiple of the block size");
+    }
+    let count = (buf.len() / block_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= block_count => Ok(count),
+        _ => Err("Read beyond the end of the device"),
+    }
+}
+
+/// Disk package instance read through its ```read-blocks``` method
+pub struct Disk {
+    instance: Instance,
+    block_size: usize,
+    block_count: u64,
+}
+
+impl Disk {
+    /// Opens a disk from a null terminated device specifier
+    pub fn open(prom: &PROM, dev_spec: &str) -> Result<Self, &'static str> {
+        Disk::from_instance(Instance::open(prom, dev_spec)?)
+    }
+
+    /// Queries the geometry of an open disk instance
+    ///
+    /// The size comes from ```#blocks``` or, failing that, from ```size```.
+    pub fn from_instance(instance: Instance) -> Result<Self, &'static str> {
+        let mut rets = [0usize; 2];
+        let block_size = match instance.call_method("block-size\0", &[], &mut rets[..1]) {
+            Ok(()) if rets[0] > 0 => rets[0],
+            _ => DEFAULT_BLOCK_SIZE,
+        };
+
+        let block_count = match instance.call_method("#blocks\0", &[], &mut rets[..1]) {
+            Ok(()) => rets[0] as u64,
+            Err(_) => match instance.size() {
+                Ok(size) => size / block_size as u64,
+                Err(_) => return Err("Could not get the size of the disk"),
+            },
+        };
+
+        Ok(Disk {
+            instance,
+            block_size,
+            block_count,
+        })
+    }
+
+    /// Underlying package instance
+    pub fn instance(&self) -> &Instance {
+        &self.instance
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let count = check_read(self.block_size, self.block_count, lba, buf)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        let block = usize::try_from(lba).map_err(|_| "Block index does not fit in a cell")?;
+        let mut read = [0usize];
+        // ( addr block# #blocks -- #read )
+        let args = [buf.as_mut_ptr() as usize, block, count as usize];
+        match self.instance.call_method("read-blocks\0", &args, &mut read) {
+            Ok(()) if read[0] as u64 == count => Ok(()),
+            Ok(()) => Err("Short read from disk"),
+            // Not every disk package implements read-blocks, fall back to the byte interface
+            Err(_) => {
+                self.instance.seek(lba * self.block_size as u64)?;
+                self.instance.read_exact(buf)
+            }
+        }
+    }
+}
+
+/// Block device over a buffer in memory, such as a ramdisk
+pub struct MemoryDisk<'a> {
+    data: &'a [u8],
+    block_size: usize,
+}
+
+impl<'a> MemoryDisk<'a> {
+    /// A trailing partial block is not accessible
+    pub fn new(data: &'a [u8], block_size: usize) -> Self {
+        MemoryDisk { data, block_size }
+    }
+}
+
+impl BlockDevice for MemoryDisk<'_> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_read(self.block_size, self.block_count(), lba, buf)?;
+        let start = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+}
+
+/// Contiguous range of blocks of another device, such as a partition
+pub struct BlockRange<D: BlockDevice> {
+    device: D,
+    start: u64,
+    count: u64,
+}
+
+impl<D: BlockDevice> BlockRange<D> {
+    /// Fails if the range does not fit in the device
+    ///
+    /// # Arguments
+    ///
+    /// ```start```: first block of the range
+    /// ```count```: number of blocks in the range
+    pub fn new(device: D, start: u64, count: u64) -> Result<Self, &'static str> {
+        match start.checked_add(count) {
+            Some(end) if end <= device.block_count() => Ok(BlockRange {
+                device,
+                start,
+                count,
+            }),
+            _ => Err("Block range extends beyond the end of the device"),
+        }
+    }
+
+    /// Range given in bytes, for tables using units other than the device blocks
+    ///
+    /// Fails if the range is outside the device or not aligned to its blocks.
+    ///
+    /// # Arguments
+    ///
+    /// ```offset```: start of the range in bytes
+    /// ```size```: size of the range in bytes
+    pub fn from_byte_range(device: D, offset: u64, size: u64) -> Result<Self, &'static str> {
+        let block_size = device.block_size() as u64;
+        if !offset.is_multiple_of(block_size) || !size.is_multiple_of(block_size) {
+            return Err("Block range is not aligned to the device blocks");
+        }
+        BlockRange::new(device, offset / block_size, size / block_size)
+    }
+
+    /// First block of the range in the underlying device
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockRange<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_read(self.device.block_size(), self.count, lba, buf)?;
+        self.device.read_blocks(self.start + lba, buf)
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Boot parameters left by the firmware in '/chosen'
+//!
+//! Firmwares disagree on property names and encodings: SLOF and PowerVM use
+//! ```bootargs```, Apple OF may leave the arguments in ```boot-args``` and
+//! pads strings with nulls, and the initrd cells are 32 or 64 bit depending on
+//! who wrote them.
+
+use alloc::string::{String, ToString};
+use core::str;
+
+use crate::devspec::DevSpec;
+use crate::{PHandle, PROM};
+
+/// Properties holding the boot device specifier, in order of preference
+const BOOTPATH_PROPS: &[&str] = &["bootpath\0", "boot-path\0"];
+/// Properties holding the arguments typed after ```boot```, in order of preference
+const BOOTARGS_PROPS: &[&str] = &["bootargs\0", "boot-args\0"];
+/// Properties holding the console path, in order of preference
+const STDOUT_PATH_PROPS: &[&str] = &["linux,stdout-path\0", "stdout-path\0"];
+
+/// Initial ramdisk location
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Initrd {
+    pub start: u64,
+    /// Address right after the last byte
+    pub end: u64,
+}
+
+impl Initrd {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// Arguments given to the client program
+///
+/// Words are separated by whitespace, double quotes group words with spaces
+/// like the Linux kernel command line does.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootArgs {
+    raw: String,
+}
+
+impl BootArgs {
+    pub fn new(raw: &str) -> Self {
+        BootArgs {
+            raw: raw.trim().to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// Iterates over the words of the arguments
+    pub fn iter(&self) -> Words<'_> {
+        Words { rest: &self.raw }
+    }
+
+    /// Value of a ```key=value``` argument without quotes, empty for a bare ```key```
+    ///
+    /// The last occurrence wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter()
+            .filter_map(|word| match word.split_once('=') {
+                Some((name, value)) if name == key => Some(unquote(value)),
+                None if word == key => Some(""),
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Whether a bare flag or ```key=value``` argument is present
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+/// Iterator over the words of [`BootArgs`]
+pub struct Words<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut quoted = false;
+        let end = rest
+            .char_indices()
+            .find(|(_, c)| {
+                if *c == '"' {
+                    quoted = !quoted;
+                }
+                c.is_whitespace() && !quoted
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    match value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        true => &value[1..value.len() - 1],
+        false => value,
+    }
+}
+
+/// Parameters the client program was booted with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BootContext {
+    /// Device and file the client program was loaded from
+    pub bootpath: Option<DevSpec>,
+    /// Arguments typed after ```boot```
+    pub bootargs: BootArgs,
+    /// Initrd loaded by the firmware or a previous stage
+    pub initrd: Option<Initrd>,
+    /// Console device
+    pub stdout_path: Option<DevSpec>,
+}
+
+impl BootContext {
+    /// Reads the boot parameters from '/chosen'
+    ///
+    /// Missing or malformed properties are left empty, only failing to access
+    /// '/chosen' is an error.
+    pub fn from_chosen(prom: &PROM) -> Result<Self, &'static str> {
+        if prom.chosen.is_null() {
+            return Err("Could not find /chosen");
+        }
+
+        let bootpath = first_string(prom, prom.chosen, BOOTPATH_PROPS)
+            .and_then(|path| DevSpec::parse(&path).ok());
+        let bootargs = first_string(prom, prom.chosen, BOOTARGS_PROPS)
+            .map(|args| BootArgs::new(&args))
+            .unwrap_or_default();
+
+        let initrd = match (
+            read_cells(prom, "linux,initrd-start\0"),
+            read_cells(prom, "linux,initrd-end\0"),
+        ) {
+            (Some(start), Some(end)) if end > start => Some(Initrd { start, end }),
+            _ => None,
+        };
+
+        let stdout_path = first_string(prom, prom.chosen, STDOUT_PATH_PROPS)
+            .or_else(|| stdout_package_path(prom))
+            .and_then(|path| DevSpec::parse(&path).ok());
+
+        Ok(BootContext {
+            bootpath,
+            bootargs,
+            initrd,
+            stdout_path,
+        })
+    }
+}
+
+// Text up to the first null, some firmwares pad strings or leave stale bytes after it
+fn decode_string(value: &[u8]) -> Option<String> {
+    let end = value.iter().position(|c| *c == 0).unwrap_or(value.len());
+    let text = str::from_utf8(&value[..end]).ok()?.trim();
+    match text.is_empty() {
+        true => None,
+        false => Some(text.to_string()),
+    }
+}
+
+fn first_string(prom: &PROM, phandle: *const PHandle, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| prom.read_property(phandle, name).ok())
+        .find_map(|value| decode_string(&value))
+}
+
+// Addresses may be stored in one or two cells regardless of the client word size
+fn read_cells(prom: &PROM, name: &str) -> Option<u64> {
+    let value = prom.read_property(prom.chosen, name).ok()?;
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value[..4].try_into().ok()?) as u64),
+        8 => Some(u64::from_be_bytes(value[..8].try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn stdout_package_path(prom: &PROM) -> Option<String> {
+    if prom.stdout.is_null() {
+        return None;
+    }
+
+    let package = prom.instance_to_package(prom.stdout).ok()?;
+    let mut path = [0u8; 256];
+    let len = prom.package_to_path(package, &mut path).ok()?;
+    decode_string(path.get(..len)?)
+}
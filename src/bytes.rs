@@ -0,0 +1,39 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Fixed width integers at an offset of on-disk and firmware structures
+//!
+//! Callers check that the field lies within ```data``` before reading it.
+
+fn array<const N: usize>(data: &[u8], offset: usize) -> [u8; N] {
+    let mut raw = [0u8; N];
+    raw.copy_from_slice(&data[offset..offset + N]);
+    raw
+}
+
+pub(crate) fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn le64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(array(data, offset))
+}
+
+pub(crate) fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(array(data, offset))
+}
+
+pub(crate) fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(array(data, offset))
+}
+
+pub(crate) fn be64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(array(data, offset))
+}
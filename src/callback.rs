@@ -0,0 +1,106 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Support for firmware initiated requests registered through ```set-callback```
+
+use core::{slice, str};
+
+use crate::services::Args;
+use crate::OF_SIZE_ERR;
+
+/// Handler for the requests Open Firmware makes back into the client program
+pub trait ClientCallbacks {
+    /// Handles a callback request
+    ///
+    /// # Arguments
+    ///
+    /// ```service```: name of the requested service, e.g. ```translate```
+    /// ```args```: input argument cells
+    /// ```rets```: return cells to be filled by the handler
+    ///
+    /// # Returns
+    ///
+    /// An error if the service is unknown or could not be performed, which is
+    /// reported back to the firmware as ```-1```
+    fn callback(
+        &self,
+        service: &str,
+        args: &[usize],
+        rets: &mut [usize],
+    ) -> Result<(), &'static str>;
+}
+
+/// Callback state replaced by [`crate::PROM::set_callback`]
+///
+/// Hand it to [`crate::PROM::restore_callback`] to reinstall the previous handler
+#[derive(Clone, Copy)]
+pub struct PreviousCallback {
+    pub(crate) entry: Option<extern "C" fn(*mut Args) -> usize>,
+    pub(crate) handler: Option<&'static dyn ClientCallbacks>,
+}
+
+impl PreviousCallback {
+    /// Entry point that was registered with the firmware, if any
+    pub fn entry(&self) -> Option<extern "C" fn(*mut Args) -> usize> {
+        self.entry
+    }
+}
+
+static mut HANDLER: Option<&'static dyn ClientCallbacks> = None;
+
+pub(crate) fn handler() -> Option<&'static dyn ClientCallbacks> {
+    unsafe { HANDLER }
+}
+
+pub(crate) fn set_handler(handler: Option<&'static dyn ClientCallbacks>) {
+    unsafe {
+        HANDLER = handler;
+    }
+}
+
+/// Entry point registered with the firmware, it dispatches every request to
+/// the installed [`ClientCallbacks`] handler
+///
+/// The argument array follows the same layout used by the client interface:
+/// an [`Args`] header followed by ```nargs``` argument cells and ```nret```
+/// return cells.
+pub(crate) extern "C" fn callback_entry(args: *mut Args) -> usize {
+    let handler = match handler() {
+        Some(handler) => handler,
+        None => return OF_SIZE_ERR,
+    };
+
+    if args.is_null() {
+        return OF_SIZE_ERR;
+    }
+
+    let header = unsafe { &*args };
+    if header.service.is_null() {
+        return OF_SIZE_ERR;
+    }
+
+    let mut len = 0;
+    while unsafe { *header.service.add(len) } != 0 {
+        len += 1;
+    }
+    let service = match str::from_utf8(unsafe { slice::from_raw_parts(header.service, len) }) {
+        Ok(service) => service,
+        Err(_) => return OF_SIZE_ERR,
+    };
+
+    let cells = unsafe { args.add(1) as *mut usize };
+    let (args, rets) = unsafe {
+        (
+            slice::from_raw_parts(cells, header.nargs),
+            slice::from_raw_parts_mut(cells.add(header.nargs), header.nret),
+        )
+    };
+
+    match handler.callback(service, args, rets) {
+        Ok(()) => 0,
+        Err(_) => OF_SIZE_ERR,
+    }
+}
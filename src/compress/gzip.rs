@@ -0,0 +1,177 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! gzip (RFC 1952) stream decoder, as used by compressed kernels and initrds
+
+use alloc::vec::Vec;
+
+use super::inflate::Inflate;
+use crate::crc32::Crc32;
+use crate::io::Read;
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const METHOD_DEFLATE: u8 = 8;
+const FLAG_HCRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const FLAG_RESERVED: u8 = 0xe0;
+
+/// gzip stream decoder, verifying the CRC-32 and size of the data at the end
+///
+/// Concatenated members are decoded one after the other as a single stream,
+/// like ```gzip -d``` does. Data following the last member that does not
+/// start another one, such as zero padding, is ignored.
+pub struct Gzip<R: Read> {
+    inflate: Inflate<R>,
+    crc: Crc32,
+    name: Option<Vec<u8>>,
+    mtime: u32,
+    done: bool,
+}
+
+// Reads from the stream while keeping the CRC-32 of the header
+struct Header<'a, R: Read> {
+    input: &'a mut R,
+    crc: Crc32,
+}
+
+impl<R: Read> Header<'_, R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], &'static str> {
+        let mut buf = [0u8; N];
+        self.input
+            .read_exact(&mut buf)
+            .map_err(|_| "Truncated gzip header")?;
+        self.crc.update(&buf);
+        Ok(buf)
+    }
+
+    // Zero terminated field, without the terminator
+    fn string(&mut self) -> Result<Vec<u8>, &'static str> {
+        let mut string = Vec::new();
+        loop {
+            match self.bytes::<1>()? {
+                [0] => return Ok(string),
+                [byte] => string.push(byte),
+            }
+        }
+    }
+}
+
+// Original file name and modification time of a member
+type Member = (Option<Vec<u8>>, u32);
+
+// Reads a member header, None if the input does not continue with the gzip magic
+fn read_header<R: Read>(input: &mut R) -> Result<Option<Member>, &'static str> {
+    let mut magic = [0u8; 2];
+    let mut len = 0;
+    while len < magic.len() {
+        match input.read(&mut magic[len..])? {
+            0 => return Ok(None),
+            n => len += n,
+        }
+    }
+    if magic != MAGIC {
+        return Ok(None);
+    }
+
+    let mut header = Header {
+        input,
+        crc: Crc32::new(),
+    };
+    header.crc.update(&magic);
+    let fixed = header.bytes::<8>()?;
+    if fixed[0] != METHOD_DEFLATE {
+        return Err("Unknown gzip compression method");
+    }
+    let flags = fixed[1];
+    if flags & FLAG_RESERVED != 0 {
+        return Err("Unknown gzip header flags");
+    }
+
+    if flags & FLAG_EXTRA != 0 {
+        let len = u16::from_le_bytes(header.bytes()?);
+        for _ in 0..len {
+            header.bytes::<1>()?;
+        }
+    }
+    let name = match flags & FLAG_NAME {
+        0 => None,
+        _ => Some(header.string()?),
+    };
+    if flags & FLAG_COMMENT != 0 {
+        header.string()?;
+    }
+    if flags & FLAG_HCRC != 0 {
+        let crc = header.crc.finish() as u16;
+        if u16::from_le_bytes(header.bytes()?) != crc {
+            return Err("gzip header checksum mismatch");
+        }
+    }
+    Ok(Some((
+        name,
+        u32::from_le_bytes([fixed[2], fixed[3], fixed[4], fixed[5]]),
+    )))
+}
+
+impl<R: Read> Gzip<R> {
+    /// Reads the header of the first member
+    pub fn new(mut input: R) -> Result<Self, &'static str> {
+        let (name, mtime) = read_header(&mut input)?.ok_or("Not a gzip stream")?;
+        Ok(Gzip {
+            inflate: Inflate::new(input),
+            crc: Crc32::new(),
+            name,
+            mtime,
+            done: false,
+        })
+    }
+
+    /// Original file name stored in the header of the first member, if any
+    pub fn name(&self) -> Option<&[u8]> {
+        self.name.as_deref()
+    }
+
+    /// Modification time of the original file in seconds since the epoch, 0 if unknown
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+}
+
+impl<R: Read> Read for Gzip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        loop {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            let len = self.inflate.read(buf)?;
+            self.crc.update(&buf[..len]);
+            if len > 0 {
+                return Ok(len);
+            }
+
+            // CRC-32 and size modulo 2^32 of the uncompressed data of the member
+            let mut trailer = [0u8; 8];
+            self.inflate.read_trailer(&mut trailer)?;
+            let (crc, size) = trailer.split_at(4);
+            if crc != self.crc.finish().to_le_bytes() {
+                return Err("gzip checksum mismatch");
+            }
+            if size != (self.inflate.total_out() as u32).to_le_bytes() {
+                return Err("gzip size mismatch");
+            }
+
+            match read_header(&mut self.inflate.trailer())? {
+                Some(_) => {
+                    self.inflate.reset();
+                    self.crc = Crc32::new();
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
@@ -0,0 +1,451 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Raw deflate (RFC 1951) decoder
+//!
+//! The decoder keeps the 32 KiB history window and one block worth of
+//! Huffman tables, and decodes only as much as each ```read``` asks for.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::io::Read;
+
+const WINDOW_SIZE: usize = 32768;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const INPUT_SIZE: usize = 4096;
+const MAX_BITS: usize = 15;
+/// Codes up to this length are decoded with a single table lookup
+const FAST_BITS: u32 = 9;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Canonical Huffman code
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+    /// Symbol and length of short codes, indexed by the bit reversed code
+    fast: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, &'static str> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject over-subscribed codes, incomplete ones fail when an unused code is met
+        let mut left = 1i32;
+        for count in &counts[1..] {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err("Invalid deflate Huffman code");
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+
+        let mut fast = vec![0u16; 1 << FAST_BITS];
+        let mut code = 0u32;
+        let mut index = 0;
+        for len in 1..=FAST_BITS {
+            for _ in 0..counts[len as usize] {
+                let reversed = code.reverse_bits() >> (32 - len);
+                let entry = symbols[index] << 4 | len as u16;
+                for fill in (reversed..1 << FAST_BITS).step_by(1 << len) {
+                    fast[fill as usize] = entry;
+                }
+                code += 1;
+                index += 1;
+            }
+            code <<= 1;
+        }
+
+        Ok(Huffman {
+            counts,
+            symbols,
+            fast,
+        })
+    }
+
+    /// Code without symbols, until a block header sets the real ones
+    fn empty() -> Self {
+        Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: Vec::new(),
+            fast: vec![0; 1 << FAST_BITS],
+        }
+    }
+
+    fn fixed_literals() -> Result<Self, &'static str> {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        Huffman::new(&lengths)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    BlockHeader,
+    Stored(usize),
+    Compressed,
+    Done,
+}
+
+/// Raw deflate stream decoder
+pub struct Inflate<R: Read> {
+    input: R,
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+    buffer_len: usize,
+    input_done: bool,
+    bits: u64,
+    bit_count: u32,
+    window: Vec<u8>,
+    /// Bytes produced so far, the window position is taken from it
+    total_out: u64,
+    state: State,
+    last_block: bool,
+    literals: Huffman,
+    distances: Huffman,
+    /// Pending length and distance of a match partially copied out
+    copy: (usize, usize),
+}
+
+impl<R: Read> Inflate<R> {
+    pub fn new(input: R) -> Self {
+        Inflate {
+            input,
+            buffer: vec![0u8; INPUT_SIZE],
+            buffer_pos: 0,
+            buffer_len: 0,
+            input_done: false,
+            bits: 0,
+            bit_count: 0,
+            window: vec![0u8; WINDOW_SIZE],
+            total_out: 0,
+            state: State::BlockHeader,
+            last_block: false,
+            literals: Huffman::empty(),
+            distances: Huffman::empty(),
+            copy: (0, 0),
+        }
+    }
+
+    /// Whether the final block has been decoded completely
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done && self.copy.0 == 0
+    }
+
+    /// Amount of bytes decoded so far
+    pub fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, &'static str> {
+        if self.buffer_pos == self.buffer_len {
+            if self.input_done {
+                return Ok(None);
+            }
+            self.buffer_len = self.input.read(&mut self.buffer)?;
+            self.buffer_pos = 0;
+            if self.buffer_len == 0 {
+                self.input_done = true;
+                return Ok(None);
+            }
+        }
+        self.buffer_pos += 1;
+        Ok(Some(self.buffer[self.buffer_pos - 1]))
+    }
+
+    // Loads whole bytes into the bit buffer, as many as fit or remain
+    fn refill(&mut self) -> Result<(), &'static str> {
+        while self.bit_count <= 56 {
+            match self.next_byte()? {
+                Some(byte) => {
+                    self.bits |= (byte as u64) << self.bit_count;
+                    self.bit_count += 8;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn take_bits(&mut self, count: u32) -> Result<u32, &'static str> {
+        if self.bit_count < count {
+            self.refill()?;
+            if self.bit_count < count {
+                return Err("Truncated deflate stream");
+            }
+        }
+        let value = (self.bits & ((1 << count) - 1)) as u32;
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Reads bytes following the deflate stream, such as a container trailer
+    pub fn read_trailer(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        let partial = self.bit_count % 8;
+        self.take_bits(partial)?;
+        for byte in buf.iter_mut() {
+            *byte = self.take_bits(8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Input following the deflate stream, starting at the next byte boundary
+    pub fn trailer(&mut self) -> Trailer<'_, R> {
+        Trailer { inflate: self }
+    }
+
+    /// Starts decoding another deflate stream from the input that follows
+    pub fn reset(&mut self) {
+        self.state = State::BlockHeader;
+        self.last_block = false;
+        self.copy = (0, 0);
+        self.total_out = 0;
+    }
+
+    fn decode(&mut self, literals: bool) -> Result<u16, &'static str> {
+        if self.bit_count < MAX_BITS as u32 {
+            self.refill()?;
+        }
+        let code = match literals {
+            true => &self.literals,
+            false => &self.distances,
+        };
+
+        let entry = code.fast[(self.bits & ((1 << FAST_BITS) - 1)) as usize];
+        let len = (entry & 0xf) as u32;
+        if len != 0 && len <= self.bit_count {
+            self.bits >>= len;
+            self.bit_count -= len;
+            return Ok(entry >> 4);
+        }
+
+        // Canonical decoding one bit at a time for the longer codes
+        let (mut code_value, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            if self.bit_count == 0 {
+                return Err("Truncated deflate stream");
+            }
+            code_value |= (self.bits & 1) as i32;
+            self.bits >>= 1;
+            self.bit_count -= 1;
+            let count = code.counts[len] as i32;
+            if code_value - first < count {
+                return Ok(code.symbols[(index + code_value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code_value <<= 1;
+        }
+        Err("Invalid deflate Huffman code")
+    }
+
+    fn read_dynamic_tables(&mut self) -> Result<(), &'static str> {
+        let literal_count = self.take_bits(5)? as usize + 257;
+        let distance_count = self.take_bits(5)? as usize + 1;
+        let code_length_count = self.take_bits(4)? as usize + 4;
+        if literal_count > 286 || distance_count > 30 {
+            return Err("Invalid deflate block header");
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for index in &CODE_LENGTH_ORDER[..code_length_count] {
+            code_lengths[*index] = self.take_bits(3)? as u8;
+        }
+        // The code length code is decoded through the literal table
+        self.literals = Huffman::new(&code_lengths)?;
+
+        let mut lengths = [0u8; 286 + 30];
+        let total = literal_count + distance_count;
+        let mut index = 0;
+        while index < total {
+            let symbol = self.decode(true)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if index > 0 => (lengths[index - 1], 3 + self.take_bits(2)? as usize),
+                17 => (0, 3 + self.take_bits(3)? as usize),
+                18 => (0, 11 + self.take_bits(7)? as usize),
+                _ => return Err("Invalid deflate code lengths"),
+            };
+            if index + repeat > total {
+                return Err("Invalid deflate code lengths");
+            }
+            lengths[index..index + repeat].fill(value);
+            index += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err("Deflate block has no end code");
+        }
+
+        self.literals = Huffman::new(&lengths[..literal_count])?;
+        self.distances = Huffman::new(&lengths[literal_count..total])?;
+        Ok(())
+    }
+
+    fn block_header(&mut self) -> Result<(), &'static str> {
+        if self.last_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+        self.last_block = self.take_bits(1)? == 1;
+        self.state = match self.take_bits(2)? {
+            0 => {
+                let partial = self.bit_count % 8;
+                self.take_bits(partial)?;
+                let len = self.take_bits(16)?;
+                if len != !self.take_bits(16)? & 0xffff {
+                    return Err("Invalid deflate stored block length");
+                }
+                State::Stored(len as usize)
+            }
+            1 => {
+                self.literals = Huffman::fixed_literals()?;
+                self.distances = Huffman::new(&[5u8; 30])?;
+                State::Compressed
+            }
+            2 => {
+                self.read_dynamic_tables()?;
+                State::Compressed
+            }
+            _ => return Err("Invalid deflate block type"),
+        };
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.window[self.total_out as usize & WINDOW_MASK] = byte;
+        self.total_out += 1;
+    }
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut written = 0;
+        while written < buf.len() {
+            let (len, distance) = self.copy;
+            if len > 0 {
+                let count = len.min(buf.len() - written);
+                for out in &mut buf[written..written + count] {
+                    let byte = self.window[(self.total_out as usize - distance) & WINDOW_MASK];
+                    self.push(byte);
+                    *out = byte;
+                }
+                written += count;
+                self.copy.0 -= count;
+                continue;
+            }
+
+            match self.state {
+                State::BlockHeader => self.block_header()?,
+                State::Stored(0) => self.state = State::BlockHeader,
+                State::Stored(remaining) => {
+                    let byte = self.take_bits(8)? as u8;
+                    self.push(byte);
+                    buf[written] = byte;
+                    written += 1;
+                    self.state = State::Stored(remaining - 1);
+                }
+                State::Compressed => match self.decode(true)? {
+                    literal @ 0..=255 => {
+                        self.push(literal as u8);
+                        buf[written] = literal as u8;
+                        written += 1;
+                    }
+                    256 => self.state = State::BlockHeader,
+                    symbol => {
+                        let symbol = symbol as usize - 257;
+                        if symbol >= LENGTH_BASE.len() {
+                            return Err("Invalid deflate length code");
+                        }
+                        let len = LENGTH_BASE[symbol] as usize
+                            + self.take_bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+                        let symbol = self.decode(false)? as usize;
+                        if symbol >= DISTANCE_BASE.len() {
+                            return Err("Invalid deflate distance code");
+                        }
+                        let distance = DISTANCE_BASE[symbol] as usize
+                            + self.take_bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+                        if distance as u64 > self.total_out {
+                            return Err("Deflate distance is too far back");
+                        }
+                        self.copy = (len, distance);
+                    }
+                },
+                State::Done => break,
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Reader over the input of an [`Inflate`] after its deflate stream
+pub struct Trailer<'a, R: Read> {
+    inflate: &'a mut Inflate<R>,
+}
+
+impl<R: Read> Read for Trailer<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let inflate = &mut *self.inflate;
+        let partial = inflate.bit_count % 8;
+        inflate.take_bits(partial)?;
+        let mut len = 0;
+        for byte in buf.iter_mut() {
+            // Bytes already in the bit buffer come first
+            let next = match inflate.bit_count {
+                0 => inflate.next_byte()?,
+                _ => Some(inflate.take_bits(8)? as u8),
+            };
+            match next {
+                Some(next) => *byte = next,
+                None => break,
+            }
+            len += 1;
+        }
+        Ok(len)
+    }
+}
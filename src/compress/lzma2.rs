@@ -0,0 +1,638 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! LZMA2 decoder, the compression used inside xz files
+//!
+//! Compressed chunks are read whole, they are at most 64 KiB. The dictionary
+//! is a ring buffer that grows up to the dictionary size as data is decoded,
+//! so small files do not need memory for the whole dictionary.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::io::Read;
+
+const STATES: usize = 12;
+/// States up to this one were last a literal
+const LIT_STATES: usize = 7;
+const POS_STATES_MAX: usize = 1 << 4;
+const MATCH_LEN_MIN: usize = 2;
+const LEN_LOW_SYMBOLS: usize = 1 << 3;
+const LEN_MID_SYMBOLS: usize = 1 << 3;
+const LEN_HIGH_SYMBOLS: usize = 1 << 8;
+const DIST_STATES: usize = 4;
+const DIST_SLOTS: usize = 1 << 6;
+const DIST_MODEL_START: u32 = 4;
+const DIST_MODEL_END: u32 = 14;
+const FULL_DISTANCES: usize = 1 << (DIST_MODEL_END / 2);
+const ALIGN_BITS: u32 = 4;
+const ALIGN_SIZE: usize = 1 << ALIGN_BITS;
+const LITERAL_CODER_SIZE: usize = 0x300;
+const LITERAL_CODERS_MAX: usize = 1 << 4;
+
+const RC_TOP_VALUE: u32 = 1 << 24;
+const RC_BIT_MODEL_TOTAL_BITS: u32 = 11;
+const RC_BIT_MODEL_TOTAL: u16 = 1 << RC_BIT_MODEL_TOTAL_BITS;
+const RC_MOVE_BITS: u32 = 5;
+const RC_INIT_BYTES: usize = 5;
+const PROB_INIT: u16 = RC_BIT_MODEL_TOTAL / 2;
+
+/// Largest compressed chunk
+const CHUNK_MAX: usize = 1 << 16;
+
+/// Dictionary size stored in a one byte LZMA2 filter property, as in xz headers
+pub fn dict_size(property: u8) -> Result<u32, &'static str> {
+    match property {
+        0..=39 => Ok((2 | (property as u32 & 1)) << (property / 2 + 11)),
+        40 => Ok(u32::MAX),
+        _ => Err("Invalid LZMA2 dictionary size"),
+    }
+}
+
+// Range decoder over a compressed chunk read into memory
+struct RangeDecoder {
+    range: u32,
+    code: u32,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl RangeDecoder {
+    fn init(&mut self, len: usize) -> Result<(), &'static str> {
+        if len < RC_INIT_BYTES || self.buf[0] != 0 {
+            return Err("Invalid LZMA2 chunk");
+        }
+        self.range = u32::MAX;
+        self.code = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]);
+        self.pos = RC_INIT_BYTES;
+        self.len = len;
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        self.pos == self.len && self.code == 0
+    }
+
+    fn normalize(&mut self) -> Result<(), &'static str> {
+        if self.range < RC_TOP_VALUE {
+            if self.pos == self.len {
+                return Err("Truncated LZMA2 chunk");
+            }
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.buf[self.pos] as u32;
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> Result<usize, &'static str> {
+        self.normalize()?;
+        let bound = (self.range >> RC_BIT_MODEL_TOTAL_BITS) * *prob as u32;
+        if self.code < bound {
+            self.range = bound;
+            *prob += (RC_BIT_MODEL_TOTAL - *prob) >> RC_MOVE_BITS;
+            Ok(0)
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *prob -= *prob >> RC_MOVE_BITS;
+            Ok(1)
+        }
+    }
+
+    // Symbol coded most significant bit first, below ```probs.len()```
+    fn bittree(&mut self, probs: &mut [u16]) -> Result<usize, &'static str> {
+        let mut symbol = 1;
+        while symbol < probs.len() {
+            symbol = symbol * 2 + self.bit(&mut probs[symbol])?;
+        }
+        Ok(symbol - probs.len())
+    }
+
+    // Value coded least significant bit first, with the tree at ```probs[offset..]```
+    fn bittree_reverse(
+        &mut self,
+        probs: &mut [u16],
+        offset: usize,
+        bits: u32,
+    ) -> Result<u32, &'static str> {
+        let mut symbol = 1;
+        let mut value = 0;
+        for i in 0..bits {
+            let prob = probs
+                .get_mut(offset + symbol - 1)
+                .ok_or("Invalid LZMA2 distance")?;
+            let bit = self.bit(prob)?;
+            symbol = symbol * 2 + bit;
+            value |= (bit as u32) << i;
+        }
+        Ok(value)
+    }
+
+    // Bits with fixed probabilities
+    fn direct(&mut self, bits: u32) -> Result<u32, &'static str> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            self.normalize()?;
+            self.range >>= 1;
+            self.code = self.code.wrapping_sub(self.range);
+            let mask = 0u32.wrapping_sub(self.code >> 31);
+            self.code = self.code.wrapping_add(self.range & mask);
+            value = (value << 1).wrapping_add(mask.wrapping_add(1));
+        }
+        Ok(value)
+    }
+}
+
+// Ring buffer with the decoded data, output is copied out from ```start``` to ```pos```
+struct Dict {
+    buf: Vec<u8>,
+    size: usize,
+    pos: usize,
+    start: usize,
+    /// Bytes that can be referred to by matches
+    full: usize,
+    /// Bytes decoded since the last reset
+    total: u64,
+}
+
+impl Dict {
+    fn reset(&mut self) {
+        self.pos = 0;
+        self.start = 0;
+        self.full = 0;
+        self.total = 0;
+    }
+
+    // Byte ```dist + 1``` positions back, 0 before the start
+    fn get(&self, dist: usize) -> u8 {
+        if dist >= self.full {
+            return 0;
+        }
+        let index = match dist < self.pos {
+            true => self.pos - dist - 1,
+            false => self.pos + self.size - dist - 1,
+        };
+        self.buf.get(index).copied().unwrap_or(0)
+    }
+
+    fn put(&mut self, byte: u8) {
+        match self.buf.get_mut(self.pos) {
+            Some(slot) => *slot = byte,
+            None => self.buf.push(byte),
+        }
+        self.pos += 1;
+        self.full = self.size.min(self.full + 1);
+        self.total += 1;
+    }
+
+    // Copies up to ```len``` bytes from ```dist + 1``` back, leaving the rest of ```len``` when the limit is hit
+    fn repeat(&mut self, len: &mut usize, dist: usize, limit: usize) -> Result<(), &'static str> {
+        if *len == 0 {
+            return Ok(());
+        }
+        if dist >= self.full {
+            return Err("Invalid LZMA2 match distance");
+        }
+        let count = (*len).min(limit - self.pos);
+        *len -= count;
+        for _ in 0..count {
+            self.put(self.get(dist));
+        }
+        Ok(())
+    }
+
+    // Reads uncompressed data from the input
+    fn copy_from<R: Read>(&mut self, input: &mut R, limit: usize) -> Result<usize, &'static str> {
+        if self.buf.len() < limit {
+            self.buf.resize(limit, 0);
+        }
+        let len = input.read(&mut self.buf[self.pos..limit])?;
+        if len == 0 {
+            return Err("Truncated LZMA2 chunk");
+        }
+        self.pos += len;
+        self.full = self.size.min(self.full + len);
+        self.total += len as u64;
+        Ok(len)
+    }
+
+    fn copy_out(&mut self, out: &mut [u8]) -> usize {
+        let len = out.len().min(self.pos - self.start);
+        out[..len].copy_from_slice(&self.buf[self.start..self.start + len]);
+        self.start += len;
+        len
+    }
+}
+
+struct LengthDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; LEN_LOW_SYMBOLS]; POS_STATES_MAX],
+    mid: [[u16; LEN_MID_SYMBOLS]; POS_STATES_MAX],
+    high: [u16; LEN_HIGH_SYMBOLS],
+}
+
+impl LengthDecoder {
+    fn new() -> Self {
+        LengthDecoder {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; LEN_LOW_SYMBOLS]; POS_STATES_MAX],
+            mid: [[PROB_INIT; LEN_MID_SYMBOLS]; POS_STATES_MAX],
+            high: [PROB_INIT; LEN_HIGH_SYMBOLS],
+        }
+    }
+
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> Result<usize, &'static str> {
+        if rc.bit(&mut self.choice)? == 0 {
+            return Ok(MATCH_LEN_MIN + rc.bittree(&mut self.low[pos_state])?);
+        }
+        if rc.bit(&mut self.choice2)? == 0 {
+            return Ok(MATCH_LEN_MIN + LEN_LOW_SYMBOLS + rc.bittree(&mut self.mid[pos_state])?);
+        }
+        Ok(MATCH_LEN_MIN + LEN_LOW_SYMBOLS + LEN_MID_SYMBOLS + rc.bittree(&mut self.high)?)
+    }
+}
+
+// Adaptive probabilities of everything but literals
+struct Probs {
+    is_match: [[u16; POS_STATES_MAX]; STATES],
+    is_rep: [u16; STATES],
+    is_rep0: [u16; STATES],
+    is_rep1: [u16; STATES],
+    is_rep2: [u16; STATES],
+    is_rep0_long: [[u16; POS_STATES_MAX]; STATES],
+    dist_slot: [[u16; DIST_SLOTS]; DIST_STATES],
+    dist_special: [u16; FULL_DISTANCES - DIST_MODEL_END as usize],
+    dist_align: [u16; ALIGN_SIZE],
+    match_len: LengthDecoder,
+    rep_len: LengthDecoder,
+}
+
+impl Probs {
+    fn new() -> Self {
+        Probs {
+            is_match: [[PROB_INIT; POS_STATES_MAX]; STATES],
+            is_rep: [PROB_INIT; STATES],
+            is_rep0: [PROB_INIT; STATES],
+            is_rep1: [PROB_INIT; STATES],
+            is_rep2: [PROB_INIT; STATES],
+            is_rep0_long: [[PROB_INIT; POS_STATES_MAX]; STATES],
+            dist_slot: [[PROB_INIT; DIST_SLOTS]; DIST_STATES],
+            dist_special: [PROB_INIT; FULL_DISTANCES - DIST_MODEL_END as usize],
+            dist_align: [PROB_INIT; ALIGN_SIZE],
+            match_len: LengthDecoder::new(),
+            rep_len: LengthDecoder::new(),
+        }
+    }
+}
+
+// State of the LZMA decoder
+struct Lzma {
+    state: usize,
+    reps: [usize; 4],
+    /// Match bytes left to copy when the dictionary limit was hit
+    len: usize,
+    lc: u32,
+    lp_mask: usize,
+    pos_mask: usize,
+    probs: Probs,
+    literal: Vec<u16>,
+}
+
+impl Lzma {
+    fn new() -> Self {
+        Lzma {
+            state: 0,
+            reps: [0; 4],
+            len: 0,
+            lc: 0,
+            lp_mask: 0,
+            pos_mask: 0,
+            probs: Probs::new(),
+            literal: vec![PROB_INIT; LITERAL_CODER_SIZE * LITERAL_CODERS_MAX],
+        }
+    }
+
+    // Literal context bits, literal position bits and position bits from the properties byte
+    fn set_properties(&mut self, props: u8) -> Result<(), &'static str> {
+        let (lc, lp, pb) = (props % 9, props / 9 % 5, props / 45);
+        if props >= 9 * 5 * 5 || lc + lp > 4 {
+            return Err("Invalid LZMA2 properties");
+        }
+        self.lc = lc as u32;
+        self.lp_mask = (1 << lp) - 1;
+        self.pos_mask = (1 << pb) - 1;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.state = 0;
+        self.reps = [0; 4];
+        self.len = 0;
+        self.probs = Probs::new();
+        self.literal.fill(PROB_INIT);
+    }
+
+    fn literal(&mut self, rc: &mut RangeDecoder, dict: &mut Dict) -> Result<(), &'static str> {
+        let prev = dict.get(0) as usize;
+        let coder = (prev >> (8 - self.lc)) + ((dict.total as usize & self.lp_mask) << self.lc);
+        let probs = &mut self.literal[coder * LITERAL_CODER_SIZE..][..LITERAL_CODER_SIZE];
+
+        let mut symbol = 1;
+        if self.state < LIT_STATES {
+            while symbol < 0x100 {
+                symbol = symbol * 2 + rc.bit(&mut probs[symbol])?;
+            }
+        } else {
+            // Coded along the byte at the last match distance, while they agree
+            let mut match_byte = (dict.get(self.reps[0]) as usize) << 1;
+            let mut offset = 0x100;
+            while symbol < 0x100 {
+                let match_bit = match_byte & offset;
+                match_byte <<= 1;
+                let bit = rc.bit(&mut probs[offset + match_bit + symbol])?;
+                symbol = symbol * 2 + bit;
+                offset &= match bit {
+                    0 => !match_bit,
+                    _ => match_bit,
+                };
+            }
+        }
+        dict.put(symbol as u8);
+
+        self.state = match self.state {
+            0..=3 => 0,
+            4..=9 => self.state - 3,
+            _ => self.state - 6,
+        };
+        Ok(())
+    }
+
+    fn match_(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> Result<(), &'static str> {
+        self.state = if self.state < LIT_STATES { 7 } else { 10 };
+        self.reps.copy_within(0..3, 1);
+        self.len = self.probs.match_len.decode(rc, pos_state)?;
+
+        let dist_state = (self.len - MATCH_LEN_MIN).min(DIST_STATES - 1);
+        let slot = rc.bittree(&mut self.probs.dist_slot[dist_state])? as u32;
+        self.reps[0] = if slot < DIST_MODEL_START {
+            slot as usize
+        } else {
+            let bits = (slot >> 1) - 1;
+            let base = (2 | (slot & 1)) << bits;
+            let low = if slot < DIST_MODEL_END {
+                let offset = (base - slot) as usize;
+                rc.bittree_reverse(&mut self.probs.dist_special, offset, bits)?
+            } else {
+                let high = rc.direct(bits - ALIGN_BITS)? << ALIGN_BITS;
+                high | rc.bittree_reverse(&mut self.probs.dist_align, 1, ALIGN_BITS)?
+            };
+            // Distances of all ones mark the end of LZMA streams, LZMA2 does not use them
+            base.wrapping_add(low) as usize
+        };
+        Ok(())
+    }
+
+    fn rep_match(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> Result<(), &'static str> {
+        let state = self.state;
+        if rc.bit(&mut self.probs.is_rep0[state])? == 0 {
+            if rc.bit(&mut self.probs.is_rep0_long[state][pos_state])? == 0 {
+                self.state = if state < LIT_STATES { 9 } else { 11 };
+                self.len = 1;
+                return Ok(());
+            }
+        } else {
+            let dist = if rc.bit(&mut self.probs.is_rep1[state])? == 0 {
+                self.reps[1]
+            } else if rc.bit(&mut self.probs.is_rep2[state])? == 0 {
+                let dist = self.reps[2];
+                self.reps[2] = self.reps[1];
+                dist
+            } else {
+                let dist = self.reps[3];
+                self.reps.copy_within(1..3, 2);
+                dist
+            };
+            self.reps[1] = self.reps[0];
+            self.reps[0] = dist;
+        }
+        self.state = if state < LIT_STATES { 8 } else { 11 };
+        self.len = self.probs.rep_len.decode(rc, pos_state)?;
+        Ok(())
+    }
+
+    // Decodes into the dictionary until it reaches ```limit```
+    fn run(
+        &mut self,
+        rc: &mut RangeDecoder,
+        dict: &mut Dict,
+        limit: usize,
+    ) -> Result<(), &'static str> {
+        dict.repeat(&mut self.len, self.reps[0], limit)?;
+        while dict.pos < limit {
+            let pos_state = dict.total as usize & self.pos_mask;
+            if rc.bit(&mut self.probs.is_match[self.state][pos_state])? == 0 {
+                self.literal(rc, dict)?;
+            } else {
+                if rc.bit(&mut self.probs.is_rep[self.state])? == 0 {
+                    self.match_(rc, pos_state)?;
+                } else {
+                    self.rep_match(rc, pos_state)?;
+                }
+                dict.repeat(&mut self.len, self.reps[0], limit)?;
+            }
+        }
+        // Reads ahead what the next bit would, so the end of the chunk is found in place
+        rc.normalize()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Header,
+    Lzma(usize),
+    Uncompressed(usize),
+    End,
+}
+
+/// LZMA2 decoder reading from a separate input, so that containers can read around it
+pub struct Lzma2Decoder {
+    dict: Dict,
+    rc: RangeDecoder,
+    lzma: Lzma,
+    chunk: Chunk,
+    need_dict_reset: bool,
+    need_properties: bool,
+}
+
+impl Lzma2Decoder {
+    /// Decoder with a dictionary of up to ```dict_size``` bytes
+    pub fn new(dict_size: u32) -> Self {
+        let mut decoder = Lzma2Decoder {
+            dict: Dict {
+                buf: Vec::new(),
+                size: 0,
+                pos: 0,
+                start: 0,
+                full: 0,
+                total: 0,
+            },
+            rc: RangeDecoder {
+                range: 0,
+                code: 0,
+                buf: vec![0u8; CHUNK_MAX],
+                pos: 0,
+                len: 0,
+            },
+            lzma: Lzma::new(),
+            chunk: Chunk::Header,
+            need_dict_reset: true,
+            need_properties: true,
+        };
+        decoder.reset(dict_size);
+        decoder
+    }
+
+    /// Prepares to decode a new stream, keeping the allocated memory
+    pub fn reset(&mut self, dict_size: u32) {
+        // Matches refer to at most 4 GiB back
+        self.dict.size = (dict_size as usize).max(4096);
+        self.dict.buf.truncate(self.dict.size);
+        self.dict.reset();
+        self.chunk = Chunk::Header;
+        self.need_dict_reset = true;
+        self.need_properties = true;
+    }
+
+    /// Whether the end of the stream was decoded
+    pub fn is_done(&self) -> bool {
+        self.chunk == Chunk::End && self.dict.start == self.dict.pos
+    }
+
+    fn read_header<R: Read>(&mut self, input: &mut R) -> Result<(), &'static str> {
+        let mut control = [0u8];
+        input.read_exact(&mut control)?;
+        let control = control[0];
+        if control == 0 {
+            self.chunk = Chunk::End;
+            return Ok(());
+        }
+
+        if control >= 0xe0 || control == 0x01 {
+            self.need_properties = true;
+            self.need_dict_reset = false;
+            self.dict.reset();
+        } else if self.need_dict_reset {
+            return Err("LZMA2 stream does not start with a dictionary reset");
+        }
+
+        if control < 0x80 {
+            if control > 0x02 {
+                return Err("Invalid LZMA2 chunk");
+            }
+            let mut size = [0u8; 2];
+            input.read_exact(&mut size)?;
+            self.chunk = Chunk::Uncompressed(u16::from_be_bytes(size) as usize + 1);
+            return Ok(());
+        }
+
+        let mut sizes = [0u8; 4];
+        input.read_exact(&mut sizes)?;
+        let uncompressed = ((control as usize & 0x1f) << 16)
+            + u16::from_be_bytes([sizes[0], sizes[1]]) as usize
+            + 1;
+        let compressed = u16::from_be_bytes([sizes[2], sizes[3]]) as usize + 1;
+        if control >= 0xc0 {
+            let mut props = [0u8];
+            input.read_exact(&mut props)?;
+            self.lzma.set_properties(props[0])?;
+            self.need_properties = false;
+            self.lzma.reset();
+        } else if self.need_properties {
+            return Err("LZMA2 chunk without properties");
+        } else if control >= 0xa0 {
+            self.lzma.reset();
+        }
+
+        input.read_exact(&mut self.rc.buf[..compressed])?;
+        self.rc.init(compressed)?;
+        self.chunk = Chunk::Lzma(uncompressed);
+        Ok(())
+    }
+
+    /// Decodes up to ```out.len()``` bytes
+    ///
+    /// # Returns
+    ///
+    /// The amount of bytes decoded, 0 at the end of the stream. The input is
+    /// not read past the end marker.
+    pub fn decode<R: Read>(
+        &mut self,
+        input: &mut R,
+        out: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        loop {
+            if self.dict.start < self.dict.pos || out.is_empty() {
+                return Ok(self.dict.copy_out(out));
+            }
+            if self.dict.pos == self.dict.size {
+                self.dict.pos = 0;
+                self.dict.start = 0;
+            }
+
+            match self.chunk {
+                Chunk::Header => self.read_header(input)?,
+                Chunk::End => return Ok(0),
+                Chunk::Uncompressed(left) => {
+                    let limit = self.dict.size.min(self.dict.pos + left);
+                    let len = self.dict.copy_from(input, limit)?;
+                    self.chunk = match left - len {
+                        0 => Chunk::Header,
+                        left => Chunk::Uncompressed(left),
+                    };
+                }
+                Chunk::Lzma(left) => {
+                    let limit = self.dict.size.min(self.dict.pos + left);
+                    let start = self.dict.pos;
+                    self.lzma.run(&mut self.rc, &mut self.dict, limit)?;
+                    self.chunk = match left - (self.dict.pos - start) {
+                        0 if self.lzma.len == 0 && self.rc.is_finished() => Chunk::Header,
+                        0 => return Err("LZMA2 chunk does not end with its data"),
+                        left => Chunk::Lzma(left),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Raw LZMA2 stream decoder
+pub struct Lzma2<R: Read> {
+    input: R,
+    decoder: Lzma2Decoder,
+}
+
+impl<R: Read> Lzma2<R> {
+    pub fn new(input: R, dict_size: u32) -> Self {
+        Lzma2 {
+            input,
+            decoder: Lzma2Decoder::new(dict_size),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: Read> Read for Lzma2<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.decoder.decode(&mut self.input, buf)
+    }
+}
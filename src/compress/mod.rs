@@ -0,0 +1,130 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Streaming decompressors
+//!
+//! Decoders wrap any [```Read```] source and are readers
+//! themselves, so compressed kernels and filesystem extents can be expanded
+//! while they are read, without holding the compressed data in memory.
+//!
+//! The zstd and xz decoders are built with the ```zstd``` and ```xz```
+//! features, their formats are recognized either way. ```zstd``` is enabled
+//! by default, as btrfs needs it.
+
+pub mod gzip;
+pub mod inflate;
+#[cfg(feature = "xz")]
+pub mod lzma2;
+#[cfg(feature = "xz")]
+pub mod xz;
+pub mod zlib;
+#[cfg(feature = "zstd")]
+pub mod zstd;
+
+use alloc::boxed::Box;
+
+use crate::io::Read;
+
+/// Bytes needed to tell the supported formats apart
+pub const MAGIC_SIZE: usize = 6;
+
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Little endian 0xfd2fb528
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compressed file formats recognized by their magic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Format {
+    /// Format of a stream starting with ```magic```, None if it is not compressed
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&gzip::MAGIC) {
+            return Some(Format::Gzip);
+        }
+        if magic.starts_with(&XZ_MAGIC) {
+            return Some(Format::Xz);
+        }
+        if magic.starts_with(&ZSTD_MAGIC) {
+            return Some(Format::Zstd);
+        }
+        None
+    }
+}
+
+/// Stream whose first bytes were read ahead to look for a magic
+pub struct Peeked<R: Read> {
+    magic: [u8; MAGIC_SIZE],
+    len: usize,
+    pos: usize,
+    input: R,
+}
+
+impl<R: Read> Peeked<R> {
+    /// Reads the first bytes of the stream, fewer if it is shorter
+    pub fn new(mut input: R) -> Result<Self, &'static str> {
+        let mut magic = [0u8; MAGIC_SIZE];
+        let mut len = 0;
+        while len < MAGIC_SIZE {
+            match input.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(Peeked {
+            magic,
+            len,
+            pos: 0,
+            input,
+        })
+    }
+
+    /// First bytes of the stream
+    pub fn magic(&self) -> &[u8] {
+        &self.magic[..self.len]
+    }
+}
+
+impl<R: Read> Read for Peeked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let peeked = (self.len - self.pos).min(buf.len());
+        buf[..peeked].copy_from_slice(&self.magic[self.pos..self.pos + peeked]);
+        self.pos += peeked;
+        if peeked == buf.len() {
+            return Ok(peeked);
+        }
+        Ok(peeked + self.input.read(&mut buf[peeked..])?)
+    }
+}
+
+/// Decodes a stream if its magic is that of a known format, reads it as is otherwise
+///
+/// # Returns
+///
+/// The format found, and a reader for the uncompressed data
+pub fn decompress<'a, R: Read + 'a>(
+    input: R,
+) -> Result<(Option<Format>, Box<dyn Read + 'a>), &'static str> {
+    let input = Peeked::new(input)?;
+    let format = Format::detect(input.magic());
+    let reader: Box<dyn Read + 'a> = match format {
+        Some(Format::Gzip) => Box::new(gzip::Gzip::new(input)?),
+        #[cfg(feature = "xz")]
+        Some(Format::Xz) => Box::new(xz::Xz::new(input)?),
+        #[cfg(not(feature = "xz"))]
+        Some(Format::Xz) => return Err("xz support is not enabled"),
+        #[cfg(feature = "zstd")]
+        Some(Format::Zstd) => Box::new(zstd::Zstd::new(input)?),
+        #[cfg(not(feature = "zstd"))]
+        Some(Format::Zstd) => return Err("zstd support is not enabled"),
+        None => Box::new(input),
+    };
+    Ok((format, reader))
+}
@@ -0,0 +1,510 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! xz stream decoder, as used by compressed kernels and initrds
+//!
+//! Blocks are LZMA2 compressed, optionally after the PowerPC branch filter
+//! the kernel build applies to its images. CRC-32 and CRC-64 integrity checks
+//! are verified, as are the index and footer of each stream. Concatenated
+//! streams and stream padding are read as one.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::lzma2::{self, Lzma2Decoder};
+use super::XZ_MAGIC;
+use crate::crc32::{crc32, Crc32};
+use crate::io::Read;
+
+const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+const HEADER_SIZE: usize = 12;
+
+const CHECK_NONE: u8 = 0x00;
+const CHECK_CRC32: u8 = 0x01;
+const CHECK_CRC64: u8 = 0x04;
+
+const FILTER_POWERPC: u64 = 0x05;
+const FILTER_LZMA2: u64 = 0x21;
+
+const BLOCK_FLAGS_RESERVED: u8 = 0x3c;
+const BLOCK_COMPRESSED_SIZE: u8 = 0x40;
+const BLOCK_UNCOMPRESSED_SIZE: u8 = 0x80;
+
+/// Decoded data kept for the branch filter, which works on whole instructions
+const OUTPUT_SIZE: usize = 64 * 1024;
+
+const CRC64_POLYNOMIAL: u64 = 0xc96c_5795_d787_0f42;
+
+const fn make_crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ CRC64_POLYNOMIAL,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC64_TABLE: [u64; 256] = make_crc64_table();
+
+/// Incremental CRC-64 computation, with the ECMA-182 polynomial xz uses
+#[derive(Clone, Copy, Debug)]
+pub struct Crc64 {
+    state: u64,
+}
+
+impl Default for Crc64 {
+    fn default() -> Self {
+        Crc64::new()
+    }
+}
+
+impl Crc64 {
+    pub fn new() -> Self {
+        Crc64 { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state =
+                CRC64_TABLE[((self.state ^ *byte as u64) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// Checksum of the data fed so far
+    pub fn finish(&self) -> u64 {
+        !self.state
+    }
+}
+
+// Integrity check of the uncompressed data of a block
+#[derive(Clone, Copy)]
+enum Check {
+    None,
+    Crc32(Crc32),
+    Crc64(Crc64),
+}
+
+impl Check {
+    fn new(id: u8) -> Result<Self, &'static str> {
+        match id {
+            CHECK_NONE => Ok(Check::None),
+            CHECK_CRC32 => Ok(Check::Crc32(Crc32::new())),
+            CHECK_CRC64 => Ok(Check::Crc64(Crc64::new())),
+            _ => Err("Unsupported xz integrity check"),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Check::None => 0,
+            Check::Crc32(_) => 4,
+            Check::Crc64(_) => 8,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Check::None => (),
+            Check::Crc32(crc) => crc.update(data),
+            Check::Crc64(crc) => crc.update(data),
+        }
+    }
+
+    fn matches(&self, stored: &[u8]) -> bool {
+        match self {
+            Check::None => true,
+            Check::Crc32(crc) => stored == crc.finish().to_le_bytes(),
+            Check::Crc64(crc) => stored == crc.finish().to_le_bytes(),
+        }
+    }
+}
+
+// Counts the bytes read, for the sizes kept in the index
+struct Counted<R: Read> {
+    input: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = self.input.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+// Variable length integer, at most 9 bytes of 7 bits
+fn parse_vli(data: &[u8], pos: &mut usize) -> Result<u64, &'static str> {
+    let mut value = 0u64;
+    for i in 0..9 {
+        let byte = *data.get(*pos).ok_or("Truncated xz header")?;
+        *pos += 1;
+        if i > 0 && byte == 0 {
+            return Err("Invalid xz integer");
+        }
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Invalid xz integer")
+}
+
+// Reads a variable length integer, keeping its bytes for the checksum
+fn read_vli<R: Read>(input: &mut R, bytes: &mut Vec<u8>) -> Result<u64, &'static str> {
+    let start = bytes.len();
+    loop {
+        let mut byte = [0u8];
+        input.read_exact(&mut byte)?;
+        bytes.push(byte[0]);
+        if byte[0] & 0x80 == 0 || bytes.len() - start == 9 {
+            break;
+        }
+    }
+    let mut pos = start;
+    parse_vli(bytes, &mut pos)
+}
+
+// Flags of the stream header and footer, naming the integrity check
+fn parse_stream_flags(flags: &[u8]) -> Result<u8, &'static str> {
+    if flags[0] != 0 || flags[1] & 0xf0 != 0 {
+        return Err("Unsupported xz stream flags");
+    }
+    Ok(flags[1])
+}
+
+// Branch filter reverting the absolute addresses the encoder made of relative branches
+fn powerpc_decode(data: &mut [u8], pos: u32) {
+    for (i, instruction) in data.chunks_exact_mut(4).enumerate() {
+        let mut value = u32::from_be_bytes([
+            instruction[0],
+            instruction[1],
+            instruction[2],
+            instruction[3],
+        ]);
+        // Relative branch with link
+        if value & 0xfc00_0003 == 0x4800_0001 {
+            let address = pos.wrapping_add(4 * i as u32);
+            let target = (value & 0x03ff_fffc).wrapping_sub(address);
+            value = 0x4800_0001 | (target & 0x03ff_fffc);
+            instruction.copy_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Block,
+    Done,
+}
+
+struct Block {
+    /// Size of the block header and where the compressed data starts
+    header_size: u64,
+    data_start: u64,
+    compressed_size: Option<u64>,
+    uncompressed_size: Option<u64>,
+    uncompressed: u64,
+    /// Start offset of the branch filter, if used
+    powerpc: Option<u32>,
+    check: Check,
+}
+
+/// xz stream decoder
+pub struct Xz<R: Read> {
+    input: Counted<R>,
+    lzma2: Option<Lzma2Decoder>,
+    check_id: u8,
+    /// Unpadded and uncompressed sizes of the blocks read in the current stream
+    records: Vec<(u64, u64)>,
+    block: Option<Block>,
+    state: State,
+    output: Vec<u8>,
+    /// Decoded bytes in ```output```, those up to ```ready``` are filtered
+    filled: usize,
+    ready: usize,
+    pos: usize,
+}
+
+impl<R: Read> Xz<R> {
+    /// Reads the stream header
+    pub fn new(input: R) -> Result<Self, &'static str> {
+        let mut xz = Xz {
+            input: Counted { input, count: 0 },
+            lzma2: None,
+            check_id: CHECK_NONE,
+            records: Vec::new(),
+            block: None,
+            state: State::Block,
+            output: vec![0u8; OUTPUT_SIZE],
+            filled: 0,
+            ready: 0,
+            pos: 0,
+        };
+        let mut header = [0u8; HEADER_SIZE];
+        xz.input.read_exact(&mut header)?;
+        xz.start_stream(&header)?;
+        Ok(xz)
+    }
+
+    fn start_stream(&mut self, header: &[u8; HEADER_SIZE]) -> Result<(), &'static str> {
+        if header[..6] != XZ_MAGIC {
+            return Err("Not an xz stream");
+        }
+        if header[8..] != crc32(&header[6..8]).to_le_bytes() {
+            return Err("xz stream header checksum mismatch");
+        }
+        self.check_id = parse_stream_flags(&header[6..8])?;
+        Check::new(self.check_id)?;
+        self.records.clear();
+        Ok(())
+    }
+
+    // Reads a block header, or the index and footer when the stream ends
+    fn next_block(&mut self) -> Result<(), &'static str> {
+        let mut size = [0u8];
+        self.input.read_exact(&mut size)?;
+        if size[0] == 0 {
+            self.read_index()?;
+            return self.next_stream();
+        }
+
+        let header_size = (size[0] as usize + 1) * 4;
+        let mut header = vec![0u8; header_size];
+        header[0] = size[0];
+        self.input.read_exact(&mut header[1..])?;
+        let (header, crc) = header.split_at(header_size - 4);
+        if crc != crc32(header).to_le_bytes() {
+            return Err("xz block header checksum mismatch");
+        }
+
+        let flags = header[1];
+        if flags & BLOCK_FLAGS_RESERVED != 0 {
+            return Err("Unsupported xz block flags");
+        }
+        let mut pos = 2;
+        let compressed_size = match flags & BLOCK_COMPRESSED_SIZE {
+            0 => None,
+            _ => Some(parse_vli(header, &mut pos)?),
+        };
+        let uncompressed_size = match flags & BLOCK_UNCOMPRESSED_SIZE {
+            0 => None,
+            _ => Some(parse_vli(header, &mut pos)?),
+        };
+
+        let filters = (flags & 0x03) as usize + 1;
+        let mut powerpc = None;
+        let mut dict_size = None;
+        for i in 0..filters {
+            let id = parse_vli(header, &mut pos)?;
+            let len = parse_vli(header, &mut pos)? as usize;
+            let props = header
+                .get(pos..pos.saturating_add(len))
+                .ok_or("Truncated xz block header")?;
+            pos += len;
+            let last = i == filters - 1;
+            match (id, props, last) {
+                (FILTER_LZMA2, [props], true) => dict_size = Some(lzma2::dict_size(*props)?),
+                (FILTER_POWERPC, [], false) => powerpc = Some(0),
+                (FILTER_POWERPC, [a, b, c, d], false) => {
+                    let start = u32::from_le_bytes([*a, *b, *c, *d]);
+                    if start % 4 != 0 {
+                        return Err("Invalid xz branch filter offset");
+                    }
+                    powerpc = Some(start);
+                }
+                _ => return Err("Unsupported xz filter chain"),
+            }
+        }
+        if header[pos..].iter().any(|b| *b != 0) {
+            return Err("Invalid xz block header padding");
+        }
+        let dict_size = dict_size.ok_or("Unsupported xz filter chain")?;
+
+        match &mut self.lzma2 {
+            Some(lzma2) => lzma2.reset(dict_size),
+            None => self.lzma2 = Some(Lzma2Decoder::new(dict_size)),
+        }
+        self.block = Some(Block {
+            header_size: header_size as u64,
+            data_start: self.input.count,
+            compressed_size,
+            uncompressed_size,
+            uncompressed: 0,
+            powerpc,
+            check: Check::new(self.check_id)?,
+        });
+        Ok(())
+    }
+
+    // Checks the sizes, padding and integrity check once the block data ends
+    fn end_block(&mut self, block: Block) -> Result<(), &'static str> {
+        let compressed = self.input.count - block.data_start;
+        if block.compressed_size.is_some_and(|size| size != compressed)
+            || block
+                .uncompressed_size
+                .is_some_and(|size| size != block.uncompressed)
+        {
+            return Err("xz block size mismatch");
+        }
+
+        let mut padding = [0u8; 3];
+        let padding = &mut padding[..(4 - compressed as usize % 4) % 4];
+        self.input.read_exact(padding)?;
+        if padding.iter().any(|b| *b != 0) {
+            return Err("Invalid xz block padding");
+        }
+        let mut stored = [0u8; 8];
+        let stored = &mut stored[..block.check.size()];
+        self.input.read_exact(stored)?;
+        if !block.check.matches(stored) {
+            return Err("xz checksum mismatch");
+        }
+
+        let unpadded = block.header_size + compressed + block.check.size() as u64;
+        self.records.push((unpadded, block.uncompressed));
+        Ok(())
+    }
+
+    // Reads the index, after its indicator byte, and the stream footer
+    fn read_index(&mut self) -> Result<(), &'static str> {
+        let mut index = vec![0u8];
+        let count = read_vli(&mut self.input, &mut index)?;
+        if count != self.records.len() as u64 {
+            return Err("xz index does not match the blocks");
+        }
+        for i in 0..self.records.len() {
+            let unpadded = read_vli(&mut self.input, &mut index)?;
+            let uncompressed = read_vli(&mut self.input, &mut index)?;
+            if (unpadded, uncompressed) != self.records[i] {
+                return Err("xz index does not match the blocks");
+            }
+        }
+        let mut padding = [0u8; 3];
+        let padding = &mut padding[..(4 - index.len() % 4) % 4];
+        self.input.read_exact(padding)?;
+        if padding.iter().any(|b| *b != 0) {
+            return Err("Invalid xz index padding");
+        }
+        index.extend_from_slice(padding);
+        let mut crc = [0u8; 4];
+        self.input.read_exact(&mut crc)?;
+        if crc != crc32(&index).to_le_bytes() {
+            return Err("xz index checksum mismatch");
+        }
+
+        let mut footer = [0u8; HEADER_SIZE];
+        self.input.read_exact(&mut footer)?;
+        if footer[10..] != FOOTER_MAGIC || footer[..4] != crc32(&footer[4..10]).to_le_bytes() {
+            return Err("Invalid xz stream footer");
+        }
+        let backward_size =
+            (u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]) as u64 + 1) * 4;
+        if backward_size != index.len() as u64 + 4
+            || parse_stream_flags(&footer[8..10])? != self.check_id
+        {
+            return Err("xz stream footer does not match the stream");
+        }
+        Ok(())
+    }
+
+    // Skips stream padding and reads the header of the next stream, if any
+    fn next_stream(&mut self) -> Result<(), &'static str> {
+        loop {
+            let mut word = [0u8; 4];
+            let mut len = 0;
+            while len < word.len() {
+                match self.input.read(&mut word[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            match (len, word) {
+                (0, _) => {
+                    self.state = State::Done;
+                    return Ok(());
+                }
+                (4, [0, 0, 0, 0]) => continue,
+                (4, _) => {
+                    let mut header = [0u8; HEADER_SIZE];
+                    header[..4].copy_from_slice(&word);
+                    self.input.read_exact(&mut header[4..])?;
+                    return self.start_stream(&header);
+                }
+                _ => return Err("Invalid xz stream padding"),
+            }
+        }
+    }
+
+    // Decodes more data into the output buffer
+    fn fill(&mut self) -> Result<(), &'static str> {
+        // Bytes left over from the branch filter go first
+        self.output.copy_within(self.ready..self.filled, 0);
+        self.filled -= self.ready;
+        self.ready = 0;
+        self.pos = 0;
+
+        while self.ready == 0 {
+            if self.state == State::Done {
+                return Ok(());
+            }
+            let (block, lzma2) = match (&mut self.block, &mut self.lzma2) {
+                (Some(block), Some(lzma2)) => (block, lzma2),
+                _ => {
+                    self.next_block()?;
+                    continue;
+                }
+            };
+
+            let len = lzma2.decode(&mut self.input, &mut self.output[self.filled..])?;
+            block.uncompressed += len as u64;
+            self.filled += len;
+
+            self.ready = match (len, block.powerpc) {
+                (0, _) | (_, None) => self.filled,
+                (_, Some(start)) => {
+                    let aligned = self.filled - self.filled % 4;
+                    let pos = start.wrapping_add((block.uncompressed - self.filled as u64) as u32);
+                    powerpc_decode(&mut self.output[..aligned], pos);
+                    aligned
+                }
+            };
+            // The integrity check covers the data after the filters
+            block.check.update(&self.output[..self.ready]);
+            if len == 0 {
+                if let Some(block) = self.block.take() {
+                    self.end_block(block)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Xz<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.ready {
+            self.fill()?;
+        }
+        let len = buf.len().min(self.ready - self.pos);
+        buf[..len].copy_from_slice(&self.output[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! zlib (RFC 1950) stream decoder, as used by btrfs compressed extents
+
+use super::inflate::Inflate;
+use crate::io::Read;
+
+const ADLER_MODULUS: u32 = 65521;
+/// Bytes that can be summed before the 32-bit sums may overflow
+const ADLER_CHUNK: usize = 5552;
+
+/// Incremental Adler-32 computation
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Adler32::new()
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(ADLER_CHUNK) {
+            for byte in chunk {
+                self.a += *byte as u32;
+                self.b += self.a;
+            }
+            self.a %= ADLER_MODULUS;
+            self.b %= ADLER_MODULUS;
+        }
+    }
+
+    /// Checksum of the data fed so far
+    pub fn finish(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+/// zlib stream decoder, verifying the Adler-32 of the data at the end
+pub struct Zlib<R: Read> {
+    inflate: Inflate<R>,
+    adler: Adler32,
+    verified: bool,
+}
+
+impl<R: Read> Zlib<R> {
+    /// Reads the stream header
+    ///
+    /// Streams that need a preset dictionary are rejected.
+    pub fn new(mut input: R) -> Result<Self, &'static str> {
+        let mut header = [0u8; 2];
+        input.read_exact(&mut header)?;
+        if header[0] & 0xf != 8 || header[0] >> 4 > 7 || u16::from_be_bytes(header) % 31 != 0 {
+            return Err("Not a zlib stream");
+        }
+        if header[1] & 0x20 != 0 {
+            return Err("zlib preset dictionaries are not supported");
+        }
+        Ok(Zlib {
+            inflate: Inflate::new(input),
+            adler: Adler32::new(),
+            verified: false,
+        })
+    }
+}
+
+impl<R: Read> Read for Zlib<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = self.inflate.read(buf)?;
+        self.adler.update(&buf[..len]);
+        if len == 0 && !buf.is_empty() && !self.verified {
+            let mut trailer = [0u8; 4];
+            self.inflate.read_trailer(&mut trailer)?;
+            if u32::from_be_bytes(trailer) != self.adler.finish() {
+                return Err("zlib checksum mismatch");
+            }
+            self.verified = true;
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,1048 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Zstandard (RFC 8878) stream decoder, as used by compressed kernels, initrds and btrfs extents
+//!
+//! Blocks are decoded whole, they are at most 128 KiB. Decoded data is kept
+//! for as long as the frame's window requires. Content checksums are
+//! verified, dictionaries are not supported. Concatenated and skippable frames
+//! are read as one stream.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::ZSTD_MAGIC;
+use crate::io::Read;
+
+/// Skippable frames use magic numbers 0x184d2a50 to 0x184d2a5f
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a50;
+const BLOCK_SIZE_MAX: usize = 128 * 1024;
+const WINDOW_LOG_MAX: u32 = 31;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_COMPRESSED: u8 = 2;
+
+const HUFFMAN_BITS_MAX: u32 = 11;
+const HUFFMAN_WEIGHTS_LOG_MAX: u32 = 6;
+
+const LITERAL_LENGTH_LOG_MAX: u32 = 9;
+const MATCH_LENGTH_LOG_MAX: u32 = 9;
+const OFFSET_LOG_MAX: u32 = 8;
+
+const LITERAL_LENGTH_COUNTS: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_COUNTS: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OFFSET_COUNTS: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+/// Offset codes are bit counts, up to 31 bits
+const OFFSET_CODES: usize = 32;
+
+/// Base value and extra bits of each literal length code
+const LITERAL_LENGTHS: [(u32, u32); 36] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Base value and extra bits of match length codes past 31, below they are the code plus 3
+const MATCH_LENGTHS: [(u32, u32); 21] = [
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+fn highest_bit(value: u32) -> u32 {
+    31 - value.leading_zeros()
+}
+
+const PRIME64_1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME64_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME64_3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME64_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME64_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+/// Incremental XXH64 computation, the content checksum of zstd frames
+#[derive(Clone, Debug)]
+pub struct Xxh64 {
+    seed: u64,
+    acc: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+    total: u64,
+}
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge(hash: u64, acc: u64) -> u64 {
+    (hash ^ xxh64_round(0, acc))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl Xxh64 {
+    pub fn new(seed: u64) -> Self {
+        Xxh64 {
+            seed,
+            acc: [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ],
+            buffer: [0u8; 32],
+            buffered: 0,
+            total: 0,
+        }
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (i, acc) in self.acc.iter_mut().enumerate() {
+            *acc = xxh64_round(*acc, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        if self.buffered > 0 {
+            let len = data.len().min(32 - self.buffered);
+            self.buffer[self.buffered..self.buffered + len].copy_from_slice(&data[..len]);
+            self.buffered += len;
+            data = &data[len..];
+            if self.buffered < 32 {
+                return;
+            }
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+        let mut stripes = data.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Hash of the data fed so far
+    pub fn finish(&self) -> u64 {
+        let mut hash = if self.total >= 32 {
+            let [a, b, c, d] = self.acc;
+            let hash = a
+                .rotate_left(1)
+                .wrapping_add(b.rotate_left(7))
+                .wrapping_add(c.rotate_left(12))
+                .wrapping_add(d.rotate_left(18));
+            self.acc
+                .iter()
+                .fold(hash, |hash, acc| xxh64_merge(hash, *acc))
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total);
+
+        let mut rest = &self.buffer[..self.buffered];
+        while rest.len() >= 8 {
+            hash ^= xxh64_round(0, read_u64(rest));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let word = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as u64;
+            hash ^= word.wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            hash ^= (*byte as u64).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+// Little endian bit stream read forwards, for table descriptions
+struct ForwardBits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl ForwardBits<'_> {
+    fn read(&mut self, bits: u32) -> Result<u32, &'static str> {
+        let mut value = 0;
+        for i in 0..bits {
+            let byte = self
+                .data
+                .get(self.pos / 8)
+                .ok_or("Truncated zstd table description")?;
+            value |= ((*byte as u32 >> (self.pos % 8)) & 1) << i;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    /// Bytes used so far, counting the last partial one
+    fn bytes(&self) -> usize {
+        self.pos.div_ceil(8)
+    }
+}
+
+// Bit stream read backwards from the marker bit in its last byte, for entropy coded data
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// Bits left, negative once reads went past the start
+    pos: isize,
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        match data.last() {
+            Some(last) if *last != 0 => Ok(BackwardBits {
+                data,
+                pos: (data.len() as isize - 1) * 8 + highest_bit(*last as u32) as isize,
+            }),
+            _ => Err("Invalid zstd bit stream"),
+        }
+    }
+
+    // Up to 56 bits at a position, bits before the start read as zero
+    fn peek_at(&self, pos: isize, bits: u32) -> u64 {
+        if pos < 0 {
+            let shift = (-pos) as u32;
+            return match shift < bits {
+                true => self.peek_at(0, bits - shift) << shift,
+                false => 0,
+            };
+        }
+        let start = pos as usize / 8;
+        let mut word = [0u8; 8];
+        let available = self.data.len().saturating_sub(start).min(8);
+        word[..available].copy_from_slice(&self.data[start..start + available]);
+        (u64::from_le_bytes(word) >> (pos % 8)) & ((1 << bits) - 1)
+    }
+
+    fn peek(&self, bits: u32) -> u64 {
+        self.peek_at(self.pos - bits as isize, bits)
+    }
+
+    fn consume(&mut self, bits: u32) {
+        self.pos -= bits as isize;
+    }
+
+    fn read(&mut self, bits: u32) -> u64 {
+        if bits == 0 {
+            return 0;
+        }
+        let value = self.peek(bits);
+        self.consume(bits);
+        value
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    baseline: u16,
+}
+
+// Finite state entropy decoding table
+#[derive(Clone)]
+struct FseTable {
+    log: u32,
+    entries: Vec<FseEntry>,
+}
+
+impl FseTable {
+    fn rle(symbol: u8) -> Self {
+        FseTable {
+            log: 0,
+            entries: vec![FseEntry {
+                symbol,
+                bits: 0,
+                baseline: 0,
+            }],
+        }
+    }
+
+    // Spreads the symbols over the states by their normalized counts, -1 for less than one
+    fn from_counts(counts: &[i16], log: u32) -> Result<Self, &'static str> {
+        let size = 1usize << log;
+        let total: usize = counts.iter().map(|c| c.unsigned_abs() as usize).sum();
+        if total != size || counts.len() > 256 {
+            return Err("Invalid zstd FSE table");
+        }
+
+        let mut entries = vec![FseEntry::default(); size];
+        let mut next = vec![0u32; counts.len()];
+        let mut high = size;
+        for (symbol, count) in counts.iter().enumerate() {
+            if *count == -1 {
+                high -= 1;
+                entries[high].symbol = symbol as u8;
+                next[symbol] = 1;
+            } else {
+                next[symbol] = *count as u32;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (symbol, count) in counts.iter().enumerate() {
+            for _ in 0..(*count).max(0) {
+                entries[pos].symbol = symbol as u8;
+                pos = (pos + step) & (size - 1);
+                while pos >= high {
+                    pos = (pos + step) & (size - 1);
+                }
+            }
+        }
+        if pos != 0 {
+            return Err("Invalid zstd FSE table");
+        }
+
+        for entry in entries.iter_mut() {
+            let state = next[entry.symbol as usize];
+            next[entry.symbol as usize] += 1;
+            let bits = log - highest_bit(state);
+            entry.bits = bits as u8;
+            entry.baseline = ((state << bits) as usize - size) as u16;
+        }
+        Ok(FseTable { log, entries })
+    }
+
+    // Reads a table description, returning the table and the bytes it took
+    fn read(data: &[u8], log_max: u32, symbols: usize) -> Result<(Self, usize), &'static str> {
+        let mut bits = ForwardBits { data, pos: 0 };
+        let log = bits.read(4)? + 5;
+        if log > log_max {
+            return Err("Invalid zstd FSE table");
+        }
+
+        let mut remaining = 1i32 << log;
+        let mut counts = Vec::new();
+        while remaining > 0 && counts.len() < symbols {
+            // Values up to remaining + 1, the smaller ones with a bit less
+            let width = highest_bit(remaining as u32 + 1) + 1;
+            let threshold = (1 << width) - 1 - (remaining + 1);
+            let low_mask = (1 << (width - 1)) - 1;
+            let mut value = bits.read(width - 1)? as i32;
+            if value >= threshold {
+                value |= (bits.read(1)? as i32) << (width - 1);
+                if value > low_mask {
+                    value -= threshold;
+                }
+            }
+
+            let count = value - 1;
+            remaining -= count.abs();
+            counts.push(count as i16);
+            if count == 0 {
+                loop {
+                    let repeat = bits.read(2)?;
+                    counts.extend((0..repeat).map(|_| 0));
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        if remaining != 0 || counts.len() > symbols {
+            return Err("Invalid zstd FSE table");
+        }
+        Ok((FseTable::from_counts(&counts, log)?, bits.bytes()))
+    }
+
+    fn init(&self, bits: &mut BackwardBits) -> usize {
+        bits.read(self.log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    fn update(&self, state: usize, bits: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.baseline as usize + bits.read(entry.bits as u32) as usize
+    }
+}
+
+// Huffman decoding table for literals, indexed by the next bits of the stream
+#[derive(Clone)]
+struct Huffman {
+    bits: u32,
+    /// Symbol and code length
+    table: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    // Reads a tree description, returning the table and the bytes it took
+    fn read(data: &[u8]) -> Result<(Self, usize), &'static str> {
+        let header = *data.first().ok_or("Truncated zstd Huffman tree")?;
+        let mut weights = Vec::new();
+        let used = if header < 128 {
+            // Weights coded with two interleaved FSE states
+            let data = data
+                .get(1..1 + header as usize)
+                .ok_or("Truncated zstd Huffman tree")?;
+            let (table, used) = FseTable::read(data, HUFFMAN_WEIGHTS_LOG_MAX, 256)?;
+            let mut bits = BackwardBits::new(&data[used..])?;
+            let mut states = [table.init(&mut bits), table.init(&mut bits)];
+            'decode: loop {
+                for i in 0..2 {
+                    weights.push(table.symbol(states[i]));
+                    states[i] = table.update(states[i], &mut bits);
+                    if bits.pos < 0 {
+                        weights.push(table.symbol(states[1 - i]));
+                        break 'decode;
+                    }
+                    if weights.len() > 255 {
+                        return Err("Invalid zstd Huffman tree");
+                    }
+                }
+            }
+            1 + header as usize
+        } else {
+            // Four bits per weight
+            let count = header as usize - 127;
+            let data = data
+                .get(1..1 + count.div_ceil(2))
+                .ok_or("Truncated zstd Huffman tree")?;
+            for i in 0..count {
+                weights.push(match i % 2 {
+                    0 => data[i / 2] >> 4,
+                    _ => data[i / 2] & 0xf,
+                });
+            }
+            1 + data.len()
+        };
+
+        // The weight of the last symbol completes a power of two
+        if weights.len() > 255 || weights.iter().any(|w| *w as u32 > HUFFMAN_BITS_MAX) {
+            return Err("Invalid zstd Huffman tree");
+        }
+        let total: u32 = weights
+            .iter()
+            .filter(|w| **w > 0)
+            .map(|w| 1 << (w - 1))
+            .sum();
+        if total == 0 {
+            return Err("Invalid zstd Huffman tree");
+        }
+        let bits = highest_bit(total) + 1;
+        let left = (1 << bits) - total;
+        if bits > HUFFMAN_BITS_MAX || !left.is_power_of_two() {
+            return Err("Invalid zstd Huffman tree");
+        }
+        weights.push(highest_bit(left) as u8 + 1);
+
+        let lengths: Vec<u32> = weights
+            .iter()
+            .map(|w| match w {
+                0 => 0,
+                w => bits + 1 - *w as u32,
+            })
+            .collect();
+        let mut rank = [0usize; HUFFMAN_BITS_MAX as usize + 2];
+        for length in lengths.iter().filter(|l| **l > 0) {
+            rank[*length as usize] += 1;
+        }
+        // Longer codes come first in the table
+        let mut start = [0usize; HUFFMAN_BITS_MAX as usize + 2];
+        for length in (1..=bits as usize).rev() {
+            start[length - 1] = start[length] + (rank[length] << (bits as usize - length));
+        }
+        let mut table = vec![(0u8, 0u8); 1 << bits];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length == 0 {
+                continue;
+            }
+            let len = 1 << (bits - length);
+            let first = start[*length as usize];
+            table[first..first + len].fill((symbol as u8, *length as u8));
+            start[*length as usize] += len;
+        }
+        Ok((Huffman { bits, table }, used))
+    }
+
+    fn decode_stream(&self, data: &[u8], out: &mut [u8]) -> Result<(), &'static str> {
+        let mut bits = BackwardBits::new(data)?;
+        for byte in out.iter_mut() {
+            let (symbol, length) = self.table[bits.peek(self.bits) as usize];
+            *byte = symbol;
+            bits.consume(length as u32);
+        }
+        match bits.pos {
+            0 => Ok(()),
+            _ => Err("Corrupted zstd literals"),
+        }
+    }
+}
+
+struct Frame {
+    window: usize,
+    block_max: usize,
+    content_size: Option<u64>,
+    produced: u64,
+    checksum: Option<Xxh64>,
+    last_block: bool,
+}
+
+/// zstd stream decoder
+pub struct Zstd<R: Read> {
+    input: R,
+    /// Decoded data, kept back to the window size for matches
+    history: Vec<u8>,
+    served: usize,
+    frame: Option<Frame>,
+    block: Vec<u8>,
+    literals: Vec<u8>,
+    huffman: Option<Huffman>,
+    /// Literal length, offset and match length tables kept for the repeat mode
+    tables: [Option<FseTable>; 3],
+    reps: [usize; 3],
+}
+
+impl<R: Read> Zstd<R> {
+    /// Reads the first frame header
+    pub fn new(input: R) -> Result<Self, &'static str> {
+        let mut zstd = Zstd {
+            input,
+            history: Vec::new(),
+            served: 0,
+            frame: None,
+            block: vec![0u8; BLOCK_SIZE_MAX],
+            literals: Vec::new(),
+            huffman: None,
+            tables: [None, None, None],
+            reps: [1, 4, 8],
+        };
+        if !zstd.next_frame()? {
+            return Err("Not a zstd stream");
+        }
+        Ok(zstd)
+    }
+
+    // Skips skippable frames and reads the header of the next frame, false at the end of the input
+    fn next_frame(&mut self) -> Result<bool, &'static str> {
+        loop {
+            let mut magic = [0u8; 4];
+            let mut len = 0;
+            while len < magic.len() {
+                match self.input.read(&mut magic[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            match len {
+                0 => return Ok(false),
+                4 => (),
+                _ => return Err("Truncated zstd frame"),
+            }
+
+            if magic == ZSTD_MAGIC {
+                self.read_frame_header()?;
+                return Ok(true);
+            }
+            if u32::from_le_bytes(magic) & !0xf != SKIPPABLE_MAGIC {
+                return Err("Not a zstd frame");
+            }
+            let mut size = [0u8; 4];
+            self.input.read_exact(&mut size)?;
+            let mut left = u32::from_le_bytes(size) as usize;
+            while left > 0 {
+                let len = left.min(self.block.len());
+                self.input.read_exact(&mut self.block[..len])?;
+                left -= len;
+            }
+        }
+    }
+
+    fn read_frame_header(&mut self) -> Result<(), &'static str> {
+        let mut descriptor = [0u8];
+        self.input.read_exact(&mut descriptor)?;
+        let descriptor = descriptor[0];
+        let single_segment = descriptor & 0x20 != 0;
+        if descriptor & 0x08 != 0 {
+            return Err("Unsupported zstd frame header");
+        }
+
+        let mut window = 0;
+        if !single_segment {
+            let mut byte = [0u8];
+            self.input.read_exact(&mut byte)?;
+            let log = 10 + (byte[0] >> 3) as u32;
+            if log > WINDOW_LOG_MAX {
+                return Err("zstd window is too large");
+            }
+            let base = 1u64 << log;
+            window = base + (base / 8) * (byte[0] & 7) as u64;
+        }
+
+        let mut dictionary = [0u8; 4];
+        let len = [0, 1, 2, 4][(descriptor & 3) as usize];
+        self.input.read_exact(&mut dictionary[..len])?;
+        if dictionary != [0; 4] {
+            return Err("zstd dictionaries are not supported");
+        }
+
+        let len = match (descriptor >> 6, single_segment) {
+            (0, false) => 0,
+            (0, true) => 1,
+            (1, _) => 2,
+            (2, _) => 4,
+            _ => 8,
+        };
+        let mut size = [0u8; 8];
+        self.input.read_exact(&mut size[..len])?;
+        let content_size = match len {
+            0 => None,
+            2 => Some(u64::from_le_bytes(size) + 256),
+            _ => Some(u64::from_le_bytes(size)),
+        };
+        if single_segment {
+            window = content_size.unwrap_or(0);
+        }
+
+        let window = usize::try_from(window).map_err(|_| "zstd window is too large")?;
+        self.frame = Some(Frame {
+            window,
+            block_max: window.min(BLOCK_SIZE_MAX),
+            content_size,
+            produced: 0,
+            checksum: match descriptor & 0x04 {
+                0 => None,
+                _ => Some(Xxh64::new(0)),
+            },
+            last_block: false,
+        });
+        self.history.clear();
+        self.served = 0;
+        self.huffman = None;
+        self.tables = [None, None, None];
+        self.reps = [1, 4, 8];
+        Ok(())
+    }
+
+    // Checks the content size and checksum once the last block is decoded
+    fn end_frame(&mut self, frame: Frame) -> Result<(), &'static str> {
+        if frame
+            .content_size
+            .is_some_and(|size| size != frame.produced)
+        {
+            return Err("zstd frame size mismatch");
+        }
+        if let Some(checksum) = frame.checksum {
+            let mut stored = [0u8; 4];
+            self.input.read_exact(&mut stored)?;
+            if stored != (checksum.finish() as u32).to_le_bytes() {
+                return Err("zstd checksum mismatch");
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_block(&mut self, frame: &mut Frame) -> Result<(), &'static str> {
+        // Data older than the window is dropped once enough accumulates
+        if self.history.len() > 2 * frame.window.max(BLOCK_SIZE_MAX) {
+            let old = self.history.len() - frame.window;
+            self.history.drain(..old);
+            self.served -= old;
+        }
+
+        let mut header = [0u8; 4];
+        self.input.read_exact(&mut header[..3])?;
+        let header = u32::from_le_bytes(header);
+        frame.last_block = header & 1 != 0;
+        let size = (header >> 3) as usize;
+        if size > frame.block_max {
+            return Err("zstd block is too large");
+        }
+
+        let start = self.history.len();
+        match (header >> 1) & 3 {
+            BLOCK_RAW => {
+                self.history.resize(start + size, 0);
+                self.input.read_exact(&mut self.history[start..])?;
+            }
+            BLOCK_RLE => {
+                let mut byte = [0u8];
+                self.input.read_exact(&mut byte)?;
+                self.history.resize(start + size, byte[0]);
+            }
+            BLOCK_COMPRESSED => {
+                self.input.read_exact(&mut self.block[..size])?;
+                let block = core::mem::take(&mut self.block);
+                let result = self.decode_compressed(&block[..size], frame.block_max);
+                self.block = block;
+                result?;
+            }
+            _ => return Err("Invalid zstd block type"),
+        }
+
+        frame.produced += (self.history.len() - start) as u64;
+        if let Some(checksum) = &mut frame.checksum {
+            checksum.update(&self.history[start..]);
+        }
+        Ok(())
+    }
+
+    fn decode_compressed(&mut self, block: &[u8], block_max: usize) -> Result<(), &'static str> {
+        let used = self.decode_literals(block, block_max)?;
+        let start = self.history.len();
+        self.decode_sequences(&block[used..])?;
+        if self.history.len() - start > block_max {
+            return Err("zstd block is too large");
+        }
+        Ok(())
+    }
+
+    // Decodes the literals section into ```self.literals```, returning its size
+    fn decode_literals(&mut self, block: &[u8], block_max: usize) -> Result<usize, &'static str> {
+        let truncated = "Truncated zstd literals";
+        let first = *block.first().ok_or(truncated)? as usize;
+        let kind = (first & 3) as u8;
+        let format = (first >> 2) & 3;
+        let byte = |i: usize| block.get(i).map(|b| *b as usize).ok_or(truncated);
+
+        if kind == LITERALS_RAW || kind == LITERALS_RLE {
+            let (size, header) = match format {
+                0 | 2 => (first >> 3, 1),
+                1 => ((first >> 4) + (byte(1)? << 4), 2),
+                _ => ((first >> 4) + (byte(1)? << 4) + (byte(2)? << 12), 3),
+            };
+            if size > block_max {
+                return Err("Invalid zstd literals");
+            }
+            self.literals.clear();
+            return match kind {
+                LITERALS_RAW => {
+                    let data = block.get(header..header + size).ok_or(truncated)?;
+                    self.literals.extend_from_slice(data);
+                    Ok(header + size)
+                }
+                _ => {
+                    self.literals.resize(size, byte(header)? as u8);
+                    Ok(header + 1)
+                }
+            };
+        }
+
+        let (streams, header, bits) = match format {
+            0 => (1, 3, 10),
+            1 => (4, 3, 10),
+            2 => (4, 4, 14),
+            _ => (4, 5, 18),
+        };
+        let mut value = 0u64;
+        for i in 0..header {
+            value |= (byte(i)? as u64) << (8 * i);
+        }
+        let mask = (1 << bits) - 1;
+        let size = ((value >> 4) & mask) as usize;
+        let compressed = ((value >> (4 + bits)) & mask) as usize;
+        let mut data = block.get(header..header + compressed).ok_or(truncated)?;
+        if size > block_max {
+            return Err("Invalid zstd literals");
+        }
+
+        if kind == LITERALS_COMPRESSED {
+            let (huffman, used) = Huffman::read(data)?;
+            self.huffman = Some(huffman);
+            data = &data[used..];
+        }
+        let huffman = self
+            .huffman
+            .as_ref()
+            .ok_or("zstd literals without a tree")?;
+        self.literals.resize(size, 0);
+        if streams == 1 {
+            huffman.decode_stream(data, &mut self.literals)?;
+        } else {
+            let jump = data.get(..6).ok_or(truncated)?;
+            let sizes = [
+                u16::from_le_bytes([jump[0], jump[1]]) as usize,
+                u16::from_le_bytes([jump[2], jump[3]]) as usize,
+                u16::from_le_bytes([jump[4], jump[5]]) as usize,
+            ];
+            let segment = size.div_ceil(4);
+            if segment == 0 || 3 * segment > size {
+                return Err("Invalid zstd literals");
+            }
+            let mut data = &data[6..];
+            for (i, out) in self.literals.chunks_mut(segment).enumerate() {
+                let len = match i {
+                    3 => data.len(),
+                    i => sizes[i],
+                };
+                let stream = data.get(..len).ok_or(truncated)?;
+                huffman.decode_stream(stream, out)?;
+                data = &data[len..];
+            }
+        }
+        Ok(header + compressed)
+    }
+
+    // Table for one of the sequence symbols, by its compression mode
+    fn read_table(
+        &mut self,
+        index: usize,
+        mode: u8,
+        data: &[u8],
+        pos: &mut usize,
+    ) -> Result<(), &'static str> {
+        let (counts, log, log_max, symbols): (&[i16], u32, u32, usize) = match index {
+            0 => (&LITERAL_LENGTH_COUNTS, 6, LITERAL_LENGTH_LOG_MAX, 36),
+            1 => (&OFFSET_COUNTS, 5, OFFSET_LOG_MAX, OFFSET_CODES),
+            _ => (&MATCH_LENGTH_COUNTS, 6, MATCH_LENGTH_LOG_MAX, 53),
+        };
+        self.tables[index] = Some(match mode {
+            MODE_PREDEFINED => FseTable::from_counts(counts, log)?,
+            MODE_RLE => {
+                let symbol = *data.get(*pos).ok_or("Truncated zstd sequences")?;
+                *pos += 1;
+                if symbol as usize >= symbols {
+                    return Err("Invalid zstd sequences");
+                }
+                FseTable::rle(symbol)
+            }
+            MODE_COMPRESSED => {
+                let (table, used) = FseTable::read(&data[*pos..], log_max, symbols)?;
+                *pos += used;
+                table
+            }
+            _ => match self.tables[index].take() {
+                Some(table) => table,
+                None => return Err("zstd sequences repeat a missing table"),
+            },
+        });
+        Ok(())
+    }
+
+    // Decodes the sequences section and executes the sequences into the history
+    fn decode_sequences(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        let truncated = "Truncated zstd sequences";
+        let byte = |i: usize| data.get(i).map(|b| *b as usize).ok_or(truncated);
+        let (count, mut pos) = match byte(0)? {
+            0 => (0, 1),
+            first @ 1..=127 => (first, 1),
+            first @ 128..=254 => (((first - 128) << 8) + byte(1)?, 2),
+            _ => (byte(1)? + (byte(2)? << 8) + 0x7f00, 3),
+        };
+        if count == 0 {
+            if pos != data.len() {
+                return Err("Invalid zstd sequences");
+            }
+            self.history.extend_from_slice(&self.literals);
+            return Ok(());
+        }
+
+        let modes = byte(pos)? as u8;
+        pos += 1;
+        if modes & 3 != 0 {
+            return Err("Invalid zstd sequences");
+        }
+        self.read_table(0, modes >> 6, data, &mut pos)?;
+        self.read_table(1, (modes >> 4) & 3, data, &mut pos)?;
+        self.read_table(2, (modes >> 2) & 3, data, &mut pos)?;
+        let (literal_lengths, offsets, match_lengths) = match &self.tables {
+            [Some(ll), Some(of), Some(ml)] => (ll, of, ml),
+            _ => return Err("Invalid zstd sequences"),
+        };
+
+        let mut bits = BackwardBits::new(&data[pos..])?;
+        let mut ll_state = literal_lengths.init(&mut bits);
+        let mut of_state = offsets.init(&mut bits);
+        let mut ml_state = match_lengths.init(&mut bits);
+        let mut literal = 0;
+        for i in 0..count {
+            let of_code = offsets.symbol(of_state) as u32;
+            let ml_code = match_lengths.symbol(ml_state) as usize;
+            let ll_code = literal_lengths.symbol(ll_state) as usize;
+            if of_code as usize >= OFFSET_CODES {
+                return Err("Invalid zstd sequences");
+            }
+
+            let offset = (1u64 << of_code) + bits.read(of_code);
+            let match_len = match ml_code {
+                0..=31 => ml_code + 3,
+                code => {
+                    let (base, extra) = MATCH_LENGTHS[code - 32];
+                    base as usize + bits.read(extra) as usize
+                }
+            };
+            let (base, extra) = LITERAL_LENGTHS[ll_code];
+            let literal_len = base as usize + bits.read(extra) as usize;
+            if i != count - 1 {
+                ll_state = literal_lengths.update(ll_state, &mut bits);
+                ml_state = match_lengths.update(ml_state, &mut bits);
+                of_state = offsets.update(of_state, &mut bits);
+            }
+
+            // Offsets up to 3 name recent offsets, shifted by one after no literals
+            let offset = if offset > 3 {
+                let offset = offset as usize - 3;
+                self.reps = [offset, self.reps[0], self.reps[1]];
+                offset
+            } else {
+                match offset as usize + (literal_len == 0) as usize {
+                    1 => self.reps[0],
+                    2 => {
+                        self.reps.swap(0, 1);
+                        self.reps[0]
+                    }
+                    3 => {
+                        self.reps = [self.reps[2], self.reps[0], self.reps[1]];
+                        self.reps[0]
+                    }
+                    _ => {
+                        let offset = self.reps[0].wrapping_sub(1);
+                        self.reps = [offset, self.reps[0], self.reps[1]];
+                        offset
+                    }
+                }
+            };
+
+            let end = literal + literal_len;
+            let literals = self.literals.get(literal..end).ok_or(truncated)?;
+            self.history.extend_from_slice(literals);
+            literal = end;
+
+            if offset == 0 || offset > self.history.len() {
+                return Err("Invalid zstd match offset");
+            }
+            if match_len > BLOCK_SIZE_MAX {
+                return Err("Invalid zstd sequences");
+            }
+            let from = self.history.len() - offset;
+            if offset >= match_len {
+                self.history.extend_from_within(from..from + match_len);
+            } else {
+                for i in 0..match_len {
+                    self.history.push(self.history[from + i]);
+                }
+            }
+        }
+        if bits.pos != 0 {
+            return Err("Corrupted zstd sequences");
+        }
+        self.history.extend_from_slice(&self.literals[literal..]);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Zstd<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        while self.served == self.history.len() && !buf.is_empty() {
+            let mut frame = match self.frame.take() {
+                Some(frame) => frame,
+                None => return Ok(0),
+            };
+            if frame.last_block {
+                self.end_frame(frame)?;
+                if !self.next_frame()? {
+                    return Ok(0);
+                }
+                continue;
+            }
+            let result = self.decode_block(&mut frame);
+            self.frame = Some(frame);
+            result?;
+        }
+
+        let len = buf.len().min(self.history.len() - self.served);
+        buf[..len].copy_from_slice(&self.history[self.served..self.served + len]);
+        self.served += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! cpio archives in the ```newc``` and ```crc``` formats, as used by Linux initramfs
+//!
+//! An initramfs is one or more archives concatenated, each ending with a
+//! ```TRAILER!!!``` entry and separated by zero padding. Extra files can be
+//! handed to the kernel without rebuilding the initramfs by appending a new
+//! archive with [`Writer`] and [`concat()`].
+//!
+//! Compressed archives must be decompressed with [`crate::compress`] before
+//! their entries can be read, [`Entries`] fails when it finds one.
+
+use alloc::vec::Vec;
+use core::str;
+
+use crate::fs::{FileType, MODE_DIRECTORY, MODE_REGULAR, MODE_SYMLINK};
+use crate::load::ClaimedRegion;
+use crate::PROM;
+
+/// Header magic of ```newc``` archives
+pub const MAGIC_NEWC: &[u8; 6] = b"070701";
+/// Header magic of ```crc``` archives, ```newc``` with a checksum of the file data
+pub const MAGIC_CRC: &[u8; 6] = b"070702";
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_PERMISSIONS: u32 = 0o7777;
+
+// Archive members start on 4 byte boundaries
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+// Sum of the bytes of the file data, the ```crc``` format checksum
+fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32))
+}
+
+/// Archive member
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Path in the archive, usually without a leading '/'
+    pub name: &'a str,
+    pub ino: u32,
+    /// File type and permission bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    /// Modification time in seconds since the epoch
+    pub mtime: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    /// File contents, or target of a symbolic link
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn kind(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind() == FileType::Symlink
+    }
+}
+
+/// Iterator over the members of concatenated archives in memory
+///
+/// Trailer entries are not returned. Iteration stops at the end of the data
+/// or at the first error.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    pos: usize,
+    failed: bool,
+}
+
+/// Iterates the members of the archives in ```data```
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries {
+        data,
+        pos: 0,
+        failed: false,
+    }
+}
+
+impl<'a> Entries<'a> {
+    /// Offset of the next header in the data
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        loop {
+            // Padding between archives, headers stay 4 byte aligned
+            while self.pos + 4 <= self.data.len() && self.data[self.pos..self.pos + 4] == [0; 4] {
+                self.pos += 4;
+            }
+            let rest = &self.data[self.pos.min(self.data.len())..];
+            if rest.iter().all(|byte| *byte == 0) {
+                self.pos = self.data.len();
+                return Ok(None);
+            }
+            let header = rest.get(..HEADER_SIZE).ok_or("Truncated cpio header")?;
+            let magic = &header[..6];
+            if magic != MAGIC_NEWC && magic != MAGIC_CRC {
+                return Err("Not a newc or crc cpio archive");
+            }
+
+            // Thirteen 8 digit hexadecimal fields follow the magic
+            let field = |index: usize| {
+                str::from_utf8(&header[6 + index * 8..14 + index * 8])
+                    .ok()
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .ok_or("Invalid cpio header field")
+            };
+            let file_size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_end = HEADER_SIZE
+                .checked_add(name_size)
+                .ok_or("Truncated cpio file name")?;
+            let name = match rest.get(HEADER_SIZE..name_end) {
+                Some([name @ .., 0]) => name,
+                Some(_) => return Err("cpio file name is not null terminated"),
+                None => return Err("Truncated cpio file name"),
+            };
+            let name = str::from_utf8(name).map_err(|_| "cpio file name is not UTF-8")?;
+            let data_start = align4(name_end);
+            let data = data_start
+                .checked_add(file_size)
+                .and_then(|data_end| rest.get(data_start..data_end))
+                .ok_or("Truncated cpio file data")?;
+            if magic == MAGIC_CRC && checksum(data) != field(12)? {
+                return Err("cpio file checksum mismatch");
+            }
+            self.pos += align4(data_start + file_size);
+
+            if name == TRAILER {
+                continue;
+            }
+            return Ok(Some(Entry {
+                name,
+                ino: field(0)?,
+                mode: field(1)?,
+                uid: field(2)?,
+                gid: field(3)?,
+                nlink: field(4)?,
+                mtime: field(5)?,
+                dev_major: field(7)?,
+                dev_minor: field(8)?,
+                rdev_major: field(9)?,
+                rdev_minor: field(10)?,
+                data,
+            }));
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.next_entry();
+        self.failed = entry.is_err();
+        entry.transpose()
+    }
+}
+
+/// Writes an archive in memory
+///
+/// Entries are owned by root and numbered from 1, parent directories are not
+/// created implicitly: the kernel fails to unpack a file whose directory does
+/// not exist yet, either in this archive or in a previous one.
+pub struct Writer {
+    data: Vec<u8>,
+    crc: bool,
+    next_ino: u32,
+    mtime: u32,
+}
+
+impl Writer {
+    /// Starts a ```newc``` archive
+    pub fn new() -> Self {
+        Writer {
+            data: Vec::new(),
+            crc: false,
+            next_ino: 1,
+            mtime: 0,
+        }
+    }
+
+    /// Starts a ```crc``` archive, with checksums of the file data
+    pub fn with_crc() -> Self {
+        Writer {
+            crc: true,
+            ..Writer::new()
+        }
+    }
+
+    /// Modification time of the following entries, in seconds since the epoch
+    pub fn set_mtime(&mut self, mtime: u32) {
+        self.mtime = mtime;
+    }
+
+    /// Adds a directory
+    ///
+    /// # Arguments
+    ///
+    /// ```name```: path of the directory in the archive
+    /// ```mode```: permission bits
+    pub fn dir(&mut self, name: &str, mode: u32) -> Result<(), &'static str> {
+        let mode = MODE_DIRECTORY | (mode & MODE_PERMISSIONS);
+        self.entry(name, mode, 2, &[])
+    }
+
+    /// Adds a regular file
+    ///
+    /// # Arguments
+    ///
+    /// ```name```: path of the file in the archive
+    /// ```mode```: permission bits
+    /// ```data```: contents of the file
+    pub fn file(&mut self, name: &str, mode: u32, data: &[u8]) -> Result<(), &'static str> {
+        let mode = MODE_REGULAR | (mode & MODE_PERMISSIONS);
+        self.entry(name, mode, 1, data)
+    }
+
+    /// Adds a symbolic link pointing to ```target```
+    pub fn symlink(&mut self, name: &str, target: &str) -> Result<(), &'static str> {
+        self.entry(name, MODE_SYMLINK | 0o777, 1, target.as_bytes())
+    }
+
+    fn entry(
+        &mut self,
+        name: &str,
+        mode: u32,
+        nlink: u32,
+        data: &[u8],
+    ) -> Result<(), &'static str> {
+        if name.is_empty() || name.contains('\0') || name == TRAILER {
+            return Err("Invalid cpio file name");
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.header(name, ino, mode, nlink, data)
+    }
+
+    fn header(
+        &mut self,
+        name: &str,
+        ino: u32,
+        mode: u32,
+        nlink: u32,
+        data: &[u8],
+    ) -> Result<(), &'static str> {
+        let file_size = u32::try_from(data.len()).map_err(|_| "File is too large for cpio")?;
+        let name_size = u32::try_from(name.len() + 1).map_err(|_| "Invalid cpio file name")?;
+        let check = match self.crc {
+            true => checksum(data),
+            false => 0,
+        };
+        let magic = match self.crc {
+            true => MAGIC_CRC,
+            false => MAGIC_NEWC,
+        };
+
+        self.data.extend_from_slice(magic);
+        let fields = [
+            ino, mode, 0, 0, nlink, self.mtime, file_size, 0, 0, 0, 0, name_size, check,
+        ];
+        for field in fields {
+            for shift in (0..8).rev() {
+                let digit = (field >> (shift * 4)) & 0xf;
+                self.data.push(b"0123456789ABCDEF"[digit as usize]);
+            }
+        }
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.data.resize(align4(self.data.len()), 0);
+        self.data.extend_from_slice(data);
+        self.data.resize(align4(self.data.len()), 0);
+        Ok(())
+    }
+
+    /// Ends the archive with its trailer
+    pub fn finish(mut self) -> Vec<u8> {
+        // The trailer name is short and it holds no data
+        let _ = self.header(TRAILER, 0, 0, 1, &[]);
+        self.data
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Writer::new()
+    }
+}
+
+/// Copies an initramfs followed by an extra archive into claimed memory
+///
+/// The archive is placed at the next 4 byte boundary, as the kernel expects
+/// headers to be aligned from the start of the initramfs. The initramfs may
+/// be compressed, the kernel unpacks concatenated segments of any format.
+///
+/// # Arguments
+///
+/// ```prom```: the firmware
+/// ```initrd```: original initramfs
+/// ```archive```: archive to append, as returned by [`Writer::finish`]
+///
+/// # Returns
+///
+/// A page aligned region holding the result, to be leaked and passed to the kernel
+pub fn concat(prom: &PROM, initrd: &[u8], archive: &[u8]) -> Result<ClaimedRegion, &'static str> {
+    let offset = align4(initrd.len());
+    let size = offset
+        .checked_add(archive.len())
+        .ok_or("initramfs is too large")?;
+    let mut region = ClaimedRegion::claim(prom, size, 4096)?;
+    let memory = region.as_mut_slice();
+    memory[..initrd.len()].copy_from_slice(initrd);
+    memory[initrd.len()..offset].fill(0);
+    memory[offset..].copy_from_slice(archive);
+    Ok(region)
+}
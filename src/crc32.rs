@@ -0,0 +1,118 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! CRC-32 as used by GPT, gzip and zlib (reflected polynomial 0xedb88320)
+//! and CRC-32C as used by ext4 and btrfs (reflected polynomial 0x82f63b78)
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+const POLYNOMIAL_C: u32 = 0x82f6_3b78;
+
+const fn make_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ polynomial,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE: [u32; 256] = make_table(POLYNOMIAL);
+const TABLE_C: [u32; 256] = make_table(POLYNOMIAL_C);
+
+fn update(table: &[u32; 256], mut state: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        state = table[((state ^ *byte as u32) & 0xff) as usize] ^ (state >> 8);
+    }
+    state
+}
+
+/// Incremental CRC-32 computation
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = update(&TABLE, self.state, data);
+    }
+
+    /// Checksum of the data fed so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// Checksum of a buffer
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Incremental CRC-32C computation
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32c {
+    state: u32,
+}
+
+impl Default for Crc32c {
+    fn default() -> Self {
+        Crc32c::new()
+    }
+}
+
+impl Crc32c {
+    pub fn new() -> Self {
+        Crc32c { state: !0 }
+    }
+
+    /// Continues from a raw register value, as Linux ```crc32c_le()``` callers chain checksums
+    pub fn from_state(state: u32) -> Self {
+        Crc32c { state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.state = update(&TABLE_C, self.state, data);
+    }
+
+    /// Raw register value, without the final inversion
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    /// Checksum of the data fed so far
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// CRC-32C checksum of a buffer
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
@@ -0,0 +1,314 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Device specifiers as described in section 4.3.1 of IEEE 1275
+//!
+//! A device specifier is an optional alias followed by path components of the
+//! form ```name@unit-address:arguments```, for example
+//! ```disk:2,\boot\vmlinux``` or ```/vdevice/v-scsi@2000/disk@8100000000000000:3```.
+//! The arguments of the last component usually hold a partition and a
+//! filename separated by a comma.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::{self, FromStr};
+
+use crate::PROM;
+
+/// Aliases referring to other aliases are followed up to this depth
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Path component of a device specifier
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathComponent {
+    /// Node name, may include the driver name prefix, e.g. ```PowerPC,POWER9```
+    pub name: String,
+    /// Text representation of the unit address
+    pub unit_address: Option<String>,
+    /// Arguments passed to the package when opening intermediate nodes
+    pub arguments: Option<String>,
+}
+
+impl PathComponent {
+    pub fn new(name: &str, unit_address: Option<&str>) -> Self {
+        PathComponent {
+            name: name.to_string(),
+            unit_address: unit_address.map(|unit| unit.to_string()),
+            arguments: None,
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        let (node, arguments) = match text.split_once(':') {
+            Some((node, arguments)) => (node, Some(arguments.to_string())),
+            None => (text, None),
+        };
+        let (name, unit_address) = match node.split_once('@') {
+            Some((name, unit)) => (name, Some(unit.to_string())),
+            None => (node, None),
+        };
+
+        if name.is_empty() && unit_address.is_none() {
+            return Err("Empty device path component");
+        }
+
+        Ok(PathComponent {
+            name: name.to_string(),
+            unit_address,
+            arguments,
+        })
+    }
+}
+
+impl fmt::Display for PathComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(unit) = &self.unit_address {
+            write!(f, "@{}", unit)?;
+        }
+        if let Some(arguments) = &self.arguments {
+            write!(f, ":{}", arguments)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parsed device specifier
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DevSpec {
+    /// Alias the specifier starts with, if it does not start with '/'
+    pub alias: Option<String>,
+    /// Path components following the alias or the root node
+    pub components: Vec<PathComponent>,
+    /// Arguments of the last component, or of the alias if there are no components
+    pub arguments: Option<String>,
+}
+
+impl DevSpec {
+    /// Parses a device specifier, a trailing null terminator is ignored
+    ///
+    /// The arguments of a component end at the next '/', unless a ',' comes
+    /// first, in which case they hold a filename and extend to the end of the
+    /// specifier.
+    pub fn parse(spec: &str) -> Result<Self, &'static str> {
+        let spec = spec.trim_end_matches('\0');
+        if spec.is_empty() {
+            return Err("Empty device specifier");
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = spec;
+        loop {
+            let end = component_end(rest);
+            parts.push(&rest[..end]);
+            match end < rest.len() {
+                true => rest = &rest[end + 1..],
+                false => break,
+            }
+        }
+
+        let mut devspec = DevSpec::default();
+        let mut parts = parts.into_iter();
+        let first = parts.next().unwrap_or_default();
+        if first.is_empty() {
+            // Absolute path, "/" alone refers to the root node
+            if spec == "/" {
+                return Ok(devspec);
+            }
+        } else {
+            let (alias, arguments) = match first.split_once(':') {
+                Some((alias, arguments)) => (alias, Some(arguments.to_string())),
+                None => (first, None),
+            };
+            if alias.is_empty() || alias.contains('@') {
+                return Err("Invalid alias in device specifier");
+            }
+            devspec.alias = Some(alias.to_string());
+            devspec.arguments = arguments;
+        }
+
+        for part in parts {
+            if let Some(last) = devspec.components.last_mut() {
+                last.arguments = devspec.arguments.take();
+            } else if devspec.arguments.is_some() {
+                return Err("Alias arguments must come last in the device specifier");
+            }
+            let mut component = PathComponent::parse(part)?;
+            devspec.arguments = component.arguments.take();
+            devspec.components.push(component);
+        }
+
+        Ok(devspec)
+    }
+
+    /// Device specifier for the package at an absolute path, without arguments
+    pub fn from_path(path: &str) -> Result<Self, &'static str> {
+        match path.trim_end_matches('\0').starts_with('/') {
+            true => DevSpec::parse(path),
+            false => Err("Package path must be absolute"),
+        }
+    }
+
+    /// Partition part of the arguments, the text before the ',' if any
+    ///
+    /// Arguments that start with a path separator are a filename alone.
+    pub fn partition(&self) -> Option<&str> {
+        let arguments = self.arguments.as_deref()?;
+        let partition = match arguments.split_once(',') {
+            Some((partition, _)) => partition,
+            None if arguments.starts_with(['\\', '/']) => "",
+            None => arguments,
+        };
+        match partition.is_empty() {
+            true => None,
+            false => Some(partition),
+        }
+    }
+
+    /// Filename part of the arguments, the text after the ','
+    pub fn filename(&self) -> Option<&str> {
+        let arguments = self.arguments.as_deref()?;
+        let filename = match arguments.split_once(',') {
+            Some((_, filename)) => filename,
+            None if arguments.starts_with(['\\', '/']) => arguments,
+            None => "",
+        };
+        match filename.is_empty() {
+            true => None,
+            false => Some(filename),
+        }
+    }
+
+    /// Replaces the arguments with a partition and filename
+    ///
+    /// # Arguments
+    ///
+    /// ```partition```: partition number or letter, if any
+    /// ```filename```: file path in the firmware's notation, if any
+    pub fn set_partition_file(&mut self, partition: Option<&str>, filename: Option<&str>) {
+        self.arguments = match (partition, filename) {
+            (None, None) => None,
+            (Some(partition), None) => Some(partition.to_string()),
+            (partition, Some(filename)) => {
+                let mut arguments = partition.unwrap_or_default().to_string();
+                arguments.push(',');
+                arguments.push_str(filename);
+                Some(arguments)
+            }
+        };
+    }
+
+    /// Same device specifier without the arguments of the last component
+    pub fn device(&self) -> Self {
+        DevSpec {
+            arguments: None,
+            ..self.clone()
+        }
+    }
+
+    /// Whether the specifier starts from the root node rather than an alias
+    pub fn is_absolute(&self) -> bool {
+        self.alias.is_none()
+    }
+
+    /// Replaces a leading alias with its value from the '/aliases' node
+    ///
+    /// Arguments given in the specifier take precedence over the ones stored
+    /// in the alias.
+    pub fn expand_aliases(&self, prom: &PROM) -> Result<Self, &'static str> {
+        let mut devspec = self.clone();
+        for _ in 0..MAX_ALIAS_DEPTH {
+            let alias = match &devspec.alias {
+                Some(alias) => alias,
+                None => return Ok(devspec),
+            };
+
+            let mut expanded = DevSpec::parse(&lookup_alias(prom, alias)?)?;
+            match devspec.components.is_empty() {
+                true => {
+                    if devspec.arguments.is_some() {
+                        expanded.arguments = devspec.arguments;
+                    }
+                }
+                false => {
+                    if let Some(last) = expanded.components.last_mut() {
+                        last.arguments = expanded.arguments.take();
+                    }
+                    expanded.components.append(&mut devspec.components);
+                    expanded.arguments = devspec.arguments;
+                }
+            }
+            devspec = expanded;
+        }
+
+        Err("Too many nested aliases")
+    }
+
+    /// Null terminated string to pass to ```PROM::open```
+    pub fn to_open_string(&self) -> String {
+        let mut spec = self.to_string();
+        spec.push('\0');
+        spec
+    }
+}
+
+// Offset of the '/' ending the component at the start of the text, or its length
+fn component_end(text: &str) -> usize {
+    let mut arguments = None;
+    for (i, c) in text.char_indices() {
+        match (c, arguments) {
+            // A filename right after the ':' may use '/' as separator
+            ('/', Some(start)) if i == start => return text.len(),
+            ('/', _) => return i,
+            (':', None) => arguments = Some(i + 1),
+            (',', Some(_)) => return text.len(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// Value of an alias, read from the '/aliases' node
+pub fn lookup_alias(prom: &PROM, alias: &str) -> Result<String, &'static str> {
+    let aliases = prom.find_device("/aliases\0")?;
+
+    let mut name = String::with_capacity(alias.len() + 1);
+    name.push_str(alias);
+    name.push('\0');
+
+    let value = prom
+        .read_property(aliases, &name)
+        .map_err(|_| "Unknown alias")?;
+
+    let value = str::from_utf8(&value).map_err(|_| "Invalid alias value")?;
+    Ok(value.trim_end_matches('\0').to_string())
+}
+
+impl FromStr for DevSpec {
+    type Err = &'static str;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        DevSpec::parse(spec)
+    }
+}
+
+impl fmt::Display for DevSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(alias) = &self.alias {
+            f.write_str(alias)?;
+        } else if self.components.is_empty() {
+            f.write_str("/")?;
+        }
+        for component in self.components.iter() {
+            write!(f, "/{}", component)?;
+        }
+        if let Some(arguments) = &self.arguments {
+            write!(f, ":{}", arguments)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Text dumps of the device tree as seen by the client program
+//!
+//! Nodes are visited one at a time through the client interface, so only the
+//! value of the property being printed is kept in memory.
+
+use core::fmt::{self, Write};
+use core::str;
+
+use crate::{PHandle, PROM};
+
+/// Output syntax of a dump
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Device tree source, as understood by ```dtc```
+    Dts,
+    /// Indented listing in the spirit of the ```ls``` and ```.properties``` words
+    Listing,
+}
+
+/// Prints the device tree, or a subtree, into a [`fmt::Write`] sink such as [`crate::io::Console`]
+pub struct DeviceTreeDump<'a> {
+    prom: &'a PROM,
+    style: Style,
+}
+
+fn fmt_err(_: fmt::Error) -> &'static str {
+    "Could not write device tree dump"
+}
+
+impl<'a> DeviceTreeDump<'a> {
+    pub fn new(prom: &'a PROM, style: Style) -> Self {
+        DeviceTreeDump { prom, style }
+    }
+
+    /// Dumps the whole device tree
+    pub fn dump<W: Write>(&self, out: &mut W) -> Result<(), &'static str> {
+        self.dump_node(self.prom.root()?, out)
+    }
+
+    /// Dumps the subtree at a null terminated path
+    pub fn dump_path<W: Write>(&self, path: &str, out: &mut W) -> Result<(), &'static str> {
+        self.dump_node(self.prom.find_device(path)?, out)
+    }
+
+    /// Dumps the subtree starting at ```phandle```
+    pub fn dump_node<W: Write>(
+        &self,
+        phandle: *const PHandle,
+        out: &mut W,
+    ) -> Result<(), &'static str> {
+        let mut path = [0u8; 256];
+        let len = self
+            .prom
+            .package_to_path(phandle, &mut path)?
+            .min(path.len());
+        let path = str::from_utf8(&path[..len])
+            .map_err(|_| "Invalid package path")?
+            .trim_end_matches('\0');
+        let root = path == "/" || path.is_empty();
+
+        match self.style {
+            Style::Dts => {
+                match root {
+                    true => out.write_str("/dts-v1/;\n\n").map_err(fmt_err)?,
+                    false => writeln!(out, "/* {} */", path).map_err(fmt_err)?,
+                }
+                self.walk(phandle, if root { "/" } else { base_name(path) }, 0, out)
+            }
+            Style::Listing => self.walk(phandle, path, 0, out),
+        }
+    }
+
+    fn walk<W: Write>(
+        &self,
+        phandle: *const PHandle,
+        name: &str,
+        depth: usize,
+        out: &mut W,
+    ) -> Result<(), &'static str> {
+        match self.style {
+            Style::Dts => {
+                indent(out, depth, "\t")?;
+                writeln!(out, "{} {{", name).map_err(fmt_err)?;
+            }
+            Style::Listing => {
+                indent(out, depth, "  ")?;
+                writeln!(out, "{}", name).map_err(fmt_err)?;
+            }
+        }
+
+        self.prom.for_each_property(phandle, |name, prop| {
+            // The name property is implied by the node name in source form
+            if self.style == Style::Dts && name == "name" {
+                return Ok(());
+            }
+
+            let value = self.prom.read_property(phandle, prop)?;
+
+            self.property(name, &value, depth + 1, out).map_err(fmt_err)
+        })?;
+
+        let mut child = self.prom.child(phandle)?;
+        while let Some(node) = child {
+            let mut path = [0u8; 256];
+            let len = self.prom.package_to_path(node, &mut path)?.min(path.len());
+            let path = str::from_utf8(&path[..len])
+                .map_err(|_| "Invalid package path")?
+                .trim_end_matches('\0');
+            self.walk(node, base_name(path), depth + 1, out)?;
+            child = self.prom.peer(node)?;
+        }
+
+        if self.style == Style::Dts {
+            indent(out, depth, "\t")?;
+            out.write_str("};\n").map_err(fmt_err)?;
+        }
+
+        Ok(())
+    }
+
+    fn property<W: Write>(
+        &self,
+        name: &str,
+        value: &[u8],
+        depth: usize,
+        out: &mut W,
+    ) -> fmt::Result {
+        match self.style {
+            Style::Dts => {
+                for _ in 0..depth {
+                    out.write_char('\t')?;
+                }
+                out.write_str(name)?;
+                if !value.is_empty() {
+                    out.write_str(" = ")?;
+                    format_value(value, self.style, out)?;
+                }
+                out.write_str(";\n")
+            }
+            Style::Listing => {
+                for _ in 0..depth {
+                    out.write_str("  ")?;
+                }
+                write!(out, "{:<24}", name)?;
+                if !value.is_empty() {
+                    out.write_char(' ')?;
+                    format_value(value, self.style, out)?;
+                }
+                out.write_char('\n')
+            }
+        }
+    }
+}
+
+fn indent<W: Write>(out: &mut W, depth: usize, unit: &str) -> Result<(), &'static str> {
+    for _ in 0..depth {
+        out.write_str(unit).map_err(fmt_err)?;
+    }
+    Ok(())
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Checks whether a property value is a list of null terminated printable strings
+fn is_string_list(value: &[u8]) -> bool {
+    if value.last() != Some(&0) {
+        return false;
+    }
+
+    value[..value.len() - 1]
+        .split(|c| *c == 0)
+        .all(|s| !s.is_empty() && s.iter().all(|c| (0x20..0x7f).contains(c)))
+}
+
+/// Formats a property value guessing its type: strings, 32 bit cells or bytes
+pub fn format_value<W: Write>(value: &[u8], style: Style, out: &mut W) -> fmt::Result {
+    if is_string_list(value) {
+        for (i, s) in value[..value.len() - 1].split(|c| *c == 0).enumerate() {
+            if i > 0 {
+                out.write_str(", ")?;
+            }
+            out.write_char('"')?;
+            for c in s {
+                match c {
+                    b'"' | b'\\' => write!(out, "\\{}", *c as char)?,
+                    _ => out.write_char(*c as char)?,
+                }
+            }
+            out.write_char('"')?;
+        }
+        return Ok(());
+    }
+
+    if value.len().is_multiple_of(4) {
+        if style == Style::Dts {
+            out.write_char('<')?;
+        }
+        for (i, cell) in value.chunks(4).enumerate() {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            if i > 0 {
+                out.write_char(' ')?;
+            }
+            match style {
+                Style::Dts => write!(out, "{:#x}", cell)?,
+                Style::Listing => write!(out, "{:08x}", cell)?,
+            }
+        }
+        if style == Style::Dts {
+            out.write_char('>')?;
+        }
+        return Ok(());
+    }
+
+    if style == Style::Dts {
+        out.write_char('[')?;
+    }
+    for (i, byte) in value.iter().enumerate() {
+        if i > 0 {
+            out.write_char(' ')?;
+        }
+        write!(out, "{:02x}", byte)?;
+    }
+    if style == Style::Dts {
+        out.write_char(']')?;
+    }
+    Ok(())
+}
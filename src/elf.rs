@@ -0,0 +1,322 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! ELF loader for PowerPC kernels and client programs
+//!
+//! Every ```PT_LOAD``` segment is claimed at its physical address, read from
+//! the stream and zero filled up to its memory size.
+
+use alloc::vec::Vec;
+use core::slice;
+
+use crate::io::{Read, Seek};
+use crate::PROM;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+// Kernels have a handful of program headers, this bounds the table allocation
+const MAX_PROGRAM_HEADERS: u16 = 1024;
+
+/// Machine type of 32 bit PowerPC images
+pub const EM_PPC: u16 = 20;
+/// Machine type of 64 bit PowerPC images
+pub const EM_PPC64: u16 = 21;
+
+/// ELF file class
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Elf32,
+    Elf64,
+}
+
+/// Byte order of the ELF file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(&self, data: &[u8]) -> u16 {
+        let bytes = [data[0], data[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(&self, data: &[u8]) -> u32 {
+        let bytes = [data[0], data[1], data[2], data[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u64(&self, data: &[u8]) -> u64 {
+        let (a, b) = (self.u32(data) as u64, self.u32(&data[4..]) as u64);
+        match self {
+            Endian::Little => (b << 32) | a,
+            Endian::Big => (a << 32) | b,
+        }
+    }
+}
+
+/// Fields of the ELF header needed to load the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub class: Class,
+    pub endian: Endian,
+    pub machine: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+}
+
+impl Header {
+    /// Size of the buffer needed by ```parse``` to cover both header classes
+    pub const MAX_SIZE: usize = 64;
+
+    /// Parses an ELF32 or ELF64 header of either byte order
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < 52 || &data[..4] != ELF_MAGIC {
+            return Err("Not an ELF image");
+        }
+
+        let class = match data[4] {
+            1 => Class::Elf32,
+            2 => Class::Elf64,
+            _ => return Err("Invalid ELF class"),
+        };
+        let endian = match data[5] {
+            1 => Endian::Little,
+            2 => Endian::Big,
+            _ => return Err("Invalid ELF byte order"),
+        };
+
+        let machine = endian.u16(&data[18..]);
+        let header = match class {
+            Class::Elf32 => Header {
+                class,
+                endian,
+                machine,
+                entry: endian.u32(&data[24..]) as u64,
+                phoff: endian.u32(&data[28..]) as u64,
+                phentsize: endian.u16(&data[42..]),
+                phnum: endian.u16(&data[44..]),
+            },
+            Class::Elf64 => {
+                if data.len() < 64 {
+                    return Err("ELF header is truncated");
+                }
+                Header {
+                    class,
+                    endian,
+                    machine,
+                    entry: endian.u64(&data[24..]),
+                    phoff: endian.u64(&data[32..]),
+                    phentsize: endian.u16(&data[54..]),
+                    phnum: endian.u16(&data[56..]),
+                }
+            }
+        };
+
+        let phentsize = match class {
+            Class::Elf32 => 32,
+            Class::Elf64 => 56,
+        };
+        if header.phnum == 0 {
+            return Err("ELF image has no program headers");
+        }
+        if header.phentsize != phentsize {
+            return Err("Invalid program header size");
+        }
+
+        Ok(header)
+    }
+}
+
+/// Program header entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn parse(header: &Header, data: &[u8]) -> Self {
+        let e = header.endian;
+        match header.class {
+            Class::Elf32 => ProgramHeader {
+                kind: e.u32(data),
+                offset: e.u32(&data[4..]) as u64,
+                vaddr: e.u32(&data[8..]) as u64,
+                paddr: e.u32(&data[12..]) as u64,
+                file_size: e.u32(&data[16..]) as u64,
+                mem_size: e.u32(&data[20..]) as u64,
+            },
+            Class::Elf64 => ProgramHeader {
+                kind: e.u32(data),
+                offset: e.u64(&data[8..]),
+                vaddr: e.u64(&data[16..]),
+                paddr: e.u64(&data[24..]),
+                file_size: e.u64(&data[32..]),
+                mem_size: e.u64(&data[40..]),
+            },
+        }
+    }
+}
+
+/// Segment loaded into memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Physical address the segment was claimed at
+    pub paddr: u64,
+    /// Virtual address the segment is linked at
+    pub vaddr: u64,
+    /// Size in memory, including the zero filled area
+    pub mem_size: u64,
+    /// Amount of bytes read from the image
+    pub file_size: u64,
+    /// Address returned by the firmware for the claimed range
+    pub base: *mut u8,
+}
+
+/// Result of loading an ELF image
+#[derive(Clone, Debug)]
+pub struct LoadedImage {
+    pub header: Header,
+    /// Entry point as found in the header
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    /// Lowest physical address used by the image
+    pub low: u64,
+    /// Physical address right after the end of the image
+    pub high: u64,
+}
+
+impl LoadedImage {
+    /// Returns the memory claimed for the image to the firmware
+    pub fn release(&self, prom: &PROM) {
+        for segment in self.segments.iter() {
+            prom.release(segment.base, segment.mem_size as usize);
+        }
+    }
+}
+
+/// Reads the ELF and program headers from a stream
+pub fn read_headers<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<(Header, Vec<ProgramHeader>), &'static str> {
+    let mut buf = [0u8; Header::MAX_SIZE];
+    reader.seek(0)?;
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let header = Header::parse(&buf[..len])?;
+
+    match (header.class, header.machine) {
+        (Class::Elf32, EM_PPC) | (Class::Elf64, EM_PPC64) => (),
+        (_, EM_PPC) | (_, EM_PPC64) => return Err("ELF class does not match the machine type"),
+        _ => return Err("ELF image is not a PowerPC image"),
+    }
+    if header.phnum > MAX_PROGRAM_HEADERS {
+        return Err("ELF image has too many program headers");
+    }
+
+    let entsize = header.phentsize as usize;
+    let mut table: Vec<u8> = alloc::vec![0; entsize * header.phnum as usize];
+    reader.seek(header.phoff)?;
+    reader.read_exact(&mut table)?;
+
+    let phdrs = table
+        .chunks(entsize)
+        .map(|data| ProgramHeader::parse(&header, data))
+        .collect();
+
+    Ok((header, phdrs))
+}
+
+/// Loads an ELF image from a stream, claiming every ```PT_LOAD``` segment at its physical address
+///
+/// Segments already claimed are released if loading fails.
+pub fn load<R: Read + Seek>(prom: &PROM, reader: &mut R) -> Result<LoadedImage, &'static str> {
+    let (header, phdrs) = read_headers(reader)?;
+
+    let mut image = LoadedImage {
+        header,
+        entry: header.entry,
+        segments: Vec::new(),
+        low: u64::MAX,
+        high: 0,
+    };
+
+    for phdr in phdrs.iter().filter(|phdr| phdr.kind == PT_LOAD) {
+        if phdr.mem_size == 0 {
+            continue;
+        }
+
+        if let Err(msg) = load_segment(prom, reader, phdr, &mut image) {
+            image.release(prom);
+            return Err(msg);
+        }
+    }
+
+    if image.segments.is_empty() {
+        return Err("ELF image has no loadable segments");
+    }
+
+    Ok(image)
+}
+
+fn load_segment<R: Read + Seek>(
+    prom: &PROM,
+    reader: &mut R,
+    phdr: &ProgramHeader,
+    image: &mut LoadedImage,
+) -> Result<(), &'static str> {
+    if phdr.file_size > phdr.mem_size {
+        return Err("ELF segment is larger in the file than in memory");
+    }
+
+    let (paddr, mem_size, file_size) = match (
+        usize::try_from(phdr.paddr),
+        usize::try_from(phdr.mem_size),
+        usize::try_from(phdr.file_size),
+    ) {
+        (Ok(paddr), Ok(mem_size), Ok(file_size)) if paddr.checked_add(mem_size).is_some() => {
+            (paddr, mem_size, file_size)
+        }
+        _ => return Err("ELF segment does not fit in the address space"),
+    };
+
+    let base = prom.claim_at(paddr as *mut u8, mem_size)?;
+    image.segments.push(Segment {
+        paddr: phdr.paddr,
+        vaddr: phdr.vaddr,
+        mem_size: phdr.mem_size,
+        file_size: phdr.file_size,
+        base,
+    });
+    image.low = image.low.min(phdr.paddr);
+    image.high = image.high.max(phdr.paddr + phdr.mem_size);
+
+    let memory = unsafe { slice::from_raw_parts_mut(base, mem_size) };
+    reader.seek(phdr.offset)?;
+    reader.read_exact(&mut memory[..file_size])?;
+    memory[file_size..].fill(0);
+
+    Ok(())
+}
@@ -0,0 +1,445 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Flattened Device Tree (DTB) export of the Open Firmware device tree
+//!
+//! The live tree is copied into a [`DeviceTree`] which can be edited before
+//! being serialized into a version 17 blob for kernels and hypervisors that
+//! do not use the client interface.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str;
+
+use crate::bytes::{be32, be64};
+use crate::{PHandle, PROM};
+
+/// Magic number at the start of a DTB
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Version of the generated blobs
+pub const FDT_VERSION: u32 = 17;
+/// Oldest version compatible with the generated blobs
+pub const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+/// Deepest node nesting accepted when parsing, as in libfdt
+const MAX_DEPTH: usize = 64;
+
+/// Property of a device tree node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Property {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// Device tree node, the name includes the unit address
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.to_string(),
+            properties: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Returns the value of a property
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value.as_slice())
+    }
+
+    /// Adds a property or replaces its value if it already exists
+    pub fn set_property(&mut self, name: &str, value: &[u8]) {
+        match self.properties.iter_mut().find(|prop| prop.name == name) {
+            Some(prop) => prop.value = value.to_vec(),
+            None => self.properties.push(Property {
+                name: name.to_string(),
+                value: value.to_vec(),
+            }),
+        }
+    }
+
+    /// Adds a property holding a null terminated string
+    pub fn set_property_str(&mut self, name: &str, value: &str) {
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(0);
+        self.set_property(name, &buf);
+    }
+
+    /// Adds a property holding big endian 32 bit cells
+    pub fn set_property_cells(&mut self, name: &str, cells: &[u32]) {
+        let buf: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.set_property(name, &buf);
+    }
+
+    /// Removes a property, returning its value
+    pub fn remove_property(&mut self, name: &str) -> Option<Vec<u8>> {
+        let index = self.properties.iter().position(|prop| prop.name == name)?;
+        Some(self.properties.remove(index).value)
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|node| node.name == name)
+    }
+
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        self.children.iter_mut().find(|node| node.name == name)
+    }
+
+    /// Adds a child node, replacing any existing child with the same name
+    pub fn add_child(&mut self, node: Node) -> &mut Node {
+        match self
+            .children
+            .iter()
+            .position(|child| child.name == node.name)
+        {
+            Some(index) => {
+                self.children[index] = node;
+                &mut self.children[index]
+            }
+            None => {
+                self.children.push(node);
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// Entry of the memory reservation block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    pub address: u64,
+    pub size: u64,
+}
+
+/// Editable copy of a device tree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceTree {
+    pub root: Node,
+    pub reservations: Vec<Reservation>,
+    /// Physical id of the CPU the kernel is booted on
+    pub boot_cpuid_phys: u32,
+}
+
+impl DeviceTree {
+    /// Copies the whole Open Firmware device tree
+    ///
+    /// Nodes without a ```phandle``` property get one holding their package
+    /// handle, so references between nodes kept in properties remain valid.
+    pub fn from_prom(prom: &PROM) -> Result<Self, &'static str> {
+        let root = read_node(prom, prom.root()?)?;
+
+        // The boot CPU is the one '/chosen' points at, if the firmware tells
+        let mut boot_cpuid_phys = 0;
+        let cpu = prom.get_chosen_ihandle("cpu\0");
+        if let Ok(package) = prom.instance_to_package(cpu) {
+            let mut reg = [0u8; 4];
+            if let Ok(4) = prom.get_property(package, "reg\0", reg.as_mut_ptr(), 4) {
+                boot_cpuid_phys = u32::from_be_bytes(reg);
+            }
+        }
+
+        Ok(DeviceTree {
+            root,
+            reservations: Vec::new(),
+            boot_cpuid_phys,
+        })
+    }
+
+    /// Finds a node from an absolute path such as ```/cpus/cpu@0```
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    /// Finds a node from an absolute path to modify it
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(&mut self.root, |node, name| node.child_mut(name))
+    }
+
+    /// Reserves a memory range so the kernel does not use it
+    pub fn add_reservation(&mut self, address: u64, size: u64) {
+        self.reservations.push(Reservation { address, size });
+    }
+
+    /// Serializes the tree into a DTB
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut strings = Strings::default();
+        let mut structure = Vec::new();
+        write_node(&self.root, &mut structure, &mut strings);
+        push_u32(&mut structure, FDT_END);
+
+        let off_mem_rsvmap = HEADER_SIZE;
+        let rsvmap_size = (self.reservations.len() + 1) * 16;
+        let off_dt_struct = off_mem_rsvmap + rsvmap_size;
+        let off_dt_strings = off_dt_struct + structure.len();
+        let totalsize = off_dt_strings + strings.data.len();
+
+        let mut out = Vec::with_capacity(totalsize);
+        for field in [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            self.boot_cpuid_phys,
+            strings.data.len() as u32,
+            structure.len() as u32,
+        ] {
+            push_u32(&mut out, field);
+        }
+
+        for reservation in self.reservations.iter() {
+            out.extend_from_slice(&reservation.address.to_be_bytes());
+            out.extend_from_slice(&reservation.size.to_be_bytes());
+        }
+        out.extend_from_slice(&[0; 16]);
+
+        out.extend_from_slice(&structure);
+        out.extend_from_slice(&strings.data);
+        out
+    }
+
+    /// Parses a DTB of version 16 or later
+    pub fn from_dtb(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < HEADER_SIZE || be32(data, 0) != FDT_MAGIC {
+            return Err("Not a flattened device tree");
+        }
+
+        let field = |index: usize| be32(data, index * 4) as usize;
+        let (totalsize, off_dt_struct, off_dt_strings, off_mem_rsvmap) =
+            (field(1), field(2), field(3), field(4));
+        if field(6) > FDT_VERSION as usize || field(5) < FDT_LAST_COMP_VERSION as usize {
+            return Err("Unsupported flattened device tree version");
+        }
+        if totalsize > data.len() {
+            return Err("Flattened device tree is truncated");
+        }
+        let data = &data[..totalsize];
+        // Offsets and sizes come from the blob, they may overflow on 32 bit targets
+        let block = |offset: usize, size: usize| {
+            offset
+                .checked_add(size)
+                .and_then(|end| data.get(offset..end))
+        };
+        let strings = block(off_dt_strings, field(8)).ok_or("Invalid strings block")?;
+        let structure = block(off_dt_struct, field(9)).ok_or("Invalid structure block")?;
+
+        let mut reservations = Vec::new();
+        let mut offset = off_mem_rsvmap;
+        loop {
+            let entry = block(offset, 16).ok_or("Invalid reservation block")?;
+            let reservation = Reservation {
+                address: be64(entry, 0),
+                size: be64(entry, 8),
+            };
+            if reservation.address == 0 && reservation.size == 0 {
+                break;
+            }
+            reservations.push(reservation);
+            offset += 16;
+        }
+
+        let mut parser = Parser {
+            data: structure,
+            strings,
+            offset: 0,
+        };
+        let root = match parser.token()? {
+            FDT_BEGIN_NODE => parser.node(0)?,
+            _ => return Err("Structure block does not start with a node"),
+        };
+        if parser.token()? != FDT_END {
+            return Err("Structure block is not terminated");
+        }
+
+        Ok(DeviceTree {
+            root,
+            reservations,
+            boot_cpuid_phys: field(7) as u32,
+        })
+    }
+}
+
+fn read_node(prom: &PROM, phandle: *const PHandle) -> Result<Node, &'static str> {
+    let mut path = [0u8; 256];
+    let len = prom.package_to_path(phandle, &mut path)?.min(path.len());
+    let path = str::from_utf8(&path[..len]).map_err(|_| "Invalid package path")?;
+    let path = path.trim_end_matches('\0');
+    let mut node = Node::new(path.rsplit('/').next().unwrap_or(""));
+
+    prom.for_each_property(phandle, |name, prop| {
+        // Version 16 and later carry the name in the node itself
+        if name == "name" {
+            return Ok(());
+        }
+
+        node.properties.push(Property {
+            name: name.to_string(),
+            value: prom.read_property(phandle, prop)?,
+        });
+        Ok(())
+    })?;
+
+    if node.property("phandle").is_none() && node.property("linux,phandle").is_none() {
+        node.set_property_cells("phandle", &[phandle as usize as u32]);
+    }
+
+    let mut child = prom.child(phandle)?;
+    while let Some(phandle) = child {
+        node.children.push(read_node(prom, phandle)?);
+        child = prom.peer(phandle)?;
+    }
+
+    Ok(node)
+}
+
+#[derive(Default)]
+struct Strings {
+    data: Vec<u8>,
+    offsets: BTreeMap<String, u32>,
+}
+
+impl Strings {
+    fn offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.offsets.get(name) {
+            return *offset;
+        }
+
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.offsets.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn pad(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+fn write_node(node: &Node, out: &mut Vec<u8>, strings: &mut Strings) {
+    push_u32(out, FDT_BEGIN_NODE);
+    out.extend_from_slice(node.name.as_bytes());
+    out.push(0);
+    pad(out);
+
+    for prop in node.properties.iter() {
+        push_u32(out, FDT_PROP);
+        push_u32(out, prop.value.len() as u32);
+        push_u32(out, strings.offset(&prop.name));
+        out.extend_from_slice(&prop.value);
+        pad(out);
+    }
+
+    for child in node.children.iter() {
+        write_node(child, out, strings);
+    }
+
+    push_u32(out, FDT_END_NODE);
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        if self.data.len().saturating_sub(self.offset) < 4 {
+            return Err("Structure block is truncated");
+        }
+        let value = be32(self.data, self.offset);
+        self.offset += 4;
+        Ok(value)
+    }
+
+    fn token(&mut self) -> Result<u32, &'static str> {
+        loop {
+            match self.u32()? {
+                FDT_NOP => continue,
+                token => return Ok(token),
+            }
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.offset..end))
+            .ok_or("Structure block is truncated")?;
+        // Within the block, so aligning up cannot overflow
+        self.offset = (self.offset + len).next_multiple_of(4).min(self.data.len());
+        Ok(bytes)
+    }
+
+    fn string(data: &'a [u8]) -> Result<&'a str, &'static str> {
+        let len = data
+            .iter()
+            .position(|c| *c == 0)
+            .ok_or("Unterminated string")?;
+        str::from_utf8(&data[..len]).map_err(|_| "Invalid string")
+    }
+
+    // Parses a node after its FDT_BEGIN_NODE token, ```depth``` levels below the root
+    fn node(&mut self, depth: usize) -> Result<Node, &'static str> {
+        if depth >= MAX_DEPTH {
+            return Err("Device tree nodes are nested too deep");
+        }
+        let name = Self::string(&self.data[self.offset.min(self.data.len())..])?;
+        self.bytes(name.len() + 1)?;
+        let mut node = Node::new(name);
+
+        loop {
+            match self.token()? {
+                FDT_PROP => {
+                    let len = self.u32()? as usize;
+                    let nameoff = self.u32()? as usize;
+                    let name =
+                        Self::string(self.strings.get(nameoff..).ok_or("Invalid string offset")?)?;
+                    let value = self.bytes(len)?;
+                    node.properties.push(Property {
+                        name: name.to_string(),
+                        value: value.to_vec(),
+                    });
+                }
+                FDT_BEGIN_NODE => node.children.push(self.node(depth + 1)?),
+                FDT_END_NODE => return Ok(node),
+                _ => return Err("Invalid structure block token"),
+            }
+        }
+    }
+}
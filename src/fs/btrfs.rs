@@ -0,0 +1,939 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only btrfs driver for single device filesystems
+//!
+//! Logical addresses are translated through the chunk tree, then subvolumes
+//! are found in the root tree and files in the tree of their subvolume.
+//! Paths cross into nested subvolumes the way they do on a mounted
+//! filesystem, and the default subvolume is used unless another one is
+//! selected. Tree blocks and the superblock are verified against their
+//! CRC-32C; data checksums are not, and the log tree is not replayed.
+//! Extents are read uncompressed, zlib or zstd compressed; zstd needs the
+//! ```zstd``` feature, which is enabled by default.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType};
+use crate::block::BlockDevice;
+use crate::bytes::{le16, le32, le64};
+use crate::compress::zlib::Zlib;
+#[cfg(feature = "zstd")]
+use crate::compress::zstd::Zstd;
+use crate::crc32::{crc32c, Crc32c};
+use crate::io::{Cursor, Read, Seek};
+
+const SUPERBLOCK_OFFSET: u64 = 0x10000;
+const SUPERBLOCK_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+/// Bytes covered by the checksum at the start of superblocks and tree blocks
+const CSUM_SIZE: usize = 32;
+const HEADER_SIZE: usize = 101;
+const KEY_SIZE: usize = 17;
+const ITEM_SIZE: usize = 25;
+const KEY_PTR_SIZE: usize = 33;
+const SYS_CHUNK_ARRAY_OFFSET: usize = 0x32b;
+const SYS_CHUNK_ARRAY_SIZE: usize = 2048;
+/// Tree levels go from 0 for leaves to this for the tallest roots
+const MAX_LEVEL: u8 = 7;
+/// Symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 8;
+/// Longest symbolic link target accepted
+const MAX_SYMLINK_SIZE: u64 = 4095;
+
+const INCOMPAT_MIXED_BACKREF: u64 = 0x1;
+const INCOMPAT_DEFAULT_SUBVOL: u64 = 0x2;
+const INCOMPAT_MIXED_GROUPS: u64 = 0x4;
+const INCOMPAT_COMPRESS_LZO: u64 = 0x8;
+const INCOMPAT_COMPRESS_ZSTD: u64 = 0x10;
+const INCOMPAT_BIG_METADATA: u64 = 0x20;
+const INCOMPAT_EXTENDED_IREF: u64 = 0x40;
+const INCOMPAT_RAID56: u64 = 0x80;
+const INCOMPAT_SKINNY_METADATA: u64 = 0x100;
+const INCOMPAT_NO_HOLES: u64 = 0x200;
+const INCOMPAT_METADATA_UUID: u64 = 0x400;
+const INCOMPAT_RAID1C34: u64 = 0x800;
+const INCOMPAT_SIMPLE_QUOTA: u64 = 0x10000;
+/// Incompatible features that do not get in the way of reading files
+const INCOMPAT_SUPPORTED: u64 = INCOMPAT_MIXED_BACKREF
+    | INCOMPAT_DEFAULT_SUBVOL
+    | INCOMPAT_MIXED_GROUPS
+    | INCOMPAT_COMPRESS_LZO
+    | INCOMPAT_COMPRESS_ZSTD
+    | INCOMPAT_BIG_METADATA
+    | INCOMPAT_EXTENDED_IREF
+    | INCOMPAT_RAID56
+    | INCOMPAT_SKINNY_METADATA
+    | INCOMPAT_NO_HOLES
+    | INCOMPAT_METADATA_UUID
+    | INCOMPAT_RAID1C34
+    | INCOMPAT_SIMPLE_QUOTA;
+
+/// Chunk profiles that spread data over several devices
+const CHUNK_STRIPED: u64 = 0x8 | 0x40 | 0x80 | 0x100;
+
+const FS_TREE_OBJECTID: u64 = 5;
+const ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+/// Inode of the root directory of every subvolume
+pub const ROOT_DIR_INODE: u64 = 256;
+
+const INODE_ITEM_KEY: u8 = 1;
+const DIR_ITEM_KEY: u8 = 84;
+const DIR_INDEX_KEY: u8 = 96;
+const EXTENT_DATA_KEY: u8 = 108;
+const ROOT_ITEM_KEY: u8 = 132;
+const ROOT_REF_KEY: u8 = 156;
+const CHUNK_ITEM_KEY: u8 = 228;
+
+const EXTENT_INLINE: u8 = 0;
+const EXTENT_REGULAR: u8 = 1;
+const EXTENT_PREALLOC: u8 = 2;
+
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+const COMPRESS_LZO: u8 = 2;
+const COMPRESS_ZSTD: u8 = 3;
+
+// Checksum of superblocks and tree blocks, stored in their first bytes
+fn csum_ok(block: &[u8]) -> bool {
+    crc32c(&block[CSUM_SIZE..]) == le32(block, 0)
+}
+
+/// Hash of a file name, the offset of its DIR_ITEM key
+fn name_hash(name: &[u8]) -> u64 {
+    let mut crc = Crc32c::from_state(!1);
+    crc.update(name);
+    crc.state() as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    objectid: u64,
+    kind: u8,
+    offset: u64,
+}
+
+impl Key {
+    const fn new(objectid: u64, kind: u8, offset: u64) -> Self {
+        Key {
+            objectid,
+            kind,
+            offset,
+        }
+    }
+
+    fn parse(data: &[u8]) -> Self {
+        Key::new(le64(data, 0), data[8], le64(data, 9))
+    }
+}
+
+/// Logical address range and where it lives on this device
+struct Chunk {
+    logical: u64,
+    length: u64,
+    /// None when no copy of the chunk is on this device
+    physical: Option<u64>,
+}
+
+impl Chunk {
+    fn parse(logical: u64, data: &[u8], devid: u64) -> Result<(Self, usize), &'static str> {
+        if data.len() < 48 {
+            return Err("Truncated btrfs chunk item");
+        }
+        let stripes = le16(data, 44) as usize;
+        let size = 48 + stripes * 32;
+        if stripes == 0 || data.len() < size {
+            return Err("Truncated btrfs chunk item");
+        }
+        let striped = le64(data, 24) & CHUNK_STRIPED != 0;
+        let physical = (0..stripes)
+            .map(|i| &data[48 + i * 32..])
+            .find(|stripe| !striped && le64(stripe, 0) == devid)
+            .map(|stripe| le64(stripe, 8));
+        let chunk = Chunk {
+            logical,
+            length: le64(data, 0),
+            physical,
+        };
+        Ok((chunk, size))
+    }
+}
+
+/// Inode, with the fields needed to read it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inode {
+    /// Subvolume the inode belongs to
+    pub subvolume: u64,
+    pub number: u64,
+    pub mode: u32,
+    pub links: u32,
+    /// Size in bytes
+    pub size: u64,
+    pub flags: u64,
+}
+
+impl Inode {
+    pub fn kind(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind() == FileType::Symlink
+    }
+}
+
+/// Directory entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    /// Subvolume of the inode, different from the directory's for subvolume roots
+    pub subvolume: u64,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// Subvolume, as linked from a directory of its parent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subvolume {
+    pub id: u64,
+    pub parent: u64,
+    /// Directory of the parent subvolume holding the link
+    pub dir: u64,
+    pub name: String,
+}
+
+// Parses the packed entries of a DIR_ITEM or DIR_INDEX item
+fn dir_items(data: &[u8]) -> Result<Vec<(String, Key, u8)>, &'static str> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let item = &data[offset..];
+        if item.len() < 30 {
+            return Err("Truncated btrfs directory item");
+        }
+        let name_len = le16(item, 27) as usize;
+        let len = 30 + name_len + le16(item, 25) as usize;
+        if item.len() < len {
+            return Err("Truncated btrfs directory item");
+        }
+        let name = String::from_utf8_lossy(&item[30..30 + name_len]).into_owned();
+        entries.push((name, Key::parse(item), item[29]));
+        offset += len;
+    }
+    Ok(entries)
+}
+
+// Uncompressed contents of an extent, zero padded to its size
+fn decompress(compression: u8, data: &[u8], size: usize) -> Result<Vec<u8>, &'static str> {
+    let mut out = vec![0u8; size];
+    let mut reader: Box<dyn Read> = match compression {
+        COMPRESS_ZLIB => Box::new(Zlib::new(Cursor::new(data))?),
+        COMPRESS_LZO => return Err("LZO compressed btrfs extents are not supported"),
+        #[cfg(feature = "zstd")]
+        COMPRESS_ZSTD => Box::new(Zstd::new(Cursor::new(data))?),
+        #[cfg(not(feature = "zstd"))]
+        COMPRESS_ZSTD => return Err("zstd compressed btrfs extents are not supported"),
+        _ => return Err("Unknown btrfs compression"),
+    };
+    let mut filled = 0;
+    while filled < size {
+        match reader.read(&mut out[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(out)
+}
+
+/// btrfs filesystem
+pub struct BtrfsFs<D: BlockDevice> {
+    device: D,
+    sector_size: u64,
+    node_size: usize,
+    /// Filesystem id, and the one stamped in tree blocks which may differ
+    fsid: [u8; 16],
+    metadata_fsid: [u8; 16],
+    chunks: Vec<Chunk>,
+    root_tree: (u64, u8),
+    /// Subvolume paths are resolved in, and its tree
+    subvolume: u64,
+    fs_tree: (u64, u8),
+    label: Option<String>,
+}
+
+impl<D: BlockDevice> BtrfsFs<D> {
+    /// Mounts the default subvolume of the filesystem on a device
+    ///
+    /// Fails on filesystems with incompatible features the driver does not
+    /// implement, such as zoned devices, or checksums other than CRC-32C.
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut sb = vec![0u8; SUPERBLOCK_SIZE];
+        device.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if &sb[0x40..0x48] != MAGIC {
+            return Err("Not a btrfs filesystem");
+        }
+        if le16(&sb, 0xc4) != 0 {
+            return Err("Unsupported btrfs checksum algorithm");
+        }
+        if !csum_ok(&sb) {
+            return Err("btrfs superblock checksum mismatch");
+        }
+        if le64(&sb, 0x30) != SUPERBLOCK_OFFSET {
+            return Err("Invalid btrfs superblock location");
+        }
+        let incompat = le64(&sb, 0xbc);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err("Unsupported btrfs features");
+        }
+
+        let sector_size = le32(&sb, 0x90) as u64;
+        let node_size = le32(&sb, 0x94) as usize;
+        if !sector_size.is_power_of_two()
+            || !(4096..=65536).contains(&sector_size)
+            || !node_size.is_power_of_two()
+            || !(sector_size as usize..=65536).contains(&node_size)
+        {
+            return Err("Invalid btrfs block size");
+        }
+
+        let dev_item = &sb[0xc9..0x12b];
+        let devid = le64(dev_item, 0);
+        if le64(dev_item, 8) > device.size() {
+            return Err("btrfs device is larger than the disk");
+        }
+
+        let mut fsid = [0u8; 16];
+        fsid.copy_from_slice(&sb[0x20..0x30]);
+        let mut metadata_fsid = fsid;
+        if incompat & INCOMPAT_METADATA_UUID != 0 {
+            metadata_fsid.copy_from_slice(&sb[0x23b..0x24b]);
+        }
+        let label: String = sb[0x12b..0x22b]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        // The system chunks in the superblock map the chunk tree, which maps the rest
+        let array_size = le32(&sb, 0xa0) as usize;
+        if array_size > SYS_CHUNK_ARRAY_SIZE {
+            return Err("Invalid btrfs system chunk array");
+        }
+        let array = &sb[SYS_CHUNK_ARRAY_OFFSET..SYS_CHUNK_ARRAY_OFFSET + array_size];
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < array.len() {
+            if array.len() - offset < KEY_SIZE {
+                return Err("Invalid btrfs system chunk array");
+            }
+            let key = Key::parse(&array[offset..]);
+            if key.kind != CHUNK_ITEM_KEY {
+                return Err("Invalid btrfs system chunk array");
+            }
+            let (chunk, size) = Chunk::parse(key.offset, &array[offset + KEY_SIZE..], devid)?;
+            chunks.push(chunk);
+            offset += KEY_SIZE + size;
+        }
+
+        let mut fs = BtrfsFs {
+            device,
+            sector_size,
+            node_size,
+            fsid,
+            metadata_fsid,
+            chunks,
+            root_tree: (le64(&sb, 0x50), sb[0xc6]),
+            subvolume: FS_TREE_OBJECTID,
+            fs_tree: (0, 0),
+            label: (!label.is_empty()).then_some(label),
+        };
+
+        let chunk_tree = (le64(&sb, 0x58), sb[0xc7]);
+        let min = Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, 0);
+        let max = Key::new(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, u64::MAX);
+        let mut chunks = Vec::new();
+        for (key, data) in fs.search(chunk_tree, min, max)? {
+            chunks.push(Chunk::parse(key.offset, &data, devid)?.0);
+        }
+        fs.chunks = chunks;
+
+        let default = Key::new(ROOT_TREE_DIR_OBJECTID, DIR_ITEM_KEY, name_hash(b"default"));
+        let subvolume = match fs.search(fs.root_tree, default, default)?.pop() {
+            Some((_, data)) => dir_items(&data)?
+                .into_iter()
+                .find(|(name, _, _)| name == "default")
+                .map_or(FS_TREE_OBJECTID, |(_, location, _)| location.objectid),
+            None => FS_TREE_OBJECTID,
+        };
+        fs.set_subvolume_id(subvolume)?;
+        Ok(fs)
+    }
+
+    /// Volume label, if one was set
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Filesystem id shared by all of its devices
+    pub fn uuid(&self) -> &[u8; 16] {
+        &self.fsid
+    }
+
+    /// Size of a data block in bytes
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Size of a tree block in bytes
+    pub fn node_size(&self) -> usize {
+        self.node_size
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn read_logical(&mut self, logical: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let chunk = self
+            .chunks
+            .iter()
+            .find(|c| c.logical <= logical && logical - c.logical < c.length)
+            .ok_or("btrfs logical address is not mapped")?;
+        if logical - chunk.logical + buf.len() as u64 > chunk.length {
+            return Err("btrfs read crosses a chunk boundary");
+        }
+        let physical = chunk
+            .physical
+            .ok_or("btrfs chunk is not readable from this device")?;
+        self.device.read_at(physical + logical - chunk.logical, buf)
+    }
+
+    fn read_node(&mut self, logical: u64, level: u8) -> Result<Vec<u8>, &'static str> {
+        let mut node = vec![0u8; self.node_size];
+        self.read_logical(logical, &mut node)?;
+        if !csum_ok(&node) {
+            return Err("btrfs tree block checksum mismatch");
+        }
+        if node[32..48] != self.metadata_fsid || le64(&node, 48) != logical {
+            return Err("Invalid btrfs tree block");
+        }
+        if node[100] != level {
+            return Err("Invalid btrfs tree block level");
+        }
+        Ok(node)
+    }
+
+    fn collect(
+        &mut self,
+        block: u64,
+        level: u8,
+        min: Key,
+        max: Key,
+        out: &mut Vec<(Key, Vec<u8>)>,
+    ) -> Result<(), &'static str> {
+        let node = self.read_node(block, level)?;
+        let count = le32(&node, 96) as usize;
+
+        if level == 0 {
+            if HEADER_SIZE + count * ITEM_SIZE > node.len() {
+                return Err("Invalid btrfs leaf");
+            }
+            for item in node[HEADER_SIZE..].chunks_exact(ITEM_SIZE).take(count) {
+                let key = Key::parse(item);
+                if key < min || key > max {
+                    continue;
+                }
+                let start = HEADER_SIZE + le32(item, 17) as usize;
+                let data = node
+                    .get(start..start + le32(item, 21) as usize)
+                    .ok_or("Invalid btrfs leaf")?;
+                out.push((key, data.to_vec()));
+            }
+            return Ok(());
+        }
+
+        if HEADER_SIZE + count * KEY_PTR_SIZE > node.len() {
+            return Err("Invalid btrfs node");
+        }
+        let pointers: Vec<&[u8]> = node[HEADER_SIZE..]
+            .chunks_exact(KEY_PTR_SIZE)
+            .take(count)
+            .collect();
+        // Each child holds the keys from its own up to the next child's
+        for (i, pointer) in pointers.iter().enumerate() {
+            let first = Key::parse(pointer);
+            let next = pointers.get(i + 1).map(|p| Key::parse(p));
+            if first <= max && next.is_none_or(|next| next > min) {
+                self.collect(le64(pointer, 17), level - 1, min, max, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Items of a tree with keys in an inclusive range, in key order
+    fn search(
+        &mut self,
+        root: (u64, u8),
+        min: Key,
+        max: Key,
+    ) -> Result<Vec<(Key, Vec<u8>)>, &'static str> {
+        if root.1 > MAX_LEVEL {
+            return Err("Invalid btrfs tree level");
+        }
+        let mut items = Vec::new();
+        self.collect(root.0, root.1, min, max, &mut items)?;
+        Ok(items)
+    }
+
+    fn subvolume_tree(&mut self, id: u64) -> Result<(u64, u8), &'static str> {
+        let min = Key::new(id, ROOT_ITEM_KEY, 0);
+        let max = Key::new(id, ROOT_ITEM_KEY, u64::MAX);
+        let (_, item) = self
+            .search(self.root_tree, min, max)?
+            .pop()
+            .ok_or("btrfs subvolume not found")?;
+        if item.len() < 239 {
+            return Err("Truncated btrfs root item");
+        }
+        Ok((le64(&item, 176), item[238]))
+    }
+
+    fn tree(&mut self, subvolume: u64) -> Result<(u64, u8), &'static str> {
+        match subvolume == self.subvolume {
+            true => Ok(self.fs_tree),
+            false => self.subvolume_tree(subvolume),
+        }
+    }
+
+    /// Subvolume paths are resolved in
+    pub fn subvolume(&self) -> u64 {
+        self.subvolume
+    }
+
+    /// Resolves paths in a subvolume from now on
+    pub fn set_subvolume_id(&mut self, id: u64) -> Result<(), &'static str> {
+        self.fs_tree = self.subvolume_tree(id)?;
+        self.subvolume = id;
+        Ok(())
+    }
+
+    /// Resolves paths in the subvolume at a path of the top level subvolume
+    ///
+    /// Fedora for instance keeps the root filesystem, and ```/boot``` with it,
+    /// in a subvolume named ```root```.
+    pub fn set_subvolume(&mut self, path: &str) -> Result<(), &'static str> {
+        let current = self.subvolume;
+        self.set_subvolume_id(FS_TREE_OBJECTID)?;
+        match self.lookup(path) {
+            Ok(inode) if inode.number == ROOT_DIR_INODE => self.set_subvolume_id(inode.subvolume),
+            Ok(_) => {
+                self.set_subvolume_id(current)?;
+                Err("Not a btrfs subvolume")
+            }
+            Err(e) => {
+                self.set_subvolume_id(current)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Lists the subvolumes other than the top level one
+    pub fn subvolumes(&mut self) -> Result<Vec<Subvolume>, &'static str> {
+        let min = Key::new(0, 0, 0);
+        let max = Key::new(u64::MAX, u8::MAX, u64::MAX);
+        let mut subvolumes = Vec::new();
+        for (key, data) in self.search(self.root_tree, min, max)? {
+            if key.kind != ROOT_REF_KEY {
+                continue;
+            }
+            let name_len = data.get(16..18).ok_or("Truncated btrfs root ref")?;
+            let name_len = u16::from_le_bytes([name_len[0], name_len[1]]) as usize;
+            let name = data
+                .get(18..18 + name_len)
+                .ok_or("Truncated btrfs root ref")?;
+            subvolumes.push(Subvolume {
+                id: key.offset,
+                parent: key.objectid,
+                dir: le64(&data, 0),
+                name: String::from_utf8_lossy(name).into_owned(),
+            });
+        }
+        Ok(subvolumes)
+    }
+
+    /// Root directory of the selected subvolume
+    pub fn root(&mut self) -> Result<Inode, &'static str> {
+        self.read_inode(self.subvolume, ROOT_DIR_INODE)
+    }
+
+    /// Reads an inode of a subvolume by number
+    pub fn read_inode(&mut self, subvolume: u64, number: u64) -> Result<Inode, &'static str> {
+        let tree = self.tree(subvolume)?;
+        let key = Key::new(number, INODE_ITEM_KEY, 0);
+        let (_, item) = self
+            .search(tree, key, key)?
+            .pop()
+            .ok_or("btrfs inode not found")?;
+        if item.len() < 160 {
+            return Err("Truncated btrfs inode item");
+        }
+        Ok(Inode {
+            subvolume,
+            number,
+            mode: le32(&item, 52),
+            links: le32(&item, 40),
+            size: le64(&item, 16),
+            flags: le64(&item, 64),
+        })
+    }
+
+    fn dir_entry(&self, dir: &Inode, name: String, location: Key, kind: u8) -> DirEntry {
+        // Subvolumes are linked through their root item
+        let (subvolume, inode) = match location.kind {
+            ROOT_ITEM_KEY => (location.objectid, ROOT_DIR_INODE),
+            _ => (dir.subvolume, location.objectid),
+        };
+        DirEntry {
+            name,
+            subvolume,
+            inode,
+            kind: FileType::from_dirent(kind).unwrap_or(FileType::Other),
+        }
+    }
+
+    /// Lists a directory
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+        let tree = self.tree(dir.subvolume)?;
+        let min = Key::new(dir.number, DIR_INDEX_KEY, 0);
+        let max = Key::new(dir.number, DIR_INDEX_KEY, u64::MAX);
+        let mut entries = Vec::new();
+        for (_, data) in self.search(tree, min, max)? {
+            for (name, location, kind) in dir_items(&data)? {
+                entries.push(self.dir_entry(dir, name, location, kind));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_entry(&mut self, dir: &Inode, name: &str) -> Result<DirEntry, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+        let tree = self.tree(dir.subvolume)?;
+        let key = Key::new(dir.number, DIR_ITEM_KEY, name_hash(name.as_bytes()));
+        // Names with colliding hashes share the item
+        for (_, data) in self.search(tree, key, key)? {
+            for (entry, location, kind) in dir_items(&data)? {
+                if entry == name {
+                    return Ok(self.dir_entry(dir, entry, location, kind));
+                }
+            }
+        }
+        Err("File not found")
+    }
+
+    /// Target of a symbolic link
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String, &'static str> {
+        if !inode.is_symlink() {
+            return Err("Not a symbolic link");
+        }
+        if inode.size > MAX_SYMLINK_SIZE {
+            return Err("Symbolic link target is too long");
+        }
+        let mut target = vec![0u8; inode.size as usize];
+        self.open_inode(inode)?.read_exact(&mut target)?;
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn resolve(&mut self, path: &str, follow: bool) -> Result<Inode, &'static str> {
+        let mut pending: Vec<String> = path_components(path).map(ToOwned::to_owned).collect();
+        pending.reverse();
+        // Directories leading to the current one, for '..'
+        let mut parents: Vec<Inode> = Vec::new();
+        let mut current = self.root()?;
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                current = match parents.pop() {
+                    Some(parent) => parent,
+                    None => self.root()?,
+                };
+                continue;
+            }
+            let entry = self.find_entry(&current, &name)?;
+            let inode = self.read_inode(entry.subvolume, entry.inode)?;
+
+            if inode.is_symlink() && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                let target = self.read_link(&inode)?;
+                if target.starts_with('/') {
+                    parents.clear();
+                    current = self.root()?;
+                }
+                let start = pending.len();
+                pending.extend(path_components(&target).map(ToOwned::to_owned));
+                pending[start..].reverse();
+                continue;
+            }
+            parents.push(core::mem::replace(&mut current, inode));
+        }
+
+        Ok(current)
+    }
+
+    /// Finds the inode at a path, following symbolic links
+    pub fn lookup(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, true)
+    }
+
+    /// Finds the inode at a path, returning a final symbolic link itself
+    pub fn lookup_link(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, false)
+    }
+
+    /// Opens the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let inode = self.lookup(path)?;
+        if inode.is_dir() {
+            return Err("Is a directory");
+        }
+        self.open_inode(&inode)
+    }
+
+    /// Opens the contents of a regular file or symbolic link
+    pub fn open_inode(&mut self, inode: &Inode) -> Result<File<'_, D>, &'static str> {
+        if inode.is_dir() {
+            return Err("Is a directory");
+        }
+        let tree = self.tree(inode.subvolume)?;
+        let min = Key::new(inode.number, EXTENT_DATA_KEY, 0);
+        let max = Key::new(inode.number, EXTENT_DATA_KEY, u64::MAX);
+        let mut extents = Vec::new();
+        for (key, item) in self.search(tree, min, max)? {
+            if item.len() < 21 {
+                return Err("Truncated btrfs file extent");
+            }
+            let compression = item[16];
+            if item[17] != 0 || le16(&item, 18) != 0 {
+                return Err("Encrypted btrfs extents are not supported");
+            }
+            let extent = match item[20] {
+                EXTENT_INLINE => {
+                    let data = match compression {
+                        COMPRESS_NONE => item[21..].to_vec(),
+                        _ => decompress(compression, &item[21..], le64(&item, 8) as usize)?,
+                    };
+                    Extent {
+                        offset: key.offset,
+                        len: data.len() as u64,
+                        data: ExtentData::Inline(data),
+                    }
+                }
+                EXTENT_REGULAR | EXTENT_PREALLOC => {
+                    if item.len() < 53 {
+                        return Err("Truncated btrfs file extent");
+                    }
+                    let disk_start = le64(&item, 21);
+                    let data = match (item[20], disk_start, compression) {
+                        (EXTENT_PREALLOC, _, _) | (_, 0, _) => ExtentData::Hole,
+                        (_, _, COMPRESS_NONE) => ExtentData::Disk(disk_start + le64(&item, 37)),
+                        _ => ExtentData::Compressed {
+                            compression,
+                            disk_start,
+                            disk_len: le64(&item, 29),
+                            size: le64(&item, 8),
+                            offset: le64(&item, 37),
+                        },
+                    };
+                    Extent {
+                        offset: key.offset,
+                        len: le64(&item, 45),
+                        data,
+                    }
+                }
+                _ => return Err("Unknown btrfs file extent type"),
+            };
+            extents.push(extent);
+        }
+
+        Ok(File {
+            fs: self,
+            size: inode.size,
+            extents,
+            cache: None,
+            pos: 0,
+        })
+    }
+}
+
+enum ExtentData {
+    /// Data stored in the tree, already uncompressed
+    Inline(Vec<u8>),
+    /// Logical address of uncompressed data
+    Disk(u64),
+    Compressed {
+        compression: u8,
+        disk_start: u64,
+        disk_len: u64,
+        /// Uncompressed size, and where the file data starts in it
+        size: u64,
+        offset: u64,
+    },
+    /// Sparse or preallocated range, read as zeros
+    Hole,
+}
+
+struct Extent {
+    /// Offset in the file
+    offset: u64,
+    len: u64,
+    data: ExtentData,
+}
+
+/// File opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut BtrfsFs<D>,
+    size: u64,
+    extents: Vec<Extent>,
+    /// Index and contents of the last compressed extent read
+    cache: Option<(usize, Vec<u8>)>,
+    pos: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.size.saturating_sub(self.pos));
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let index = self
+            .extents
+            .partition_point(|e| e.offset + e.len <= self.pos);
+        let extent = match self.extents.get(index) {
+            Some(extent) if extent.offset <= self.pos => extent,
+            // Holes are not recorded with the no-holes feature
+            next => {
+                let end = next.map_or(self.size, |e| e.offset);
+                let len = wanted.min(end - self.pos) as usize;
+                buf[..len].fill(0);
+                self.pos += len as u64;
+                return Ok(len);
+            }
+        };
+
+        let within = self.pos - extent.offset;
+        let len = wanted.min(extent.len - within) as usize;
+        let buf = &mut buf[..len];
+        match &extent.data {
+            ExtentData::Inline(data) => buf.copy_from_slice(&data[within as usize..][..len]),
+            ExtentData::Disk(start) => self.fs.read_logical(start + within, buf)?,
+            ExtentData::Hole => buf.fill(0),
+            ExtentData::Compressed {
+                compression,
+                disk_start,
+                disk_len,
+                size,
+                offset,
+            } => {
+                if self
+                    .cache
+                    .as_ref()
+                    .is_none_or(|(cached, _)| *cached != index)
+                {
+                    let mut compressed = vec![0u8; *disk_len as usize];
+                    self.fs.read_logical(*disk_start, &mut compressed)?;
+                    let data = decompress(*compression, &compressed, *size as usize)?;
+                    self.cache = Some((index, data));
+                }
+                let data = match &self.cache {
+                    Some((_, data)) => data,
+                    None => return Err("btrfs extent cache is empty"),
+                };
+                let start = (offset + within) as usize;
+                let data = data
+                    .get(start..start + len)
+                    .ok_or("Invalid btrfs compressed extent")?;
+                buf.copy_from_slice(data);
+            }
+        }
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for BtrfsFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Btrfs
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(BtrfsFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = BtrfsFs::read_dir(self, &dir)?;
+        Ok(super::listing(
+            entries.into_iter().map(|e| (e.name, e.kind)),
+        ))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let inode = self.lookup(path)?;
+        Ok(super::Metadata {
+            kind: inode.kind(),
+            size: inode.size,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        let inode = self.lookup_link(path)?;
+        BtrfsFs::read_link(self, &inode)
+    }
+}
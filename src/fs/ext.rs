@@ -0,0 +1,723 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only ext2, ext3 and ext4 driver
+//!
+//! Files are mapped through extent trees or the classic indirect block maps.
+//! Directories are scanned linearly, which also works on hashed directories as
+//! their index blocks look like empty entries. With ```metadata_csum``` the
+//! superblock, group descriptors, inodes, extent blocks and directory blocks
+//! are verified as they are read. The journal is not replayed.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType};
+use crate::block::BlockDevice;
+use crate::bytes::{le16, le32};
+use crate::crc32::Crc32c;
+use crate::io::{Read, Seek};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Depth limit of extent trees, as enforced by Linux
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 8;
+/// Longest symbolic link target accepted
+const MAX_SYMLINK_SIZE: u64 = 4096;
+/// Extents longer than this are preallocated and read as zeros
+const MAX_INIT_EXTENT_LEN: u16 = 32768;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Incompatible features that do not get in the way of reading files
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+const FLAG_EXTENTS: u32 = 0x8_0000;
+
+/// Inode of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+// CRC-32C without the final inversion, the way ext4 chains its checksums
+fn csum(seed: u32, data: &[u8]) -> u32 {
+    let mut crc = Crc32c::from_state(seed);
+    crc.update(data);
+    crc.state()
+}
+
+// Seed of the checksums of an inode and the blocks it owns
+fn inode_seed(seed: u32, number: u32, generation: u32) -> u32 {
+    csum(csum(seed, &number.to_le_bytes()), &generation.to_le_bytes())
+}
+
+/// Inode, with the fields needed to read it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inode {
+    pub number: u32,
+    pub mode: u16,
+    pub links: u16,
+    /// Size in bytes
+    pub size: u64,
+    pub flags: u32,
+    generation: u32,
+    /// Block map, extent tree root or symbolic link target
+    block: [u8; 60],
+}
+
+impl Inode {
+    pub fn kind(&self) -> FileType {
+        FileType::from_mode(self.mode as u32)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind() == FileType::Symlink
+    }
+}
+
+/// Directory entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub kind: FileType,
+}
+
+/// ext2, ext3 or ext4 filesystem
+pub struct ExtFs<D: BlockDevice> {
+    device: D,
+    block_size: u64,
+    block_count: u64,
+    inode_size: usize,
+    inode_count: u32,
+    inodes_per_group: u32,
+    /// Whether directory entries record the type of the file
+    filetype: bool,
+    /// First block of the inode table of each group
+    inode_tables: Vec<u64>,
+    /// Seed of the metadata checksums when ```metadata_csum``` is enabled
+    csum_seed: Option<u32>,
+    uuid: [u8; 16],
+    label: Option<String>,
+}
+
+impl<D: BlockDevice> ExtFs<D> {
+    /// Mounts the filesystem on a device
+    ///
+    /// Fails on filesystems with incompatible features the driver does not
+    /// implement, such as inline data, encryption or ```meta_bg```.
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        device.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if le16(&sb, 56) != MAGIC {
+            return Err("Not an ext2/3/4 filesystem");
+        }
+
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err("Invalid ext4 block size");
+        }
+        let block_size = 1024u64 << log_block_size;
+
+        let dynamic = le32(&sb, 76) >= 1;
+        let incompat = if dynamic { le32(&sb, 96) } else { 0 };
+        let ro_compat = if dynamic { le32(&sb, 100) } else { 0 };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err("Unsupported ext4 features");
+        }
+
+        let inode_size = match dynamic {
+            true => le16(&sb, 88) as usize,
+            false => 128,
+        };
+        if inode_size < 128 || !inode_size.is_power_of_two() || inode_size as u64 > block_size {
+            return Err("Invalid ext4 inode size");
+        }
+
+        let mut block_count = le32(&sb, 4) as u64;
+        if incompat & INCOMPAT_64BIT != 0 {
+            block_count |= (le32(&sb, 0x150) as u64) << 32;
+        }
+        if block_count.saturating_mul(block_size) > device.size() {
+            return Err("ext4 filesystem is larger than the device");
+        }
+
+        let csum_seed = match ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            true => {
+                if csum(!0, &sb[..0x3fc]) != le32(&sb, 0x3fc) {
+                    return Err("ext4 superblock checksum mismatch");
+                }
+                Some(match incompat & INCOMPAT_CSUM_SEED != 0 {
+                    true => le32(&sb, 0x270),
+                    false => csum(!0, &sb[104..120]),
+                })
+            }
+            false => None,
+        };
+
+        let first_data_block = le32(&sb, 20) as u64;
+        let blocks_per_group = le32(&sb, 32) as u64;
+        let inodes_per_group = le32(&sb, 40);
+        if blocks_per_group == 0 || inodes_per_group == 0 {
+            return Err("Invalid ext4 block group size");
+        }
+        let groups = block_count
+            .checked_sub(first_data_block)
+            .ok_or("Invalid ext4 block count")?
+            .div_ceil(blocks_per_group) as usize;
+
+        let desc_size = match incompat & INCOMPAT_64BIT != 0 {
+            true => le16(&sb, 0xfe) as usize,
+            false => 32,
+        };
+        if desc_size < 32 || !desc_size.is_power_of_two() || desc_size as u64 > block_size {
+            return Err("Invalid ext4 group descriptor size");
+        }
+
+        let mut descriptors = vec![0u8; groups * desc_size];
+        device.read_at((first_data_block + 1) * block_size, &mut descriptors)?;
+        let mut inode_tables = Vec::with_capacity(groups);
+        for (group, desc) in descriptors.chunks(desc_size).enumerate() {
+            if let Some(seed) = csum_seed {
+                let crc = csum(seed, &(group as u32).to_le_bytes());
+                let crc = csum(csum(crc, &desc[..0x1e]), &[0, 0]);
+                if csum(crc, &desc[0x20..]) as u16 != le16(desc, 0x1e) {
+                    return Err("ext4 group descriptor checksum mismatch");
+                }
+            }
+            let mut table = le32(desc, 8) as u64;
+            if desc_size >= 64 {
+                table |= (le32(desc, 0x28) as u64) << 32;
+            }
+            inode_tables.push(table);
+        }
+
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&sb[104..120]);
+        let label: String = sb[120..136]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        Ok(ExtFs {
+            device,
+            block_size,
+            block_count,
+            inode_size,
+            inode_count: le32(&sb, 0),
+            inodes_per_group,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            inode_tables,
+            csum_seed,
+            uuid,
+            label: (!label.is_empty()).then_some(label),
+        })
+    }
+
+    /// Volume label, if one was set
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn uuid(&self) -> &[u8; 16] {
+        &self.uuid
+    }
+
+    /// Size of a block in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Whether metadata checksums are verified
+    pub fn has_metadata_csum(&self) -> bool {
+        self.csum_seed.is_some()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Reads an inode by number
+    pub fn read_inode(&mut self, number: u32) -> Result<Inode, &'static str> {
+        if number == 0 || number > self.inode_count {
+            return Err("Invalid ext4 inode number");
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = ((number - 1) % self.inodes_per_group) as u64;
+        let table = *self
+            .inode_tables
+            .get(group)
+            .ok_or("Invalid ext4 inode number")?;
+
+        let mut raw = vec![0u8; self.inode_size];
+        self.device.read_at(
+            table * self.block_size + index * self.inode_size as u64,
+            &mut raw,
+        )?;
+
+        let generation = le32(&raw, 0x64);
+        if let Some(seed) = self.csum_seed {
+            // The high half of the checksum lives in the extra fields when they are large enough
+            let has_hi = raw.len() > 128 && le16(&raw, 0x80) >= 4;
+            let stored = match has_hi {
+                true => le16(&raw, 0x7c) as u32 | (le16(&raw, 0x82) as u32) << 16,
+                false => le16(&raw, 0x7c) as u32,
+            };
+            raw[0x7c..0x7e].fill(0);
+            if has_hi {
+                raw[0x82..0x84].fill(0);
+            }
+            let crc = csum(inode_seed(seed, number, generation), &raw);
+            let crc = if has_hi { crc } else { crc & 0xffff };
+            if crc != stored {
+                return Err("ext4 inode checksum mismatch");
+            }
+        }
+
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&raw[0x28..0x64]);
+        Ok(Inode {
+            number,
+            mode: le16(&raw, 0),
+            links: le16(&raw, 0x1a),
+            size: le32(&raw, 4) as u64 | (le32(&raw, 0x6c) as u64) << 32,
+            flags: le32(&raw, 0x20),
+            generation,
+            block,
+        })
+    }
+
+    /// Physical block backing a logical block of a file, None for holes
+    ///
+    /// # Returns
+    ///
+    /// The physical block and how many blocks from there on are contiguous on
+    /// disk, or are holes too.
+    pub fn map_block(
+        &mut self,
+        inode: &Inode,
+        logical: u64,
+    ) -> Result<(Option<u64>, u64), &'static str> {
+        let (block, run) = match inode.flags & FLAG_EXTENTS != 0 {
+            true => self.map_extent(inode, logical)?,
+            false => self.map_indirect(inode, logical)?,
+        };
+        match block {
+            Some(block) if block.saturating_add(run) > self.block_count => {
+                Err("ext4 file block outside of the filesystem")
+            }
+            block => Ok((block, run)),
+        }
+    }
+
+    fn map_extent(
+        &mut self,
+        inode: &Inode,
+        logical: u64,
+    ) -> Result<(Option<u64>, u64), &'static str> {
+        let mut node = inode.block.to_vec();
+        let mut expected_depth = None;
+
+        loop {
+            let entries = le16(&node, 2) as usize;
+            let depth = le16(&node, 6);
+            if le16(&node, 0) != EXTENT_MAGIC
+                || entries > le16(&node, 4) as usize
+                || 12 + entries * 12 > node.len()
+                || depth > MAX_EXTENT_DEPTH
+                || expected_depth.is_some_and(|expected| expected != depth)
+            {
+                return Err("Corrupted ext4 extent tree");
+            }
+            let mut entries = node[12..12 + entries * 12].chunks(12);
+
+            if depth == 0 {
+                for extent in entries {
+                    let start = le32(extent, 0) as u64;
+                    let raw_len = le16(extent, 4);
+                    let (len, initialized) = match raw_len > MAX_INIT_EXTENT_LEN {
+                        true => (raw_len - MAX_INIT_EXTENT_LEN, false),
+                        false => (raw_len, true),
+                    };
+                    let physical = (le16(extent, 6) as u64) << 32 | le32(extent, 8) as u64;
+
+                    if logical < start {
+                        return Ok((None, start - logical));
+                    }
+                    let offset = logical - start;
+                    if offset < len as u64 {
+                        let block = initialized.then_some(physical + offset);
+                        return Ok((block, len as u64 - offset));
+                    }
+                }
+                return Ok((None, u64::MAX));
+            }
+
+            let first = entries.next().ok_or("Corrupted ext4 extent tree")?;
+            if logical < le32(first, 0) as u64 {
+                return Ok((None, le32(first, 0) as u64 - logical));
+            }
+            let index = entries
+                .take_while(|index| le32(index, 0) as u64 <= logical)
+                .last()
+                .unwrap_or(first);
+            let leaf = (le16(index, 8) as u64) << 32 | le32(index, 4) as u64;
+
+            node = vec![0u8; self.block_size as usize];
+            self.read_block(leaf, &mut node)?;
+            if let Some(seed) = self.csum_seed {
+                let end = 12 + le16(&node, 4) as usize * 12;
+                if end + 4 > node.len()
+                    || csum(
+                        inode_seed(seed, inode.number, inode.generation),
+                        &node[..end],
+                    ) != le32(&node, end)
+                {
+                    return Err("ext4 extent block checksum mismatch");
+                }
+            }
+            expected_depth = Some(depth - 1);
+        }
+    }
+
+    fn map_indirect(
+        &mut self,
+        inode: &Inode,
+        logical: u64,
+    ) -> Result<(Option<u64>, u64), &'static str> {
+        let per_block = self.block_size / 4;
+
+        // Direct blocks, then one, two and three levels of indirection
+        let (pointers, mut index, levels) = match logical {
+            logical if logical < 12 => (inode.block.to_vec(), logical, 0),
+            logical => {
+                let mut rest = logical - 12;
+                let mut span = per_block;
+                let mut level = 1;
+                while rest >= span {
+                    rest -= span;
+                    span = span.saturating_mul(per_block);
+                    level += 1;
+                    if level > 3 {
+                        return Err("ext4 file block beyond the block map");
+                    }
+                }
+                let mut levels = vec![0u64; level];
+                for digit in levels.iter_mut().rev() {
+                    *digit = rest % per_block;
+                    rest /= per_block;
+                }
+                let top = le32(&inode.block, (11 + level) * 4) as u64;
+                let mut pointers = vec![0u8; self.block_size as usize];
+                let mut block = top;
+                for (depth, digit) in levels.iter().enumerate() {
+                    if block == 0 {
+                        return Ok((None, 1));
+                    }
+                    self.read_block(block, &mut pointers)?;
+                    if depth + 1 < levels.len() {
+                        block = le32(&pointers, *digit as usize * 4) as u64;
+                    }
+                }
+                (pointers, levels[level - 1], level)
+            }
+        };
+
+        let block = le32(&pointers, index as usize * 4) as u64;
+        let count = match levels {
+            0 => 12,
+            _ => per_block,
+        };
+        // Count the following pointers in the same block that are contiguous
+        let mut run = 1;
+        index += 1;
+        while index < count {
+            let expected = match block {
+                0 => 0,
+                block => block + run,
+            };
+            if le32(&pointers, index as usize * 4) as u64 != expected {
+                break;
+            }
+            run += 1;
+            index += 1;
+        }
+        Ok(((block != 0).then_some(block), run))
+    }
+
+    fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        if block >= self.block_count {
+            return Err("ext4 block outside of the filesystem");
+        }
+        self.device.read_at(block * self.block_size, buf)
+    }
+
+    // All the entries of a directory, '.' and '..' included
+    fn dir_entries(&mut self, dir: &Inode) -> Result<Vec<(String, u32, u8)>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+
+        let mut data = vec![0u8; dir.size as usize];
+        self.open_inode(dir)?.read_exact(&mut data)?;
+
+        let block_size = self.block_size as usize;
+        let seed = self
+            .csum_seed
+            .map(|seed| inode_seed(seed, dir.number, dir.generation));
+        let mut entries = Vec::new();
+
+        for block in data.chunks(block_size) {
+            let tail = block.len() - 12.min(block.len());
+            // Directory blocks end with a fake entry holding their checksum
+            if let Some(seed) = seed {
+                let has_tail = block.len() == block_size
+                    && le32(block, tail) == 0
+                    && le16(block, tail + 4) == 12
+                    && block[tail + 7] == 0xde;
+                if has_tail && csum(seed, &block[..tail]) != le32(block, tail + 8) {
+                    return Err("ext4 directory block checksum mismatch");
+                }
+            }
+
+            let mut offset = 0;
+            while offset + 8 <= block.len() {
+                let inode = le32(block, offset);
+                let rec_len = match le16(block, offset + 4) as usize {
+                    0 | 65535 if block_size == 65536 => 65536,
+                    rec_len => rec_len,
+                };
+                let (name_len, kind) = match self.filetype {
+                    true => (block[offset + 6] as usize, block[offset + 7]),
+                    false => (le16(block, offset + 6) as usize, 0),
+                };
+                if rec_len < 8 || offset + rec_len > block.len() || 8 + name_len > rec_len {
+                    return Err("Corrupted ext4 directory entry");
+                }
+                if inode != 0 {
+                    let name = &block[offset + 8..offset + 8 + name_len];
+                    entries.push((String::from_utf8_lossy(name).into_owned(), inode, kind));
+                }
+                offset += rec_len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Lists a directory, '.' and '..' excluded
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries = Vec::new();
+        for (name, inode, kind) in self.dir_entries(dir)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let kind = match FileType::from_dirent(kind) {
+                Some(kind) => kind,
+                None => self.read_inode(inode)?.kind(),
+            };
+            entries.push(DirEntry { name, inode, kind });
+        }
+        Ok(entries)
+    }
+
+    /// Target of a symbolic link
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String, &'static str> {
+        if !inode.is_symlink() {
+            return Err("Not a symbolic link");
+        }
+        if inode.size > MAX_SYMLINK_SIZE {
+            return Err("Symbolic link target is too long");
+        }
+
+        // Short targets are stored in place of the block map
+        let target = match inode.size < 60 && inode.flags & FLAG_EXTENTS == 0 {
+            true => inode.block[..inode.size as usize].to_vec(),
+            false => {
+                let mut target = vec![0u8; inode.size as usize];
+                self.open_inode(inode)?.read_exact(&mut target)?;
+                target
+            }
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn resolve(&mut self, path: &str, follow: bool) -> Result<Inode, &'static str> {
+        let mut pending: Vec<String> = path_components(path).map(ToOwned::to_owned).collect();
+        pending.reverse();
+        let mut current = self.read_inode(ROOT_INODE)?;
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            let inode = self
+                .dir_entries(&current)?
+                .into_iter()
+                .find(|(entry, _, _)| *entry == name)
+                .ok_or("File not found")?
+                .1;
+            let inode = self.read_inode(inode)?;
+
+            if inode.is_symlink() && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                let target = self.read_link(&inode)?;
+                if target.starts_with('/') {
+                    current = self.read_inode(ROOT_INODE)?;
+                }
+                let start = pending.len();
+                pending.extend(path_components(&target).map(ToOwned::to_owned));
+                pending[start..].reverse();
+                continue;
+            }
+            current = inode;
+        }
+
+        Ok(current)
+    }
+
+    /// Finds the inode at a path, following symbolic links
+    pub fn lookup(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, true)
+    }
+
+    /// Finds the inode at a path, returning a final symbolic link itself
+    pub fn lookup_link(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, false)
+    }
+
+    /// Opens the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let inode = self.lookup(path)?;
+        if inode.is_dir() {
+            return Err("Is a directory");
+        }
+        self.open_inode(&inode)
+    }
+
+    /// Opens the contents of an inode, directories included
+    pub fn open_inode(&mut self, inode: &Inode) -> Result<File<'_, D>, &'static str> {
+        Ok(File {
+            fs: self,
+            inode: inode.clone(),
+            pos: 0,
+        })
+    }
+}
+
+/// File opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut ExtFs<D>,
+    inode: Inode,
+    pos: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.inode.size
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.inode.size.saturating_sub(self.pos));
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let block_size = self.fs.block_size;
+        let offset = self.pos % block_size;
+        let (block, run) = self.fs.map_block(&self.inode, self.pos / block_size)?;
+        let len = wanted.min(run.saturating_mul(block_size) - offset) as usize;
+        match block {
+            Some(block) => self
+                .fs
+                .device
+                .read_at(block * block_size + offset, &mut buf[..len])?,
+            None => buf[..len].fill(0),
+        }
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.inode.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for ExtFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Ext
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(ExtFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = ExtFs::read_dir(self, &dir)?;
+        Ok(super::listing(
+            entries.into_iter().map(|e| (e.name, e.kind)),
+        ))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let inode = self.lookup(path)?;
+        Ok(super::Metadata {
+            kind: inode.kind(),
+            size: inode.size,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        let inode = self.lookup_link(path)?;
+        ExtFs::read_link(self, &inode)
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only FAT12, FAT16 and FAT32 driver with long filename support
+//!
+//! The FAT type is derived from the cluster count as the specification
+//! mandates. Only one sector of the allocation table is cached, so the memory
+//! used does not depend on the size of the volume.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType};
+use crate::block::BlockDevice;
+use crate::bytes::{le16, le32};
+use crate::io::{Read, Seek};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Attribute combination marking long filename entries
+pub const ATTR_LONG_NAME: u8 = 0x0f;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const LFN_UNITS: usize = 13;
+const BOOT_SIGNATURE: u8 = 0x29;
+/// Case flags in the reserved byte of short entries, as written by Windows NT and Linux
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// Allocation table width
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Directory entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    /// Long name if there is one, the short name otherwise
+    pub name: String,
+    /// 8.3 name, with the case flags applied
+    pub short_name: String,
+    pub attributes: u8,
+    /// First cluster, 0 for empty files
+    pub cluster: u32,
+    /// File size in bytes, 0 for directories
+    pub size: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn kind(&self) -> FileType {
+        match self.is_dir() {
+            true => FileType::Directory,
+            false => FileType::Regular,
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Checksum of a short name stored in its long name entries
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name[..11].iter().fold(0u8, |sum, c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c)
+    })
+}
+
+// Short names use an OEM code page, bytes outside of ASCII are taken as Latin-1
+fn short_name(entry: &[u8]) -> String {
+    let mut raw = [0u8; 11];
+    raw.copy_from_slice(&entry[..11]);
+    if raw[0] == 0x05 {
+        raw[0] = 0xe5;
+    }
+
+    let case = entry[12];
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+        bytes[..len]
+            .iter()
+            .map(|c| match lower {
+                true => c.to_ascii_lowercase() as char,
+                false => *c as char,
+            })
+            .collect()
+    };
+
+    let mut name = part(&raw[..8], case & NT_LOWER_BASE != 0);
+    let ext = part(&raw[8..], case & NT_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+// UTF-16 units of a long name entry, in name order
+fn lfn_units(entry: &[u8]) -> [u16; LFN_UNITS] {
+    let mut units = [0u16; LFN_UNITS];
+    let offsets = (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2));
+    for (unit, offset) in units.iter_mut().zip(offsets) {
+        *unit = le16(entry, offset);
+    }
+    units
+}
+
+/// Decodes the entries of a directory, skipping deleted entries, the volume label and '.' and '..'
+pub fn parse_dir(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    // Long name being assembled, the sequence number expected next and its checksum
+    let mut long: Vec<u16> = Vec::new();
+    let mut expected = 0u8;
+    let mut checksum = 0u8;
+
+    for entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+        match entry[0] {
+            0x00 => break,
+            0xe5 => {
+                expected = 0;
+                long.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        let attributes = entry[11];
+        if attributes & 0x3f == ATTR_LONG_NAME {
+            let sequence = entry[0] & 0x1f;
+            if entry[0] & 0x40 != 0 {
+                long.clear();
+                checksum = entry[13];
+            } else if sequence != expected || entry[13] != checksum {
+                expected = 0;
+                long.clear();
+                continue;
+            }
+            long.splice(0..0, lfn_units(entry));
+            expected = sequence.saturating_sub(1);
+            continue;
+        }
+
+        let complete = expected == 0 && !long.is_empty() && lfn_checksum(entry) == checksum;
+        let name = match complete {
+            true => {
+                let end = long
+                    .iter()
+                    .position(|c| *c == 0 || *c == 0xffff)
+                    .unwrap_or(long.len());
+                Some(
+                    char::decode_utf16(long[..end].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect::<String>(),
+                )
+            }
+            false => None,
+        };
+        expected = 0;
+        long.clear();
+
+        if attributes & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short = short_name(entry);
+        if short == "." || short == ".." {
+            continue;
+        }
+
+        entries.push(DirEntry {
+            name: name.unwrap_or_else(|| short.clone()),
+            short_name: short,
+            attributes,
+            cluster: ((le16(entry, 20) as u32) << 16) | le16(entry, 26) as u32,
+            size: le32(entry, 28),
+        });
+    }
+
+    entries
+}
+
+/// FAT volume
+pub struct FatFs<D: BlockDevice> {
+    device: D,
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    cluster_count: u32,
+    /// Offset of the first allocation table
+    fat_offset: u64,
+    /// Offset and size of the fixed root directory of FAT12 and FAT16
+    root_offset: u64,
+    root_size: usize,
+    /// First cluster of the root directory of FAT32
+    root_cluster: u32,
+    data_offset: u64,
+    label: Option<String>,
+    /// Allocation table sector last read and its contents
+    cached_sector: u64,
+    cache: Vec<u8>,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Mounts the FAT volume on a device, validating its BIOS parameter block
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut boot = [0u8; 512];
+        device.read_at(0, &mut boot)?;
+
+        let bytes_per_sector = le16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = le16(&boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = le16(&boot, 17) as u64;
+        let total = match le16(&boot, 19) {
+            0 => le32(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_size = match le16(&boot, 22) {
+            0 => le32(&boot, 36) as u64,
+            size => size as u64,
+        };
+
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_size == 0
+        {
+            return Err("Not a FAT filesystem");
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved + fats * fat_size + root_sectors;
+        let clusters = total
+            .checked_sub(data_sector)
+            .ok_or("FAT filesystem has no data area")?
+            / sectors_per_cluster;
+        if total * bytes_per_sector > device.size() {
+            return Err("FAT filesystem is larger than the device");
+        }
+
+        let fat_type = match clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err("Invalid FAT root directory size");
+        }
+
+        let label_offset = match fat_type {
+            FatType::Fat32 => 71,
+            _ => 43,
+        };
+        let label = match boot[label_offset - 5] == BOOT_SIGNATURE {
+            true => {
+                let label: String = boot[label_offset..label_offset + 11]
+                    .iter()
+                    .map(|c| *c as char)
+                    .collect();
+                let label = label.trim_end();
+                (!label.is_empty() && label != "NO NAME").then(|| label.into())
+            }
+            false => None,
+        };
+
+        Ok(FatFs {
+            device,
+            fat_type,
+            bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            cluster_count: clusters as u32,
+            fat_offset: reserved * bytes_per_sector,
+            root_offset: (reserved + fats * fat_size) * bytes_per_sector,
+            root_size: (root_entries as usize) * DIR_ENTRY_SIZE,
+            root_cluster: le32(&boot, 44),
+            data_offset: data_sector * bytes_per_sector,
+            label,
+            cached_sector: u64::MAX,
+            cache: vec![0u8; bytes_per_sector as usize],
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Volume label from the boot sector, if one was set
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Size of a cluster in bytes
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Entry standing for the root directory
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::new(),
+            short_name: String::new(),
+            attributes: ATTR_DIRECTORY,
+            cluster: match self.fat_type {
+                FatType::Fat32 => self.root_cluster,
+                _ => 0,
+            },
+            size: 0,
+        }
+    }
+
+    fn fat_byte(&mut self, offset: u64) -> Result<u8, &'static str> {
+        let sector = offset / self.bytes_per_sector;
+        if sector != self.cached_sector {
+            self.cached_sector = u64::MAX;
+            self.device
+                .read_at(sector * self.bytes_per_sector, &mut self.cache)?;
+            self.cached_sector = sector;
+        }
+        Ok(self.cache[(offset % self.bytes_per_sector) as usize])
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), &'static str> {
+        match cluster >= 2 && cluster - 2 < self.cluster_count {
+            true => Ok(()),
+            false => Err("Invalid FAT cluster number"),
+        }
+    }
+
+    /// Cluster following another in its chain, None at the end of the chain
+    pub fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        self.check_cluster(cluster)?;
+
+        let base = self.fat_offset;
+        let (next, end, bad) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = base + cluster as u64 + cluster as u64 / 2;
+                let pair = u16::from_le_bytes([self.fat_byte(offset)?, self.fat_byte(offset + 1)?]);
+                let next = match cluster & 1 {
+                    0 => pair & 0xfff,
+                    _ => pair >> 4,
+                };
+                (next as u32, 0xff8, 0xff7)
+            }
+            FatType::Fat16 => {
+                let offset = base + cluster as u64 * 2;
+                let next = u16::from_le_bytes([self.fat_byte(offset)?, self.fat_byte(offset + 1)?]);
+                (next as u32, 0xfff8, 0xfff7)
+            }
+            FatType::Fat32 => {
+                let offset = base + cluster as u64 * 4;
+                let mut bytes = [0u8; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.fat_byte(offset + i as u64)?;
+                }
+                (
+                    u32::from_le_bytes(bytes) & 0x0fff_ffff,
+                    0x0fff_fff8,
+                    0x0fff_fff7,
+                )
+            }
+        };
+
+        match next {
+            next if next >= end => Ok(None),
+            next if next == bad => Err("Bad cluster in FAT chain"),
+            next => {
+                self.check_cluster(next)?;
+                Ok(Some(next))
+            }
+        }
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    // Contents of a whole cluster chain
+    fn read_chain(&mut self, first: u32) -> Result<Vec<u8>, &'static str> {
+        let mut data = Vec::new();
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            if data.len() as u64 >= self.cluster_count as u64 * self.cluster_size {
+                return Err("FAT cluster chain loops");
+            }
+            self.check_cluster(current)?;
+            let start = data.len();
+            data.resize(start + self.cluster_size as usize, 0);
+            let offset = self.cluster_offset(current);
+            self.device.read_at(offset, &mut data[start..])?;
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(data)
+    }
+
+    /// Lists a directory
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+
+        let data = match dir.cluster {
+            // Root directory of FAT12 and FAT16
+            0 => {
+                let mut data = vec![0u8; self.root_size];
+                self.device.read_at(self.root_offset, &mut data)?;
+                data
+            }
+            cluster => self.read_chain(cluster)?,
+        };
+        Ok(parse_dir(&data))
+    }
+
+    /// Finds the entry at a path, names are compared ignoring ASCII case
+    pub fn lookup(&mut self, path: &str) -> Result<DirEntry, &'static str> {
+        let mut entry = self.root();
+        for component in path_components(path) {
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|child| child.matches(component))
+                .ok_or("File not found")?;
+        }
+        Ok(entry)
+    }
+
+    /// Opens the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let entry = self.lookup(path)?;
+        self.open_entry(&entry)
+    }
+
+    /// Opens a file from its directory entry
+    pub fn open_entry(&mut self, entry: &DirEntry) -> Result<File<'_, D>, &'static str> {
+        if entry.is_dir() {
+            return Err("Is a directory");
+        }
+        if entry.size > 0 {
+            self.check_cluster(entry.cluster)?;
+        }
+
+        Ok(File {
+            first_cluster: entry.cluster,
+            size: entry.size as u64,
+            pos: 0,
+            cluster: entry.cluster,
+            cluster_index: 0,
+            fs: self,
+        })
+    }
+}
+
+/// File opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut FatFs<D>,
+    first_cluster: u32,
+    size: u64,
+    pos: u64,
+    /// Cluster reached while walking the chain and its index in the chain
+    cluster: u32,
+    cluster_index: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Walks the chain up to the cluster holding the current position
+    fn seek_cluster(&mut self) -> Result<(), &'static str> {
+        let index = self.pos / self.fs.cluster_size;
+        if index < self.cluster_index {
+            self.cluster = self.first_cluster;
+            self.cluster_index = 0;
+        }
+        while self.cluster_index < index {
+            self.cluster = self
+                .fs
+                .next_cluster(self.cluster)?
+                .ok_or("FAT cluster chain is shorter than the file")?;
+            self.cluster_index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.size.saturating_sub(self.pos));
+        if wanted == 0 {
+            return Ok(0);
+        }
+        self.seek_cluster()?;
+
+        // Extend the read over clusters that follow each other on disk
+        let start = self.fs.cluster_offset(self.cluster) + self.pos % self.fs.cluster_size;
+        let mut available = self.fs.cluster_size - self.pos % self.fs.cluster_size;
+        while available < wanted {
+            match self.fs.next_cluster(self.cluster)? {
+                Some(next) if next == self.cluster + 1 => {
+                    self.cluster = next;
+                    self.cluster_index += 1;
+                    available += self.fs.cluster_size;
+                }
+                _ => break,
+            }
+        }
+
+        let len = wanted.min(available) as usize;
+        self.fs.device.read_at(start, &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for FatFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Fat
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(FatFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = FatFs::read_dir(self, &dir)?;
+        Ok(super::listing(entries.into_iter().map(|e| {
+            let kind = e.kind();
+            (e.name, kind)
+        })))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let entry = self.lookup(path)?;
+        Ok(super::Metadata {
+            kind: entry.kind(),
+            size: entry.size as u64,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        self.lookup(path)?;
+        Err("Not a symbolic link")
+    }
+}
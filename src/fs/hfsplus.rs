@@ -0,0 +1,976 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only HFS+ driver, also covering HFSX and the original HFS
+//!
+//! HFS+ volumes embedded in an HFS wrapper, as Mac OS 8 and 9 formatted
+//! them, are found through the wrapper. Plain HFS volumes, like the
+//! ```Apple_Bootstrap``` partitions written by ```hformat```, share the
+//! B-tree layout and are read with the same code. Files are looked up in
+//! the catalog B-tree and their data forks read through the extents listed
+//! in the catalog and the extents overflow B-tree. Resource forks are
+//! ignored and the journal is not replayed.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType};
+use crate::block::BlockDevice;
+use crate::bytes::{be16, be32, be64};
+use crate::io::{Read, Seek};
+
+const HEADER_OFFSET: u64 = 1024;
+const HFS_SIGNATURE: u16 = 0x4244; // "BD"
+const HFSPLUS_SIGNATURE: u16 = 0x482b; // "H+"
+const HFSX_SIGNATURE: u16 = 0x4858; // "HX"
+
+const ROOT_PARENT_ID: u32 = 1;
+/// Catalog node id of the root folder
+pub const ROOT_FOLDER_ID: u32 = 2;
+const CATALOG_FILE_ID: u32 = 4;
+
+const NODE_LEAF: u8 = 0xff;
+const NODE_INDEX: u8 = 0;
+const NODE_HEADER: u8 = 1;
+const NODE_DESCRIPTOR_SIZE: usize = 14;
+/// B-tree depth limit, far above what the node counts allow
+const MAX_DEPTH: usize = 16;
+
+const RECORD_FOLDER: u16 = 1;
+const RECORD_FILE: u16 = 2;
+const FORK_DATA: u8 = 0;
+/// Key comparison of HFSX catalogs that makes names case sensitive
+const KEY_COMPARE_BINARY: u8 = 0xbc;
+
+const SYMLINK_TYPE: &[u8; 8] = b"slnkrhap";
+const HARD_LINK_TYPE: &[u8; 8] = b"hlnkhfs+";
+/// Folder holding the inodes of hard linked files
+const PRIVATE_DATA_DIR: &str = "\0\0\0\0HFS+ Private Data";
+
+/// Symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 8;
+/// Longest symbolic link target accepted
+const MAX_SYMLINK_SIZE: u64 = 1024;
+
+/// Mac OS Roman characters 0x80 to 0xff, used by HFS names
+const MAC_ROMAN: [char; 128] = [
+    'Ä', 'Å', 'Ç', 'É', 'Ñ', 'Ö', 'Ü', 'á', 'à', 'â', 'ä', 'ã', 'å', 'ç', 'é', 'è', 'ê', 'ë', 'í',
+    'ì', 'î', 'ï', 'ñ', 'ó', 'ò', 'ô', 'ö', 'õ', 'ú', 'ù', 'û', 'ü', '†', '°', '¢', '£', '§', '•',
+    '¶', 'ß', '®', '©', '™', '´', '¨', '≠', 'Æ', 'Ø', '∞', '±', '≤', '≥', '¥', 'µ', '∂', '∑', '∏',
+    'π', '∫', 'ª', 'º', 'Ω', 'æ', 'ø', '¿', '¡', '¬', '√', 'ƒ', '≈', '∆', '«', '»', '…', '\u{a0}',
+    'À', 'Ã', 'Õ', 'Œ', 'œ', '–', '—', '“', '”', '‘', '’', '÷', '◊', 'ÿ', 'Ÿ', '⁄', '€', '‹', '›',
+    'ﬁ', 'ﬂ', '‡', '·', '‚', '„', '‰', 'Â', 'Ê', 'Á', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', 'Ó', 'Ô',
+    '\u{f8ff}', 'Ò', 'Ú', 'Û', 'Ù', 'ı', 'ˆ', '˜', '¯', '˘', '˙', '˚', '¸', '˝', '˛', 'ˇ',
+];
+
+fn mac_roman(name: &[u8]) -> String {
+    name.iter()
+        .map(|c| match *c {
+            0..=0x7f => *c as char,
+            _ => MAC_ROMAN[*c as usize - 0x80],
+        })
+        .collect()
+}
+
+/// Flavor of the volume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    /// Original HFS, with Mac OS Roman names
+    Hfs,
+    HfsPlus,
+    /// HFS+ variant that may have case sensitive names
+    Hfsx,
+}
+
+/// Range of allocation blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Catalog entry of a file or folder
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    /// Catalog node id
+    pub id: u32,
+    /// Catalog node id of the folder holding the entry
+    pub parent: u32,
+    pub kind: FileType,
+    /// Size of the data fork in bytes
+    pub size: u64,
+    /// Finder type and creator codes of files
+    pub file_type: [u8; 4],
+    pub creator: [u8; 4],
+    /// Allocation blocks of the data fork, and the extents the catalog lists
+    blocks: u32,
+    extents: Vec<Extent>,
+    /// Inode number of hard links
+    link: Option<u32>,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == FileType::Symlink
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tree {
+    Extents,
+    Catalog,
+}
+
+/// B-tree file
+struct BTree {
+    extents: Vec<Extent>,
+    node_size: usize,
+    root: u32,
+}
+
+/// Node of a B-tree
+struct Node {
+    kind: u8,
+    next: u32,
+    records: Vec<Vec<u8>>,
+}
+
+/// HFS, HFS+ or HFSX volume
+pub struct HfsPlusFs<D: BlockDevice> {
+    device: D,
+    flavor: Flavor,
+    /// Offset of allocation block 0 on the device
+    offset: u64,
+    block_size: u64,
+    extents_tree: BTree,
+    catalog: BTree,
+    case_sensitive: bool,
+    volume_name: String,
+    /// Folder holding the system files, as set by the Finder or ```bless```
+    blessed: u32,
+    private_dir: Option<u32>,
+}
+
+// Reads from a fork at a byte offset, allocation blocks mapped through its extents
+fn read_fork<D: BlockDevice>(
+    device: &mut D,
+    offset: u64,
+    block_size: u64,
+    extents: &[Extent],
+    mut pos: u64,
+    mut buf: &mut [u8],
+) -> Result<(), &'static str> {
+    let mut start = 0;
+    for extent in extents {
+        let len = extent.count as u64 * block_size;
+        while !buf.is_empty() && pos >= start && pos < start + len {
+            let count = (buf.len() as u64).min(start + len - pos) as usize;
+            let physical = offset + extent.start as u64 * block_size + (pos - start);
+            device.read_at(physical, &mut buf[..count])?;
+            buf = &mut buf[count..];
+            pos += count as u64;
+        }
+        start += len;
+    }
+    match buf.is_empty() {
+        true => Ok(()),
+        false => Err("Read beyond the end of an HFS fork"),
+    }
+}
+
+impl<D: BlockDevice> HfsPlusFs<D> {
+    /// Mounts the volume on a device
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut header = [0u8; 512];
+        device.read_at(HEADER_OFFSET, &mut header)?;
+
+        let mut offset = 0;
+        if be16(&header, 0) == HFS_SIGNATURE {
+            if be16(&header, 124) != HFSPLUS_SIGNATURE {
+                return Self::new_hfs(device, &header);
+            }
+            // HFS+ volume embedded in an HFS wrapper
+            let first_block = be16(&header, 28) as u64 * 512;
+            offset = first_block + be16(&header, 126) as u64 * be32(&header, 20) as u64;
+            device.read_at(offset + HEADER_OFFSET, &mut header)?;
+        }
+
+        let flavor = match (be16(&header, 0), be16(&header, 2)) {
+            (HFSPLUS_SIGNATURE, 4) => Flavor::HfsPlus,
+            (HFSX_SIGNATURE, 5) => Flavor::Hfsx,
+            (HFSPLUS_SIGNATURE | HFSX_SIGNATURE, _) => return Err("Unsupported HFS+ version"),
+            _ => return Err("Not an HFS or HFS+ volume"),
+        };
+        let block_size = be32(&header, 40) as u64;
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err("Invalid HFS+ block size");
+        }
+        if offset + be32(&header, 44) as u64 * block_size > device.size() {
+            return Err("HFS+ volume is larger than the device");
+        }
+
+        let fork = |at: usize| -> (u32, Vec<Extent>) {
+            let extents = (0..8)
+                .map(|i| Extent {
+                    start: be32(&header, at + 16 + i * 8),
+                    count: be32(&header, at + 20 + i * 8),
+                })
+                .filter(|e| e.count > 0)
+                .collect();
+            (be32(&header, at + 12), extents)
+        };
+        let mut fs = Self::mount(
+            device,
+            flavor,
+            offset,
+            block_size,
+            fork(192),
+            fork(272),
+            be32(&header, 80),
+        )?;
+
+        // The volume name is kept in the thread record of the root folder
+        let thread = fs
+            .records(Tree::Catalog, ROOT_FOLDER_ID)?
+            .into_iter()
+            .find(|record| fs.catalog_name(record).is_empty())
+            .ok_or("HFS+ root folder thread not found")?;
+        let data = &thread[fs.key_len(&thread)..];
+        let len = data.get(8..10).ok_or("Truncated HFS+ thread record")?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let name = data
+            .get(10..10 + len * 2)
+            .ok_or("Truncated HFS+ thread record")?;
+        fs.volume_name =
+            char::decode_utf16(name.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])))
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+
+        fs.private_dir = fs
+            .find(ROOT_FOLDER_ID, PRIVATE_DATA_DIR)
+            .ok()
+            .filter(Entry::is_dir)
+            .map(|entry| entry.id);
+        Ok(fs)
+    }
+
+    fn new_hfs(device: D, mdb: &[u8]) -> Result<Self, &'static str> {
+        let block_size = be32(mdb, 20) as u64;
+        if block_size == 0 || !block_size.is_multiple_of(512) {
+            return Err("Invalid HFS block size");
+        }
+        let offset = be16(mdb, 28) as u64 * 512;
+        if offset + be16(mdb, 18) as u64 * block_size > device.size() {
+            return Err("HFS volume is larger than the device");
+        }
+
+        let fork = |at: usize| -> (u32, Vec<Extent>) {
+            let extents = (0..3)
+                .map(|i| Extent {
+                    start: be16(mdb, at + 4 + i * 4) as u32,
+                    count: be16(mdb, at + 6 + i * 4) as u32,
+                })
+                .filter(|e| e.count > 0)
+                .collect();
+            ((be32(mdb, at) as u64).div_ceil(block_size) as u32, extents)
+        };
+        let name_len = (mdb[36] as usize).min(27);
+        let volume_name = mac_roman(&mdb[37..37 + name_len]);
+
+        let mut fs = Self::mount(
+            device,
+            Flavor::Hfs,
+            offset,
+            block_size,
+            fork(130),
+            fork(146),
+            be32(mdb, 92),
+        )?;
+        fs.volume_name = volume_name;
+        Ok(fs)
+    }
+
+    fn mount(
+        device: D,
+        flavor: Flavor,
+        offset: u64,
+        block_size: u64,
+        extents_fork: (u32, Vec<Extent>),
+        catalog_fork: (u32, Vec<Extent>),
+        blessed: u32,
+    ) -> Result<Self, &'static str> {
+        let mut fs = HfsPlusFs {
+            device,
+            flavor,
+            offset,
+            block_size,
+            extents_tree: BTree {
+                extents: extents_fork.1,
+                node_size: 512,
+                root: 0,
+            },
+            catalog: BTree {
+                extents: Vec::new(),
+                node_size: 512,
+                root: 0,
+            },
+            case_sensitive: false,
+            volume_name: String::new(),
+            blessed,
+            private_dir: None,
+        };
+        if fs.extents_tree.extents.iter().map(|e| e.count).sum::<u32>() < extents_fork.0 {
+            return Err("HFS extents overflow file is fragmented");
+        }
+        fs.load_tree(Tree::Extents)?;
+
+        // The catalog may need more extents than the volume header holds
+        fs.catalog.extents = fs.fork_extents(CATALOG_FILE_ID, catalog_fork.1, catalog_fork.0)?;
+        let compare = fs.load_tree(Tree::Catalog)?;
+        fs.case_sensitive = flavor == Flavor::Hfsx && compare == KEY_COMPARE_BINARY;
+        Ok(fs)
+    }
+
+    // Reads the header node of a B-tree, returning its key comparison type
+    fn load_tree(&mut self, tree: Tree) -> Result<u8, &'static str> {
+        let mut header = [0u8; NODE_DESCRIPTOR_SIZE + 106];
+        let extents = match tree {
+            Tree::Extents => &self.extents_tree.extents,
+            Tree::Catalog => &self.catalog.extents,
+        };
+        read_fork(
+            &mut self.device,
+            self.offset,
+            self.block_size,
+            extents,
+            0,
+            &mut header,
+        )?;
+        if header[8] != NODE_HEADER {
+            return Err("Invalid HFS B-tree header");
+        }
+        let record = &header[NODE_DESCRIPTOR_SIZE..];
+        let node_size = be16(record, 18) as usize;
+        if !node_size.is_power_of_two() || !(512..=32768).contains(&node_size) {
+            return Err("Invalid HFS B-tree node size");
+        }
+        let root = be32(record, 2);
+        let state = match tree {
+            Tree::Extents => &mut self.extents_tree,
+            Tree::Catalog => &mut self.catalog,
+        };
+        state.node_size = node_size;
+        state.root = root;
+        Ok(record[37])
+    }
+
+    fn tree(&self, tree: Tree) -> &BTree {
+        match tree {
+            Tree::Extents => &self.extents_tree,
+            Tree::Catalog => &self.catalog,
+        }
+    }
+
+    fn read_node(&mut self, tree: Tree, number: u32) -> Result<Node, &'static str> {
+        let state = match tree {
+            Tree::Extents => &self.extents_tree,
+            Tree::Catalog => &self.catalog,
+        };
+        let node_size = state.node_size;
+        let mut data = vec![0u8; node_size];
+        read_fork(
+            &mut self.device,
+            self.offset,
+            self.block_size,
+            &state.extents,
+            number as u64 * node_size as u64,
+            &mut data,
+        )?;
+
+        let count = be16(&data, 10) as usize;
+        if NODE_DESCRIPTOR_SIZE + 2 * (count + 1) > node_size {
+            return Err("Invalid HFS B-tree node");
+        }
+        // Record offsets are stored backwards from the end of the node
+        let offsets: Vec<usize> = (0..=count)
+            .map(|i| be16(&data, node_size - 2 * (i + 1)) as usize)
+            .collect();
+        let mut records = Vec::with_capacity(count);
+        for pair in offsets.windows(2) {
+            if pair[0] < NODE_DESCRIPTOR_SIZE || pair[1] < pair[0] || pair[1] > node_size {
+                return Err("Invalid HFS B-tree node");
+            }
+            records.push(data[pair[0]..pair[1]].to_vec());
+        }
+        Ok(Node {
+            kind: data[8],
+            next: be32(&data, 0),
+            records,
+        })
+    }
+
+    // Length of the key at the start of a record, padded to keep the data aligned
+    fn key_len(&self, record: &[u8]) -> usize {
+        let len = match self.flavor {
+            Flavor::Hfs => 1 + record.first().copied().unwrap_or(0) as usize,
+            _ => 2 + be16(record, 0) as usize,
+        };
+        len.next_multiple_of(2)
+    }
+
+    // Catalog parent id or extents file id the record is sorted by
+    fn key_id(&self, tree: Tree, record: &[u8]) -> Result<u32, &'static str> {
+        let at = match (self.flavor, tree) {
+            (Flavor::Hfs, _) | (_, Tree::Catalog) => 2,
+            (_, Tree::Extents) => 4,
+        };
+        record
+            .get(at..at + 4)
+            .map(|id| be32(id, 0))
+            .ok_or("Truncated HFS B-tree key")
+    }
+
+    // Leaf records whose keys start with an id, in key order
+    fn records(&mut self, tree: Tree, id: u32) -> Result<Vec<Vec<u8>>, &'static str> {
+        let mut number = self.tree(tree).root;
+        if number == 0 {
+            return Ok(Vec::new());
+        }
+        let mut node = self.read_node(tree, number)?;
+        let mut depth = 0;
+        while node.kind == NODE_INDEX {
+            depth += 1;
+            if depth > MAX_DEPTH || node.records.is_empty() {
+                return Err("Invalid HFS B-tree index");
+            }
+            // The last child starting before the id may hold its first records
+            let mut child = None;
+            for record in &node.records {
+                let key_len = self.key_len(record);
+                if child.is_some() && self.key_id(tree, record)? >= id {
+                    break;
+                }
+                let pointer = record
+                    .get(key_len..key_len + 4)
+                    .ok_or("Truncated HFS index record")?;
+                child = Some(be32(pointer, 0));
+            }
+            number = child.ok_or("Invalid HFS B-tree index")?;
+            node = self.read_node(tree, number)?;
+        }
+
+        let mut records = Vec::new();
+        let mut leaves = 0;
+        loop {
+            if node.kind != NODE_LEAF {
+                return Err("Invalid HFS B-tree leaf");
+            }
+            for record in node.records {
+                match self.key_id(tree, &record)? {
+                    key if key == id => records.push(record),
+                    key if key > id => return Ok(records),
+                    _ => {}
+                }
+            }
+            leaves += 1;
+            if node.next == 0 || node.next == number || leaves > 1 << 20 {
+                return Ok(records);
+            }
+            number = node.next;
+            node = self.read_node(tree, number)?;
+        }
+    }
+
+    // Extents of a data fork, completed from the extents overflow file
+    fn fork_extents(
+        &mut self,
+        id: u32,
+        mut extents: Vec<Extent>,
+        blocks: u32,
+    ) -> Result<Vec<Extent>, &'static str> {
+        let listed: u32 = extents.iter().map(|e| e.count).sum();
+        if listed >= blocks {
+            return Ok(extents);
+        }
+
+        let (fork_at, data_at) = match self.flavor {
+            Flavor::Hfs => (1, 8),
+            _ => (2, 12),
+        };
+        let mut overflow: Vec<(u32, Vec<Extent>)> = Vec::new();
+        for record in self.records(Tree::Extents, id)? {
+            if record.get(fork_at) != Some(&FORK_DATA) {
+                continue;
+            }
+            let data = record.get(data_at..).ok_or("Truncated HFS extent record")?;
+            let list = match self.flavor {
+                Flavor::Hfs if data.len() >= 12 => (0..3)
+                    .map(|i| Extent {
+                        start: be16(data, i * 4) as u32,
+                        count: be16(data, i * 4 + 2) as u32,
+                    })
+                    .collect(),
+                Flavor::HfsPlus | Flavor::Hfsx if data.len() >= 64 => (0..8)
+                    .map(|i| Extent {
+                        start: be32(data, i * 8),
+                        count: be32(data, i * 8 + 4),
+                    })
+                    .collect(),
+                _ => return Err("Truncated HFS extent record"),
+            };
+            let start = match self.flavor {
+                Flavor::Hfs => be16(&record, 6) as u32,
+                _ => be32(&record, 8),
+            };
+            overflow.push((start, list));
+        }
+        overflow.sort_by_key(|(start, _)| *start);
+
+        let mut total = listed;
+        for (start, list) in overflow {
+            if start != total {
+                return Err("Missing HFS overflow extents");
+            }
+            for extent in list.into_iter().filter(|e| e.count > 0) {
+                total += extent.count;
+                extents.push(extent);
+            }
+        }
+        match total >= blocks {
+            true => Ok(extents),
+            false => Err("Missing HFS overflow extents"),
+        }
+    }
+
+    fn catalog_name(&self, record: &[u8]) -> String {
+        match self.flavor {
+            Flavor::Hfs => {
+                let len = record.get(6).copied().unwrap_or(0) as usize;
+                mac_roman(record.get(7..7 + len).unwrap_or(&[]))
+            }
+            _ => {
+                let len = record.get(6..8).map_or(0, |l| be16(l, 0) as usize);
+                let name = record.get(8..8 + len * 2).unwrap_or(&[]);
+                char::decode_utf16(name.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])))
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+        }
+    }
+
+    // Entry of a folder or file record, None for thread records
+    fn entry(&self, record: &[u8]) -> Result<Option<Entry>, &'static str> {
+        let truncated = "Truncated HFS catalog record";
+        let parent = self.key_id(Tree::Catalog, record)?;
+        let name = self.catalog_name(record);
+        let data = record.get(self.key_len(record)..).ok_or(truncated)?;
+        let mut entry = Entry {
+            name,
+            id: 0,
+            parent,
+            kind: FileType::Directory,
+            size: 0,
+            file_type: [0; 4],
+            creator: [0; 4],
+            blocks: 0,
+            extents: Vec::new(),
+            link: None,
+        };
+
+        match self.flavor {
+            Flavor::Hfs => {
+                let kind = *data.first().ok_or(truncated)? as u16;
+                match kind {
+                    RECORD_FOLDER if data.len() >= 10 => entry.id = be32(data, 6),
+                    RECORD_FILE if data.len() >= 86 => {
+                        entry.id = be32(data, 20);
+                        entry.kind = FileType::Regular;
+                        entry.file_type.copy_from_slice(&data[4..8]);
+                        entry.creator.copy_from_slice(&data[8..12]);
+                        entry.size = be32(data, 26) as u64;
+                        entry.blocks = (be32(data, 30) as u64).div_ceil(self.block_size) as u32;
+                        entry.extents = (0..3)
+                            .map(|i| Extent {
+                                start: be16(data, 74 + i * 4) as u32,
+                                count: be16(data, 76 + i * 4) as u32,
+                            })
+                            .filter(|e| e.count > 0)
+                            .collect();
+                    }
+                    RECORD_FOLDER | RECORD_FILE => return Err(truncated),
+                    _ => return Ok(None),
+                }
+            }
+            Flavor::HfsPlus | Flavor::Hfsx => {
+                let kind = be16(data.get(..2).ok_or(truncated)?, 0);
+                match kind {
+                    RECORD_FOLDER if data.len() >= 88 => entry.id = be32(data, 8),
+                    RECORD_FILE if data.len() >= 168 => {
+                        entry.id = be32(data, 8);
+                        entry.file_type.copy_from_slice(&data[48..52]);
+                        entry.creator.copy_from_slice(&data[52..56]);
+                        entry.kind = match &data[48..56] {
+                            t if t == SYMLINK_TYPE => FileType::Symlink,
+                            _ => FileType::from_mode(be16(data, 42) as u32),
+                        };
+                        // Files created before permissions existed have no mode
+                        if be16(data, 42) == 0 && entry.kind == FileType::Other {
+                            entry.kind = FileType::Regular;
+                        }
+                        if &data[48..56] == HARD_LINK_TYPE {
+                            entry.link = Some(be32(data, 44));
+                        }
+                        entry.size = be64(data, 88);
+                        entry.blocks = be32(data, 100);
+                        entry.extents = (0..8)
+                            .map(|i| Extent {
+                                start: be32(data, 104 + i * 8),
+                                count: be32(data, 108 + i * 8),
+                            })
+                            .filter(|e| e.count > 0)
+                            .collect();
+                    }
+                    RECORD_FOLDER | RECORD_FILE => return Err(truncated),
+                    _ => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    fn names_match(&self, a: &str, b: &str) -> bool {
+        match self.case_sensitive {
+            true => a == b,
+            false => a
+                .chars()
+                .flat_map(char::to_lowercase)
+                .eq(b.chars().flat_map(char::to_lowercase)),
+        }
+    }
+
+    // Replaces a hard link with the file it points to, keeping its name and folder
+    fn follow_hard_link(&mut self, entry: Entry) -> Result<Entry, &'static str> {
+        let (Some(link), Some(private_dir)) = (entry.link, self.private_dir) else {
+            return Ok(entry);
+        };
+        let inode = self.find(private_dir, &alloc::format!("iNode{}", link))?;
+        Ok(Entry {
+            name: entry.name,
+            parent: entry.parent,
+            link: None,
+            ..inode
+        })
+    }
+
+    fn find(&mut self, dir: u32, name: &str) -> Result<Entry, &'static str> {
+        for record in self.records(Tree::Catalog, dir)? {
+            if !self.names_match(&self.catalog_name(&record), name) {
+                continue;
+            }
+            if let Some(entry) = self.entry(&record)? {
+                return self.follow_hard_link(entry);
+            }
+        }
+        Err("File not found")
+    }
+
+    /// Flavor of the volume
+    pub fn flavor(&self) -> Flavor {
+        self.flavor
+    }
+
+    pub fn volume_name(&self) -> &str {
+        &self.volume_name
+    }
+
+    /// Size of an allocation block in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Root folder of the volume
+    pub fn root(&self) -> Entry {
+        Entry {
+            name: self.volume_name.clone(),
+            id: ROOT_FOLDER_ID,
+            parent: ROOT_PARENT_ID,
+            kind: FileType::Directory,
+            size: 0,
+            file_type: [0; 4],
+            creator: [0; 4],
+            blocks: 0,
+            extents: Vec::new(),
+            link: None,
+        }
+    }
+
+    /// Finds a file or folder by catalog node id, through its thread record
+    pub fn entry_by_id(&mut self, id: u32) -> Result<Entry, &'static str> {
+        if id == ROOT_FOLDER_ID {
+            return Ok(self.root());
+        }
+        let thread = self
+            .records(Tree::Catalog, id)?
+            .into_iter()
+            .find(|record| self.catalog_name(record).is_empty())
+            .ok_or("HFS catalog thread not found")?;
+        let data = &thread[self.key_len(&thread)..];
+        let (parent, name) = match self.flavor {
+            Flavor::Hfs => {
+                let parent = data.get(10..14).ok_or("Truncated HFS thread record")?;
+                let len = *data.get(14).ok_or("Truncated HFS thread record")? as usize;
+                let name = data
+                    .get(15..15 + len)
+                    .ok_or("Truncated HFS thread record")?;
+                (be32(parent, 0), mac_roman(name))
+            }
+            _ => {
+                let header = data.get(..10).ok_or("Truncated HFS+ thread record")?;
+                let len = be16(header, 8) as usize;
+                let name = data
+                    .get(10..10 + len * 2)
+                    .ok_or("Truncated HFS+ thread record")?;
+                let name =
+                    char::decode_utf16(name.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])))
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect::<String>();
+                (be32(header, 4), name)
+            }
+        };
+        self.find(parent, &name)
+    }
+
+    /// Blessed folder, where Open Firmware looks for the boot file
+    pub fn blessed(&mut self) -> Result<Entry, &'static str> {
+        match self.blessed {
+            0 => Err("HFS volume has no blessed folder"),
+            id => self.entry_by_id(id),
+        }
+    }
+
+    /// Finds a file in a folder by its Finder type, like ```tbxi``` boot files
+    pub fn find_by_type(
+        &mut self,
+        dir: &Entry,
+        file_type: &[u8; 4],
+    ) -> Result<Entry, &'static str> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| !entry.is_dir() && &entry.file_type == file_type)
+            .ok_or("File not found")
+    }
+
+    /// Lists a folder, hiding the private metadata folders
+    pub fn read_dir(&mut self, dir: &Entry) -> Result<Vec<Entry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+        let mut entries = Vec::new();
+        for record in self.records(Tree::Catalog, dir.id)? {
+            if let Some(entry) = self.entry(&record)? {
+                if !entry.name.starts_with('\0') {
+                    entries.push(self.follow_hard_link(entry)?);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Target of a symbolic link
+    pub fn read_link(&mut self, entry: &Entry) -> Result<String, &'static str> {
+        if !entry.is_symlink() {
+            return Err("Not a symbolic link");
+        }
+        if entry.size > MAX_SYMLINK_SIZE {
+            return Err("Symbolic link target is too long");
+        }
+        let mut target = vec![0u8; entry.size as usize];
+        self.open_entry(entry)?.read_exact(&mut target)?;
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn resolve(&mut self, path: &str, follow: bool) -> Result<Entry, &'static str> {
+        let mut pending: Vec<String> = path_components(path).map(ToOwned::to_owned).collect();
+        pending.reverse();
+        // Folders leading to the current one, for '..'
+        let mut parents: Vec<Entry> = Vec::new();
+        let mut current = self.root();
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                current = parents.pop().unwrap_or_else(|| self.root());
+                continue;
+            }
+            if !current.is_dir() {
+                return Err("Not a directory");
+            }
+            let entry = self.find(current.id, &name)?;
+
+            if entry.is_symlink() && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                let target = self.read_link(&entry)?;
+                if target.starts_with('/') {
+                    parents.clear();
+                    current = self.root();
+                }
+                let start = pending.len();
+                pending.extend(path_components(&target).map(ToOwned::to_owned));
+                pending[start..].reverse();
+                continue;
+            }
+            parents.push(core::mem::replace(&mut current, entry));
+        }
+
+        Ok(current)
+    }
+
+    /// Finds the entry at a path, following symbolic links
+    pub fn lookup(&mut self, path: &str) -> Result<Entry, &'static str> {
+        self.resolve(path, true)
+    }
+
+    /// Finds the entry at a path, returning a final symbolic link itself
+    pub fn lookup_link(&mut self, path: &str) -> Result<Entry, &'static str> {
+        self.resolve(path, false)
+    }
+
+    /// Opens the data fork of the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let entry = self.lookup(path)?;
+        self.open_entry(&entry)
+    }
+
+    /// Opens the data fork of a file
+    pub fn open_entry(&mut self, entry: &Entry) -> Result<File<'_, D>, &'static str> {
+        if entry.is_dir() {
+            return Err("Is a directory");
+        }
+        let extents = self.fork_extents(entry.id, entry.extents.clone(), entry.blocks)?;
+        let allocated: u64 = extents.iter().map(|e| e.count as u64).sum();
+        if entry.size > allocated * self.block_size {
+            return Err("HFS file is larger than its extents");
+        }
+        Ok(File {
+            fs: self,
+            size: entry.size,
+            extents,
+            pos: 0,
+        })
+    }
+}
+
+/// Data fork opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut HfsPlusFs<D>,
+    size: u64,
+    extents: Vec<Extent>,
+    pos: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.size.saturating_sub(self.pos)) as usize;
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        // Stop at the end of the extent holding the position
+        let block_size = self.fs.block_size;
+        let mut start = 0;
+        let mut len = wanted;
+        for extent in &self.extents {
+            let end = start + extent.count as u64 * block_size;
+            if self.pos < end {
+                len = wanted.min((end - self.pos) as usize);
+                break;
+            }
+            start = end;
+        }
+        read_fork(
+            &mut self.fs.device,
+            self.fs.offset,
+            block_size,
+            &self.extents,
+            self.pos,
+            &mut buf[..len],
+        )?;
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for HfsPlusFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Hfs
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(HfsPlusFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = HfsPlusFs::read_dir(self, &dir)?;
+        Ok(super::listing(
+            entries.into_iter().map(|e| (e.name, e.kind)),
+        ))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let entry = self.lookup(path)?;
+        Ok(super::Metadata {
+            kind: entry.kind,
+            size: entry.size,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        let entry = self.lookup_link(path)?;
+        HfsPlusFs::read_link(self, &entry)
+    }
+}
@@ -0,0 +1,606 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only ISO 9660 driver with the Rock Ridge and Joliet extensions
+//!
+//! Rock Ridge names and symbolic links are used when the primary volume has
+//! them, the Joliet tree is used otherwise and plain ISO 9660 names come last.
+//! Without Rock Ridge names are compared ignoring ASCII case, which is what
+//! paths such as ```cdrom:\ppc\bootinfo.txt``` expect. Volumes are addressed
+//! in 2048 byte sectors whatever the block size of the device.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType, MODE_DIRECTORY, MODE_SYMLINK, MODE_TYPE_MASK};
+use crate::block::BlockDevice;
+use crate::bytes::{le16, le32};
+use crate::io::{Read, Seek};
+
+/// Size of the sectors volume descriptors are stored in
+pub const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Descriptors looked at before giving up on finding the terminator
+const MAX_DESCRIPTORS: u64 = 32;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+/// Escape sequences of the three Joliet UCS-2 levels
+const JOLIET_ESCAPES: [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Continuation areas followed for a single directory record
+const MAX_CONTINUATIONS: usize = 16;
+const MAX_SYMLINKS: usize = 8;
+
+const NM_CONTINUE: u8 = 0x01;
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+
+/// Naming used for the directory tree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Naming {
+    /// Upper case names from the primary volume descriptor
+    Iso9660,
+    /// UCS-2 names from a supplementary volume descriptor
+    Joliet,
+    /// POSIX names and symbolic links from the primary volume descriptor
+    RockRidge,
+}
+
+/// Contiguous part of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block
+    pub block: u32,
+    /// Size in bytes
+    pub size: u32,
+}
+
+/// Directory entry, with all the extents of multi-extent files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub flags: u8,
+    pub extents: Vec<Extent>,
+    /// POSIX mode from Rock Ridge
+    pub mode: Option<u32>,
+    /// Rock Ridge symbolic link target
+    pub symlink: Option<String>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.flags & FLAG_DIRECTORY != 0
+            || self
+                .mode
+                .is_some_and(|mode| mode & MODE_TYPE_MASK == MODE_DIRECTORY)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.symlink.is_some()
+            || self
+                .mode
+                .is_some_and(|mode| mode & MODE_TYPE_MASK == MODE_SYMLINK)
+    }
+
+    pub fn kind(&self) -> FileType {
+        if self.is_symlink() {
+            FileType::Symlink
+        } else if self.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        }
+    }
+
+    /// Size in bytes, the extents added up
+    pub fn size(&self) -> u64 {
+        self.extents.iter().map(|e| e.size as u64).sum()
+    }
+}
+
+// Rock Ridge fields of a directory record
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    symlink: Option<String>,
+}
+
+/// ISO 9660 volume
+pub struct IsoFs<D: BlockDevice> {
+    device: D,
+    block_size: u64,
+    naming: Naming,
+    volume_id: String,
+    /// Bytes to skip at the start of system use areas, from the SUSP SP entry
+    susp_skip: usize,
+    root: DirEntry,
+}
+
+impl<D: BlockDevice> IsoFs<D> {
+    /// Mounts the volume, picking Rock Ridge, Joliet or plain names in that order
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut primary = None;
+        let mut joliet = None;
+
+        let mut descriptor = [0u8; SECTOR_SIZE as usize];
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            device.read_at(sector * SECTOR_SIZE, &mut descriptor)?;
+            if &descriptor[1..6] != STANDARD_ID {
+                break;
+            }
+            match descriptor[0] {
+                DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY
+                    if joliet.is_none()
+                        && JOLIET_ESCAPES.iter().any(|e| descriptor[88..91] == e[..]) =>
+                {
+                    joliet = Some(descriptor)
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        let primary = primary.ok_or("Not an ISO 9660 volume")?;
+
+        let block_size = le16(&primary, 128) as u64;
+        if !matches!(block_size, 512 | 1024 | 2048) {
+            return Err("Invalid ISO 9660 logical block size");
+        }
+
+        let mut fs = IsoFs {
+            device,
+            block_size,
+            naming: Naming::Iso9660,
+            volume_id: String::from_utf8_lossy(&primary[40..72]).trim_end().into(),
+            susp_skip: 0,
+            root: parse_root(&primary[156..190])?,
+        };
+
+        // Rock Ridge announces itself with a SUSP SP entry in the first record of the root
+        let mut sector = vec![0u8; fs.block_size as usize];
+        let root_block = fs.root.extents[0].block;
+        fs.read_block(root_block, &mut sector)?;
+        let record = record_at(&sector, 0).ok_or("Corrupted ISO 9660 directory")?;
+        let system_use = system_use(record);
+        if system_use.len() >= 7 && &system_use[..2] == b"SP" && system_use[4..6] == [0xbe, 0xef] {
+            fs.naming = Naming::RockRidge;
+            fs.susp_skip = system_use[6] as usize;
+        } else if let Some(joliet) = joliet {
+            fs.naming = Naming::Joliet;
+            fs.root = parse_root(&joliet[156..190])?;
+            fs.volume_id = decode_ucs2(&joliet[40..72]).trim_end().into();
+        }
+
+        Ok(fs)
+    }
+
+    pub fn naming(&self) -> Naming {
+        self.naming
+    }
+
+    /// Volume identifier, from the descriptor matching the naming in use
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    /// Entry standing for the root directory
+    pub fn root(&self) -> DirEntry {
+        self.root.clone()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn read_block(&mut self, block: u32, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.device.read_at(block as u64 * self.block_size, buf)
+    }
+
+    // Rock Ridge entries of a record, following continuation areas
+    fn rock_ridge(&mut self, record: &[u8]) -> Result<RockRidge, &'static str> {
+        let mut rr = RockRidge::default();
+        let mut area = system_use(record)
+            .get(self.susp_skip..)
+            .unwrap_or(&[])
+            .to_vec();
+        let mut name = String::new();
+        let mut has_name = false;
+        let mut link = SymlinkBuilder::default();
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let len = area[offset + 2] as usize;
+                if len < 4 || offset + len > area.len() {
+                    break;
+                }
+                let entry = &area[offset..offset + len];
+                match &entry[..2] {
+                    // Names for '.' and '..' only carry flags
+                    b"NM" if len >= 5 && entry[4] & !NM_CONTINUE == 0 => {
+                        name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                        has_name = true;
+                    }
+                    b"SL" if len >= 5 => link.push(entry),
+                    b"PX" if len >= 12 => rr.mode = Some(le32(entry, 4)),
+                    b"CE" if len >= 28 => {
+                        continuation = Some((le32(entry, 4), le32(entry, 12), le32(entry, 20)))
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+                offset += len;
+            }
+
+            let Some((block, start, size)) = continuation else {
+                break;
+            };
+            if start as u64 + size as u64 > self.block_size {
+                return Err("Invalid Rock Ridge continuation area");
+            }
+            let mut sector = vec![0u8; self.block_size as usize];
+            self.read_block(block, &mut sector)?;
+            area = sector[start as usize..(start + size) as usize].to_vec();
+        }
+
+        rr.name = has_name.then_some(name);
+        rr.symlink = link.finish();
+        Ok(rr)
+    }
+
+    /// Lists a directory, '.' and '..' excluded
+    pub fn read_dir(&mut self, dir: &DirEntry) -> Result<Vec<DirEntry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+
+        let block_size = self.block_size as usize;
+        let mut entries: Vec<DirEntry> = Vec::new();
+        // Whether the last entry continues in the next record
+        let mut pending = false;
+
+        for extent in &dir.extents {
+            let mut data = vec![0u8; (extent.size as usize).div_ceil(block_size) * block_size];
+            self.read_block(extent.block, &mut data)?;
+
+            for sector in data.chunks(block_size) {
+                let mut offset = 0;
+                while let Some(record) = record_at(sector, offset) {
+                    offset += record.len();
+                    let id = &record[33..33 + record[32] as usize];
+                    if id == [0] || id == [1] {
+                        continue;
+                    }
+                    if record[26] != 0 || record[27] != 0 {
+                        return Err("Interleaved ISO 9660 files are not supported");
+                    }
+
+                    let flags = record[25];
+                    let extent = Extent {
+                        block: le32(record, 2),
+                        size: le32(record, 10),
+                    };
+                    if pending {
+                        if let Some(last) = entries.last_mut() {
+                            last.extents.push(extent);
+                            last.flags = flags;
+                        }
+                        pending = flags & FLAG_MULTI_EXTENT != 0;
+                        continue;
+                    }
+                    pending = flags & FLAG_MULTI_EXTENT != 0;
+
+                    let (name, mode, symlink) = match self.naming {
+                        Naming::RockRidge => {
+                            let rr = self.rock_ridge(record)?;
+                            let name = rr.name.unwrap_or_else(|| iso_name(id));
+                            (name, rr.mode, rr.symlink)
+                        }
+                        Naming::Joliet => (strip_version(&decode_ucs2(id)), None, None),
+                        Naming::Iso9660 => (iso_name(id), None, None),
+                    };
+                    entries.push(DirEntry {
+                        name,
+                        flags,
+                        extents: vec![extent],
+                        mode,
+                        symlink,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn matches(&self, entry: &DirEntry, name: &str) -> bool {
+        match self.naming {
+            Naming::RockRidge => entry.name == name,
+            _ => entry.name.eq_ignore_ascii_case(name),
+        }
+    }
+
+    fn resolve(&mut self, path: &str, follow: bool) -> Result<DirEntry, &'static str> {
+        let mut pending: Vec<String> = path_components(path).map(ToOwned::to_owned).collect();
+        pending.reverse();
+        // Directories leading to the current one, for '..'
+        let mut parents: Vec<DirEntry> = Vec::new();
+        let mut current = self.root();
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                current = parents.pop().unwrap_or_else(|| self.root());
+                continue;
+            }
+            let entry = self
+                .read_dir(&current)?
+                .into_iter()
+                .find(|entry| self.matches(entry, &name))
+                .ok_or("File not found")?;
+
+            if let Some(target) = entry
+                .symlink
+                .as_ref()
+                .filter(|_| follow || !pending.is_empty())
+            {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                if target.starts_with('/') {
+                    parents.clear();
+                    current = self.root();
+                }
+                let start = pending.len();
+                pending.extend(path_components(target).map(ToOwned::to_owned));
+                pending[start..].reverse();
+                continue;
+            }
+            parents.push(core::mem::replace(&mut current, entry));
+        }
+
+        Ok(current)
+    }
+
+    /// Finds the entry at a path, following symbolic links
+    pub fn lookup(&mut self, path: &str) -> Result<DirEntry, &'static str> {
+        self.resolve(path, true)
+    }
+
+    /// Finds the entry at a path, returning a final symbolic link itself
+    pub fn lookup_link(&mut self, path: &str) -> Result<DirEntry, &'static str> {
+        self.resolve(path, false)
+    }
+
+    /// Opens the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let entry = self.lookup(path)?;
+        self.open_entry(&entry)
+    }
+
+    /// Opens a file from its directory entry
+    pub fn open_entry(&mut self, entry: &DirEntry) -> Result<File<'_, D>, &'static str> {
+        if entry.is_dir() {
+            return Err("Is a directory");
+        }
+        Ok(File {
+            extents: entry.extents.clone(),
+            size: entry.size(),
+            pos: 0,
+            fs: self,
+        })
+    }
+}
+
+// Directory record at an offset of a sector, None past the last one
+fn record_at(sector: &[u8], offset: usize) -> Option<&[u8]> {
+    let len = *sector.get(offset)? as usize;
+    if len < 34 || offset + len > sector.len() || 33 + sector[offset + 32] as usize > len {
+        return None;
+    }
+    Some(&sector[offset..offset + len])
+}
+
+fn parse_root(record: &[u8]) -> Result<DirEntry, &'static str> {
+    if record[0] < 34 || record[25] & FLAG_DIRECTORY == 0 {
+        return Err("Invalid ISO 9660 root directory");
+    }
+    Ok(DirEntry {
+        name: String::new(),
+        flags: record[25],
+        extents: vec![Extent {
+            block: le32(record, 2),
+            size: le32(record, 10),
+        }],
+        mode: None,
+        symlink: None,
+    })
+}
+
+// System use area of a record, after the identifier and its padding byte
+fn system_use(record: &[u8]) -> &[u8] {
+    let id_len = record[32] as usize;
+    let start = 33 + id_len + (1 - id_len % 2);
+    record.get(start..).unwrap_or(&[])
+}
+
+fn strip_version(name: &str) -> String {
+    let name = match name.rfind(';') {
+        Some(end) => &name[..end],
+        None => name,
+    };
+    name.strip_suffix('.').unwrap_or(name).into()
+}
+
+fn iso_name(id: &[u8]) -> String {
+    strip_version(&String::from_utf8_lossy(id))
+}
+
+fn decode_ucs2(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+// Symbolic link target assembled from SL entries
+#[derive(Default)]
+struct SymlinkBuilder {
+    components: Vec<String>,
+    absolute: bool,
+    /// Whether the last component continues in the next one
+    open: bool,
+    found: bool,
+}
+
+impl SymlinkBuilder {
+    fn push(&mut self, entry: &[u8]) {
+        self.found = true;
+        let mut offset = 5;
+        while offset + 2 <= entry.len() {
+            let (flags, len) = (entry[offset], entry[offset + 1] as usize);
+            let text = entry.get(offset + 2..offset + 2 + len).unwrap_or(&[]);
+            offset += 2 + len;
+
+            let text = match flags & (SL_CURRENT | SL_PARENT | SL_ROOT) {
+                SL_ROOT => {
+                    self.absolute = true;
+                    continue;
+                }
+                SL_CURRENT => ".".into(),
+                SL_PARENT => "..".into(),
+                _ => String::from_utf8_lossy(text).into_owned(),
+            };
+            match (self.open, self.components.last_mut()) {
+                (true, Some(last)) => last.push_str(&text),
+                _ => self.components.push(text),
+            }
+            self.open = flags & SL_CONTINUE != 0;
+        }
+    }
+
+    fn finish(self) -> Option<String> {
+        if !self.found {
+            return None;
+        }
+        let mut target = String::new();
+        if self.absolute {
+            target.push('/');
+        }
+        target.push_str(&self.components.join("/"));
+        Some(target)
+    }
+}
+
+/// File opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut IsoFs<D>,
+    extents: Vec<Extent>,
+    size: u64,
+    pos: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.size.saturating_sub(self.pos));
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        // Find the extent holding the position, reads stop at its end
+        let mut start = 0;
+        for extent in &self.extents {
+            let end = start + extent.size as u64;
+            if self.pos < end {
+                let offset = self.pos - start;
+                let len = wanted.min(end - self.pos) as usize;
+                let disk_offset = extent.block as u64 * self.fs.block_size + offset;
+                self.fs.device.read_at(disk_offset, &mut buf[..len])?;
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            start = end;
+        }
+        Ok(0)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for IsoFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Iso9660
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(IsoFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = IsoFs::read_dir(self, &dir)?;
+        Ok(super::listing(entries.into_iter().map(|e| {
+            let kind = e.kind();
+            (e.name, kind)
+        })))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let entry = self.lookup(path)?;
+        let size = match entry.is_dir() {
+            true => 0,
+            false => entry.size(),
+        };
+        Ok(super::Metadata {
+            kind: entry.kind(),
+            size,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        self.lookup_link(path)?.symlink.ok_or("Not a symbolic link")
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only filesystem drivers working on any [`crate::block::BlockDevice`]
+//!
+//! Paths accept both '/' and the '\\' separator used in Open Firmware
+//! device specifiers. Each driver implements [`FileSystem`], so that
+//! [`probe`] can pick the right one for a device.
+
+pub mod btrfs;
+pub mod ext;
+pub mod fat;
+pub mod hfsplus;
+pub mod iso9660;
+pub mod vfs;
+pub mod xfs;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::block::BlockDevice;
+use crate::io::{Read, Seek};
+use crate::partition::Table;
+
+pub(crate) const MODE_TYPE_MASK: u32 = 0o170000;
+pub(crate) const MODE_DIRECTORY: u32 = 0o040000;
+pub(crate) const MODE_REGULAR: u32 = 0o100000;
+pub(crate) const MODE_SYMLINK: u32 = 0o120000;
+
+/// Kind of file an inode or directory entry refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    /// Devices, pipes and sockets
+    Other,
+}
+
+impl FileType {
+    /// Type from the file type bits of a POSIX mode
+    pub fn from_mode(mode: u32) -> Self {
+        match mode & MODE_TYPE_MASK {
+            MODE_REGULAR => FileType::Regular,
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Type from the file type byte of ext2 and XFS directory entries, None when not recorded
+    pub fn from_dirent(kind: u8) -> Option<Self> {
+        match kind {
+            0 => None,
+            1 => Some(FileType::Regular),
+            2 => Some(FileType::Directory),
+            7 => Some(FileType::Symlink),
+            _ => Some(FileType::Other),
+        }
+    }
+}
+
+/// Splits a path into its components, skipping empty and '.' components
+pub fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// Filesystem formats recognized by [`detect`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsType {
+    Btrfs,
+    /// ext2, ext3 or ext4
+    Ext,
+    Fat,
+    /// HFS, HFS+ or HFSX
+    Hfs,
+    Iso9660,
+    Xfs,
+}
+
+/// Type and size of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// Size in bytes, 0 for directories
+    pub size: u64,
+}
+
+/// Directory entry as listed through [`FileSystem::read_dir`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+/// File opened through [`FileSystem::open`]
+pub trait File: Read + Seek {
+    /// File size in bytes
+    fn size(&self) -> u64;
+}
+
+/// Operations shared by the filesystem drivers
+///
+/// Paths start from the root directory and symbolic links are followed,
+/// except for the last component given to [`FileSystem::read_link`].
+pub trait FileSystem {
+    /// Format of the filesystem
+    fn fs_type(&self) -> FsType;
+
+    /// Opens a regular file for reading
+    fn open(&mut self, path: &str) -> Result<Box<dyn File + '_>, &'static str>;
+
+    /// Lists a directory, '.' and '..' excluded
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, &'static str>;
+
+    /// Type and size of the file at a path
+    fn metadata(&mut self, path: &str) -> Result<Metadata, &'static str>;
+
+    /// Target of the symbolic link at a path
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str>;
+}
+
+// Whether bytes at an offset of the device match a signature, false when out of the device
+fn has_magic<D: BlockDevice>(device: &mut D, offset: u64, magic: &[u8]) -> bool {
+    let mut buf = [0u8; 8];
+    let buf = &mut buf[..magic.len()];
+    device.read_at(offset, buf).is_ok() && buf == magic
+}
+
+// Whether the first sector holds a plausible FAT BIOS parameter block
+fn has_fat_bpb<D: BlockDevice>(device: &mut D) -> bool {
+    let mut sector = [0u8; 512];
+    if device.read_at(0, &mut sector).is_err() || sector[510..] != [0x55, 0xaa] {
+        return false;
+    }
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let reserved = u16::from_le_bytes([sector[14], sector[15]]);
+    matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sector[13].is_power_of_two()
+        && reserved > 0
+        && matches!(sector[16], 1 | 2)
+}
+
+/// Recognizes the filesystem of a device by its signature
+///
+/// FAT has no signature of its own and is recognized by its boot sector,
+/// it is checked last.
+pub fn detect<D: BlockDevice>(device: &mut D) -> Option<FsType> {
+    if has_magic(device, 1024 + 56, &[0x53, 0xef]) {
+        Some(FsType::Ext)
+    } else if has_magic(device, 0, b"XFSB") {
+        Some(FsType::Xfs)
+    } else if has_magic(device, 0x10040, b"_BHRfS_M") {
+        Some(FsType::Btrfs)
+    } else if has_magic(device, 32769, b"CD001") {
+        Some(FsType::Iso9660)
+    } else if has_magic(device, 1024, b"H+")
+        || has_magic(device, 1024, b"HX")
+        || has_magic(device, 1024, b"BD")
+    {
+        Some(FsType::Hfs)
+    } else if has_fat_bpb(device) {
+        Some(FsType::Fat)
+    } else {
+        None
+    }
+}
+
+/// Mounts a device with the driver for the filesystem [`detect`] finds on it
+pub fn probe<'a, D: BlockDevice + 'a>(
+    mut device: D,
+) -> Result<Box<dyn FileSystem + 'a>, &'static str> {
+    Ok(
+        match detect(&mut device).ok_or("No known filesystem found")? {
+            FsType::Btrfs => Box::new(btrfs::BtrfsFs::new(device)?),
+            FsType::Ext => Box::new(ext::ExtFs::new(device)?),
+            FsType::Fat => Box::new(fat::FatFs::new(device)?),
+            FsType::Hfs => Box::new(hfsplus::HfsPlusFs::new(device)?),
+            FsType::Iso9660 => Box::new(iso9660::IsoFs::new(device)?),
+            FsType::Xfs => Box::new(xfs::XfsFs::new(device)?),
+        },
+    )
+}
+
+/// Mounts a partition of a disk, or the whole disk
+///
+/// ```partition``` is the partition argument of a device specifier. Without
+/// one, a filesystem covering the whole disk is used, or else the first
+/// partition holding a known filesystem. FAT boot sectors are easily confused
+/// with an MBR, so partitions are tried before a whole disk FAT filesystem.
+pub fn mount<'a, D: BlockDevice + 'a>(
+    mut device: D,
+    partition: Option<&str>,
+) -> Result<Box<dyn FileSystem + 'a>, &'static str> {
+    if let Some(partition) = partition {
+        let table = Table::read(&mut device)?;
+        return probe(table.open_by_name(device, partition)?);
+    }
+
+    let whole = detect(&mut device);
+    if whole.is_some_and(|kind| kind != FsType::Fat) {
+        return probe(device);
+    }
+    if let Ok(table) = Table::read(&mut device) {
+        for number in table.numbers() {
+            let found = match table.open(&mut device, number) {
+                Ok(mut range) => detect(&mut range).is_some(),
+                Err(_) => false,
+            };
+            if found {
+                return probe(table.open(device, number)?);
+            }
+        }
+    }
+    match whole {
+        Some(_) => probe(device),
+        None => Err("No known filesystem found"),
+    }
+}
+
+// Listing through the trait from a driver's entries
+fn listing(entries: impl Iterator<Item = (String, FileType)>) -> Vec<DirEntry> {
+    entries
+        .map(|(name, kind)| DirEntry { name, kind })
+        .collect()
+}
@@ -0,0 +1,135 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Files named by device specifiers, read through the crate's own drivers
+//!
+//! A specifier such as ```hd:2,\boot\vmlinux``` names a disk, a partition and
+//! a path. The disk is opened without arguments and read through
+//! [`crate::partition::Table`] and [`super::probe`], rather than through the
+//! firmware's disk-label package. Mounted filesystems are kept, so that
+//! loading several files from a disk reads its partition table and
+//! superblock once.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::{DirEntry, File, FileSystem, Metadata};
+use crate::block::{BlockDevice, Disk};
+use crate::devspec::DevSpec;
+use crate::PROM;
+
+struct Mount {
+    /// Device specifier without arguments, aliases expanded
+    device: String,
+    partition: Option<String>,
+    fs: Box<dyn FileSystem>,
+}
+
+/// Filesystems mounted from device specifiers
+pub struct Vfs {
+    prom: PROM,
+    mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub fn new(prom: &PROM) -> Self {
+        Vfs {
+            prom: *prom,
+            mounts: Vec::new(),
+        }
+    }
+
+    /// Mounts a block device the firmware does not know about, such as a ramdisk
+    ///
+    /// # Arguments
+    ///
+    /// ```device```: name specifiers use for the device, aliases are not expanded
+    /// ```partition```: partition argument, as in [`super::mount`]
+    /// ```disk```: the device
+    pub fn mount<D: BlockDevice + 'static>(
+        &mut self,
+        device: &str,
+        partition: Option<&str>,
+        disk: D,
+    ) -> Result<(), &'static str> {
+        let fs = super::mount(disk, partition)?;
+        self.mounts.push(Mount {
+            device: device.to_owned(),
+            partition: partition.map(ToOwned::to_owned),
+            fs,
+        });
+        Ok(())
+    }
+
+    /// Drops the mounted filesystems, closing their devices
+    pub fn unmount_all(&mut self) {
+        self.mounts.clear();
+    }
+
+    /// Filesystem and path within it named by a device specifier
+    ///
+    /// The device is mounted on first use. A specifier without a filename
+    /// names the root directory.
+    pub fn resolve(&mut self, spec: &str) -> Result<(&mut dyn FileSystem, String), &'static str> {
+        let mut spec = DevSpec::parse(spec)?;
+        let known = |mounts: &[Mount], spec: &DevSpec| {
+            let device = spec.device().to_string();
+            let partition = spec.partition();
+            mounts
+                .iter()
+                .position(|m| m.device == device && m.partition.as_deref() == partition)
+        };
+
+        let mut index = known(&self.mounts, &spec);
+        if index.is_none() {
+            spec = spec.expand_aliases(&self.prom)?;
+            index = known(&self.mounts, &spec);
+        }
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let disk = Disk::open(&self.prom, &spec.device().to_open_string())?;
+                let partition = spec.partition();
+                let fs = super::mount(disk, partition)?;
+                self.mounts.push(Mount {
+                    device: spec.device().to_string(),
+                    partition: partition.map(ToOwned::to_owned),
+                    fs,
+                });
+                self.mounts.len() - 1
+            }
+        };
+
+        let path = spec.filename().unwrap_or("\\").to_string();
+        Ok((self.mounts[index].fs.as_mut(), path))
+    }
+
+    /// Opens the file named by a device specifier
+    pub fn open(&mut self, spec: &str) -> Result<Box<dyn File + '_>, &'static str> {
+        let (fs, path) = self.resolve(spec)?;
+        fs.open(&path)
+    }
+
+    /// Lists the directory named by a device specifier
+    pub fn read_dir(&mut self, spec: &str) -> Result<Vec<DirEntry>, &'static str> {
+        let (fs, path) = self.resolve(spec)?;
+        fs.read_dir(&path)
+    }
+
+    /// Type and size of the file named by a device specifier
+    pub fn metadata(&mut self, spec: &str) -> Result<Metadata, &'static str> {
+        let (fs, path) = self.resolve(spec)?;
+        fs.metadata(&path)
+    }
+
+    /// Target of the symbolic link named by a device specifier
+    pub fn read_link(&mut self, spec: &str) -> Result<String, &'static str> {
+        let (fs, path) = self.resolve(spec)?;
+        fs.read_link(&path)
+    }
+}
@@ -0,0 +1,868 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Read-only XFS driver
+//!
+//! Both v4 and v5 filesystems are mounted; on v5 the superblock, inodes,
+//! extent tree blocks, directory data blocks and remote symbolic links are
+//! verified against their CRC-32C as they are read. Short form, block, leaf
+//! and node directories are listed by scanning their data blocks, the hash
+//! indexes are not used. The log is not replayed and the realtime device is
+//! not supported.
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{path_components, FileType};
+use crate::block::BlockDevice;
+use crate::bytes::{be16, be32, be64};
+use crate::crc32::Crc32c;
+use crate::io::{Read, Seek};
+
+const SUPERBLOCK_SIZE: usize = 512;
+const MAGIC: u32 = 0x5846_5342; // "XFSB"
+const INODE_MAGIC: u16 = 0x494e; // "IN"
+const BMAP_MAGIC: u32 = 0x424d_4150; // "BMAP"
+const BMAP_CRC_MAGIC: u32 = 0x424d_4133; // "BMA3"
+const DIR_BLOCK_MAGIC: u32 = 0x5844_3242; // "XD2B"
+const DIR_DATA_MAGIC: u32 = 0x5844_3244; // "XD2D"
+const DIR_BLOCK_CRC_MAGIC: u32 = 0x5844_4233; // "XDB3"
+const DIR_DATA_CRC_MAGIC: u32 = 0x5844_4433; // "XDD3"
+const SYMLINK_MAGIC: u32 = 0x5853_4c4d; // "XSLM"
+
+/// Offset of the CRC in every v5 metadata structure it protects
+const SB_CRC_OFFSET: usize = 224;
+const INODE_CRC_OFFSET: usize = 100;
+const BMAP_CRC_OFFSET: usize = 64;
+const DIR_CRC_OFFSET: usize = 4;
+const SYMLINK_CRC_OFFSET: usize = 12;
+
+const BMAP_HEADER_SIZE: usize = 24;
+const BMAP_CRC_HEADER_SIZE: usize = 72;
+const DIR_HEADER_SIZE: usize = 16;
+const DIR_CRC_HEADER_SIZE: usize = 64;
+const SYMLINK_HEADER_SIZE: usize = 56;
+const INODE_CORE_SIZE: usize = 100;
+const INODE_CRC_CORE_SIZE: usize = 176;
+
+const INCOMPAT_FTYPE: u32 = 0x1;
+const INCOMPAT_SPINODES: u32 = 0x2;
+const INCOMPAT_META_UUID: u32 = 0x4;
+const INCOMPAT_BIGTIME: u32 = 0x8;
+const INCOMPAT_NREXT64: u32 = 0x20;
+/// Incompatible features that do not get in the way of reading files
+const INCOMPAT_SUPPORTED: u32 =
+    INCOMPAT_FTYPE | INCOMPAT_SPINODES | INCOMPAT_META_UUID | INCOMPAT_BIGTIME | INCOMPAT_NREXT64;
+/// v4 feature bit of the file type in directory entries
+const FEATURES2_FTYPE: u32 = 0x200;
+/// Inode flag of the 64-bit extent counters
+const FLAG2_NREXT64: u64 = 0x10;
+
+const FORMAT_LOCAL: u8 = 1;
+const FORMAT_EXTENTS: u8 = 2;
+const FORMAT_BTREE: u8 = 3;
+
+/// Directory blocks past this byte offset hold the hash index and free space
+const DIR_LEAF_OFFSET: u64 = 1 << 35;
+/// Free space tag of unused directory data entries
+const DIR_FREE_TAG: u16 = 0xffff;
+/// Depth limit of extent trees
+const MAX_BTREE_LEVEL: u16 = 8;
+/// Symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 8;
+/// Longest symbolic link target accepted
+const MAX_SYMLINK_SIZE: u64 = 1024;
+
+// Checks the little endian CRC-32C stored at an offset, computed with the field zeroed
+fn crc_ok(data: &[u8], offset: usize) -> bool {
+    let mut crc = Crc32c::new();
+    crc.update(&data[..offset]);
+    crc.update(&[0; 4]);
+    crc.update(&data[offset + 4..]);
+    let stored = &data[offset..offset + 4];
+    crc.finish() == u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]])
+}
+
+/// Extent of a file, in filesystem blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block
+    pub offset: u64,
+    /// First block on the device
+    pub block: u64,
+    pub count: u64,
+    /// Preallocated but never written, reads as zeros
+    pub unwritten: bool,
+}
+
+/// Inode, with the fields needed to read it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inode {
+    pub number: u64,
+    pub mode: u16,
+    pub links: u32,
+    /// Size in bytes
+    pub size: u64,
+    /// Layout of the data fork
+    format: u8,
+    extents: u64,
+    /// Data fork: inline data, extent list or extent tree root
+    fork: Vec<u8>,
+}
+
+impl Inode {
+    pub fn kind(&self) -> FileType {
+        FileType::from_mode(self.mode as u32)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind() == FileType::Symlink
+    }
+}
+
+/// Directory entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// XFS filesystem
+pub struct XfsFs<D: BlockDevice> {
+    device: D,
+    block_size: u64,
+    inode_size: usize,
+    ag_blocks: u64,
+    ag_count: u64,
+    ag_block_log: u8,
+    inodes_per_block_log: u8,
+    dir_block_size: u64,
+    root_inode: u64,
+    /// Whether metadata carries CRCs, as on v5 filesystems
+    crc: bool,
+    /// Whether directory entries record the type of the file
+    ftype: bool,
+    nrext64: bool,
+    uuid: [u8; 16],
+    label: Option<String>,
+}
+
+impl<D: BlockDevice> XfsFs<D> {
+    /// Mounts the filesystem on a device
+    ///
+    /// Fails on filesystems with incompatible features the driver does not
+    /// implement, such as a realtime device or a dirty log.
+    pub fn new(mut device: D) -> Result<Self, &'static str> {
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        device.read_at(0, &mut sb)?;
+        if be32(&sb, 0) != MAGIC {
+            return Err("Not an XFS filesystem");
+        }
+
+        let crc = match be16(&sb, 100) & 0xf {
+            4 => false,
+            5 => true,
+            _ => return Err("Unsupported XFS version"),
+        };
+        let sector_size = be16(&sb, 102) as usize;
+        if !(512..=32768).contains(&sector_size) || !sector_size.is_power_of_two() {
+            return Err("Invalid XFS sector size");
+        }
+        let incompat = match crc {
+            true => {
+                let mut sector = vec![0u8; sector_size];
+                device.read_at(0, &mut sector)?;
+                if !crc_ok(&sector, SB_CRC_OFFSET) {
+                    return Err("XFS superblock checksum mismatch");
+                }
+                be32(&sb, 216)
+            }
+            false => 0,
+        };
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err("Unsupported XFS features");
+        }
+        if be64(&sb, 16) != 0 {
+            return Err("XFS realtime devices are not supported");
+        }
+        if sb[126] != 0 {
+            return Err("XFS filesystem creation did not complete");
+        }
+
+        let block_log = sb[120];
+        let inode_log = sb[122];
+        let inodes_per_block_log = sb[123];
+        let ag_block_log = sb[124];
+        if !(9..=16).contains(&block_log)
+            || !(8..=11).contains(&inode_log)
+            || inode_log + inodes_per_block_log != block_log
+            || ag_block_log > 31
+        {
+            return Err("Invalid XFS geometry");
+        }
+        let block_size = 1u64 << block_log;
+        if be32(&sb, 4) as u64 != block_size {
+            return Err("Invalid XFS block size");
+        }
+
+        let ag_blocks = be32(&sb, 84) as u64;
+        let ag_count = be32(&sb, 88) as u64;
+        if ag_blocks == 0 || ag_count == 0 || ag_blocks > 1 << ag_block_log {
+            return Err("Invalid XFS allocation group size");
+        }
+        let blocks = be64(&sb, 8);
+        if blocks.saturating_mul(block_size) > device.size() {
+            return Err("XFS filesystem is larger than the device");
+        }
+
+        let dir_block_log = sb[192];
+        if block_log + dir_block_log > 16 {
+            return Err("Invalid XFS directory block size");
+        }
+
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&sb[32..48]);
+        let label: String = sb[108..120]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        Ok(XfsFs {
+            device,
+            block_size,
+            inode_size: 1 << inode_log,
+            ag_blocks,
+            ag_count,
+            ag_block_log,
+            inodes_per_block_log,
+            dir_block_size: block_size << dir_block_log,
+            root_inode: be64(&sb, 56),
+            crc,
+            ftype: incompat & INCOMPAT_FTYPE != 0 || be32(&sb, 200) & FEATURES2_FTYPE != 0,
+            nrext64: incompat & INCOMPAT_NREXT64 != 0,
+            uuid,
+            label: (!label.is_empty()).then_some(label),
+        })
+    }
+
+    /// Volume label, if one was set
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn uuid(&self) -> &[u8; 16] {
+        &self.uuid
+    }
+
+    /// Size of a block in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Whether metadata checksums are verified
+    pub fn has_crc(&self) -> bool {
+        self.crc
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Block on the device of a block number split by allocation group
+    fn device_block(&self, block: u64) -> Result<u64, &'static str> {
+        let group = block >> self.ag_block_log;
+        let index = block & ((1 << self.ag_block_log) - 1);
+        if group >= self.ag_count || index >= self.ag_blocks {
+            return Err("Invalid XFS block number");
+        }
+        Ok(group * self.ag_blocks + index)
+    }
+
+    fn read_block(&mut self, block: u64, len: usize) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; len];
+        let block = self.device_block(block)?;
+        self.device.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    /// Inode of the root directory
+    pub fn root(&mut self) -> Result<Inode, &'static str> {
+        self.read_inode(self.root_inode)
+    }
+
+    /// Reads an inode by number
+    pub fn read_inode(&mut self, number: u64) -> Result<Inode, &'static str> {
+        let block = self.device_block(number >> self.inodes_per_block_log)?;
+        let index = number & ((1 << self.inodes_per_block_log) - 1);
+        let mut raw = vec![0u8; self.inode_size];
+        self.device.read_at(
+            block * self.block_size + index * self.inode_size as u64,
+            &mut raw,
+        )?;
+
+        if be16(&raw, 0) != INODE_MAGIC {
+            return Err("Invalid XFS inode");
+        }
+        let version = raw[4];
+        let core_size = match version {
+            1 | 2 => INODE_CORE_SIZE,
+            3 if self.crc => {
+                if !crc_ok(&raw, INODE_CRC_OFFSET) || be64(&raw, 152) != number {
+                    return Err("XFS inode checksum mismatch");
+                }
+                INODE_CRC_CORE_SIZE
+            }
+            _ => return Err("Unsupported XFS inode version"),
+        };
+
+        let big_extents = version == 3 && self.nrext64 && be64(&raw, 120) & FLAG2_NREXT64 != 0;
+        let extents = match big_extents {
+            true => be64(&raw, 24),
+            false => be32(&raw, 76) as u64,
+        };
+        let fork_end = match raw[82] {
+            0 => self.inode_size,
+            offset => core_size + offset as usize * 8,
+        };
+        if fork_end > self.inode_size {
+            return Err("Invalid XFS inode fork offset");
+        }
+
+        Ok(Inode {
+            number,
+            mode: be16(&raw, 2),
+            links: match version {
+                1 => be16(&raw, 6) as u32,
+                _ => be32(&raw, 16),
+            },
+            size: be64(&raw, 56),
+            format: raw[5],
+            extents,
+            fork: raw[core_size..fork_end].to_vec(),
+        })
+    }
+
+    fn parse_extents(&self, records: &[u8], out: &mut Vec<Extent>) -> Result<(), &'static str> {
+        for record in records.chunks_exact(16) {
+            let high = be64(record, 0);
+            let low = be64(record, 8);
+            out.push(Extent {
+                offset: (high & !(1 << 63)) >> 9,
+                block: self.device_block((high & 0x1ff) << 43 | low >> 21)?,
+                count: low & 0x1f_ffff,
+                unwritten: high >> 63 != 0,
+            });
+        }
+        Ok(())
+    }
+
+    // Collects the extents below a node of the extent tree
+    fn walk_btree(
+        &mut self,
+        block: u64,
+        level: u16,
+        owner: u64,
+        out: &mut Vec<Extent>,
+    ) -> Result<(), &'static str> {
+        let data = self.read_block(block, self.block_size as usize)?;
+        let header = match be32(&data, 0) {
+            BMAP_CRC_MAGIC if self.crc => {
+                if !crc_ok(&data, BMAP_CRC_OFFSET) || be64(&data, 56) != owner {
+                    return Err("XFS extent tree checksum mismatch");
+                }
+                BMAP_CRC_HEADER_SIZE
+            }
+            BMAP_MAGIC if !self.crc => BMAP_HEADER_SIZE,
+            _ => return Err("Invalid XFS extent tree block"),
+        };
+        if be16(&data, 4) != level {
+            return Err("Invalid XFS extent tree level");
+        }
+
+        let records = be16(&data, 6) as usize;
+        let max_records = (data.len() - header) / 16;
+        if records > max_records {
+            return Err("Invalid XFS extent tree block");
+        }
+        match level {
+            0 => self.parse_extents(&data[header..header + records * 16], out),
+            _ => {
+                let pointers = header + max_records * 8;
+                for i in 0..records {
+                    let child = be64(&data, pointers + i * 8);
+                    self.walk_btree(child, level - 1, owner, out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Extents of the data fork of an inode, sorted by logical block
+    pub fn extents(&mut self, inode: &Inode) -> Result<Vec<Extent>, &'static str> {
+        let mut extents = Vec::new();
+        match inode.format {
+            FORMAT_EXTENTS => {
+                let len = (inode.extents as usize)
+                    .checked_mul(16)
+                    .filter(|len| *len <= inode.fork.len())
+                    .ok_or("Invalid XFS extent count")?;
+                self.parse_extents(&inode.fork[..len], &mut extents)?;
+            }
+            FORMAT_BTREE => {
+                if inode.fork.len() < 4 {
+                    return Err("Invalid XFS extent tree root");
+                }
+                let level = be16(&inode.fork, 0);
+                let records = be16(&inode.fork, 2) as usize;
+                let max_records = (inode.fork.len() - 4) / 16;
+                if level == 0 || level > MAX_BTREE_LEVEL || records > max_records {
+                    return Err("Invalid XFS extent tree root");
+                }
+                let pointers = 4 + max_records * 8;
+                for i in 0..records {
+                    let child = be64(&inode.fork, pointers + i * 8);
+                    self.walk_btree(child, level - 1, inode.number, &mut extents)?;
+                }
+                if extents.len() as u64 != inode.extents {
+                    return Err("Invalid XFS extent count");
+                }
+            }
+            FORMAT_LOCAL => return Err("XFS inode data is stored inline"),
+            _ => return Err("Unsupported XFS inode format"),
+        }
+        if extents
+            .windows(2)
+            .any(|w| w[0].offset + w[0].count > w[1].offset)
+        {
+            return Err("Overlapping XFS extents");
+        }
+        Ok(extents)
+    }
+
+    // Reads bytes at a logical offset of a file, holes and unwritten extents read as zeros
+    fn read_mapped(
+        &mut self,
+        extents: &[Extent],
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        let block = offset / self.block_size;
+        let index = extents.partition_point(|e| e.offset + e.count <= block);
+        let (len, source) = match extents.get(index) {
+            Some(extent) if extent.offset <= block => {
+                let end = (extent.offset + extent.count) * self.block_size;
+                let len = (buf.len() as u64).min(end - offset) as usize;
+                let skip = offset - extent.offset * self.block_size;
+                (
+                    len,
+                    (!extent.unwritten).then_some(extent.block * self.block_size + skip),
+                )
+            }
+            Some(extent) => {
+                let len = (buf.len() as u64).min(extent.offset * self.block_size - offset);
+                (len as usize, None)
+            }
+            None => (buf.len(), None),
+        };
+        match source {
+            Some(position) => self.device.read_at(position, &mut buf[..len])?,
+            None => buf[..len].fill(0),
+        }
+        Ok(len)
+    }
+
+    fn read_mapped_exact(
+        &mut self,
+        extents: &[Extent],
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> Result<(), &'static str> {
+        while !buf.is_empty() {
+            let len = self.read_mapped(extents, offset, buf)?;
+            offset += len as u64;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    fn short_form_entries(&self, dir: &Inode) -> Result<Vec<DirEntry>, &'static str> {
+        let data = dir
+            .fork
+            .get(..dir.size as usize)
+            .ok_or("Invalid XFS short form directory")?;
+        let truncated = "Truncated XFS short form directory";
+        if data.len() < 2 {
+            return Err(truncated);
+        }
+        let count = data[0] as usize;
+        let inode_size = if data[1] > 0 { 8 } else { 4 };
+        let read_inode = |offset: usize| -> Result<u64, &'static str> {
+            let raw = data.get(offset..offset + inode_size).ok_or(truncated)?;
+            Ok(match inode_size {
+                8 => be64(raw, 0),
+                _ => be32(raw, 0) as u64,
+            })
+        };
+
+        let mut entries = vec![
+            DirEntry {
+                name: ".".to_owned(),
+                inode: dir.number,
+                kind: FileType::Directory,
+            },
+            DirEntry {
+                name: "..".to_owned(),
+                inode: read_inode(2)?,
+                kind: FileType::Directory,
+            },
+        ];
+        let mut offset = 2 + inode_size;
+        for _ in 0..count {
+            let len = *data.get(offset).ok_or(truncated)? as usize;
+            // Skip the length and the offset the entry would have in a data block
+            offset += 3;
+            let name = data.get(offset..offset + len).ok_or(truncated)?;
+            offset += len;
+            let kind = match self.ftype {
+                true => {
+                    offset += 1;
+                    *data.get(offset - 1).ok_or(truncated)?
+                }
+                false => 0,
+            };
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                inode: read_inode(offset)?,
+                kind: FileType::from_dirent(kind).unwrap_or(FileType::Other),
+            });
+            offset += inode_size;
+        }
+        Ok(entries)
+    }
+
+    fn data_block_entries(
+        &self,
+        block: &[u8],
+        entries: &mut Vec<DirEntry>,
+    ) -> Result<(), &'static str> {
+        let (header, block_format) = match be32(block, 0) {
+            DIR_BLOCK_CRC_MAGIC if self.crc => (DIR_CRC_HEADER_SIZE, true),
+            DIR_DATA_CRC_MAGIC if self.crc => (DIR_CRC_HEADER_SIZE, false),
+            DIR_BLOCK_MAGIC if !self.crc => (DIR_HEADER_SIZE, true),
+            DIR_DATA_MAGIC if !self.crc => (DIR_HEADER_SIZE, false),
+            _ => return Err("Invalid XFS directory block"),
+        };
+        if self.crc && !crc_ok(block, DIR_CRC_OFFSET) {
+            return Err("XFS directory block checksum mismatch");
+        }
+
+        // Single block directories keep their hash index and a tail at the end
+        let end = match block_format {
+            true => {
+                let leaves = be32(block, block.len() - 8) as usize;
+                leaves
+                    .checked_mul(8)
+                    .and_then(|len| (block.len() - 8).checked_sub(len))
+                    .filter(|end| *end >= header)
+                    .ok_or("Invalid XFS directory block")?
+            }
+            false => block.len(),
+        };
+
+        let mut offset = header;
+        while offset < end {
+            if end - offset < 8 {
+                return Err("Invalid XFS directory entry");
+            }
+            if be16(block, offset) == DIR_FREE_TAG {
+                let len = be16(block, offset + 2) as usize;
+                if len == 0 || !len.is_multiple_of(8) {
+                    return Err("Invalid XFS directory entry");
+                }
+                offset += len;
+                continue;
+            }
+
+            let inode = be64(block, offset);
+            let len = block[offset + 8] as usize;
+            let name_end = offset + 9 + len;
+            let size = (9 + len + self.ftype as usize + 2).next_multiple_of(8);
+            if len == 0 || offset + size > end {
+                return Err("Invalid XFS directory entry");
+            }
+            let kind = match self.ftype {
+                true => block[name_end],
+                false => 0,
+            };
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(&block[offset + 9..name_end]).into_owned(),
+                inode,
+                kind: FileType::from_dirent(kind).unwrap_or(FileType::Other),
+            });
+            offset += size;
+        }
+        Ok(())
+    }
+
+    // Every entry of a directory, '.' and '..' included
+    fn dir_entries(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, &'static str> {
+        if !dir.is_dir() {
+            return Err("Not a directory");
+        }
+        if dir.format == FORMAT_LOCAL {
+            return self.short_form_entries(dir);
+        }
+
+        let extents = self.extents(dir)?;
+        let blocks_per_dir_block = self.dir_block_size / self.block_size;
+        let leaf_block = DIR_LEAF_OFFSET / self.block_size;
+        let mut dir_blocks: Vec<u64> = Vec::new();
+        for extent in &extents {
+            let first = extent.offset / blocks_per_dir_block;
+            let last = (extent.offset + extent.count).min(leaf_block);
+            for dir_block in first..last.div_ceil(blocks_per_dir_block) {
+                if dir_blocks.last() != Some(&dir_block) {
+                    dir_blocks.push(dir_block);
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut data = vec![0u8; self.dir_block_size as usize];
+        for dir_block in dir_blocks {
+            self.read_mapped_exact(&extents, dir_block * self.dir_block_size, &mut data)?;
+            self.data_block_entries(&data, &mut entries)?;
+        }
+        Ok(entries)
+    }
+
+    /// Lists a directory, '.' and '..' excluded
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries = self.dir_entries(dir)?;
+        entries.retain(|entry| entry.name != "." && entry.name != "..");
+        if !self.ftype {
+            for entry in entries.iter_mut() {
+                entry.kind = self.read_inode(entry.inode)?.kind();
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Target of a symbolic link
+    pub fn read_link(&mut self, inode: &Inode) -> Result<String, &'static str> {
+        if !inode.is_symlink() {
+            return Err("Not a symbolic link");
+        }
+        if inode.size > MAX_SYMLINK_SIZE {
+            return Err("Symbolic link target is too long");
+        }
+        let size = inode.size as usize;
+
+        let target = match inode.format {
+            FORMAT_LOCAL => inode
+                .fork
+                .get(..size)
+                .ok_or("Invalid XFS symbolic link")?
+                .to_vec(),
+            _ => {
+                let extents = self.extents(inode)?;
+                let header = if self.crc { SYMLINK_HEADER_SIZE } else { 0 };
+                let mut target = Vec::with_capacity(size);
+                let mut block = vec![0u8; self.block_size as usize];
+                let mut logical = 0;
+                while target.len() < size {
+                    self.read_mapped_exact(&extents, logical * self.block_size, &mut block)?;
+                    let len = (size - target.len()).min(block.len() - header);
+                    if self.crc
+                        && (be32(&block, 0) != SYMLINK_MAGIC
+                            || !crc_ok(&block, SYMLINK_CRC_OFFSET)
+                            || be32(&block, 4) as usize != target.len()
+                            || be32(&block, 8) as usize != len
+                            || be64(&block, 32) != inode.number)
+                    {
+                        return Err("XFS symbolic link checksum mismatch");
+                    }
+                    target.extend_from_slice(&block[header..header + len]);
+                    logical += 1;
+                }
+                target
+            }
+        };
+        Ok(String::from_utf8_lossy(&target).into_owned())
+    }
+
+    fn resolve(&mut self, path: &str, follow: bool) -> Result<Inode, &'static str> {
+        let mut pending: Vec<String> = path_components(path).map(ToOwned::to_owned).collect();
+        pending.reverse();
+        let mut current = self.root()?;
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            let inode = self
+                .dir_entries(&current)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or("File not found")?
+                .inode;
+            let inode = self.read_inode(inode)?;
+
+            if inode.is_symlink() && (follow || !pending.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err("Too many levels of symbolic links");
+                }
+                let target = self.read_link(&inode)?;
+                if target.starts_with('/') {
+                    current = self.root()?;
+                }
+                let start = pending.len();
+                pending.extend(path_components(&target).map(ToOwned::to_owned));
+                pending[start..].reverse();
+                continue;
+            }
+            current = inode;
+        }
+
+        Ok(current)
+    }
+
+    /// Finds the inode at a path, following symbolic links
+    pub fn lookup(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, true)
+    }
+
+    /// Finds the inode at a path, returning a final symbolic link itself
+    pub fn lookup_link(&mut self, path: &str) -> Result<Inode, &'static str> {
+        self.resolve(path, false)
+    }
+
+    /// Opens the file at a path for reading
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, &'static str> {
+        let inode = self.lookup(path)?;
+        if inode.is_dir() {
+            return Err("Is a directory");
+        }
+        self.open_inode(&inode)
+    }
+
+    /// Opens the contents of an inode, directories included
+    pub fn open_inode(&mut self, inode: &Inode) -> Result<File<'_, D>, &'static str> {
+        let contents = match inode.format {
+            FORMAT_LOCAL => Contents::Inline(inode.fork.clone()),
+            _ => Contents::Mapped(self.extents(inode)?),
+        };
+        Ok(File {
+            fs: self,
+            size: inode.size,
+            contents,
+            pos: 0,
+        })
+    }
+}
+
+enum Contents {
+    Inline(Vec<u8>),
+    Mapped(Vec<Extent>),
+}
+
+/// File opened for reading
+pub struct File<'a, D: BlockDevice> {
+    fs: &'a mut XfsFs<D>,
+    size: u64,
+    contents: Contents,
+    pos: u64,
+}
+
+impl<D: BlockDevice> File<'_, D> {
+    /// File size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl<D: BlockDevice> Read for File<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let wanted = (buf.len() as u64).min(self.size.saturating_sub(self.pos)) as usize;
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let len = match &self.contents {
+            Contents::Inline(data) => {
+                let start = self.pos as usize;
+                let data = data
+                    .get(start..start + wanted)
+                    .ok_or("Invalid XFS inline data")?;
+                buf[..wanted].copy_from_slice(data);
+                wanted
+            }
+            Contents::Mapped(extents) => {
+                self.fs.read_mapped(extents, self.pos, &mut buf[..wanted])?
+            }
+        };
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: BlockDevice> Seek for File<'_, D> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match pos <= self.size {
+            true => {
+                self.pos = pos;
+                Ok(())
+            }
+            false => Err("Seek beyond the end of the file"),
+        }
+    }
+}
+
+impl<D: BlockDevice> super::File for File<'_, D> {
+    fn size(&self) -> u64 {
+        File::size(self)
+    }
+}
+
+impl<D: BlockDevice> super::FileSystem for XfsFs<D> {
+    fn fs_type(&self) -> super::FsType {
+        super::FsType::Xfs
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn super::File + '_>, &'static str> {
+        Ok(Box::new(XfsFs::open(self, path)?))
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<super::DirEntry>, &'static str> {
+        let dir = self.lookup(path)?;
+        let entries = XfsFs::read_dir(self, &dir)?;
+        Ok(super::listing(
+            entries.into_iter().map(|e| (e.name, e.kind)),
+        ))
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<super::Metadata, &'static str> {
+        let inode = self.lookup(path)?;
+        Ok(super::Metadata {
+            kind: inode.kind(),
+            size: inode.size,
+        })
+    }
+
+    fn read_link(&mut self, path: &str) -> Result<String, &'static str> {
+        let inode = self.lookup_link(path)?;
+        XfsFs::read_link(self, &inode)
+    }
+}
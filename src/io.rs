@@ -0,0 +1,222 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Stream traits shared by device instances and in memory buffers
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use crate::services::{self, Args};
+use crate::{IHandle, OF_SIZE_ERR, PROM};
+
+/// Source of bytes
+pub trait Read {
+    /// Reads up to ```buf.len()``` bytes
+    ///
+    /// # Returns
+    ///
+    /// The amount of bytes read, 0 at the end of the stream
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    /// Fills ```buf``` completely, failing if the stream ends before
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), &'static str> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err("Unexpected end of stream"),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stream with random access
+pub trait Seek {
+    /// Moves to an absolute position in bytes
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str>;
+}
+
+impl<T: Read + ?Sized> Read for &mut T {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        (**self).read(buf)
+    }
+}
+
+impl<T: Seek + ?Sized> Seek for &mut T {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        (**self).seek(pos)
+    }
+}
+
+impl<T: Read + ?Sized> Read for Box<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        (**self).read(buf)
+    }
+}
+
+impl<T: Seek + ?Sized> Seek for Box<T> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        (**self).seek(pos)
+    }
+}
+
+/// Open package instance, closed when dropped
+pub struct Instance {
+    prom: PROM,
+    handle: *const IHandle,
+}
+
+impl Instance {
+    /// Opens a device from a null terminated device specifier
+    pub fn open(prom: &PROM, dev_spec: &str) -> Result<Self, &'static str> {
+        Ok(Instance {
+            prom: *prom,
+            handle: prom.open(dev_spec)?,
+        })
+    }
+
+    /// Takes ownership of an instance handle returned by ```PROM::open```
+    pub fn from_handle(prom: &PROM, handle: *const IHandle) -> Self {
+        Instance {
+            prom: *prom,
+            handle,
+        }
+    }
+
+    /// Instance handle
+    pub fn handle(&self) -> *const IHandle {
+        self.handle
+    }
+
+    /// Open Firmware environment the instance was opened in
+    pub fn prom(&self) -> &PROM {
+        &self.prom
+    }
+
+    /// Calls a method of the instance, see ```PROM::call_method```
+    pub fn call_method(
+        &self,
+        method: &str,
+        args: &[usize],
+        rets: &mut [usize],
+    ) -> Result<(), &'static str> {
+        self.prom.call_method(self.handle, method, args, rets)
+    }
+
+    /// Size in bytes reported by the ```size``` method, of disks and of open files
+    pub fn size(&self) -> Result<u64, &'static str> {
+        let mut rets = [0usize; 2];
+        // ( -- size.lo size.hi )
+        self.call_method("size\0", &[], &mut rets)?;
+        // Each cell holds 32 bits of the size on 32 bit systems
+        let high = (rets[1] as u64).checked_shl(usize::BITS).unwrap_or(0);
+        Ok(high | rets[0] as u64)
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.prom.close(self.handle);
+    }
+}
+
+impl Read for Instance {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        self.prom.read(self.handle, buf.as_mut_ptr(), buf.len())
+    }
+}
+
+impl Seek for Instance {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        let bits = usize::BITS;
+        let mut args = services::SeekArgs {
+            args: Args {
+                service: c"seek".as_ptr().cast(),
+                nargs: 3,
+                nret: 1,
+            },
+            handle: self.handle,
+            // Each cell holds 32 bits of the position on 32 bit systems
+            pos_hi: pos.checked_shr(bits).unwrap_or(0) as isize,
+            pos_low: pos as usize as isize,
+            status: 0,
+        };
+
+        match (self.prom.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not seek device"),
+            _ => match args.status {
+                -1 => Err("seek not implemented for this device"),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// Stream over an in memory buffer
+pub struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    /// Current position in bytes
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Read for Cursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let remaining = self.data.get(self.pos..).unwrap_or(&[]);
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Seek for Cursor<'_> {
+    fn seek(&mut self, pos: u64) -> Result<(), &'static str> {
+        match usize::try_from(pos) {
+            Ok(pos) if pos <= self.data.len() => {
+                self.pos = pos;
+                Ok(())
+            }
+            _ => Err("Seek beyond the end of the buffer"),
+        }
+    }
+}
+
+/// Formatted output into the firmware console
+///
+/// Line feeds are followed by a carriage return, like ```PROM::write_line``` does.
+pub struct Console {
+    prom: PROM,
+}
+
+impl Console {
+    pub fn new(prom: &PROM) -> Self {
+        Console { prom: *prom }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.prom.write_stdout("\n\r").map_err(|_| fmt::Error)?;
+            }
+            if !line.is_empty() {
+                self.prom.write_stdout(line).map_err(|_| fmt::Error)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -11,8 +11,36 @@ extern crate alloc;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
+pub mod block;
+pub mod boot;
+mod bytes;
+pub mod callback;
+pub mod compress;
+pub mod cpio;
+pub mod crc32;
+pub mod devspec;
+pub mod dump;
+pub mod elf;
+pub mod fdt;
+pub mod fs;
+pub mod io;
+pub mod linux;
+pub mod load;
+pub mod mmu;
+pub mod net;
+pub mod partition;
+pub mod symbols;
+
+use callback::{ClientCallbacks, PreviousCallback};
+use linux::KernelEntry;
+use mmu::Mmu;
+use symbols::{SymbolLookup, SymbolTable};
+
 const OF_SIZE_ERR: usize = usize::MAX;
 
+/// Maximum amount of stack arguments and results supported by ```call_method```
+pub const MAX_METHOD_CELLS: usize = 16;
+
 extern "C" fn fallback_entry(_args: *mut Args) -> usize {
     OF_SIZE_ERR
 }
@@ -123,6 +151,67 @@ pub mod services {
         pub result: usize,
         pub blocks_read: usize,
     }
+
+    #[repr(C)]
+    pub struct SetCallbackArgs {
+        pub args: Args,
+        pub newfunc: Option<extern "C" fn(*mut Args) -> usize>,
+        pub oldfunc: Option<extern "C" fn(*mut Args) -> usize>,
+    }
+
+    #[repr(C)]
+    pub struct SetSymbolLookupArgs {
+        pub args: Args,
+        pub sym_to_value: *const u8,
+        pub value_to_sym: *const u8,
+    }
+
+    #[repr(C)]
+    pub struct NodeArgs {
+        pub args: Args,
+        pub phandle: *const PHandle,
+        pub node: *const PHandle,
+    }
+
+    #[repr(C)]
+    pub struct NextPropArgs {
+        pub args: Args,
+        pub phandle: *const PHandle,
+        pub previous: *const u8,
+        pub buf: *mut u8,
+        pub flag: isize,
+    }
+
+    #[repr(C)]
+    pub struct PackageToPathArgs {
+        pub args: Args,
+        pub phandle: *const PHandle,
+        pub buf: *mut u8,
+        pub buflen: usize,
+        pub length: isize,
+    }
+
+    #[repr(C)]
+    pub struct TestArgs {
+        pub args: Args,
+        pub name: *const u8,
+        pub missing: usize,
+    }
+
+    #[repr(C)]
+    pub struct InstanceToPackageArgs {
+        pub args: Args,
+        pub ihandle: *const IHandle,
+        pub phandle: *const PHandle,
+    }
+
+    #[repr(C)]
+    pub struct PropLenArgs {
+        pub args: Args,
+        pub phandle: *const PHandle,
+        pub prop: *const u8,
+        pub size: usize,
+    }
 }
 
 use services::{Args, CallMethodArgs};
@@ -132,6 +221,8 @@ static mut GLOBAL_PROM: PROM = PROM {
     entry_fn: fallback_entry,
     stdout: ptr::null_mut(),
     chosen: ptr::null_mut(),
+    mmu: ptr::null_mut(),
+    memory: ptr::null_mut(),
 };
 
 #[cfg(not(feature = "no_panic_handler"))]
@@ -162,6 +253,10 @@ pub struct PROM {
     pub chosen: *const PHandle,
     /// Instance handle into stdout
     pub stdout: *const IHandle,
+    /// Instance handle into the MMU, null if '/chosen' does not provide one
+    pub mmu: *const IHandle,
+    /// Instance handle into the memory node, null if '/chosen' does not provide one
+    pub memory: *const IHandle,
 }
 
 impl PROM {
@@ -175,6 +270,8 @@ impl PROM {
             entry_fn: entry,
             chosen: ptr::null_mut(),
             stdout: ptr::null_mut(),
+            mmu: ptr::null_mut(),
+            memory: ptr::null_mut(),
         };
 
         ret.init()?;
@@ -193,9 +290,26 @@ impl PROM {
 
         self.stdout = stdout;
         self.chosen = chosen;
+
+        // Only some environments provide these, so they are not a hard requirement
+        self.mmu = self.get_chosen_ihandle("mmu\0");
+        self.memory = self.get_chosen_ihandle("memory\0");
         Ok(())
     }
 
+    fn get_chosen_ihandle(&self, prop: &str) -> *const IHandle {
+        let mut ihandle: *const IHandle = ptr::null_mut();
+        match self.get_property(
+            self.chosen,
+            prop,
+            &mut ihandle as *mut *const IHandle,
+            core::mem::size_of::<*const IHandle>(),
+        ) {
+            Ok(_) => ihandle,
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
     /// Exits the client program back into Open Firmware
     pub fn exit(&self) -> ! {
         let mut args = Args {
@@ -296,6 +410,331 @@ impl PROM {
         }
     }
 
+    /// Set property on package
+    ///
+    /// # Arguments
+    ///
+    /// ```phandle```: package handle
+    /// ```prop```: null terminated property name
+    /// ```buf```: new value of the property
+    ///
+    /// # Returns
+    ///
+    /// The size of the property as stored by the firmware
+    pub fn set_property(
+        &self,
+        phandle: *const PHandle,
+        prop: &str,
+        buf: &[u8],
+    ) -> Result<usize, &'static str> {
+        let mut args = services::PropArgs {
+            args: Args {
+                service: "setprop\0".as_ptr(),
+                nargs: 4,
+                nret: 1,
+            },
+            phandle,
+            prop: prop.as_ptr(),
+            buf: buf.as_ptr(),
+            buflen: buf.len(),
+            size: 0,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not set property"),
+            _ => match args.size {
+                OF_SIZE_ERR => Err("Could not set property"),
+                size => Ok(size),
+            },
+        }
+    }
+
+    /// Get the length of a property from package
+    ///
+    /// # Arguments
+    ///
+    /// ```phandle```: package handle
+    /// ```prop```: null terminated property name
+    ///
+    /// # Returns
+    ///
+    /// The size in bytes of the property value, an error if the property does not exist
+    pub fn get_property_len(
+        &self,
+        phandle: *const PHandle,
+        prop: &str,
+    ) -> Result<usize, &'static str> {
+        let mut args = services::PropLenArgs {
+            args: Args {
+                service: "getproplen\0".as_ptr(),
+                nargs: 2,
+                nret: 1,
+            },
+            phandle,
+            prop: prop.as_ptr(),
+            size: OF_SIZE_ERR,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not retreive property length"),
+            _ => match args.size {
+                OF_SIZE_ERR => Err("Property does not exist"),
+                size => Ok(size),
+            },
+        }
+    }
+
+    /// Reads the whole value of a property from package
+    ///
+    /// # Arguments
+    ///
+    /// ```phandle```: package handle
+    /// ```prop```: null terminated property name
+    pub fn read_property(
+        &self,
+        phandle: *const PHandle,
+        prop: &str,
+    ) -> Result<alloc::vec::Vec<u8>, &'static str> {
+        let len = self.get_property_len(phandle, prop)?;
+        let mut value = alloc::vec![0u8; len];
+        let len = self.get_property(phandle, prop, value.as_mut_ptr(), len)?;
+        value.truncate(len);
+        Ok(value)
+    }
+
+    /// Returns the package an instance was opened from
+    pub fn instance_to_package(
+        &self,
+        ihandle: *const IHandle,
+    ) -> Result<*const PHandle, &'static str> {
+        let mut args = services::InstanceToPackageArgs {
+            args: Args {
+                service: "instance-to-package\0".as_ptr(),
+                nargs: 1,
+                nret: 1,
+            },
+            ihandle,
+            phandle: ptr::null(),
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not retrieve package from instance"),
+            _ => match args.phandle as usize {
+                0 | OF_SIZE_ERR => Err("Instance has no package"),
+                _ => Ok(args.phandle),
+            },
+        }
+    }
+
+    /// Returns the root node of the device tree
+    pub fn root(&self) -> Result<*const PHandle, &'static str> {
+        match self.peer(ptr::null())? {
+            Some(root) => Ok(root),
+            None => Err("Could not find the root node"),
+        }
+    }
+
+    /// Returns the next sibling of a node, or the root node if ```phandle``` is null
+    pub fn peer(&self, phandle: *const PHandle) -> Result<Option<*const PHandle>, &'static str> {
+        self.relative("peer\0", phandle)
+    }
+
+    /// Returns the first child of a node
+    pub fn child(&self, phandle: *const PHandle) -> Result<Option<*const PHandle>, &'static str> {
+        self.relative("child\0", phandle)
+    }
+
+    /// Returns the parent of a node
+    pub fn parent(&self, phandle: *const PHandle) -> Result<Option<*const PHandle>, &'static str> {
+        self.relative("parent\0", phandle)
+    }
+
+    fn relative(
+        &self,
+        service: &str,
+        phandle: *const PHandle,
+    ) -> Result<Option<*const PHandle>, &'static str> {
+        let mut args = services::NodeArgs {
+            args: Args {
+                service: service.as_ptr(),
+                nargs: 1,
+                nret: 1,
+            },
+            phandle,
+            node: ptr::null(),
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not walk the device tree"),
+            _ => match args.node as usize {
+                0 => Ok(None),
+                OF_SIZE_ERR => Err("Invalid package handle"),
+                _ => Ok(Some(args.node)),
+            },
+        }
+    }
+
+    /// Gets the name of the property following ```previous```
+    ///
+    /// # Arguments
+    ///
+    /// ```phandle```: package handle
+    /// ```previous```: null terminated property name, ```"\0"``` to get the first property
+    /// ```buf```: buffer to store the null terminated name of the next property
+    ///
+    /// # Returns
+    ///
+    /// The length of the name without the null terminator, ```None``` if there are no more properties
+    pub fn next_property(
+        &self,
+        phandle: *const PHandle,
+        previous: &str,
+        buf: &mut [u8; 32],
+    ) -> Result<Option<usize>, &'static str> {
+        let mut args = services::NextPropArgs {
+            args: Args {
+                service: "nextprop\0".as_ptr(),
+                nargs: 3,
+                nret: 1,
+            },
+            phandle,
+            previous: previous.as_ptr(),
+            buf: buf.as_mut_ptr(),
+            flag: -1,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not retrieve next property"),
+            _ => match args.flag {
+                0 => Ok(None),
+                1 => Ok(Some(buf.iter().position(|c| *c == 0).unwrap_or(buf.len()))),
+                _ => Err("Invalid package handle"),
+            },
+        }
+    }
+
+    /// Calls ```f``` with the name of every property of a package
+    ///
+    /// The name is passed twice, as is and null terminated for the other
+    /// property services.
+    pub(crate) fn for_each_property<F>(
+        &self,
+        phandle: *const PHandle,
+        mut f: F,
+    ) -> Result<(), &'static str>
+    where
+        F: FnMut(&str, &str) -> Result<(), &'static str>,
+    {
+        let mut name = [0u8; 32];
+        let mut previous = [0u8; 33];
+        let mut len = 0;
+        loop {
+            let terminated =
+                core::str::from_utf8(&previous[..len + 1]).map_err(|_| "Invalid property name")?;
+            len = match self.next_property(phandle, terminated, &mut name)? {
+                Some(len) => len,
+                None => return Ok(()),
+            };
+            previous[..len].copy_from_slice(&name[..len]);
+            previous[len] = 0;
+            let terminated =
+                core::str::from_utf8(&previous[..len + 1]).map_err(|_| "Invalid property name")?;
+            f(&terminated[..len], terminated)?;
+        }
+    }
+
+    /// Gets the full path of a package
+    ///
+    /// # Returns
+    ///
+    /// The length of the path, which may be larger than ```buf```
+    pub fn package_to_path(
+        &self,
+        phandle: *const PHandle,
+        buf: &mut [u8],
+    ) -> Result<usize, &'static str> {
+        let mut args = services::PackageToPathArgs {
+            args: Args {
+                service: "package-to-path\0".as_ptr(),
+                nargs: 3,
+                nret: 1,
+            },
+            phandle,
+            buf: buf.as_mut_ptr(),
+            buflen: buf.len(),
+            length: -1,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not retrieve package path"),
+            _ => match args.length {
+                length if length < 0 => Err("Invalid package handle"),
+                length => Ok(length as usize),
+            },
+        }
+    }
+
+    /// Checks whether the firmware implements a client interface service
+    ///
+    /// # Arguments
+    ///
+    /// ```name```: null terminated service name
+    pub fn test(&self, name: &str) -> bool {
+        let mut args = services::TestArgs {
+            args: Args {
+                service: "test\0".as_ptr(),
+                nargs: 1,
+                nret: 1,
+            },
+            name: name.as_ptr(),
+            missing: OF_SIZE_ERR,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => false,
+            _ => args.missing == 0,
+        }
+    }
+
+    /// Stops all firmware activity (DMA, interrupts) before handing over the machine
+    pub fn quiesce(&self) {
+        let mut args = Args {
+            service: "quiesce\0".as_ptr(),
+            nargs: 0,
+            nret: 0,
+        };
+
+        let _ = (self.entry_fn)(&mut args as *mut Args);
+    }
+
+    /// Boots a Linux kernel already loaded in memory
+    ///
+    /// # Arguments
+    ///
+    /// ```entry```: kernel entry point
+    /// ```initrd```: address and size of the initrd, if any
+    /// ```cmdline```: kernel command line, without null terminator
+    ///
+    /// # Safety
+    ///
+    /// The entry point must point to a kernel loaded in memory
+    ///
+    /// # Returns
+    ///
+    /// Only returns if the boot parameters could not be set or the kernel
+    /// entry point cannot be branched to
+    pub unsafe fn boot_linux(
+        &self,
+        entry: KernelEntry,
+        initrd: Option<(usize, usize)>,
+        cmdline: &str,
+    ) -> &'static str {
+        match linux::prepare(self, entry, initrd, cmdline) {
+            Ok(boot) => boot.jump(),
+            Err(msg) => msg,
+        }
+    }
+
     /// Allocate heap memory
     ///
     /// # Arguments
@@ -325,6 +764,38 @@ impl PROM {
         }
     }
 
+    /// Claims memory at a fixed address
+    ///
+    /// # Arguments
+    ///
+    /// ```addr```: The address of the range to be claimed
+    /// ```size```: The amount of bytes to be claimed
+    ///
+    /// # Returns
+    ///
+    /// The base of the claimed range as returned by the firmware
+    pub fn claim_at(&self, addr: *mut u8, size: usize) -> Result<*mut u8, &'static str> {
+        let mut args = services::ClaimArgs {
+            args: Args {
+                service: "claim\0".as_ptr(),
+                nargs: 3,
+                nret: 1,
+            },
+            virt: addr,
+            size,
+            align: 0,
+            ret: ptr::null_mut(),
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not claim memory range"),
+            _ => match args.ret as usize {
+                OF_SIZE_ERR => Err("Could not claim memory range"),
+                _ => Ok(args.ret),
+            },
+        }
+    }
+
     /// Release allocated heap memory by the ```claim``` method
     pub fn release(&self, virt: *mut u8, size: usize) {
         let mut args = services::ReleaseArgs {
@@ -405,6 +876,38 @@ impl PROM {
         }
     }
 
+    /// Write operation
+    ///
+    /// # Arguments
+    ///
+    /// ```handle```: Instance handle
+    /// ```buffer```: Content to write
+    ///
+    /// # Returns
+    ///
+    /// Number of bytes written, as reported by the device
+    pub fn write(&self, handle: *const IHandle, buffer: &[u8]) -> Result<usize, &'static str> {
+        let mut args = services::WriteArgs {
+            args: Args {
+                service: "write\0".as_ptr(),
+                nargs: 3,
+                nret: 1,
+            },
+            stdout: handle,
+            msg: buffer.as_ptr(),
+            len: buffer.len(),
+            ret: OF_SIZE_ERR,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not write device"),
+            _ => match args.ret {
+                OF_SIZE_ERR => Err("Could not write device"),
+                written => Ok(written),
+            },
+        }
+    }
+
     pub fn close(&self, handle: *const IHandle) -> Result<(), &'static str> {
         let mut args = services::CloseArgs {
             args: Args {
@@ -470,6 +973,155 @@ impl PROM {
         }
     }
 
+    /// Calls a method of a package instance
+    ///
+    /// # Arguments
+    ///
+    /// ```handle```: Instance handle
+    /// ```method```: null terminated method name
+    /// ```args```: stack arguments in the order of the method's stack diagram, the last one ends up at the top of the stack
+    /// ```rets```: buffer for the stack results in the order of the method's stack diagram
+    ///
+    /// # Returns
+    ///
+    /// An error if the method could not be called or if it threw an exception
+    pub fn call_method(
+        &self,
+        handle: *const IHandle,
+        method: &str,
+        args: &[usize],
+        rets: &mut [usize],
+    ) -> Result<(), &'static str> {
+        if args.len() > MAX_METHOD_CELLS || rets.len() > MAX_METHOD_CELLS {
+            return Err("Too many arguments for call-method");
+        }
+
+        // Args header, method and ihandle, stack arguments, catch-result and stack results
+        let mut cells = [0usize; 3 + 2 + MAX_METHOD_CELLS + 1 + MAX_METHOD_CELLS];
+        cells[0] = "call-method\0".as_ptr() as usize;
+        cells[1] = 2 + args.len();
+        cells[2] = 1 + rets.len();
+        cells[3] = method.as_ptr() as usize;
+        cells[4] = handle as usize;
+
+        // The top of the stack is the first cell after the ihandle
+        for (cell, arg) in cells[5..].iter_mut().zip(args.iter().rev()) {
+            *cell = *arg;
+        }
+        let catch = 5 + args.len();
+        cells[catch] = OF_SIZE_ERR;
+
+        match (self.entry_fn)(cells.as_mut_ptr() as *mut Args) {
+            OF_SIZE_ERR => return Err("Could not call method"),
+            _ => {
+                if cells[catch] != 0 {
+                    return Err("Method threw an exception");
+                }
+            }
+        }
+
+        for (ret, cell) in rets.iter_mut().rev().zip(cells[catch + 1..].iter()) {
+            *ret = *cell;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the MMU and memory services resolved from '/chosen'
+    pub fn mmu(&self) -> Result<Mmu, &'static str> {
+        Mmu::new(*self)
+    }
+
+    /// Registers a handler for the requests Open Firmware makes into the client program
+    ///
+    /// # Returns
+    ///
+    /// The previously installed callback, to be reinstalled with ```restore_callback```
+    pub fn set_callback(
+        &self,
+        handler: &'static dyn ClientCallbacks,
+    ) -> Result<PreviousCallback, &'static str> {
+        let previous = callback::handler();
+        callback::set_handler(Some(handler));
+
+        match self.set_callback_entry(Some(callback::callback_entry)) {
+            Ok(entry) => Ok(PreviousCallback {
+                entry,
+                handler: previous,
+            }),
+            Err(msg) => {
+                callback::set_handler(previous);
+                Err(msg)
+            }
+        }
+    }
+
+    /// Reinstalls a callback replaced by ```set_callback```
+    pub fn restore_callback(&self, previous: PreviousCallback) -> Result<(), &'static str> {
+        self.set_callback_entry(previous.entry)?;
+        callback::set_handler(previous.handler);
+        Ok(())
+    }
+
+    fn set_callback_entry(
+        &self,
+        entry: Option<extern "C" fn(*mut Args) -> usize>,
+    ) -> Result<Option<extern "C" fn(*mut Args) -> usize>, &'static str> {
+        let mut args = services::SetCallbackArgs {
+            args: Args {
+                service: "set-callback\0".as_ptr(),
+                nargs: 1,
+                nret: 1,
+            },
+            newfunc: entry,
+            oldfunc: None,
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => Err("Could not set callback"),
+            _ => Ok(args.oldfunc),
+        }
+    }
+
+    /// Lets the firmware debugger translate between addresses and symbol names
+    ///
+    /// The symbol table is installed as the callback handler and registered with
+    /// the ```set-symbol-lookup``` service under the ```sym-to-value``` and
+    /// ```value-to-sym``` callback names. Other callbacks are still passed to the
+    /// handler installed before, such as the one answering ```translate```.
+    ///
+    /// # Returns
+    ///
+    /// The previously installed callback, to be reinstalled with ```restore_callback```
+    pub fn set_symbol_lookup(
+        &self,
+        table: &'static SymbolTable<'static>,
+    ) -> Result<PreviousCallback, &'static str> {
+        let lookup = alloc::boxed::Box::new(SymbolLookup {
+            table,
+            next: callback::handler(),
+        });
+        let previous = self.set_callback(alloc::boxed::Box::leak(lookup))?;
+
+        let mut args = services::SetSymbolLookupArgs {
+            args: Args {
+                service: "set-symbol-lookup\0".as_ptr(),
+                nargs: 2,
+                nret: 0,
+            },
+            sym_to_value: "sym-to-value\0".as_ptr(),
+            value_to_sym: "value-to-sym\0".as_ptr(),
+        };
+
+        match (self.entry_fn)(&mut args.args as *mut Args) {
+            OF_SIZE_ERR => {
+                let _ = self.restore_callback(previous);
+                Err("Could not set symbol lookup")
+            }
+            _ => Ok(previous),
+        }
+    }
+
     /*pub fn read_blocks(
         &self,
         handle: *const IHandle,
@@ -0,0 +1,209 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Linux/PowerPC kernel handoff following the Open Firmware boot protocol
+//!
+//! The kernel is entered with ```r3``` holding the initrd start, ```r4``` the
+//! initrd size and ```r5``` the client interface entry point, the command line
+//! and initrd location are also published in '/chosen'.
+
+use alloc::vec::Vec;
+use core::mem::transmute;
+
+use crate::elf::LoadedImage;
+use crate::PROM;
+
+/// Kernel entry point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KernelEntry {
+    /// Address of the first instruction
+    Code(usize),
+    /// Address of an ELFv1 function descriptor (entry, TOC and environment)
+    Descriptor(usize),
+}
+
+impl KernelEntry {
+    /// Entry point of a loaded ELF image, at the address its segment was loaded at
+    pub fn from_elf(image: &LoadedImage) -> Result<Self, &'static str> {
+        for segment in image.segments.iter() {
+            // Some images use physical addresses for the entry point
+            for start in [segment.vaddr, segment.paddr] {
+                let end = start
+                    .checked_add(segment.mem_size)
+                    .ok_or("Kernel segment does not fit in the address space")?;
+                if (start..end).contains(&image.entry) {
+                    let offset = (image.entry - start) as usize;
+                    return Ok(KernelEntry::Code(segment.base as usize + offset));
+                }
+            }
+        }
+
+        Err("Entry point is outside of the loaded segments")
+    }
+}
+
+/// Register setup for entering the kernel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinuxBoot {
+    pub entry: KernelEntry,
+    /// Initrd start address, 0 if there is no initrd
+    pub r3: usize,
+    /// Initrd size in bytes
+    pub r4: usize,
+    /// Client interface entry point
+    pub r5: usize,
+}
+
+/// Where [`LinuxBoot::jump`] transfers control to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Branch {
+    /// Call through the ELFv1 function descriptor at this address
+    Descriptor(usize),
+    /// Branch to the first instruction with ```r2``` holding the TOC pointer
+    Code { code: usize, toc: usize },
+}
+
+impl LinuxBoot {
+    /// Branch into the kernel for the ABI of this client
+    ///
+    /// Only ELFv1 clients can call a function descriptor, others such as 32
+    /// bit and ELFv2 clients load the code address and TOC pointer from its
+    /// first two 64 bit words.
+    ///
+    /// # Safety
+    ///
+    /// Descriptor entry points must point to a readable function descriptor
+    pub unsafe fn branch(&self) -> Result<Branch, &'static str> {
+        match self.entry {
+            // The kernel sets up its own TOC
+            KernelEntry::Code(code) => Ok(Branch::Code { code, toc: 0 }),
+            #[cfg(target_abi = "elfv1")]
+            KernelEntry::Descriptor(descriptor) => Ok(Branch::Descriptor(descriptor)),
+            #[cfg(not(target_abi = "elfv1"))]
+            KernelEntry::Descriptor(descriptor) => {
+                let words = descriptor as *const u64;
+                let word = |index: usize| {
+                    usize::try_from(words.add(index).read_unaligned())
+                        .map_err(|_| "Kernel entry point does not fit in the address space")
+                };
+                Ok(Branch::Code {
+                    code: word(0)?,
+                    toc: word(1)?,
+                })
+            }
+        }
+    }
+
+    /// Transfers control to the kernel
+    ///
+    /// # Safety
+    ///
+    /// The entry point must point to a kernel loaded in memory
+    ///
+    /// # Returns
+    ///
+    /// Only returns if the kernel entry point cannot be branched to
+    pub unsafe fn jump(&self) -> &'static str {
+        type KernelFn = extern "C" fn(usize, usize, usize) -> !;
+
+        let branch = match self.branch() {
+            Ok(branch) => branch,
+            Err(msg) => return msg,
+        };
+        match branch {
+            #[cfg(target_abi = "elfv1")]
+            Branch::Descriptor(descriptor) => {
+                transmute::<usize, KernelFn>(descriptor)(self.r3, self.r4, self.r5)
+            }
+            #[cfg(not(target_abi = "elfv1"))]
+            Branch::Descriptor(_) => "Function descriptors can only be called by ELFv1 clients",
+            // Function pointers are descriptors in the ELFv1 ABI. This frame never goes away.
+            #[cfg(target_abi = "elfv1")]
+            Branch::Code { code, toc } => {
+                let descriptor: [usize; 3] = [code, toc, 0];
+                transmute::<*const usize, KernelFn>(descriptor.as_ptr())(self.r3, self.r4, self.r5)
+            }
+            #[cfg(all(
+                any(target_arch = "powerpc", target_arch = "powerpc64"),
+                not(target_abi = "elfv1")
+            ))]
+            Branch::Code { code, toc } => core::arch::asm!(
+                "mtctr {code}",
+                "mr 2, {toc}",
+                "bctr",
+                code = in(reg) code,
+                toc = in(reg) toc,
+                in("r3") self.r3,
+                in("r4") self.r4,
+                in("r5") self.r5,
+                options(noreturn),
+            ),
+            // Hosts running the tests have no TOC
+            #[cfg(not(any(target_arch = "powerpc", target_arch = "powerpc64")))]
+            Branch::Code { code, .. } => {
+                transmute::<usize, KernelFn>(code)(self.r3, self.r4, self.r5)
+            }
+        }
+    }
+}
+
+/// Client interface entry point as an instruction address
+fn client_interface(prom: &PROM) -> usize {
+    let entry = prom.entry_fn as usize;
+
+    // The function pointer refers to a descriptor, the kernel expects the code address
+    #[cfg(target_abi = "elfv1")]
+    let entry = unsafe { *(entry as *const usize) };
+
+    entry
+}
+
+/// Publishes the boot parameters in '/chosen' and computes the kernel register setup
+///
+/// The ```quiesce``` service is called when the firmware implements it, no
+/// further client interface calls should be made after this besides jumping
+/// into the kernel.
+///
+/// # Arguments
+///
+/// ```entry```: kernel entry point
+/// ```initrd```: address and size of the initrd, if any
+/// ```cmdline```: kernel command line, without null terminator
+pub fn prepare(
+    prom: &PROM,
+    entry: KernelEntry,
+    initrd: Option<(usize, usize)>,
+    cmdline: &str,
+) -> Result<LinuxBoot, &'static str> {
+    let mut bootargs: Vec<u8> = Vec::with_capacity(cmdline.len() + 1);
+    bootargs.extend_from_slice(cmdline.as_bytes());
+    bootargs.push(0);
+    prom.set_property(prom.chosen, "bootargs\0", &bootargs)?;
+
+    let (initrd_start, initrd_size) = initrd.unwrap_or((0, 0));
+    if initrd_size > 0 {
+        let initrd_end = initrd_start
+            .checked_add(initrd_size)
+            .ok_or("Initrd does not fit in the address space")?;
+        prom.set_property(
+            prom.chosen,
+            "linux,initrd-start\0",
+            &initrd_start.to_be_bytes(),
+        )?;
+        prom.set_property(prom.chosen, "linux,initrd-end\0", &initrd_end.to_be_bytes())?;
+    }
+
+    if prom.test("quiesce\0") {
+        prom.quiesce();
+    }
+
+    Ok(LinuxBoot {
+        entry,
+        r3: initrd_start,
+        r4: initrd_size,
+        r5: client_interface(prom),
+    })
+}
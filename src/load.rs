@@ -0,0 +1,221 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Files read through the firmware's own disk-label and filesystem packages
+//!
+//! Opening a device specifier with a filename, like ```disk:2,\vmlinux```,
+//! gives an instance whose ```read``` returns the contents of the file. The
+//! size is taken from the ```size``` or ```#bytes``` method when the package
+//! implements one, otherwise the file is read in chunks until the end.
+//!
+//! Files compressed in a format known to [`crate::compress`] are
+//! decompressed while they are read.
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::slice;
+
+use crate::compress;
+use crate::devspec::DevSpec;
+use crate::io::{Instance, Read};
+use crate::PROM;
+
+/// Bytes read at a time, and between progress reports
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Memory claimed from the firmware, released when dropped
+pub struct ClaimedRegion {
+    prom: PROM,
+    base: *mut u8,
+    size: usize,
+}
+
+impl ClaimedRegion {
+    /// Claims memory anywhere, see ```PROM::claim```
+    pub fn claim(prom: &PROM, size: usize, align: usize) -> Result<Self, &'static str> {
+        Ok(ClaimedRegion {
+            prom: *prom,
+            base: prom.claim(size, align)?,
+            size,
+        })
+    }
+
+    /// Claims memory at a fixed address, see ```PROM::claim_at```
+    pub fn claim_at(prom: &PROM, addr: *mut u8, size: usize) -> Result<Self, &'static str> {
+        Ok(ClaimedRegion {
+            prom: *prom,
+            base: prom.claim_at(addr, size)?,
+            size,
+        })
+    }
+
+    /// Address returned by the firmware
+    pub fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.base, self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.base, self.size) }
+    }
+
+    /// Keeps the memory claimed, for ranges handed over to the loaded program
+    ///
+    /// # Returns
+    ///
+    /// The base address and size of the region
+    pub fn leak(self) -> (*mut u8, usize) {
+        let region = (self.base, self.size);
+        core::mem::forget(self);
+        region
+    }
+}
+
+impl Drop for ClaimedRegion {
+    fn drop(&mut self) {
+        self.prom.release(self.base, self.size);
+    }
+}
+
+/// Size of the file an instance reads, None when the package does not tell
+pub fn file_size(instance: &Instance) -> Option<u64> {
+    if let Ok(size) = instance.size() {
+        return Some(size);
+    }
+    let mut rets = [0usize];
+    match instance.call_method("#bytes\0", &[], &mut rets) {
+        Ok(()) => Some(rets[0] as u64),
+        Err(_) => None,
+    }
+}
+
+// Opens a device specifier that names a file
+fn open_file(prom: &PROM, spec: &str) -> Result<Instance, &'static str> {
+    let devspec = DevSpec::parse(spec)?;
+    if devspec.filename().is_none() {
+        return Err("Device specifier has no filename");
+    }
+    Instance::open(prom, &devspec.to_open_string())
+}
+
+// Counts the bytes read from the file, compressed or not, for progress reports
+struct Counted<'a, R: Read> {
+    input: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = self.input.read(buf)?;
+        self.count.set(self.count.get() + len as u64);
+        Ok(len)
+    }
+}
+
+/// Reads a whole file through the firmware, decompressing it if needed
+///
+/// # Arguments
+///
+/// ```prom```: the firmware
+/// ```spec```: device specifier with a partition and filename, aliases are allowed
+/// ```progress```: called with the bytes read from the file so far and its size, when known
+pub fn load_file(
+    prom: &PROM,
+    spec: &str,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<Vec<u8>, &'static str> {
+    let file = open_file(prom, spec)?;
+    let size = file_size(&file);
+    let count = Cell::new(0);
+    let (format, mut reader) = compress::decompress(Counted {
+        input: file,
+        count: &count,
+    })?;
+
+    // Sizes reported by the package are a hint, the file is read to its end anyway.
+    // Room for one more chunk keeps the read that finds the end from reallocating.
+    let mut data = Vec::new();
+    if let (Some(size), None) = (size, format) {
+        let capacity = usize::try_from(size)
+            .ok()
+            .and_then(|size| size.checked_add(CHUNK_SIZE))
+            .ok_or("File is too large")?;
+        data.try_reserve_exact(capacity)
+            .map_err(|_| "Not enough memory for the file")?;
+    }
+    let mut len = 0;
+    loop {
+        if data.len() - len < CHUNK_SIZE {
+            data.resize(len + CHUNK_SIZE, 0);
+        }
+        match reader.read(&mut data[len..len + CHUNK_SIZE])? {
+            0 => break,
+            n => len += n,
+        }
+        progress(count.get(), size);
+    }
+
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Reads a whole file through the firmware into claimed memory, decompressing it if needed
+///
+/// Fails if the file does not fit in the region.
+///
+/// # Arguments
+///
+/// ```prom```: the firmware
+/// ```spec```: device specifier with a partition and filename, aliases are allowed
+/// ```region```: memory the file is read into, from its start
+/// ```progress```: called with the bytes read from the file so far and its size, when known
+///
+/// # Returns
+///
+/// The size of the file, uncompressed
+pub fn load_file_into(
+    prom: &PROM,
+    spec: &str,
+    region: &mut ClaimedRegion,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<usize, &'static str> {
+    let file = open_file(prom, spec)?;
+    let size = file_size(&file);
+    let count = Cell::new(0);
+    let (format, mut reader) = compress::decompress(Counted {
+        input: file,
+        count: &count,
+    })?;
+    if format.is_none() && size.is_some_and(|size| size > region.size() as u64) {
+        return Err("File does not fit in the claimed region");
+    }
+
+    let memory = region.as_mut_slice();
+    let mut len = 0;
+    loop {
+        let end = memory.len().min(len + CHUNK_SIZE);
+        if len == end {
+            // The region is full, the file must end here
+            let mut probe = [0u8];
+            return match reader.read(&mut probe)? {
+                0 => Ok(len),
+                _ => Err("File does not fit in the claimed region"),
+            };
+        }
+        match reader.read(&mut memory[len..end])? {
+            0 => return Ok(len),
+            n => len += n,
+        }
+        progress(count.get(), size);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! MMU and memory services available through the '/chosen' ```mmu``` and ```memory``` instances
+//!
+//! On systems running with translation enabled ```PROM::claim``` only claims
+//! virtual ranges mapped by the firmware, these helpers let the client program
+//! claim physical and virtual ranges separately and set up the mappings itself.
+//!
+//! Physical addresses take as many cells as the ```#address-cells``` of the
+//! root node, the parent of the memory node, two on SLOF. Each cell holds 32 bits of the address, with the
+//! most significant one on top of the stack (```phys.lo phys.hi```).
+
+use alloc::{vec, vec::Vec};
+
+use crate::{IHandle, PHandle, PROM};
+
+/// A range mapped by the firmware, as found in the MMU ```translations``` property
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Translation {
+    pub virt: u64,
+    pub size: u64,
+    pub phys: u64,
+    pub mode: u64,
+}
+
+/// Result of translating a virtual address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub phys: u64,
+    pub mode: usize,
+}
+
+/// Handle into the MMU and memory services
+#[derive(Clone, Copy)]
+pub struct Mmu {
+    prom: PROM,
+    /// Instance handle into the MMU
+    pub mmu: *const IHandle,
+    /// Instance handle into the memory node, null if '/chosen' does not provide one
+    pub memory: *const IHandle,
+    /// Cells of the physical addresses taken and returned by the methods, 1 or 2
+    pub phys_cells: usize,
+}
+
+impl Mmu {
+    /// Creates an MMU handle from the instances resolved on initialization
+    ///
+    /// # Errors
+    ///
+    /// If '/chosen' does not provide an ```mmu``` instance
+    pub fn new(prom: PROM) -> Result<Self, &'static str> {
+        if prom.mmu.is_null() {
+            return Err("MMU instance is not present");
+        }
+
+        // Physical addresses are in the address space of the root node, the parent of memory
+        let phys_cells = property_cells(&prom, prom.root()?, "#address-cells\0").unwrap_or(1);
+        if !(1..=2).contains(&phys_cells) {
+            return Err("Unsupported amount of physical address cells");
+        }
+
+        Ok(Mmu {
+            prom,
+            mmu: prom.mmu,
+            memory: prom.memory,
+            phys_cells,
+        })
+    }
+
+    // Appends a physical address to method arguments
+    fn push_phys(&self, args: &mut Vec<usize>, phys: u64) {
+        match self.phys_cells {
+            1 => args.push(phys as usize),
+            _ => args.extend([phys as u32 as usize, (phys >> 32) as usize]),
+        }
+    }
+
+    // Physical address from method results, in stack order
+    fn pop_phys(&self, cells: &[usize]) -> u64 {
+        match cells {
+            [phys] => *phys as u64,
+            [lo, hi] => ((*hi as u64) << 32) | (*lo as u32 as u64),
+            _ => 0,
+        }
+    }
+
+    /// Translates a virtual address
+    ///
+    /// # Returns
+    ///
+    /// The physical address and mode, or ```None``` if ```virt``` is not mapped
+    pub fn translate(&self, virt: usize) -> Result<Option<Mapping>, &'static str> {
+        // ( virt -- false | phys.lo ... phys.hi mode true )
+        let mut rets = [0; 4];
+        let rets = &mut rets[..self.phys_cells + 2];
+        self.prom
+            .call_method(self.mmu, "translate\0", &[virt], rets)?;
+
+        let (phys, rets) = rets.split_at(self.phys_cells);
+        match rets[1] {
+            0 => Ok(None),
+            _ => Ok(Some(Mapping {
+                phys: self.pop_phys(phys),
+                mode: rets[0],
+            })),
+        }
+    }
+
+    /// Maps ```size``` bytes at the physical address ```phys``` into ```virt```
+    pub fn map(
+        &self,
+        phys: u64,
+        virt: usize,
+        size: usize,
+        mode: usize,
+    ) -> Result<(), &'static str> {
+        // ( phys.lo ... phys.hi virt size mode -- )
+        let mut args = Vec::new();
+        self.push_phys(&mut args, phys);
+        args.extend([virt, size, mode]);
+        self.prom.call_method(self.mmu, "map\0", &args, &mut [])
+    }
+
+    /// Removes the mapping of ```size``` bytes at ```virt```
+    pub fn unmap(&self, virt: usize, size: usize) -> Result<(), &'static str> {
+        // ( virt size -- )
+        self.prom
+            .call_method(self.mmu, "unmap\0", &[virt, size], &mut [])
+    }
+
+    /// Claims a physical memory range from the memory node
+    ///
+    /// # Arguments
+    ///
+    /// ```phys```: physical address to claim, ignored when ```align``` is not 0
+    /// ```size```: size of the range in bytes
+    /// ```align```: alignment of the range to be allocated, 0 to claim exactly ```phys```
+    pub fn claim_phys(&self, phys: u64, size: usize, align: usize) -> Result<u64, &'static str> {
+        if self.memory.is_null() {
+            return Err("Memory instance is not present");
+        }
+
+        // ( [phys.lo ... phys.hi] size align -- base.lo ... base.hi )
+        let mut args = Vec::new();
+        if align == 0 {
+            self.push_phys(&mut args, phys);
+        }
+        args.extend([size, align]);
+        let mut rets = [0; 2];
+        let rets = &mut rets[..self.phys_cells];
+        self.prom.call_method(self.memory, "claim\0", &args, rets)?;
+
+        match rets.iter().all(|cell| *cell == usize::MAX) {
+            true => Err("Could not claim memory range"),
+            false => Ok(self.pop_phys(rets)),
+        }
+    }
+
+    /// Claims a virtual address range from the MMU
+    ///
+    /// # Arguments
+    ///
+    /// ```virt```: virtual address to claim, ignored when ```align``` is not 0
+    /// ```size```: size of the range in bytes
+    /// ```align```: alignment of the range to be allocated, 0 to claim exactly ```virt```
+    pub fn claim_virt(
+        &self,
+        virt: usize,
+        size: usize,
+        align: usize,
+    ) -> Result<usize, &'static str> {
+        claim(&self.prom, self.mmu, virt, size, align)
+    }
+
+    /// Decodes the ranges mapped by the firmware from the ```translations``` property
+    ///
+    /// Virtual addresses use the root node ```#address-cells```, sizes its
+    /// ```#size-cells``` and physical addresses the cells of the memory node.
+    pub fn translations(&self) -> Result<Vec<Translation>, &'static str> {
+        let package = self.prom.instance_to_package(self.mmu)?;
+        let len = self.prom.get_property_len(package, "translations\0")?;
+
+        let mut buf: Vec<u8> = vec![0; len];
+        let len = self
+            .prom
+            .get_property(package, "translations\0", buf.as_mut_ptr(), len)?;
+        buf.truncate(len);
+
+        let root = self.prom.find_device("/\0")?;
+        let address_cells = get_cells(&self.prom, root, "#address-cells\0", 2);
+        let size_cells = get_cells(&self.prom, root, "#size-cells\0", 1);
+
+        decode_translations(&buf, address_cells, size_cells, self.phys_cells)
+    }
+}
+
+fn claim(
+    prom: &PROM,
+    ihandle: *const IHandle,
+    addr: usize,
+    size: usize,
+    align: usize,
+) -> Result<usize, &'static str> {
+    // ( [addr] size align -- base ), addr is only present when align is 0
+    let mut rets = [0];
+    match align {
+        0 => prom.call_method(ihandle, "claim\0", &[addr, size, align], &mut rets)?,
+        _ => prom.call_method(ihandle, "claim\0", &[size, align], &mut rets)?,
+    }
+
+    match rets[0] {
+        usize::MAX => Err("Could not claim memory range"),
+        base => Ok(base),
+    }
+}
+
+fn property_cells(prom: &PROM, phandle: *const PHandle, prop: &str) -> Option<usize> {
+    let mut cells = [0u8; 4];
+    match prom.get_property(phandle, prop, cells.as_mut_ptr(), cells.len()) {
+        Ok(4) => Some(u32::from_be_bytes(cells) as usize),
+        _ => None,
+    }
+}
+
+fn get_cells(prom: &PROM, phandle: *const PHandle, prop: &str, default: usize) -> usize {
+    property_cells(prom, phandle, prop).unwrap_or(default)
+}
+
+fn read_cells(data: &[u8], cells: usize) -> u64 {
+    data[..cells * 4].chunks(4).fold(0, |value, cell| {
+        (value << 32) | u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as u64
+    })
+}
+
+/// Decodes a ```translations``` property
+///
+/// Each entry is made of the virtual address, size, physical address and a
+/// single mode cell, every cell being a 32 bit big endian value.
+pub fn decode_translations(
+    data: &[u8],
+    virt_cells: usize,
+    size_cells: usize,
+    phys_cells: usize,
+) -> Result<Vec<Translation>, &'static str> {
+    if virt_cells > 2 || size_cells > 2 || phys_cells > 2 {
+        return Err("Unsupported amount of cells in translations");
+    }
+
+    let entry = (virt_cells + size_cells + phys_cells + 1) * 4;
+    if !data.len().is_multiple_of(entry) {
+        return Err("Invalid translations property length");
+    }
+
+    Ok(data
+        .chunks(entry)
+        .map(|data| {
+            let (virt, data) = data.split_at(virt_cells * 4);
+            let (size, data) = data.split_at(size_cells * 4);
+            let (phys, mode) = data.split_at(phys_cells * 4);
+            Translation {
+                virt: read_cells(virt, virt_cells),
+                size: read_cells(size, size_cells),
+                phys: read_cells(phys, phys_cells),
+                mode: read_cells(mode, 1),
+            }
+        })
+        .collect())
+}
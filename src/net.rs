@@ -0,0 +1,164 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Raw Ethernet frames through Open Firmware ```network``` devices
+//!
+//! Network packages transmit one frame per ```write``` and return one received
+//! frame per ```read```. Reads do not wait for traffic: a package with no
+//! frame pending returns -2 or 0, which [`Interface::receive`] reports as
+//! ```None``` so that callers can poll and keep their own timeouts.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str;
+
+use crate::io::Instance;
+use crate::{PHandle, PROM};
+
+/// Ethernet header: destination and source addresses and the EtherType
+pub const HEADER_SIZE: usize = 14;
+/// Payload size assumed when the device does not report one
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Value of the ```device_type``` property of network devices
+const DEVICE_TYPE: &[u8] = b"network";
+/// Properties holding the MAC address, in order of preference
+const MAC_PROPERTIES: [&str; 2] = ["mac-address\0", "local-mac-address\0"];
+// Returned by ```read``` when no frame has arrived
+const NO_FRAME: usize = -2isize as usize;
+
+/// Hardware address of an interface
+pub type MacAddress = [u8; 6];
+
+/// Open network device
+pub struct Interface {
+    instance: Instance,
+    mac: MacAddress,
+    mtu: usize,
+}
+
+fn is_network(prom: &PROM, phandle: *const PHandle) -> bool {
+    prom.read_property(phandle, "device_type\0")
+        .is_ok_and(|value| value.split(|c| *c == 0).next() == Some(DEVICE_TYPE))
+}
+
+impl Interface {
+    /// Opens a network device
+    ///
+    /// # Arguments
+    ///
+    /// ```prom```: the firmware
+    /// ```dev_spec```: null terminated device specifier, optionally with arguments
+    /// like ```net:speed=auto```
+    pub fn open(prom: &PROM, dev_spec: &str) -> Result<Self, &'static str> {
+        let instance = Instance::open(prom, dev_spec)?;
+        let package = prom.instance_to_package(instance.handle())?;
+        if !is_network(prom, package) {
+            return Err("Not a network device");
+        }
+
+        // mac-address is the one in use, local-mac-address the one built into the device
+        let mac = MAC_PROPERTIES
+            .iter()
+            .filter_map(|name| prom.read_property(package, name).ok())
+            .find_map(|value| MacAddress::try_from(value.as_slice()).ok())
+            .ok_or("Network device has no MAC address")?;
+
+        // max-frame-size counts the Ethernet header along with the payload
+        let mtu = match prom
+            .read_property(package, "max-frame-size\0")
+            .ok()
+            .as_deref()
+        {
+            Some([a, b, c, d]) => (u32::from_be_bytes([*a, *b, *c, *d]) as usize)
+                .checked_sub(HEADER_SIZE)
+                .filter(|mtu| *mtu > 0)
+                .ok_or("Invalid network device frame size")?,
+            _ => DEFAULT_MTU,
+        };
+
+        Ok(Interface { instance, mac, mtu })
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// Largest payload of a frame, without the Ethernet header
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Largest frame [`Interface::send`] takes and [`Interface::receive`] returns
+    pub fn max_frame_size(&self) -> usize {
+        self.mtu + HEADER_SIZE
+    }
+
+    pub fn instance(&self) -> &Instance {
+        &self.instance
+    }
+
+    /// Transmits a frame, starting with its Ethernet header
+    ///
+    /// The device pads frames shorter than the Ethernet minimum and appends
+    /// the frame check sequence.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if frame.len() < HEADER_SIZE {
+            return Err("Frame is shorter than the Ethernet header");
+        }
+        if frame.len() > self.max_frame_size() {
+            return Err("Frame is larger than the interface MTU");
+        }
+        match self.instance.prom().write(self.instance.handle(), frame)? {
+            written if written == frame.len() => Ok(()),
+            _ => Err("Could not send frame"),
+        }
+    }
+
+    /// Takes the next received frame without waiting for one
+    ///
+    /// # Arguments
+    ///
+    /// ```buf```: receives the frame with its Ethernet header, should hold
+    /// [`Interface::max_frame_size`] bytes
+    ///
+    /// # Returns
+    ///
+    /// The length of the frame, None when no frame is pending
+    pub fn receive(&mut self, buf: &mut [u8]) -> Result<Option<usize>, &'static str> {
+        let prom = self.instance.prom();
+        match prom.read(self.instance.handle(), buf.as_mut_ptr(), buf.len())? {
+            0 | NO_FRAME => Ok(None),
+            len if len > buf.len() => Err("Invalid frame length"),
+            len => Ok(Some(len)),
+        }
+    }
+}
+
+/// Paths of the nodes with a ```device_type``` of ```network```, in device tree order
+pub fn find_interfaces(prom: &PROM) -> Result<Vec<String>, &'static str> {
+    let mut interfaces = Vec::new();
+    let mut pending = vec![prom.root()?];
+    while let Some(node) = pending.pop() {
+        if is_network(prom, node) {
+            let mut path = [0u8; 256];
+            let len = prom.package_to_path(node, &mut path)?.min(path.len());
+            let path = str::from_utf8(&path[..len]).map_err(|_| "Invalid package path")?;
+            interfaces.push(String::from(path.trim_end_matches('\0')));
+        }
+
+        // Children are pushed in reverse so that the first one is visited next
+        let mut children = Vec::new();
+        let mut child = prom.child(node)?;
+        while let Some(phandle) = child {
+            children.push(phandle);
+            child = prom.peer(phandle)?;
+        }
+        pending.extend(children.into_iter().rev());
+    }
+    Ok(interfaces)
+}
@@ -0,0 +1,194 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Apple Partition Map, as found on PowerMac disks and CDs
+//!
+//! Block 0 holds the driver descriptor and the map entries follow from block
+//! 1, the map describes itself in its first entry. Partitions are numbered
+//! after their map entry starting at 1, like Apple OF does in ```disk:3```.
+//! Map blocks are usually 512 bytes even on devices with larger blocks.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, BlockRange};
+use crate::bytes::{be16, be32};
+
+const DDR_SIGNATURE: &[u8; 2] = b"ER";
+const PM_SIGNATURE: &[u8; 2] = b"PM";
+const ENTRY_SIZE: usize = 512;
+/// Upper bound on the number of map entries, in case the map is corrupted
+const MAX_ENTRIES: u32 = 1024;
+
+/// Type of the entry describing the partition map itself
+pub const TYPE_PARTITION_MAP: &str = "Apple_partition_map";
+/// HFS and HFS+ volumes
+pub const TYPE_HFS: &str = "Apple_HFS";
+/// New World bootstrap partition holding the boot loader, HFS formatted
+pub const TYPE_BOOTSTRAP: &str = "Apple_Bootstrap";
+/// Unix filesystems, used by Linux for its partitions
+pub const TYPE_UNIX_SVR2: &str = "Apple_UNIX_SVR2";
+/// Unallocated space
+pub const TYPE_FREE: &str = "Apple_Free";
+
+// Null padded name, bytes outside of ASCII are taken as Latin-1
+fn name_at(data: &[u8], offset: usize) -> String {
+    data[offset..offset + 32]
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as char)
+        .collect()
+}
+
+/// Device driver listed in the driver descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Driver {
+    /// First block of the driver, in map blocks
+    pub block: u32,
+    /// Size of the driver, in 512 byte blocks
+    pub size: u16,
+    /// Operating system type
+    pub kind: u16,
+}
+
+/// Partition map entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// Partition number as used in device specifiers
+    pub number: usize,
+    pub name: String,
+    /// Partition type, such as [`TYPE_HFS`]
+    pub kind: String,
+    /// First block of the partition, in map blocks
+    pub start: u64,
+    /// Number of map blocks in the partition
+    pub count: u64,
+    /// Size of the map blocks the range is expressed in
+    pub block_size: usize,
+    /// First block of the data area relative to the partition
+    pub data_start: u32,
+    /// Number of blocks in the data area
+    pub data_count: u32,
+    /// Status flags, such as valid, allocated or bootable
+    pub status: u32,
+}
+
+impl Partition {
+    fn parse(number: usize, block_size: usize, entry: &[u8]) -> Result<Self, &'static str> {
+        if &entry[..2] != PM_SIGNATURE {
+            return Err("Missing partition map entry signature");
+        }
+
+        Ok(Partition {
+            number,
+            name: name_at(entry, 16),
+            kind: name_at(entry, 48),
+            start: be32(entry, 8) as u64,
+            count: be32(entry, 12) as u64,
+            block_size,
+            data_start: be32(entry, 80),
+            data_count: be32(entry, 84),
+            status: be32(entry, 88),
+        })
+    }
+
+    /// Exposes the partition as a block device
+    ///
+    /// Fails if the partition is outside the device, or does not start and end
+    /// on a device block boundary.
+    pub fn open<D: BlockDevice>(&self, device: D) -> Result<BlockRange<D>, &'static str> {
+        let block_size = self.block_size as u64;
+        match (
+            self.start.checked_mul(block_size),
+            self.count.checked_mul(block_size),
+        ) {
+            (Some(offset), Some(size)) => BlockRange::from_byte_range(device, offset, size),
+            _ => Err("Partition does not fit in the device"),
+        }
+    }
+}
+
+/// Parsed Apple Partition Map
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Apm {
+    /// Size of the blocks the map is expressed in
+    pub block_size: usize,
+    /// Size of the device in map blocks, as recorded by the driver descriptor
+    pub block_count: u32,
+    pub drivers: Vec<Driver>,
+    /// Map entries in order, the map itself included
+    pub partitions: Vec<Partition>,
+}
+
+impl Apm {
+    /// Reads the driver descriptor and the partition map of a device
+    ///
+    /// A missing driver descriptor is tolerated when the map is found with 512
+    /// byte blocks.
+    pub fn read<D: BlockDevice>(device: &mut D) -> Result<Self, &'static str> {
+        let mut block = [0u8; ENTRY_SIZE];
+        device.read_at(0, &mut block)?;
+
+        let mut apm = Apm {
+            block_size: ENTRY_SIZE,
+            block_count: 0,
+            drivers: Vec::new(),
+            partitions: Vec::new(),
+        };
+
+        if &block[..2] == DDR_SIGNATURE {
+            apm.block_size = match be16(&block, 2) as usize {
+                0 => ENTRY_SIZE,
+                size if size < ENTRY_SIZE => return Err("Invalid partition map block size"),
+                size => size,
+            };
+            apm.block_count = be32(&block, 4);
+            let count = (be16(&block, 16) as usize).min((ENTRY_SIZE - 18) / 8);
+            apm.drivers = block[18..18 + count * 8]
+                .chunks(8)
+                .map(|driver| Driver {
+                    block: be32(driver, 0),
+                    size: be16(driver, 4),
+                    kind: be16(driver, 6),
+                })
+                .collect();
+        }
+
+        // The first entry tells how many entries the map has
+        let mut number = 1;
+        let mut entries = 1;
+        while number <= entries {
+            let offset = number as u64 * apm.block_size as u64;
+            device.read_at(offset, &mut block)?;
+            let partition = Partition::parse(number as usize, apm.block_size, &block)?;
+            if number == 1 {
+                entries = be32(&block, 4);
+                if entries == 0 || entries > MAX_ENTRIES {
+                    return Err("Invalid partition map size");
+                }
+            }
+            apm.partitions.push(partition);
+            number += 1;
+        }
+
+        Ok(apm)
+    }
+
+    /// Partition by the number used in device specifiers
+    pub fn get(&self, number: usize) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// First partition of a given type, such as [`TYPE_BOOTSTRAP`]
+    pub fn find_by_type(&self, kind: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.kind == kind)
+    }
+
+    /// First partition with a given name
+    pub fn find_by_name(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+}
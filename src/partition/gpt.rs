@@ -0,0 +1,353 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! GUID partition tables
+//!
+//! Both the primary header at block 1 and the backup header at the end of the
+//! disk are validated with their CRC-32, the partition entries are taken from
+//! the primary table unless it is damaged. Partitions are numbered after their
+//! entry index starting at 1, like the disk-label package and Linux do.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::block::{BlockDevice, BlockRange};
+use crate::bytes::{le32, le64};
+use crate::crc32::crc32;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+/// Upper bound on the size of the entry array, the specification requires 16KiB at least
+const MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// GUID in the mixed endian layout used on disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// All zero GUID marking unused entries
+    pub const NIL: Guid = Guid([0; 16]);
+
+    /// Builds a GUID from the fields of its text form
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+            data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    /// GUID from its on disk representation
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Guid(bytes)
+    }
+
+    /// On disk representation
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Parses the ```XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX``` form, case insensitive
+    pub fn parse(text: &str) -> Result<Self, &'static str> {
+        let text = text.as_bytes();
+        if text.len() != 36 || [8, 13, 18, 23].iter().any(|i| text[*i] != b'-') {
+            return Err("Invalid GUID");
+        }
+
+        let mut digits = [0u8; 16];
+        let mut nibbles = text.iter().filter(|c| **c != b'-');
+        for digit in digits.iter_mut() {
+            let mut byte = 0;
+            for _ in 0..2 {
+                let nibble = nibbles
+                    .next()
+                    .and_then(|c| (*c as char).to_digit(16))
+                    .ok_or("Invalid GUID")?;
+                byte = (byte << 4) | nibble as u8;
+            }
+            *digit = byte;
+        }
+
+        let d = digits;
+        Ok(Guid::from_fields(
+            u32::from_be_bytes([d[0], d[1], d[2], d[3]]),
+            u16::from_be_bytes([d[4], d[5]]),
+            u16::from_be_bytes([d[6], d[7]]),
+            [d[8], d[9], d[10], d[11], d[12], d[13], d[14], d[15]],
+        ))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Guid::NIL
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// PowerPC PReP boot partition holding the client program
+pub const TYPE_PREP_BOOT: Guid = Guid::from_fields(
+    0x9e1a2d38,
+    0xc612,
+    0x4316,
+    [0xaa, 0x26, 0x8b, 0x49, 0x52, 0x1e, 0x5a, 0x8b],
+);
+/// Linux filesystem data
+pub const TYPE_LINUX_FILESYSTEM: Guid = Guid::from_fields(
+    0x0fc63daf,
+    0x8483,
+    0x4772,
+    [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+);
+/// Linux extended boot partition, as used for '/boot'
+pub const TYPE_LINUX_EXTENDED_BOOT: Guid = Guid::from_fields(
+    0xbc13c2ff,
+    0x59e6,
+    0x4262,
+    [0xa3, 0x52, 0xb2, 0x75, 0xfd, 0x6f, 0x71, 0x72],
+);
+/// EFI system partition
+pub const TYPE_EFI_SYSTEM: Guid = Guid::from_fields(
+    0xc12a7328,
+    0xf81f,
+    0x11d2,
+    [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+);
+
+fn guid_at(data: &[u8], offset: usize) -> Guid {
+    Guid(data[offset..offset + 16].try_into().unwrap_or_default())
+}
+
+/// Partition table header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub revision: u32,
+    /// Block holding this header
+    pub my_lba: u64,
+    /// Block holding the other copy of the header
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    /// First block of the partition entry array
+    pub entries_lba: u64,
+    pub num_entries: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl Header {
+    /// Parses a header and checks its signature and CRC-32
+    pub fn parse(block: &[u8]) -> Result<Self, &'static str> {
+        if block.len() < MIN_HEADER_SIZE || &block[..8] != SIGNATURE {
+            return Err("Missing GPT header signature");
+        }
+
+        let size = le32(block, 12) as usize;
+        if size < MIN_HEADER_SIZE || size > block.len() {
+            return Err("Invalid GPT header size");
+        }
+        let mut copy = block[..size].to_vec();
+        copy[16..20].fill(0);
+        if crc32(&copy) != le32(block, 16) {
+            return Err("GPT header checksum mismatch");
+        }
+
+        let header = Header {
+            revision: le32(block, 8),
+            my_lba: le64(block, 24),
+            alternate_lba: le64(block, 32),
+            first_usable_lba: le64(block, 40),
+            last_usable_lba: le64(block, 48),
+            disk_guid: guid_at(block, 56),
+            entries_lba: le64(block, 72),
+            num_entries: le32(block, 80),
+            entry_size: le32(block, 84),
+            entries_crc32: le32(block, 88),
+        };
+
+        let entry_size = header.entry_size as usize;
+        if entry_size < MIN_ENTRY_SIZE || !entry_size.is_multiple_of(8) {
+            return Err("Invalid GPT entry size");
+        }
+        if header.entries_size() > MAX_ENTRIES_SIZE {
+            return Err("GPT entry array is too large");
+        }
+        Ok(header)
+    }
+
+    /// Size of the partition entry array in bytes
+    pub fn entries_size(&self) -> usize {
+        (self.num_entries as usize).saturating_mul(self.entry_size as usize)
+    }
+}
+
+/// Partition entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// Partition number as used in device specifiers
+    pub number: usize,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    /// First block of the partition
+    pub start: u64,
+    /// Number of blocks in the partition
+    pub count: u64,
+    pub attributes: u64,
+    /// Partition label
+    pub name: String,
+}
+
+impl Partition {
+    fn parse(number: usize, entry: &[u8]) -> Result<Option<Self>, &'static str> {
+        let type_guid = guid_at(entry, 0);
+        if type_guid.is_nil() {
+            return Ok(None);
+        }
+
+        let (first, last) = (le64(entry, 32), le64(entry, 40));
+        if last < first {
+            return Err("GPT partition ends before it starts");
+        }
+
+        let units = entry[56..128]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0);
+        let name = char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        Ok(Some(Partition {
+            number,
+            type_guid,
+            unique_guid: guid_at(entry, 16),
+            start: first,
+            count: last - first + 1,
+            attributes: le64(entry, 48),
+            name,
+        }))
+    }
+
+    /// Exposes the partition as a block device, failing if it is outside the device
+    pub fn open<D: BlockDevice>(&self, device: D) -> Result<BlockRange<D>, &'static str> {
+        BlockRange::new(device, self.start, self.count)
+    }
+}
+
+/// Parsed GUID partition table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gpt {
+    /// Header the partitions were read from, the primary one when it is valid
+    pub header: Header,
+    /// Whether the primary header and its entries are intact
+    pub primary_valid: bool,
+    /// Whether the backup header and its entries are intact
+    pub backup_valid: bool,
+    pub partitions: Vec<Partition>,
+}
+
+impl Gpt {
+    /// Reads and validates the partition table of a device
+    ///
+    /// The backup table is used when the primary one is damaged, failing only
+    /// when neither is valid.
+    pub fn read<D: BlockDevice>(device: &mut D) -> Result<Self, &'static str> {
+        let last_lba = device
+            .block_count()
+            .checked_sub(1)
+            .ok_or("Device is empty")?;
+
+        let primary = read_table(device, 1);
+        let backup_lba = match &primary {
+            Ok((header, _)) if header.alternate_lba <= last_lba => header.alternate_lba,
+            _ => last_lba,
+        };
+        let backup = read_table(device, backup_lba);
+
+        let (primary_valid, backup_valid) = (primary.is_ok(), backup.is_ok());
+        let (header, partitions) = primary.or(backup)?;
+        Ok(Gpt {
+            header,
+            primary_valid,
+            backup_valid,
+            partitions,
+        })
+    }
+
+    /// Partition by the number used in device specifiers
+    pub fn get(&self, number: usize) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// First partition of a given type
+    pub fn find_by_type(&self, type_guid: &Guid) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.type_guid == *type_guid)
+    }
+
+    /// First partition with a given label
+    pub fn find_by_label(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// PowerPC PReP boot partition
+    pub fn prep(&self) -> Option<&Partition> {
+        self.find_by_type(&TYPE_PREP_BOOT)
+    }
+}
+
+// Reads the header at a block and its partition entries, checking both CRCs
+fn read_table<D: BlockDevice>(
+    device: &mut D,
+    lba: u64,
+) -> Result<(Header, Vec<Partition>), &'static str> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8; block_size];
+    device.read_blocks(lba, &mut block)?;
+
+    let header = Header::parse(&block)?;
+    if header.my_lba != lba {
+        return Err("GPT header is not at its own location");
+    }
+
+    let size = header.entries_size();
+    let mut entries = vec![0u8; size.div_ceil(block_size) * block_size];
+    device.read_blocks(header.entries_lba, &mut entries)?;
+    entries.truncate(size);
+    if crc32(&entries) != header.entries_crc32 {
+        return Err("GPT partition entries checksum mismatch");
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks(header.entry_size as usize).enumerate() {
+        if let Some(partition) = Partition::parse(i + 1, entry)? {
+            partitions.push(partition);
+        }
+    }
+
+    Ok((header, partitions))
+}
@@ -0,0 +1,175 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! MBR/DOS partition tables
+//!
+//! Primary partitions are numbered 1 to 4 after their slot, logical partitions
+//! inside an extended partition are numbered from 5 in chain order, like the
+//! disk-label package and Linux do.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, BlockRange};
+
+const SIGNATURE_OFFSET: usize = 510;
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+/// Upper bound on the length of the logical partition chain, in case it loops
+const MAX_LOGICAL: usize = 128;
+
+/// Partition type of extended partitions using CHS addressing
+pub const TYPE_EXTENDED: u8 = 0x05;
+/// Partition type of extended partitions using LBA addressing
+pub const TYPE_EXTENDED_LBA: u8 = 0x0f;
+/// Partition type of Linux extended partitions
+pub const TYPE_EXTENDED_LINUX: u8 = 0x85;
+/// Partition type of the PowerPC PReP boot partition
+pub const TYPE_PREP: u8 = 0x41;
+/// Partition type covering the disk in a GPT protective MBR
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+fn is_extended(kind: u8) -> bool {
+    matches!(
+        kind,
+        TYPE_EXTENDED | TYPE_EXTENDED_LBA | TYPE_EXTENDED_LINUX
+    )
+}
+
+/// Partition entry, with addresses relative to the start of the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// Partition number as used in device specifiers
+    pub number: usize,
+    /// Partition type byte
+    pub kind: u8,
+    pub bootable: bool,
+    /// First block of the partition
+    pub start: u64,
+    /// Number of blocks in the partition
+    pub count: u64,
+}
+
+impl Partition {
+    /// Whether this is an extended partition holding logical partitions
+    pub fn is_extended(&self) -> bool {
+        is_extended(self.kind)
+    }
+
+    /// Exposes the partition as a block device, failing if it is outside the device
+    pub fn open<D: BlockDevice>(&self, device: D) -> Result<BlockRange<D>, &'static str> {
+        BlockRange::new(device, self.start, self.count)
+    }
+}
+
+// Decodes the four entries of a boot record numbered after their slot, empty slots are None
+fn parse_entries(sector: &[u8]) -> Result<[Option<Partition>; 4], &'static str> {
+    if sector.len() < 512 || sector[SIGNATURE_OFFSET..SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+        return Err("Missing MBR boot signature");
+    }
+
+    let mut entries = [None; 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let raw = &sector[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        let kind = raw[4];
+        let start = u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64;
+        let count = u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]) as u64;
+        if kind != 0 && count != 0 {
+            *entry = Some(Partition {
+                number: i + 1,
+                kind,
+                bootable: raw[0] & 0x80 != 0,
+                start,
+                count,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Parsed MBR partition table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mbr {
+    /// Disk signature at offset 440
+    pub disk_signature: u32,
+    /// Primary partitions followed by logical partitions, extended partitions included
+    pub partitions: Vec<Partition>,
+}
+
+impl Mbr {
+    /// Parses the primary partitions from the first sector, extended partitions are not followed
+    pub fn parse(sector: &[u8]) -> Result<Self, &'static str> {
+        let partitions = parse_entries(sector)?.into_iter().flatten().collect();
+
+        Ok(Mbr {
+            disk_signature: u32::from_le_bytes([
+                sector[440],
+                sector[441],
+                sector[442],
+                sector[443],
+            ]),
+            partitions,
+        })
+    }
+
+    /// Reads the partition table of a device, including logical partitions
+    pub fn read<D: BlockDevice>(device: &mut D) -> Result<Self, &'static str> {
+        if device.block_size() < 512 {
+            return Err("Block size is too small for an MBR");
+        }
+        let mut sector = vec![0u8; device.block_size()];
+        device.read_blocks(0, &mut sector)?;
+
+        let mut mbr = Mbr::parse(&sector)?;
+        let extended = mbr.partitions.iter().find(|p| p.is_extended()).copied();
+        if let Some(extended) = extended {
+            mbr.read_logical(device, &extended, &mut sector)?;
+        }
+        Ok(mbr)
+    }
+
+    fn read_logical<D: BlockDevice>(
+        &mut self,
+        device: &mut D,
+        extended: &Partition,
+        sector: &mut [u8],
+    ) -> Result<(), &'static str> {
+        let mut ebr = extended.start;
+        for number in 5..5 + MAX_LOGICAL {
+            device.read_blocks(ebr, sector)?;
+            let entries = parse_entries(sector)?;
+
+            // The first entry is relative to the EBR, the link is relative to the extended partition
+            if let Some(logical) = entries[0] {
+                self.partitions.push(Partition {
+                    number,
+                    start: ebr + logical.start,
+                    ..logical
+                });
+            }
+            match entries[1] {
+                Some(link) if link.is_extended() && link.start != 0 => {
+                    ebr = extended.start + link.start;
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        Err("Too many logical partitions")
+    }
+
+    /// Partition by the number used in device specifiers
+    pub fn get(&self, number: usize) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// Whether this is the protective MBR of a GPT disk
+    pub fn is_protective(&self) -> bool {
+        self.partitions
+            .iter()
+            .any(|p| p.kind == TYPE_GPT_PROTECTIVE)
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Partition table parsers working on any [`crate::block::BlockDevice`]
+//!
+//! These do not depend on the firmware's disk-label package, partitions are
+//! exposed as [`crate::block::BlockRange`] devices.
+
+pub mod apm;
+pub mod gpt;
+pub mod mbr;
+pub mod sun;
+
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, BlockRange};
+
+/// Partition table found on a disk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Table {
+    Gpt(gpt::Gpt),
+    Apm(apm::Apm),
+    Sun(sun::SunLabel),
+    Mbr(mbr::Mbr),
+}
+
+impl Table {
+    /// Reads the partition table of a device, whatever its format
+    ///
+    /// GPT comes first, so that the protective MBR of GPT disks is not
+    /// mistaken for the partition table.
+    pub fn read<D: BlockDevice>(device: &mut D) -> Result<Self, &'static str> {
+        if let Ok(table) = gpt::Gpt::read(device) {
+            return Ok(Table::Gpt(table));
+        }
+        if let Ok(table) = apm::Apm::read(device) {
+            return Ok(Table::Apm(table));
+        }
+        if let Ok(table) = sun::SunLabel::read(device) {
+            return Ok(Table::Sun(table));
+        }
+        match mbr::Mbr::read(device) {
+            Ok(table) => Ok(Table::Mbr(table)),
+            Err(_) => Err("No partition table found"),
+        }
+    }
+
+    /// Numbers of the partitions in table order, extended MBR partitions left out
+    pub fn numbers(&self) -> Vec<usize> {
+        match self {
+            Table::Gpt(table) => table.partitions.iter().map(|p| p.number).collect(),
+            Table::Apm(table) => table.partitions.iter().map(|p| p.number).collect(),
+            Table::Sun(table) => table.slices.iter().map(|s| s.number).collect(),
+            Table::Mbr(table) => table
+                .partitions
+                .iter()
+                .filter(|p| !p.is_extended())
+                .map(|p| p.number)
+                .collect(),
+        }
+    }
+
+    /// Exposes a partition by number as a block device
+    pub fn open<D: BlockDevice>(
+        &self,
+        device: D,
+        number: usize,
+    ) -> Result<BlockRange<D>, &'static str> {
+        let not_found = "Partition not found";
+        match self {
+            Table::Gpt(table) => table.get(number).ok_or(not_found)?.open(device),
+            Table::Apm(table) => table.get(number).ok_or(not_found)?.open(device),
+            Table::Sun(table) => table.get(number).ok_or(not_found)?.open(device),
+            Table::Mbr(table) => match table.get(number) {
+                Some(partition) if !partition.is_extended() => partition.open(device),
+                _ => Err(not_found),
+            },
+        }
+    }
+
+    /// Exposes a partition as a block device, by the argument naming it in device specifiers
+    ///
+    /// ```partition``` is a partition number, or a slice letter on Sun labels.
+    pub fn open_by_name<D: BlockDevice>(
+        &self,
+        device: D,
+        partition: &str,
+    ) -> Result<BlockRange<D>, &'static str> {
+        if let Ok(number) = partition.parse::<usize>() {
+            return self.open(device, number);
+        }
+        match (self, partition.as_bytes()) {
+            (Table::Sun(table), [letter]) => table
+                .get_by_letter(*letter as char)
+                .ok_or("Partition not found")?
+                .open(device),
+            _ => Err("Invalid partition name"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Sun disk labels with their VTOC, as used by OpenBoot on SPARC machines
+//!
+//! The label fills sector 0 and describes eight slices in cylinders, OpenBoot
+//! refers to them by letter in device specifiers, ```disk:a``` being slice 0.
+//! All the 16 bit words of the label XOR to zero.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, BlockRange};
+use crate::bytes::{be16, be32};
+
+const LABEL_SIZE: usize = 512;
+const SECTOR_SIZE: u64 = 512;
+const MAGIC: u16 = 0xdabe;
+const VTOC_SANITY: u32 = 0x600d_deed;
+const VTOC_VERSION: u32 = 1;
+const MAX_SLICES: usize = 8;
+
+const VTOC_OFFSET: usize = 128;
+const GEOMETRY_OFFSET: usize = 420;
+const SLICES_OFFSET: usize = 444;
+
+/// Slice holding the boot program
+pub const TAG_BOOT: u16 = 0x01;
+/// Root filesystem
+pub const TAG_ROOT: u16 = 0x02;
+pub const TAG_SWAP: u16 = 0x03;
+pub const TAG_USR: u16 = 0x04;
+/// Slice covering the whole disk, conventionally slice 2
+pub const TAG_BACKUP: u16 = 0x05;
+pub const TAG_VAR: u16 = 0x07;
+pub const TAG_HOME: u16 = 0x08;
+/// Linux swap, as written by Linux partitioning tools
+pub const TAG_LINUX_SWAP: u16 = 0x82;
+/// Linux filesystem, as written by Linux partitioning tools
+pub const TAG_LINUX_NATIVE: u16 = 0x83;
+
+/// Slice is not meant to be mounted
+pub const FLAG_UNMOUNTABLE: u16 = 0x01;
+/// Slice is read only
+pub const FLAG_READ_ONLY: u16 = 0x10;
+
+fn text_at(data: &[u8], offset: usize, len: usize) -> String {
+    data[offset..offset + len]
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as char)
+        .collect()
+}
+
+/// Checksum making all the 16 bit words of a label XOR to zero
+pub fn checksum(label: &[u8]) -> u16 {
+    label[..LABEL_SIZE - 2]
+        .chunks(2)
+        .fold(0, |sum, word| sum ^ u16::from_be_bytes([word[0], word[1]]))
+}
+
+/// Disk slice
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Slice {
+    /// Slice index, 0 to 7
+    pub number: usize,
+    /// Slice contents from the VTOC, 0 when the label has no VTOC
+    pub tag: u16,
+    /// Slice flags from the VTOC
+    pub flags: u16,
+    /// First sector of the slice
+    pub start: u64,
+    /// Number of sectors in the slice
+    pub count: u64,
+}
+
+impl Slice {
+    /// Letter naming the slice in device specifiers
+    pub fn letter(&self) -> char {
+        (b'a' + self.number as u8) as char
+    }
+
+    /// Exposes the slice as a block device, failing if it is outside the device
+    pub fn open<D: BlockDevice>(&self, device: D) -> Result<BlockRange<D>, &'static str> {
+        // Saturating keeps corrupted labels out of the device rather than wrapping around
+        BlockRange::from_byte_range(
+            device,
+            self.start.saturating_mul(SECTOR_SIZE),
+            self.count.saturating_mul(SECTOR_SIZE),
+        )
+    }
+}
+
+/// Parsed Sun disk label
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SunLabel {
+    /// Text describing the disk, such as its model and geometry
+    pub info: String,
+    /// Volume name from the VTOC
+    pub volume: Option<String>,
+    /// Data cylinders
+    pub cylinders: u16,
+    /// Alternate cylinders
+    pub alternate_cylinders: u16,
+    pub heads: u16,
+    pub sectors_per_track: u16,
+    /// Slices with a size, in slice order
+    pub slices: Vec<Slice>,
+}
+
+impl SunLabel {
+    /// Parses a label from the first sector, checking its magic and checksum
+    pub fn parse(sector: &[u8]) -> Result<Self, &'static str> {
+        if sector.len() < LABEL_SIZE || be16(sector, LABEL_SIZE - 4) != MAGIC {
+            return Err("Missing Sun disk label magic");
+        }
+        if checksum(sector) != be16(sector, LABEL_SIZE - 2) {
+            return Err("Sun disk label checksum mismatch");
+        }
+
+        let heads = be16(sector, GEOMETRY_OFFSET + 16);
+        let sectors_per_track = be16(sector, GEOMETRY_OFFSET + 18);
+        let cylinder = heads as u64 * sectors_per_track as u64;
+
+        // Tags and flags are only meaningful with a sane VTOC
+        let vtoc = &sector[VTOC_OFFSET..];
+        let has_vtoc = be32(vtoc, 0) == VTOC_VERSION && be32(vtoc, 60) == VTOC_SANITY;
+
+        let slices = (0..MAX_SLICES)
+            .map(|number| {
+                let entry = SLICES_OFFSET + number * 8;
+                let (tag, flags) = match has_vtoc {
+                    true => (be16(vtoc, 14 + number * 4), be16(vtoc, 16 + number * 4)),
+                    false => (0, 0),
+                };
+                Slice {
+                    number,
+                    tag,
+                    flags,
+                    start: (be32(sector, entry) as u64).saturating_mul(cylinder),
+                    count: be32(sector, entry + 4) as u64,
+                }
+            })
+            .filter(|slice| slice.count > 0)
+            .collect();
+
+        Ok(SunLabel {
+            info: text_at(sector, 0, 128),
+            volume: has_vtoc.then(|| text_at(vtoc, 4, 8)),
+            cylinders: be16(sector, GEOMETRY_OFFSET + 12),
+            alternate_cylinders: be16(sector, GEOMETRY_OFFSET + 14),
+            heads,
+            sectors_per_track,
+            slices,
+        })
+    }
+
+    /// Reads the label of a device
+    pub fn read<D: BlockDevice>(device: &mut D) -> Result<Self, &'static str> {
+        let mut sector = [0u8; LABEL_SIZE];
+        device.read_at(0, &mut sector)?;
+        SunLabel::parse(&sector)
+    }
+
+    /// Slice by index
+    pub fn get(&self, number: usize) -> Option<&Slice> {
+        self.slices.iter().find(|s| s.number == number)
+    }
+
+    /// Slice by the letter used in device specifiers
+    pub fn get_by_letter(&self, letter: char) -> Option<&Slice> {
+        self.slices.iter().find(|s| s.letter() == letter)
+    }
+
+    /// First slice with a given VTOC tag
+    pub fn find_by_tag(&self, tag: u16) -> Option<&Slice> {
+        self.slices.iter().find(|s| s.tag == tag)
+    }
+}
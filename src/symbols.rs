@@ -0,0 +1,325 @@
+// Copyright 2021 Alberto Ruiz <aruiz@redhat.com>
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Symbol table for the ```set-symbol-lookup``` service
+//!
+//! The firmware debugger can ask the client program to translate addresses into
+//! symbol names (and back) through the callback mechanism. The table is embedded
+//! in the client image as a linker section reserved with [`crate::symbol_table_section`].
+//!
+//! This crate does not fill the section. After linking, the build of the client
+//! has to replace its contents with the output of [`encode`] or [`encode_nm`],
+//! e.g. with ```objcopy --update-section```. A section left zeroed is rejected
+//! by [`SymbolTable::from_section`].
+
+use alloc::vec::Vec;
+use core::{slice, str};
+
+use crate::bytes::{be32, be64};
+use crate::callback::ClientCallbacks;
+
+/// Magic number at the start of an encoded symbol table
+pub const MAGIC: &[u8; 8] = b"OFSYMTAB";
+
+/// Name of the linker section holding the symbol table
+pub const SECTION: &str = ".ieee1275_symtab";
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+/// Reserves a zeroed linker section to be filled with an encoded symbol table
+///
+/// ```ignore
+/// ieee1275::symbol_table_section!(SYMTAB, 64 * 1024);
+/// let table = SymbolTable::from_section(&SYMTAB)?;
+/// ```
+#[macro_export]
+macro_rules! symbol_table_section {
+    ($name:ident, $size:expr) => {
+        #[used]
+        #[link_section = ".ieee1275_symtab"]
+        static $name: [u8; $size] = [0; $size];
+    };
+}
+
+/// A symbol of the client program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub addr: u64,
+    pub size: u64,
+    pub name: &'a str,
+}
+
+/// Read only view over an encoded symbol table
+///
+/// The encoding is big endian: the [`MAGIC`], the amount of entries and the
+/// offset of the string area as 32 bit values, followed by the entries sorted by
+/// address (64 bit address, 64 bit size, 32 bit name offset and 32 bit name
+/// length). Names are null terminated so they can be handed to the firmware.
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+    strings: usize,
+}
+
+// Start of the name of an entry and its length, without the null terminator
+fn name_range(data: &[u8], strings: usize, entry: usize) -> Option<(usize, usize)> {
+    let offset = strings.checked_add(be32(data, entry + 16) as usize)?;
+    let len = be32(data, entry + 20) as usize;
+    // Room for the terminator too, offsets are 32 bit wide on 32 bit targets
+    offset.checked_add(len)?.checked_add(1)?;
+    Some((offset, len))
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parses an encoded symbol table
+    pub fn new(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+            return Err("Invalid symbol table");
+        }
+
+        let count = be32(data, 8) as usize;
+        let strings = be32(data, 12) as usize;
+        let entries_end = count
+            .checked_mul(ENTRY_SIZE)
+            .and_then(|len| len.checked_add(HEADER_SIZE));
+        match entries_end {
+            Some(end) if end <= strings && strings <= data.len() => {}
+            _ => return Err("Symbol table is truncated"),
+        }
+
+        let table = SymbolTable {
+            data,
+            count,
+            strings,
+        };
+
+        for i in 0..count {
+            let entry = HEADER_SIZE + i * ENTRY_SIZE;
+            let name = name_range(data, strings, entry)
+                .and_then(|(offset, len)| data.get(offset..offset + len + 1));
+            match name {
+                Some([name @ .., 0]) if str::from_utf8(name).is_ok() => {}
+                _ => return Err("Invalid symbol name"),
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Number of symbols in the table
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the symbol at ```index```, symbols are sorted by address
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        if index >= self.count {
+            return None;
+        }
+
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        // Names were validated on creation
+        let (offset, len) = name_range(self.data, self.strings, entry)?;
+        let name = unsafe { str::from_utf8_unchecked(self.data.get(offset..offset + len)?) };
+
+        Some(Symbol {
+            addr: be64(self.data, entry),
+            size: be64(self.data, entry + 8),
+            name,
+        })
+    }
+
+    /// Finds the symbol containing ```addr```
+    ///
+    /// # Returns
+    ///
+    /// The symbol and the offset of ```addr``` from its start
+    pub fn lookup(&self, addr: u64) -> Option<(Symbol<'a>, u64)> {
+        // Index of the first symbol starting after addr
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.get(mid)?.addr <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        let symbol = self.get(low.checked_sub(1)?)?;
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        Some((symbol, offset))
+    }
+
+    /// Finds the address of the symbol named ```name```
+    pub fn resolve(&self, name: &str) -> Option<u64> {
+        (0..self.count)
+            .filter_map(|i| self.get(i))
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.addr)
+    }
+}
+
+impl SymbolTable<'static> {
+    /// Parses the table stored in a section reserved with [`crate::symbol_table_section`]
+    pub fn from_section(section: &'static [u8]) -> Result<Self, &'static str> {
+        // The section is filled after linking, keep the compiler from assuming
+        // it still holds the zeroes it was declared with
+        let ptr = core::hint::black_box(section.as_ptr());
+        Self::new(unsafe { slice::from_raw_parts(ptr, section.len()) })
+    }
+}
+
+/// Callback handler answering the symbol lookups from a table and passing any
+/// other request on to the handler that was installed before it
+pub(crate) struct SymbolLookup {
+    pub(crate) table: &'static SymbolTable<'static>,
+    pub(crate) next: Option<&'static dyn ClientCallbacks>,
+}
+
+impl ClientCallbacks for SymbolLookup {
+    fn callback(
+        &self,
+        service: &str,
+        args: &[usize],
+        rets: &mut [usize],
+    ) -> Result<(), &'static str> {
+        match (service, self.next) {
+            ("sym-to-value" | "value-to-sym", _) => self.table.callback(service, args, rets),
+            (_, Some(next)) => next.callback(service, args, rets),
+            (_, None) => Err("Unknown callback service"),
+        }
+    }
+}
+
+impl ClientCallbacks for SymbolTable<'static> {
+    fn callback(
+        &self,
+        service: &str,
+        args: &[usize],
+        rets: &mut [usize],
+    ) -> Result<(), &'static str> {
+        match service {
+            // ( value -- offset symname )
+            "value-to-sym" => {
+                if args.is_empty() || rets.len() < 2 {
+                    return Err("Invalid value-to-sym arguments");
+                }
+
+                match self.lookup(args[0] as u64) {
+                    Some((symbol, offset)) => {
+                        rets[0] = offset as usize;
+                        // Names are followed by their null terminator in the table
+                        rets[1] = symbol.name.as_ptr() as usize;
+                    }
+                    None => {
+                        rets[0] = usize::MAX;
+                        rets[1] = 0;
+                    }
+                }
+                Ok(())
+            }
+            // ( symname -- error value )
+            "sym-to-value" => {
+                if args.is_empty() || rets.len() < 2 {
+                    return Err("Invalid sym-to-value arguments");
+                }
+
+                let name = args[0] as *const u8;
+                if name.is_null() {
+                    return Err("Invalid sym-to-value arguments");
+                }
+                let mut len = 0;
+                while unsafe { *name.add(len) } != 0 {
+                    len += 1;
+                }
+                let name = unsafe { slice::from_raw_parts(name, len) };
+
+                match str::from_utf8(name)
+                    .ok()
+                    .and_then(|name| self.resolve(name))
+                {
+                    Some(addr) => {
+                        rets[0] = 0;
+                        rets[1] = addr as usize;
+                    }
+                    None => {
+                        rets[0] = usize::MAX;
+                        rets[1] = 0;
+                    }
+                }
+                Ok(())
+            }
+            _ => Err("Unknown callback service"),
+        }
+    }
+}
+
+/// Encodes a symbol table from a list of symbols, symbols do not need to be sorted
+pub fn encode(symbols: &[Symbol<'_>]) -> Vec<u8> {
+    let mut sorted: Vec<&Symbol<'_>> = symbols.iter().collect();
+    sorted.sort_by_key(|symbol| symbol.addr);
+
+    let strings = HEADER_SIZE + sorted.len() * ENTRY_SIZE;
+    let mut out = Vec::with_capacity(strings);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(sorted.len() as u32).to_be_bytes());
+    out.extend_from_slice(&(strings as u32).to_be_bytes());
+
+    let mut names = Vec::new();
+    for symbol in sorted {
+        out.extend_from_slice(&symbol.addr.to_be_bytes());
+        out.extend_from_slice(&symbol.size.to_be_bytes());
+        out.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        out.extend_from_slice(&(symbol.name.len() as u32).to_be_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+        names.push(0);
+    }
+
+    out.extend_from_slice(&names);
+    out
+}
+
+/// Encodes the text symbols from the output of ```nm```
+///
+/// Lines are expected in the ```address type name``` format, sizes are taken
+/// from the distance to the next symbol. Demangled names (```nm -C```) are
+/// preferred as they are what shows up in the debugger.
+pub fn encode_nm(nm: &str) -> Vec<u8> {
+    let mut symbols: Vec<Symbol<'_>> = nm
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().splitn(3, ' ');
+            let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?.trim();
+            match kind {
+                "T" | "t" | "W" | "w" if !name.is_empty() => Some(Symbol {
+                    addr,
+                    size: 0,
+                    name,
+                }),
+                _ => None,
+            }
+        })
+        .collect();
+
+    symbols.sort_by_key(|symbol| symbol.addr);
+    for i in 1..symbols.len() {
+        symbols[i - 1].size = symbols[i].addr - symbols[i - 1].addr;
+    }
+
+    encode(&symbols)
+}
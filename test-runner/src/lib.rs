@@ -8,9 +8,70 @@ extern crate ieee1275;
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, mem::size_of, usize};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::{BTreeMap, HashMap, HashSet, VecDeque},
+        ffi::CStr,
+        io::{Seek as _, SeekFrom, Write as _},
+        mem::size_of,
+        os::unix::fs::symlink,
+        path::Path,
+        process::{Command, Stdio},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex, OnceLock,
+        },
+        usize,
+    };
 
-    use ieee1275::{services, services::Args, IHandle, PHandle, PROM};
+    use ieee1275::{
+        block::{BlockDevice, Disk, MemoryDisk},
+        boot::{BootArgs, BootContext, Initrd},
+        callback::ClientCallbacks,
+        compress::{
+            self,
+            gzip::Gzip,
+            inflate::Inflate,
+            lzma2::Lzma2,
+            xz::Xz,
+            zlib::{self, Zlib},
+            zstd::{Xxh64, Zstd},
+            Format,
+        },
+        cpio,
+        crc32::{crc32, crc32c, Crc32},
+        devspec::{self, DevSpec, PathComponent},
+        dump::{self, DeviceTreeDump, Style},
+        elf::{self, Class, Endian},
+        fdt::{self, DeviceTree, Node},
+        fs::{
+            self,
+            btrfs::{self, BtrfsFs},
+            ext::{self, ExtFs},
+            fat::{self, FatFs, FatType},
+            hfsplus::{Flavor, HfsPlusFs},
+            iso9660::{self, IsoFs},
+            vfs::Vfs,
+            xfs::XfsFs,
+            FileType, FsType,
+        },
+        io::{Cursor, Instance, Read, Seek},
+        linux::{self, Branch, KernelEntry, LinuxBoot},
+        load::{self, ClaimedRegion},
+        mmu::{self, Mapping, Translation},
+        net::{self, Interface},
+        partition::{
+            apm::{self, Apm},
+            gpt::{self, Gpt, Guid},
+            mbr::{self, Mbr},
+            sun::{self, SunLabel},
+            Table,
+        },
+        services,
+        services::Args,
+        symbols::{self, Symbol, SymbolTable},
+        IHandle, PHandle, PROM,
+    };
 
     // Infrastructure to mock an Open Firmware implementation
 
@@ -20,11 +81,19 @@ mod tests {
     const CHOSEN_PHANDLE: usize = 0xdeadbeef;
     const STDOUT_IHANDLE: usize = 0xdecafbad;
     const DISK_IHANDLE: usize = 0xfeedd15c;
+    const ROOT_PHANDLE: usize = 0x0b0070ed;
+    const MMU_IHANDLE: usize = 0x3e3e3e3e;
+    const MMU_PHANDLE: usize = 0x3e3e0000;
+    const MEMORY_IHANDLE: usize = 0x3e300000;
+    const MMU_MODE: usize = 0x10;
 
     struct MockProm {
         stdout: String,
         stdout_ihandle: usize,
         chosen_phandle: usize,
+        callback: Option<extern "C" fn(*mut Args) -> usize>,
+        symbol_lookup: Option<(String, String)>,
+        mappings: Vec<(usize, usize, usize, usize)>,
     }
 
     struct Heap {
@@ -35,13 +104,266 @@ mod tests {
         stdout: String::new(),
         stdout_ihandle: STDOUT_IHANDLE,
         chosen_phandle: CHOSEN_PHANDLE,
+        callback: None,
+        symbol_lookup: None,
+        mappings: Vec::new(),
     };
+    // Serializes tests that install callbacks, as the handler is global
+    static CALLBACK_LOCK: Mutex<()> = Mutex::new(());
     static mut HEAP: Heap = Heap { heap: None };
 
+    // Properties set by the client, keyed by package handle and name
+    type PropertyStore = HashMap<(usize, Vec<u8>), Vec<u8>>;
+
+    // Files, open instances and fixed address claims are kept per test thread
+    thread_local! {
+        static FILES: RefCell<HashMap<Vec<u8>, Vec<u8>>> = RefCell::new(HashMap::new());
+        static INSTANCES: RefCell<HashMap<usize, (Vec<u8>, usize)>> = RefCell::new(HashMap::new());
+        static CLAIMED: RefCell<HashMap<usize, Vec<u8>>> = RefCell::new(HashMap::new());
+        static PROPS: RefCell<PropertyStore> = RefCell::new(HashMap::new());
+        static QUIESCED: Cell<bool> = const { Cell::new(false) };
+        static READ_BLOCKS: Cell<bool> = const { Cell::new(true) };
+        // Method open files report their size with, if any
+        static SIZE_METHOD: Cell<&'static [u8]> = const { Cell::new(b"size") };
+        // Open instances of the network device, frames waiting to be read and the script answering sent ones
+        static NIC_INSTANCES: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+        static NIC_RX: RefCell<VecDeque<Vec<u8>>> = const { RefCell::new(VecDeque::new()) };
+        static NIC_SCRIPT: RefCell<Option<NicScript>> = const { RefCell::new(None) };
+    }
+    static NEXT_IHANDLE: AtomicUsize = AtomicUsize::new(0x1000_0000);
+
+    fn mock_file(path: &str, data: Vec<u8>) {
+        FILES.with(|files| files.borrow_mut().insert(path.as_bytes().to_vec(), data));
+    }
+
+    // Called with each frame the client sends, returns the frames the network answers with
+    type NicScript = Box<dyn FnMut(&[u8]) -> Vec<Vec<u8>>>;
+
+    fn mock_nic(script: impl FnMut(&[u8]) -> Vec<Vec<u8>> + 'static) {
+        NIC_SCRIPT.with(|nic| *nic.borrow_mut() = Some(Box::new(script)));
+        NIC_RX.with(|rx| rx.borrow_mut().clear());
+    }
+
+    fn is_nic(ihandle: *const IHandle) -> bool {
+        NIC_INSTANCES.with(|nics| nics.borrow().contains(&(ihandle as usize)))
+    }
+
     fn cast_args<T>(args: *mut Args) -> &'static mut T {
         unsafe { &mut *(args as *mut T) }
     }
 
+    const CPUS_PHANDLE: usize = 0x0c0c0000;
+    const CPU0_PHANDLE: usize = 0x0c0c0001;
+    const MEMORY_PHANDLE: usize = 0x3e300001;
+    const VDEVICE_PHANDLE: usize = 0x7de00000;
+    const VSCSI_PHANDLE: usize = 0x7de02000;
+    const ALIASES_PHANDLE: usize = 0x0a11a5e5;
+    const VTY_PHANDLE: usize = 0x7d730000;
+    const LAN_PHANDLE: usize = 0x7de30002;
+    const LAN_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    // Static device tree served by the mock, properties set by the client are kept in PROPS
+    struct MockNode {
+        phandle: usize,
+        parent: usize,
+        name: &'static str,
+        props: Vec<(&'static str, Vec<u8>)>,
+    }
+
+    fn cells(cells: &[u32]) -> Vec<u8> {
+        cells.iter().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        value
+    }
+
+    fn mock_tree() -> &'static [MockNode] {
+        static TREE: OnceLock<Vec<MockNode>> = OnceLock::new();
+        TREE.get_or_init(|| {
+            vec![
+                MockNode {
+                    phandle: ROOT_PHANDLE,
+                    parent: 0,
+                    name: "",
+                    props: vec![
+                        ("#address-cells", cells(&[1])),
+                        ("#size-cells", cells(&[1])),
+                        ("compatible", string("qemu,pseries")),
+                        ("model", string("IBM pSeries (emulated by qemu)")),
+                    ],
+                },
+                MockNode {
+                    phandle: CHOSEN_PHANDLE,
+                    parent: ROOT_PHANDLE,
+                    name: "chosen",
+                    props: vec![("name", string("chosen"))],
+                },
+                MockNode {
+                    phandle: CPUS_PHANDLE,
+                    parent: ROOT_PHANDLE,
+                    name: "cpus",
+                    props: vec![
+                        ("name", string("cpus")),
+                        ("#address-cells", cells(&[1])),
+                        ("#size-cells", cells(&[0])),
+                    ],
+                },
+                MockNode {
+                    phandle: CPU0_PHANDLE,
+                    parent: CPUS_PHANDLE,
+                    name: "PowerPC,POWER9@0",
+                    props: vec![
+                        ("name", string("PowerPC,POWER9")),
+                        ("device_type", string("cpu")),
+                        ("reg", cells(&[0])),
+                        ("clock-frequency", cells(&[1_000_000_000])),
+                    ],
+                },
+                MockNode {
+                    phandle: MEMORY_PHANDLE,
+                    parent: ROOT_PHANDLE,
+                    name: "memory@0",
+                    props: vec![
+                        ("name", string("memory")),
+                        ("device_type", string("memory")),
+                        ("reg", cells(&[0, 0x2000_0000])),
+                    ],
+                },
+                MockNode {
+                    phandle: VDEVICE_PHANDLE,
+                    parent: ROOT_PHANDLE,
+                    name: "vdevice",
+                    props: vec![
+                        ("name", string("vdevice")),
+                        ("#address-cells", cells(&[1])),
+                        ("#size-cells", cells(&[0])),
+                    ],
+                },
+                MockNode {
+                    phandle: VSCSI_PHANDLE,
+                    parent: VDEVICE_PHANDLE,
+                    name: "v-scsi@2000",
+                    props: vec![
+                        ("name", string("v-scsi")),
+                        ("device_type", string("vscsi")),
+                        ("reg", cells(&[0x2000])),
+                        ("interrupt-parent", cells(&[VDEVICE_PHANDLE as u32])),
+                    ],
+                },
+                MockNode {
+                    phandle: ALIASES_PHANDLE,
+                    parent: ROOT_PHANDLE,
+                    name: "aliases",
+                    props: vec![
+                        ("name", string("aliases")),
+                        ("disk", string("/vdevice/v-scsi@2000/disk@8100000000000000")),
+                        (
+                            "cdrom",
+                            string("/vdevice/v-scsi@2000/disk@8200000000000000:1"),
+                        ),
+                        ("hd", string("disk")),
+                        ("loop", string("loop")),
+                    ],
+                },
+                MockNode {
+                    phandle: VTY_PHANDLE,
+                    parent: VDEVICE_PHANDLE,
+                    name: "vty@30000000",
+                    props: vec![
+                        ("name", string("vty")),
+                        ("device_type", string("serial")),
+                        ("reg", cells(&[0x3000_0000])),
+                    ],
+                },
+                MockNode {
+                    phandle: LAN_PHANDLE,
+                    parent: VDEVICE_PHANDLE,
+                    name: "l-lan@30000002",
+                    props: vec![
+                        ("name", string("l-lan")),
+                        ("device_type", string("network")),
+                        ("reg", cells(&[0x3000_0002])),
+                        ("local-mac-address", LAN_MAC.to_vec()),
+                        ("max-frame-size", cells(&[1514])),
+                    ],
+                },
+            ]
+        })
+    }
+
+    fn node_path(phandle: usize) -> String {
+        let tree = mock_tree();
+        let mut components = Vec::new();
+        let mut node = tree.iter().find(|node| node.phandle == phandle);
+        while let Some(current) = node {
+            if current.parent == 0 {
+                break;
+            }
+            components.push(current.name);
+            node = tree.iter().find(|node| node.phandle == current.parent);
+        }
+        components.reverse();
+        format!("/{}", components.join("/"))
+    }
+
+    fn cstr(ptr: *const u8) -> &'static [u8] {
+        unsafe { CStr::from_ptr(ptr as *const _) }.to_bytes()
+    }
+
+    fn set_prop(args: &mut services::PropArgs<u8>, value: &[u8]) -> usize {
+        let len = value.len().min(args.buflen);
+        unsafe { std::ptr::copy_nonoverlapping(value.as_ptr(), args.buf as *mut u8, len) };
+        args.size = value.len();
+        args.size
+    }
+
+    // Cells of physical addresses, from the #address-cells the client may set on the root node
+    fn phys_cells() -> usize {
+        let cells = PROPS.with(|props| {
+            props
+                .borrow()
+                .get(&(ROOT_PHANDLE, b"#address-cells".to_vec()))
+                .cloned()
+        });
+        match cells {
+            Some(cells) => u32::from_be_bytes(cells[..4].try_into().unwrap()) as usize,
+            None => 1,
+        }
+    }
+
+    // Physical address from raw method cells, phys.hi first as it is on top of the stack
+    fn phys_cells_from(cells: &[usize]) -> usize {
+        match phys_cells() {
+            1 => cells[0],
+            _ => (cells[0] << 32) | cells[1],
+        }
+    }
+
+    fn phys_cells_into(cells: &mut [usize], phys: usize) {
+        match phys_cells() {
+            1 => cells[0] = phys,
+            _ => {
+                cells[0] = phys >> 32;
+                cells[1] = phys & 0xffff_ffff;
+            }
+        }
+    }
+
+    // Splits call-method arguments into the stack arguments and the catch-result and stack results
+    fn method_cells(args: *mut Args) -> (&'static [usize], &'static mut [usize]) {
+        let header = unsafe { &*args };
+        let cells = unsafe { (args as *mut usize).add(5) };
+        unsafe {
+            (
+                std::slice::from_raw_parts(cells, header.nargs - 2),
+                std::slice::from_raw_parts_mut(cells.add(header.nargs - 2), header.nret),
+            )
+        }
+    }
+
     impl MockProm {
         fn finddevice(&self, args: *mut Args) -> usize {
             let args = cast_args::<services::FindDeviceArgs>(args);
@@ -53,6 +375,12 @@ mod tests {
             if device.starts_with(b"/chosen\0") {
                 (*args).phandle = self.chosen_phandle as *const PHandle;
                 size_of::<usize>()
+            } else if let Some(node) = mock_tree()
+                .iter()
+                .find(|node| node_path(node.phandle).as_bytes() == cstr(args.device))
+            {
+                args.phandle = node.phandle as *const PHandle;
+                size_of::<usize>()
             } else {
                 usize::MAX
             }
@@ -71,12 +399,210 @@ mod tests {
                 *stdout_address = self.stdout_ihandle;
                 args.size = size_of::<usize>();
                 args.size
+            } else if prop.starts_with(b"mmu\0") {
+                set_prop(args, &MMU_IHANDLE.to_ne_bytes())
+            } else if prop.starts_with(b"memory\0") {
+                set_prop(args, &MEMORY_IHANDLE.to_ne_bytes())
+            } else if let Some(value) = self.prop_value(args.phandle as usize, cstr(args.prop)) {
+                set_prop(args, &value)
             } else {
                 args.size = usize::MAX;
                 usize::MAX
             }
         }
 
+        fn prop_value(&self, phandle: usize, prop: &[u8]) -> Option<Vec<u8>> {
+            if let Some(value) =
+                PROPS.with(|props| props.borrow().get(&(phandle, prop.to_vec())).cloned())
+            {
+                return Some(value);
+            }
+
+            if let Some(value) = mock_tree()
+                .iter()
+                .find(|node| node.phandle == phandle)
+                .and_then(|node| node.props.iter().find(|(name, _)| name.as_bytes() == prop))
+            {
+                return Some(value.1.clone());
+            }
+
+            match (phandle, prop) {
+                (MMU_PHANDLE, b"translations") => Some(cells(&[
+                    0x0000_0000,
+                    0x0100_0000,
+                    0x0000_0000,
+                    0x10,
+                    0xfff0_0000,
+                    0x10_0000,
+                    0x0ff0_0000,
+                    0x6a,
+                ])),
+                _ => None,
+            }
+        }
+
+        fn setprop(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::PropArgs<u8>>(args);
+
+            assert_eq!(args.args.nargs, 4);
+            assert_eq!(args.args.nret, 1);
+
+            let value = unsafe { std::slice::from_raw_parts(args.buf, args.buflen) }.to_vec();
+            PROPS.with(|props| {
+                props
+                    .borrow_mut()
+                    .insert((args.phandle as usize, cstr(args.prop).to_vec()), value)
+            });
+            args.size = args.buflen;
+            0
+        }
+
+        fn test(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::TestArgs>(args);
+
+            assert_eq!(args.args.nargs, 1);
+            assert_eq!(args.args.nret, 1);
+
+            args.missing = match cstr(args.name) {
+                b"quiesce" | b"setprop" | b"test" => 0,
+                _ => 1,
+            };
+            0
+        }
+
+        fn relative(&self, service: &[u8], args: *mut Args) -> usize {
+            let args = cast_args::<services::NodeArgs>(args);
+            let tree = mock_tree();
+            let phandle = args.phandle as usize;
+
+            assert_eq!(args.args.nargs, 1);
+            assert_eq!(args.args.nret, 1);
+
+            let node = match (service, tree.iter().find(|node| node.phandle == phandle)) {
+                (b"peer", _) if phandle == 0 => Some(ROOT_PHANDLE),
+                (b"peer", Some(node)) => tree
+                    .iter()
+                    .skip_while(|sibling| sibling.phandle != phandle)
+                    .skip(1)
+                    .find(|sibling| node.parent != 0 && sibling.parent == node.parent)
+                    .map(|sibling| sibling.phandle),
+                (b"child", Some(_)) => tree
+                    .iter()
+                    .find(|child| child.parent == phandle)
+                    .map(|child| child.phandle),
+                (b"parent", Some(node)) => Some(node.parent),
+                _ => Some(usize::MAX),
+            };
+            args.node = node.unwrap_or(0) as *const PHandle;
+            0
+        }
+
+        fn property_names(&self, phandle: usize) -> Vec<Vec<u8>> {
+            let mut names: Vec<Vec<u8>> = mock_tree()
+                .iter()
+                .find(|node| node.phandle == phandle)
+                .map(|node| {
+                    node.props
+                        .iter()
+                        .map(|(name, _)| name.as_bytes().to_vec())
+                        .collect()
+                })
+                .unwrap_or_default();
+            let mut extra: Vec<Vec<u8>> = PROPS.with(|props| {
+                props
+                    .borrow()
+                    .keys()
+                    .filter(|(node, name)| *node == phandle && !names.contains(name))
+                    .map(|(_, name)| name.clone())
+                    .collect()
+            });
+            extra.sort();
+            names.extend(extra);
+            names
+        }
+
+        fn nextprop(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::NextPropArgs>(args);
+            let phandle = args.phandle as usize;
+
+            assert_eq!(args.args.nargs, 3);
+            assert_eq!(args.args.nret, 1);
+
+            if !mock_tree().iter().any(|node| node.phandle == phandle) {
+                args.flag = -1;
+                return 0;
+            }
+
+            let names = self.property_names(phandle);
+            let previous = cstr(args.previous);
+            let next = match previous.is_empty() {
+                true => names.first(),
+                false => names
+                    .iter()
+                    .skip_while(|name| name.as_slice() != previous)
+                    .nth(1),
+            };
+
+            args.flag = match next {
+                Some(name) => {
+                    let buf = unsafe { std::slice::from_raw_parts_mut(args.buf, 32) };
+                    buf[..name.len()].copy_from_slice(name);
+                    buf[name.len()] = 0;
+                    1
+                }
+                None => 0,
+            };
+            0
+        }
+
+        fn package_to_path(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::PackageToPathArgs>(args);
+            let phandle = args.phandle as usize;
+
+            assert_eq!(args.args.nargs, 3);
+            assert_eq!(args.args.nret, 1);
+
+            if !mock_tree().iter().any(|node| node.phandle == phandle) {
+                args.length = -1;
+                return 0;
+            }
+
+            let path = node_path(phandle);
+            let len = path.len().min(args.buflen);
+            unsafe { std::ptr::copy_nonoverlapping(path.as_ptr(), args.buf, len) };
+            args.length = path.len() as isize;
+            0
+        }
+
+        fn getproplen(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::PropLenArgs>(args);
+
+            assert_eq!(args.args.nargs, 2);
+            assert_eq!(args.args.nret, 1);
+
+            args.size = match self.prop_value(args.phandle as usize, cstr(args.prop)) {
+                Some(value) => value.len(),
+                None => usize::MAX,
+            };
+            0
+        }
+
+        fn instance_to_package(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::InstanceToPackageArgs>(args);
+
+            assert_eq!(args.args.nargs, 1);
+            assert_eq!(args.args.nret, 1);
+
+            args.phandle = match args.ihandle as usize {
+                MMU_IHANDLE => MMU_PHANDLE,
+                MEMORY_IHANDLE => MEMORY_PHANDLE,
+                STDOUT_IHANDLE => VTY_PHANDLE,
+                ihandle if is_nic(ihandle as *const IHandle) => LAN_PHANDLE,
+                _ => usize::MAX,
+            } as *const PHandle;
+            0
+        }
+
         fn write(&self, args: *mut Args) -> usize {
             let args = cast_args::<services::WriteArgs>(args);
             let mock_ref = unsafe { &mut MOCK };
@@ -91,7 +617,17 @@ mod tests {
                     mock_ref.stdout.push(*i as char);
                     c += 1;
                 }
+                args.ret = c;
                 c
+            } else if is_nic(args.stdout) {
+                let frame = unsafe { std::slice::from_raw_parts(args.msg, args.len) };
+                let replies = NIC_SCRIPT.with(|script| match script.borrow_mut().as_mut() {
+                    Some(script) => script(frame),
+                    None => Vec::new(),
+                });
+                NIC_RX.with(|rx| rx.borrow_mut().extend(replies));
+                args.ret = args.len;
+                0
             } else {
                 usize::MAX
             }
@@ -110,6 +646,14 @@ mod tests {
                 return usize::MAX;
             }
 
+            // Fixed address claims can't be honoured, hand back a buffer the test can inspect
+            if args.align == 0 {
+                let mut array = vec![0xa5; args.size];
+                args.ret = array.as_mut_ptr();
+                CLAIMED.with(|claimed| claimed.borrow_mut().insert(args.ret as usize, array));
+                return 0;
+            }
+
             let heap = heap_ref.heap.as_mut().unwrap();
             let mut array = vec![0 as u8; args.size];
             args.ret = array.as_mut_ptr();
@@ -121,6 +665,11 @@ mod tests {
             let args = cast_args::<services::ReleaseArgs>(args);
             let heap_ref = unsafe { &mut HEAP };
 
+            if CLAIMED.with(|claimed| claimed.borrow_mut().remove(&(args.virt as usize)).is_some())
+            {
+                return 0;
+            }
+
             if heap_ref.heap.is_none() {
                 return 0;
             }
@@ -138,18 +687,84 @@ mod tests {
             if device.starts_with(b"disk\0") {
                 args.handle = DISK_IHANDLE as *const IHandle;
                 0
+            } else if cstr(args.dev).split(|c| *c == b':').next()
+                == Some(node_path(LAN_PHANDLE).as_bytes())
+            {
+                let ihandle = NEXT_IHANDLE.fetch_add(1, Ordering::Relaxed);
+                NIC_INSTANCES.with(|nics| nics.borrow_mut().insert(ihandle));
+                args.handle = ihandle as *const IHandle;
+                0
+            } else if let Some(data) =
+                FILES.with(|files| files.borrow().get(cstr(args.dev)).cloned())
+            {
+                let ihandle = NEXT_IHANDLE.fetch_add(1, Ordering::Relaxed);
+                INSTANCES.with(|instances| instances.borrow_mut().insert(ihandle, (data, 0)));
+                args.handle = ihandle as *const IHandle;
+                0
             } else {
                 usize::MAX
             }
         }
 
         fn read(&self, args: *mut Args) -> usize {
-            let _args = cast_args::<services::ReadArgs>(args);
+            let args = cast_args::<services::ReadArgs>(args);
+            if is_nic(args.handle) {
+                // Network reads return one frame, or -2 when none has arrived
+                args.actual_size = match NIC_RX.with(|rx| rx.borrow_mut().pop_front()) {
+                    Some(frame) => {
+                        let len = frame.len().min(args.size);
+                        unsafe {
+                            std::ptr::copy_nonoverlapping(
+                                frame.as_ptr(),
+                                args.buffer as *mut u8,
+                                len,
+                            )
+                        };
+                        len
+                    }
+                    None => -2isize as usize,
+                };
+                return 0;
+            }
+            INSTANCES.with(|instances| {
+                if let Some((data, pos)) = instances.borrow_mut().get_mut(&(args.handle as usize)) {
+                    let len = args.size.min(data.len().saturating_sub(*pos));
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            data[*pos..].as_ptr(),
+                            args.buffer as *mut u8,
+                            len,
+                        )
+                    };
+                    *pos += len;
+                    args.actual_size = len;
+                }
+            });
+            0
+        }
+
+        fn seek(&self, args: *mut Args) -> usize {
+            let args = cast_args::<services::SeekArgs>(args);
+
+            assert_eq!(args.args.nargs, 3);
+            assert_eq!(args.args.nret, 1);
+
+            args.status = INSTANCES.with(|instances| {
+                match instances.borrow_mut().get_mut(&(args.handle as usize)) {
+                    Some((data, pos)) if args.pos_low as usize <= data.len() => {
+                        *pos = args.pos_low as usize;
+                        0
+                    }
+                    _ => -1,
+                }
+            });
             0
         }
 
         fn close(&self, args: *mut Args) -> usize {
-            let _args = cast_args::<services::CloseArgs>(args);
+            let args = cast_args::<services::CloseArgs>(args);
+            INSTANCES.with(|instances| instances.borrow_mut().remove(&(args.handle as usize)));
+            NIC_INSTANCES.with(|nics| nics.borrow_mut().remove(&(args.handle as usize)));
             0
         }
 
@@ -164,10 +779,130 @@ mod tests {
                 bs_args.result = 0;
                 bs_args.block_size = 512;
                 0
+            } else if cm_args.handle == MMU_IHANDLE as *const IHandle {
+                self.mmu_method(cstr(cm_args.method), args)
+            } else if cm_args.handle == MEMORY_IHANDLE as *const IHandle
+                && cstr(cm_args.method) == b"claim"
+            {
+                // ( [phys.lo ... phys.hi] size align -- base.lo ... base.hi )
+                let (stack, rets) = method_cells(args);
+                rets[0] = 0;
+                let base = if stack[0] == 0 {
+                    phys_cells_from(&stack[2..])
+                } else {
+                    0x100_0000
+                };
+                phys_cells_into(&mut rets[1..], base);
+                0
+            } else if INSTANCES
+                .with(|instances| instances.borrow().contains_key(&(cm_args.handle as usize)))
+            {
+                self.disk_method(cm_args.handle as usize, cstr(cm_args.method), args)
             } else {
                 usize::MAX
             }
         }
+
+        // Open files double as disks with 512 byte blocks
+        fn disk_method(&self, ihandle: usize, method: &[u8], args: *mut Args) -> usize {
+            let (stack, rets) = method_cells(args);
+            rets[0] = 0;
+
+            INSTANCES.with(|instances| {
+                let instances = instances.borrow();
+                let (data, _) = &instances[&ihandle];
+                match method {
+                    b"block-size" => rets[1] = 512,
+                    b"#blocks" => rets[1] = data.len() / 512,
+                    // ( -- size.lo size.hi )
+                    b"size" if SIZE_METHOD.get() == b"size" => {
+                        rets[1] = 0;
+                        rets[2] = data.len();
+                    }
+                    b"#bytes" if SIZE_METHOD.get() == b"#bytes" => rets[1] = data.len(),
+                    // ( addr block# #blocks -- #read )
+                    b"read-blocks" if READ_BLOCKS.get() => {
+                        let (count, block, addr) = (stack[0], stack[1], stack[2]);
+                        let start = (block * 512).min(data.len());
+                        let len = (count * 512).min(data.len() - start);
+                        unsafe {
+                            std::ptr::copy_nonoverlapping(
+                                data[start..].as_ptr(),
+                                addr as *mut u8,
+                                len,
+                            )
+                        };
+                        rets[1] = len / 512;
+                    }
+                    _ => rets[0] = usize::MAX,
+                }
+            });
+            0
+        }
+
+        fn mmu_method(&self, method: &[u8], args: *mut Args) -> usize {
+            let mock_ref = unsafe { &mut MOCK };
+            let (stack, rets) = method_cells(args);
+            rets[0] = 0;
+
+            match method {
+                // ( virt -- false | phys mode true )
+                b"translate" => {
+                    match mock_ref
+                        .mappings
+                        .iter()
+                        .find(|(_, virt, size, _)| (*virt..virt + size).contains(&stack[0]))
+                    {
+                        Some((phys, virt, _, mode)) => {
+                            rets[1] = usize::MAX;
+                            rets[2] = *mode;
+                            phys_cells_into(&mut rets[3..], phys + (stack[0] - virt));
+                        }
+                        None => rets[1] = 0,
+                    }
+                }
+                // ( phys.lo ... phys.hi virt size mode -- )
+                b"map" => {
+                    let phys = phys_cells_from(&stack[3..]);
+                    mock_ref.mappings.push((phys, stack[2], stack[1], stack[0]))
+                }
+                // ( virt size -- )
+                b"unmap" => mock_ref
+                    .mappings
+                    .retain(|(_, virt, size, _)| (*virt, *size) != (stack[1], stack[0])),
+                // ( [virt] size align -- base )
+                b"claim" => rets[1] = if stack[0] == 0 { stack[2] } else { 0x8000_0000 },
+                _ => rets[0] = usize::MAX,
+            }
+            0
+        }
+    }
+
+    impl MockProm {
+        fn set_callback(&mut self, args: *mut Args) -> usize {
+            let args = cast_args::<services::SetCallbackArgs>(args);
+
+            assert_eq!(args.args.nargs, 1);
+            assert_eq!(args.args.nret, 1);
+
+            args.oldfunc = self.callback;
+            self.callback = args.newfunc;
+            0
+        }
+
+        fn set_symbol_lookup(&mut self, args: *mut Args) -> usize {
+            let args = cast_args::<services::SetSymbolLookupArgs>(args);
+
+            assert_eq!(args.args.nargs, 2);
+            assert_eq!(args.args.nret, 0);
+
+            let name = |ptr| unsafe { CStr::from_ptr(ptr as *const _) }.to_str().unwrap();
+            self.symbol_lookup = Some((
+                name(args.sym_to_value).to_string(),
+                name(args.value_to_sym).to_string(),
+            ));
+            0
+        }
     }
 
     extern "C" fn mock_entry(args: *mut Args) -> usize {
@@ -191,10 +926,37 @@ mod tests {
             mock_ref.open(args)
         } else if service.starts_with(b"read\0") {
             mock_ref.read(args)
+        } else if service.starts_with(b"seek\0") {
+            mock_ref.seek(args)
         } else if service.starts_with(b"close\0") {
             mock_ref.close(args)
         } else if service.starts_with(b"call-method\0") {
             mock_ref.call_method(args)
+        } else if service.starts_with(b"peer\0") {
+            mock_ref.relative(b"peer", args)
+        } else if service.starts_with(b"child\0") {
+            mock_ref.relative(b"child", args)
+        } else if service.starts_with(b"parent\0") {
+            mock_ref.relative(b"parent", args)
+        } else if service.starts_with(b"nextprop\0") {
+            mock_ref.nextprop(args)
+        } else if service.starts_with(b"package-to-path\0") {
+            mock_ref.package_to_path(args)
+        } else if service.starts_with(b"setprop\0") {
+            mock_ref.setprop(args)
+        } else if service.starts_with(b"test\0") {
+            mock_ref.test(args)
+        } else if service.starts_with(b"quiesce\0") {
+            QUIESCED.with(|quiesced| quiesced.set(true));
+            0
+        } else if service.starts_with(b"getproplen\0") {
+            mock_ref.getproplen(args)
+        } else if service.starts_with(b"instance-to-package\0") {
+            mock_ref.instance_to_package(args)
+        } else if service.starts_with(b"set-callback\0") {
+            mock_ref.set_callback(args)
+        } else if service.starts_with(b"set-symbol-lookup\0") {
+            mock_ref.set_symbol_lookup(args)
         } else {
             println!("Service not implemented in Mock PROM");
             usize::MAX
@@ -276,6 +1038,5846 @@ mod tests {
         assert_eq!(disk, DISK_IHANDLE as *const IHandle);
     }
 
+    struct TranslateCallbacks;
+
+    impl ClientCallbacks for TranslateCallbacks {
+        fn callback(
+            &self,
+            service: &str,
+            args: &[usize],
+            rets: &mut [usize],
+        ) -> Result<(), &'static str> {
+            match service {
+                "translate" => {
+                    rets[0] = args[0] + 0x1000;
+                    Ok(())
+                }
+                _ => Err("Unknown callback"),
+            }
+        }
+    }
+
+    static TRANSLATE_CALLBACKS: TranslateCallbacks = TranslateCallbacks;
+
+    #[repr(C)]
+    struct TranslateArgs {
+        args: Args,
+        virt: usize,
+        phys: usize,
+    }
+
+    #[test]
+    fn set_callback() {
+        let _lock = CALLBACK_LOCK.lock().unwrap();
+        let mock_ref = unsafe { &mut MOCK };
+        let prom = PROM::new(mock_entry).unwrap();
+
+        let previous = prom.set_callback(&TRANSLATE_CALLBACKS).unwrap();
+        assert!(previous.entry().is_none());
+        let callback = mock_ref.callback.expect("set-callback was not called");
+
+        let mut args = TranslateArgs {
+            args: Args {
+                service: b"translate\0".as_ptr(),
+                nargs: 1,
+                nret: 1,
+            },
+            virt: 0x2000,
+            phys: 0,
+        };
+        assert_eq!(callback(&mut args.args as *mut Args), 0);
+        assert_eq!(args.phys, 0x3000);
+
+        args.args.service = b"sync\0".as_ptr();
+        assert_eq!(callback(&mut args.args as *mut Args), usize::MAX);
+
+        prom.restore_callback(previous).unwrap();
+        assert!(mock_ref.callback.is_none());
+    }
+
+    #[repr(C)]
+    struct SymbolLookupArgs {
+        args: Args,
+        arg: usize,
+        rets: [usize; 2],
+    }
+
+    #[test]
+    fn symbol_table() {
+        let encoded = symbols::encode(&[
+            Symbol {
+                addr: 0x2000,
+                size: 0x100,
+                name: "app::main",
+            },
+            Symbol {
+                addr: 0x1000,
+                size: 0x80,
+                name: "_start",
+            },
+        ]);
+        let table = SymbolTable::new(&encoded).unwrap();
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(0).unwrap().name, "_start");
+        assert_eq!(table.lookup(0x2010).unwrap().0.name, "app::main");
+        assert_eq!(table.lookup(0x2010).unwrap().1, 0x10);
+        assert!(table.lookup(0x1080).is_none());
+        assert!(table.lookup(0x800).is_none());
+        assert_eq!(table.resolve("_start"), Some(0x1000));
+        assert!(table.resolve("missing").is_none());
+
+        assert!(SymbolTable::new(&encoded[..encoded.len() - 1]).is_err());
+        assert!(SymbolTable::new(&[0u8; 32]).is_err());
+
+        // Counts, name offsets and lengths near the top of the 32 bit range
+        for field in [8, 16 + 16, 16 + 20, 16 + 24 + 16, 16 + 24 + 20] {
+            let mut corrupted = encoded.clone();
+            corrupted[field..field + 4].copy_from_slice(&[0xff; 4]);
+            assert!(SymbolTable::new(&corrupted).is_err(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn symbol_table_from_nm() {
+        let encoded = symbols::encode_nm(
+            "00001000 T _start\n00002000 d DATA\n00001040 t core::panicking::panic\n",
+        );
+        let table = SymbolTable::new(&encoded).unwrap();
+
+        assert_eq!(table.len(), 2);
+        let (symbol, offset) = table.lookup(0x1010).unwrap();
+        assert_eq!((symbol.name, symbol.size, offset), ("_start", 0x40, 0x10));
+        assert_eq!(
+            table.lookup(0x1044).unwrap().0.name,
+            "core::panicking::panic"
+        );
+    }
+
+    #[test]
+    fn set_symbol_lookup() {
+        let _lock = CALLBACK_LOCK.lock().unwrap();
+        let mock_ref = unsafe { &mut MOCK };
+        let prom = PROM::new(mock_entry).unwrap();
+
+        let encoded = symbols::encode(&[Symbol {
+            addr: 0x4000,
+            size: 0x20,
+            name: "app::load_kernel",
+        }]);
+        let encoded: &'static [u8] = Box::leak(encoded.into_boxed_slice());
+        let table: &'static SymbolTable = Box::leak(Box::new(SymbolTable::new(encoded).unwrap()));
+
+        let translate = prom.set_callback(&TRANSLATE_CALLBACKS).unwrap();
+        let previous = prom.set_symbol_lookup(table).unwrap();
+        let (sym_to_value, value_to_sym) = mock_ref.symbol_lookup.clone().unwrap();
+        let callback = mock_ref.callback.unwrap();
+
+        let service = format!("{}\0", value_to_sym);
+        let mut args = SymbolLookupArgs {
+            args: Args {
+                service: service.as_ptr(),
+                nargs: 1,
+                nret: 2,
+            },
+            arg: 0x4008,
+            rets: [0, 0],
+        };
+        assert_eq!(callback(&mut args.args as *mut Args), 0);
+        assert_eq!(args.rets[0], 8);
+        let name = unsafe { CStr::from_ptr(args.rets[1] as *const _) };
+        assert_eq!(name.to_str().unwrap(), "app::load_kernel");
+
+        let service = format!("{}\0", sym_to_value);
+        args.args.service = service.as_ptr();
+        args.arg = b"app::load_kernel\0".as_ptr() as usize;
+        assert_eq!(callback(&mut args.args as *mut Args), 0);
+        assert_eq!(args.rets, [0, 0x4000]);
+
+        // The handler installed before still answers its own services
+        let mut args = TranslateArgs {
+            args: Args {
+                service: c"translate".as_ptr().cast(),
+                nargs: 1,
+                nret: 1,
+            },
+            virt: 0x2000,
+            phys: 0,
+        };
+        assert_eq!(callback(&mut args.args as *mut Args), 0);
+        assert_eq!(args.phys, 0x3000);
+        args.args.service = c"sync".as_ptr().cast();
+        assert_eq!(callback(&mut args.args as *mut Args), usize::MAX);
+
+        prom.restore_callback(previous).unwrap();
+        prom.restore_callback(translate).unwrap();
+        assert!(mock_ref.callback.is_none());
+    }
+
+    #[test]
+    fn mmu_map_translate() {
+        let prom = PROM::new(mock_entry).unwrap();
+        assert_eq!(prom.mmu, MMU_IHANDLE as *const IHandle);
+        assert_eq!(prom.memory, MEMORY_IHANDLE as *const IHandle);
+
+        let mmu = prom.mmu().unwrap();
+        let phys = mmu.claim_phys(0x200_0000, 0x10000, 0).unwrap();
+        assert_eq!(phys, 0x200_0000);
+        let virt = mmu.claim_virt(0, 0x10000, 0x1000).unwrap();
+        assert_eq!(virt, 0x8000_0000);
+
+        assert_eq!(mmu.translate(virt + 0x10).unwrap(), None);
+        mmu.map(phys, virt, 0x10000, MMU_MODE).unwrap();
+        assert_eq!(
+            mmu.translate(virt + 0x10).unwrap(),
+            Some(Mapping {
+                phys: phys + 0x10,
+                mode: MMU_MODE,
+            })
+        );
+
+        mmu.unmap(virt, 0x10000).unwrap();
+        assert_eq!(mmu.translate(virt + 0x10).unwrap(), None);
+
+        assert!(prom
+            .call_method(MMU_IHANDLE as *const IHandle, "bogus\0", &[], &mut [])
+            .is_err());
+
+        // Physical addresses in two cells, as with the SLOF root node
+        let root = ROOT_PHANDLE as *const PHandle;
+        prom.set_property(root, "#address-cells\0", &cells(&[2]))
+            .unwrap();
+        let mmu = prom.mmu().unwrap();
+        assert_eq!(mmu.phys_cells, 2);
+        let phys = mmu.claim_phys(0x2_0000_0000, 0x10000, 0).unwrap();
+        assert_eq!(phys, 0x2_0000_0000);
+        mmu.map(phys + 0x1000, virt, 0x10000, MMU_MODE).unwrap();
+        assert_eq!(
+            mmu.translate(virt + 0x10).unwrap(),
+            Some(Mapping {
+                phys: 0x2_0000_1010,
+                mode: MMU_MODE,
+            })
+        );
+        mmu.unmap(virt, 0x10000).unwrap();
+        assert_eq!(mmu.translate(virt).unwrap(), None);
+
+        // Virtual addresses take the same two cells of the root node
+        let translations = cells(&[0, 0xfff0_0000, 0x10_0000, 1, 0x0ff0_0000, 0x6a]);
+        prom.set_property(
+            MMU_PHANDLE as *const PHandle,
+            "translations\0",
+            &translations,
+        )
+        .unwrap();
+        assert_eq!(
+            mmu.translations().unwrap(),
+            vec![Translation {
+                virt: 0xfff0_0000,
+                size: 0x10_0000,
+                phys: 0x1_0ff0_0000,
+                mode: 0x6a,
+            }]
+        );
+
+        // The memory node is not the parent of physical addresses
+        prom.set_property(
+            MEMORY_PHANDLE as *const PHandle,
+            "#address-cells\0",
+            &cells(&[1]),
+        )
+        .unwrap();
+        assert_eq!(prom.mmu().unwrap().phys_cells, 2);
+
+        prom.set_property(root, "#address-cells\0", &cells(&[3]))
+            .unwrap();
+        assert!(prom.mmu().is_err());
+    }
+
+    #[test]
+    fn mmu_translations() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let translations = prom.mmu().unwrap().translations().unwrap();
+
+        assert_eq!(
+            translations,
+            vec![
+                Translation {
+                    virt: 0,
+                    size: 0x100_0000,
+                    phys: 0,
+                    mode: 0x10,
+                },
+                Translation {
+                    virt: 0xfff0_0000,
+                    size: 0x10_0000,
+                    phys: 0x0ff0_0000,
+                    mode: 0x6a,
+                },
+            ]
+        );
+
+        let wide = [
+            0u8, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 7,
+        ];
+        assert_eq!(
+            mmu::decode_translations(&wide, 2, 1, 2).unwrap(),
+            vec![Translation {
+                virt: 0x1_0000_0000,
+                size: 0x1000,
+                phys: 0x2_0000_0000,
+                mode: 7,
+            }]
+        );
+        assert!(mmu::decode_translations(&wide[..20], 2, 1, 2).is_err());
+    }
+
+    #[derive(Clone, Copy)]
+    struct ElfSegment<'a> {
+        paddr: u64,
+        vaddr: u64,
+        data: &'a [u8],
+        mem_size: u64,
+    }
+
+    // Builds an ELF image with a program header per segment followed by the segment contents
+    fn build_elf(
+        class: Class,
+        endian: Endian,
+        machine: u16,
+        entry: u64,
+        segments: &[ElfSegment],
+    ) -> Vec<u8> {
+        let (ehsize, phentsize) = match class {
+            Class::Elf32 => (52, 32),
+            Class::Elf64 => (64, 56),
+        };
+        let mut out = vec![0u8; ehsize];
+        let put = |out: &mut Vec<u8>, offset: usize, value: u64, size: usize| {
+            let bytes = match endian {
+                Endian::Little => value.to_le_bytes()[..size].to_vec(),
+                Endian::Big => value.to_be_bytes()[8 - size..].to_vec(),
+            };
+            if out.len() < offset + size {
+                out.resize(offset + size, 0);
+            }
+            out[offset..offset + size].copy_from_slice(&bytes);
+        };
+        let word = match class {
+            Class::Elf32 => 4,
+            Class::Elf64 => 8,
+        };
+
+        out[..4].copy_from_slice(b"\x7fELF");
+        out[4] = if class == Class::Elf32 { 1 } else { 2 };
+        out[5] = if endian == Endian::Little { 1 } else { 2 };
+        out[6] = 1;
+        put(&mut out, 16, 2, 2);
+        put(&mut out, 18, machine as u64, 2);
+        put(&mut out, 20, 1, 4);
+        put(&mut out, 24, entry, word);
+        put(&mut out, 24 + word, ehsize as u64, word);
+        put(&mut out, 42 + 3 * (word - 4), phentsize as u64, 2);
+        put(&mut out, 44 + 3 * (word - 4), segments.len() as u64, 2);
+
+        let mut offset = ehsize + phentsize * segments.len();
+        for (i, segment) in segments.iter().enumerate() {
+            let ph = ehsize + i * phentsize;
+            put(&mut out, ph, 1, 4);
+            let fields = [
+                offset as u64,
+                segment.vaddr,
+                segment.paddr,
+                segment.data.len() as u64,
+                segment.mem_size,
+            ];
+            for (j, value) in fields.iter().enumerate() {
+                match class {
+                    Class::Elf32 => put(&mut out, ph + 4 + j * 4, *value, 4),
+                    Class::Elf64 => put(&mut out, ph + 8 + j * 8, *value, 8),
+                }
+            }
+            offset += segment.data.len();
+        }
+        out.resize(ehsize + phentsize * segments.len(), 0);
+        for segment in segments {
+            out.extend_from_slice(segment.data);
+        }
+        out
+    }
+
+    #[test]
+    fn elf32_load_from_buffer() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let image = build_elf(
+            Class::Elf32,
+            Endian::Big,
+            elf::EM_PPC,
+            0x10_0040,
+            &[
+                ElfSegment {
+                    paddr: 0x10_0000,
+                    vaddr: 0xc010_0000,
+                    data: &[1, 2, 3, 4, 5, 6, 7, 8],
+                    mem_size: 8,
+                },
+                ElfSegment {
+                    paddr: 0x20_0000,
+                    vaddr: 0xc020_0000,
+                    data: &[9, 10],
+                    mem_size: 0x10,
+                },
+            ],
+        );
+
+        let loaded = elf::load(&prom, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(loaded.header.class, Class::Elf32);
+        assert_eq!(loaded.header.endian, Endian::Big);
+        assert_eq!(loaded.entry, 0x10_0040);
+        assert_eq!((loaded.low, loaded.high), (0x10_0000, 0x20_0010));
+        assert_eq!(loaded.segments.len(), 2);
+        assert_eq!(loaded.segments[1].vaddr, 0xc020_0000);
+
+        let text = unsafe { std::slice::from_raw_parts(loaded.segments[0].base, 8) };
+        assert_eq!(text, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let data = unsafe { std::slice::from_raw_parts(loaded.segments[1].base, 0x10) };
+        assert_eq!(&data[..2], &[9, 10]);
+        assert!(data[2..].iter().all(|b| *b == 0), "bss was not zero filled");
+
+        loaded.release(&prom);
+        assert!(CLAIMED.with(|claimed| claimed.borrow().is_empty()));
+    }
+
+    #[test]
+    fn elf64_load_from_instance() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let payload: Vec<u8> = (0..200u8).collect();
+        mock_file(
+            "disk:2,\\vmlinux",
+            build_elf(
+                Class::Elf64,
+                Endian::Little,
+                elf::EM_PPC64,
+                0x4000_0000_0000,
+                &[ElfSegment {
+                    paddr: 0,
+                    vaddr: 0xc000_0000_0000_0000,
+                    data: &payload,
+                    mem_size: 0x1000,
+                }],
+            ),
+        );
+
+        let mut instance = Instance::open(&prom, "disk:2,\\vmlinux\0").unwrap();
+        let loaded = elf::load(&prom, &mut instance).unwrap();
+        assert_eq!(loaded.header.class, Class::Elf64);
+        assert_eq!(loaded.header.machine, elf::EM_PPC64);
+        assert_eq!(loaded.entry, 0x4000_0000_0000);
+        assert_eq!((loaded.low, loaded.high), (0, 0x1000));
+
+        let memory = unsafe { std::slice::from_raw_parts(loaded.segments[0].base, 0x1000) };
+        assert_eq!(&memory[..200], &payload[..]);
+        assert!(memory[200..].iter().all(|b| *b == 0));
+
+        // The stream is still usable after loading
+        instance.seek(1).unwrap();
+        let mut magic = [0u8; 3];
+        instance.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, b"ELF");
+    }
+
+    #[test]
+    fn elf_load_errors() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let segment = ElfSegment {
+            paddr: 0x1000,
+            vaddr: 0x1000,
+            data: &[0; 16],
+            mem_size: 16,
+        };
+
+        let x86 = build_elf(Class::Elf64, Endian::Little, 62, 0, &[segment]);
+        assert!(elf::load(&prom, &mut Cursor::new(&x86)).is_err());
+        let ppc64 = build_elf(Class::Elf32, Endian::Big, elf::EM_PPC64, 0, &[segment]);
+        assert!(elf::load(&prom, &mut Cursor::new(&ppc64)).is_err());
+        let ppc = build_elf(Class::Elf64, Endian::Big, elf::EM_PPC, 0, &[segment]);
+        assert!(elf::load(&prom, &mut Cursor::new(&ppc)).is_err());
+
+        // Program header sizes and counts come from the file
+        let image = build_elf(Class::Elf64, Endian::Little, elf::EM_PPC64, 0, &[segment]);
+        let mut padded = image.clone();
+        padded[54] = 64;
+        assert!(elf::read_headers(&mut Cursor::new(&padded)).is_err());
+        let mut huge = image.clone();
+        huge[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(elf::read_headers(&mut Cursor::new(&huge)).is_err());
+        assert!(elf::read_headers(&mut Cursor::new(&image)).is_ok());
+        assert!(elf::load(&prom, &mut Cursor::new(b"#!/bin/sh\n")).is_err());
+
+        let segments = [
+            ElfSegment {
+                paddr: 0x1000,
+                vaddr: 0x1000,
+                data: &[0; 16],
+                mem_size: 16,
+            },
+            ElfSegment {
+                paddr: 0x2000,
+                vaddr: 0x2000,
+                data: &[0; 16],
+                mem_size: 16,
+            },
+        ];
+        let truncated = build_elf(Class::Elf32, Endian::Big, elf::EM_PPC, 0, &segments);
+        let truncated = &truncated[..truncated.len() - 4];
+        assert!(elf::load(&prom, &mut Cursor::new(truncated)).is_err());
+        assert!(
+            CLAIMED.with(|claimed| claimed.borrow().is_empty()),
+            "Segments were not released after a failed load"
+        );
+    }
+
+    fn chosen_prop(name: &str) -> Option<Vec<u8>> {
+        PROPS.with(|props| {
+            props
+                .borrow()
+                .get(&(CHOSEN_PHANDLE, name.as_bytes().to_vec()))
+                .cloned()
+        })
+    }
+
+    #[test]
+    fn linux_boot_setup() {
+        let prom = PROM::new(mock_entry).unwrap();
+
+        let boot = linux::prepare(
+            &prom,
+            KernelEntry::Code(0x1_0000),
+            Some((0x200_0000, 0x8000)),
+            "root=/dev/sda2 quiet",
+        )
+        .unwrap();
+
+        assert_eq!(boot.entry, KernelEntry::Code(0x1_0000));
+        assert_eq!((boot.r3, boot.r4), (0x200_0000, 0x8000));
+        assert_eq!(
+            boot.r5,
+            mock_entry as extern "C" fn(*mut Args) -> usize as usize
+        );
+        assert!(QUIESCED.with(|quiesced| quiesced.get()));
+
+        assert_eq!(chosen_prop("bootargs").unwrap(), b"root=/dev/sda2 quiet\0");
+        assert_eq!(
+            chosen_prop("linux,initrd-start").unwrap(),
+            0x200_0000usize.to_be_bytes()
+        );
+        assert_eq!(
+            chosen_prop("linux,initrd-end").unwrap(),
+            0x200_8000usize.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn linux_boot_without_initrd() {
+        let prom = PROM::new(mock_entry).unwrap();
+
+        let boot = linux::prepare(&prom, KernelEntry::Descriptor(0x4000), None, "").unwrap();
+        assert_eq!((boot.r3, boot.r4), (0, 0));
+        assert_eq!(chosen_prop("bootargs").unwrap(), b"\0");
+        assert!(chosen_prop("linux,initrd-start").is_none());
+    }
+
+    #[test]
+    fn linux_entry_from_elf() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let image = build_elf(
+            Class::Elf64,
+            Endian::Big,
+            elf::EM_PPC64,
+            0xc000_0000_0000_0100,
+            &[ElfSegment {
+                paddr: 0,
+                vaddr: 0xc000_0000_0000_0000,
+                data: &[0; 0x200],
+                mem_size: 0x200,
+            }],
+        );
+
+        let loaded = elf::load(&prom, &mut Cursor::new(&image)).unwrap();
+        let base = loaded.segments[0].base as usize;
+        assert_eq!(
+            KernelEntry::from_elf(&loaded).unwrap(),
+            KernelEntry::Code(base + 0x100)
+        );
+        loaded.release(&prom);
+
+        // Segments wrapping around the address space
+        let mut crafted = loaded.clone();
+        crafted.segments[0].vaddr = u64::MAX - 0x100;
+        crafted.entry = 0x80;
+        assert!(KernelEntry::from_elf(&crafted).is_err());
+    }
+
+    #[test]
+    fn linux_branch_targets() {
+        let boot = |entry| LinuxBoot {
+            entry,
+            r3: 0,
+            r4: 0,
+            r5: 0,
+        };
+
+        let branch = unsafe { boot(KernelEntry::Code(0x1_0000)).branch() };
+        assert_eq!(
+            branch,
+            Ok(Branch::Code {
+                code: 0x1_0000,
+                toc: 0
+            })
+        );
+
+        // Clients other than ELFv1 ones read the code address and TOC from the descriptor
+        let descriptor: [u64; 3] = [0x20_0100, 0x1_4000, 0];
+        let branch =
+            unsafe { boot(KernelEntry::Descriptor(descriptor.as_ptr() as usize)).branch() };
+        assert_eq!(
+            branch,
+            Ok(Branch::Code {
+                code: 0x20_0100,
+                toc: 0x1_4000
+            })
+        );
+    }
+
+    #[test]
+    fn walk_device_tree() {
+        let prom = PROM::new(mock_entry).unwrap();
+
+        let root = prom.root().unwrap();
+        assert_eq!(root, ROOT_PHANDLE as *const PHandle);
+        let chosen = prom.child(root).unwrap().unwrap();
+        assert_eq!(chosen, prom.chosen);
+        let cpus = prom.peer(chosen).unwrap().unwrap();
+        assert_eq!(prom.parent(cpus).unwrap(), Some(root));
+        assert_eq!(prom.peer(root).unwrap(), None);
+
+        let mut path = [0u8; 64];
+        let cpu = prom.child(cpus).unwrap().unwrap();
+        let len = prom.package_to_path(cpu, &mut path).unwrap();
+        assert_eq!(&path[..len], b"/cpus/PowerPC,POWER9@0");
+
+        let mut name = [0u8; 32];
+        assert_eq!(prom.next_property(cpu, "\0", &mut name).unwrap(), Some(4));
+        assert_eq!(&name[..5], b"name\0");
+        assert_eq!(
+            prom.next_property(cpu, "clock-frequency\0", &mut name)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn fdt_export_round_trip() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let mut tree = DeviceTree::from_prom(&prom).unwrap();
+
+        assert_eq!(tree.root.name, "");
+        let cpu = tree.node("/cpus/PowerPC,POWER9@0").unwrap();
+        assert_eq!(cpu.property("reg").unwrap(), &[0, 0, 0, 0]);
+        assert!(
+            cpu.property("name").is_none(),
+            "name properties are implied by the node name"
+        );
+        assert_eq!(
+            cpu.property("phandle").unwrap(),
+            &(CPU0_PHANDLE as u32).to_be_bytes()
+        );
+        let vdevice = tree.node("/vdevice").unwrap().property("phandle").unwrap();
+        let vscsi = tree.node("/vdevice/v-scsi@2000").unwrap();
+        assert_eq!(vscsi.property("interrupt-parent").unwrap(), vdevice);
+
+        tree.node_mut("/chosen")
+            .unwrap()
+            .set_property_str("bootargs", "console=hvc0");
+        tree.node_mut("/memory@0")
+            .unwrap()
+            .set_property_cells("reg", &[0, 0x1000_0000]);
+        let mut reserved = Node::new("reserved-memory");
+        reserved.set_property("ranges", &[]);
+        tree.root.add_child(reserved);
+        tree.add_reservation(0x100_0000, 0x4000);
+        tree.boot_cpuid_phys = 0;
+
+        let dtb = tree.to_dtb();
+        assert_eq!(&dtb[..4], &fdt::FDT_MAGIC.to_be_bytes());
+        assert_eq!(&dtb[20..24], &17u32.to_be_bytes());
+        assert_eq!(&dtb[24..28], &16u32.to_be_bytes());
+        assert_eq!(
+            u32::from_be_bytes(dtb[4..8].try_into().unwrap()) as usize,
+            dtb.len()
+        );
+
+        let strings_offset = u32::from_be_bytes(dtb[12..16].try_into().unwrap()) as usize;
+        let strings = &dtb[strings_offset..];
+        assert_eq!(
+            strings
+                .split(|c| *c == 0)
+                .filter(|name| *name == b"reg")
+                .count(),
+            1,
+            "Property names were not deduplicated"
+        );
+
+        let parsed = DeviceTree::from_dtb(&dtb).unwrap();
+        assert_eq!(parsed, tree);
+        assert_eq!(
+            parsed
+                .node("/chosen")
+                .unwrap()
+                .property("bootargs")
+                .unwrap(),
+            b"console=hvc0\0"
+        );
+        assert_eq!(parsed.reservations[0].address, 0x100_0000);
+    }
+
+    #[test]
+    fn fdt_parse_errors() {
+        let mut tree = DeviceTree::default();
+        tree.root.set_property_cells("#address-cells", &[2]);
+        let dtb = tree.to_dtb();
+        assert_eq!(DeviceTree::from_dtb(&dtb).unwrap(), tree);
+
+        let mut bad_magic = dtb.clone();
+        bad_magic[0] = 0;
+        assert!(DeviceTree::from_dtb(&bad_magic).is_err());
+        assert!(DeviceTree::from_dtb(&dtb[..dtb.len() - 1]).is_err());
+
+        let mut bad_token = dtb.clone();
+        let struct_offset = u32::from_be_bytes(dtb[8..12].try_into().unwrap()) as usize;
+        bad_token[struct_offset + 3] = 7;
+        assert!(DeviceTree::from_dtb(&bad_token).is_err());
+
+        // Block offsets and sizes, and the property length, wrapping past the end
+        let property_len = struct_offset + 12;
+        for offset in [8, 12, 16, 32, 36, property_len] {
+            let mut corrupted = dtb.clone();
+            corrupted[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+            assert!(DeviceTree::from_dtb(&corrupted).is_err(), "{}", offset);
+        }
+
+        // Nesting is limited instead of exhausting the stack
+        let nested = |depth: usize| {
+            let mut node = Node::new("leaf");
+            for _ in 0..depth {
+                let mut parent = Node::new("node");
+                parent.add_child(node);
+                node = parent;
+            }
+            let mut tree = DeviceTree::default();
+            tree.root.add_child(node);
+            tree.to_dtb()
+        };
+        assert!(DeviceTree::from_dtb(&nested(62)).is_ok());
+        assert!(DeviceTree::from_dtb(&nested(63)).is_err());
+    }
+
+    #[test]
+    fn dump_dts() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let mut out = String::new();
+        DeviceTreeDump::new(&prom, Style::Dts)
+            .dump(&mut out)
+            .unwrap();
+
+        assert!(out.starts_with("/dts-v1/;\n\n/ {\n"));
+        assert!(out.contains("\tcompatible = \"qemu,pseries\";\n"));
+        assert!(out.contains("\t\tPowerPC,POWER9@0 {\n\t\t\tdevice_type = \"cpu\";\n"));
+        assert!(out.contains("\t\t\treg = <0x0>;\n"));
+        assert!(out.contains("\t\treg = <0x0 0x20000000>;\n"));
+        assert!(
+            !out.contains("name = "),
+            "name properties should be skipped"
+        );
+        assert!(out.ends_with("\t};\n};\n"));
+        assert_eq!(out.matches('{').count(), out.matches("};").count());
+    }
+
+    #[test]
+    fn dump_listing() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let mut out = String::new();
+        DeviceTreeDump::new(&prom, Style::Listing)
+            .dump_path("/cpus\0", &mut out)
+            .unwrap();
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "/cpus");
+        assert_eq!(lines[1], "  name                     \"cpus\"");
+        assert!(lines.contains(&"  #size-cells              00000000"));
+        assert!(lines.contains(&"  PowerPC,POWER9@0"));
+        assert!(lines.contains(&"    clock-frequency          3b9aca00"));
+    }
+
+    #[test]
+    fn dump_format_value() {
+        let format = |value: &[u8], style| {
+            let mut out = String::new();
+            dump::format_value(value, style, &mut out).unwrap();
+            out
+        };
+
+        assert_eq!(
+            format(b"ibm,vscsi\0spapr\0", Style::Dts),
+            "\"ibm,vscsi\", \"spapr\""
+        );
+        assert_eq!(format(b"a\"b\0", Style::Listing), "\"a\\\"b\"");
+        assert_eq!(
+            format(&[0, 0, 0, 1, 0, 0, 0x10, 0], Style::Dts),
+            "<0x1 0x1000>"
+        );
+        assert_eq!(format(&[0, 0, 0, 1], Style::Listing), "00000001");
+        assert_eq!(format(&[1, 2, 0xff], Style::Dts), "[01 02 ff]");
+        assert_eq!(format(&[0, 0xa], Style::Listing), "00 0a");
+        assert_eq!(format(b"\0\0\0\0", Style::Dts), "<0x0>");
+    }
+
+    #[test]
+    fn devspec_parse() {
+        let spec = DevSpec::parse("disk:2,\\boot\\vmlinux\0").unwrap();
+        assert_eq!(spec.alias.as_deref(), Some("disk"));
+        assert!(spec.components.is_empty());
+        assert_eq!(spec.partition(), Some("2"));
+        assert_eq!(spec.filename(), Some("\\boot\\vmlinux"));
+
+        let spec: DevSpec = "/vdevice/v-scsi@2000/disk@8100000000000000:3"
+            .parse()
+            .unwrap();
+        assert!(spec.is_absolute());
+        assert_eq!(
+            spec.components,
+            vec![
+                PathComponent::new("vdevice", None),
+                PathComponent::new("v-scsi", Some("2000")),
+                PathComponent::new("disk", Some("8100000000000000")),
+            ]
+        );
+        assert_eq!(spec.partition(), Some("3"));
+        assert_eq!(spec.filename(), None);
+
+        let spec = DevSpec::parse("/pci@800000020000000:probe/scsi@3:1,/etc/yaboot.conf").unwrap();
+        assert_eq!(spec.components[0].arguments.as_deref(), Some("probe"));
+        assert_eq!(spec.filename(), Some("/etc/yaboot.conf"));
+
+        let spec = DevSpec::parse("cdrom:\\ppc\\bootinfo.txt").unwrap();
+        assert_eq!(spec.partition(), None);
+        assert_eq!(spec.filename(), Some("\\ppc\\bootinfo.txt"));
+        let spec = DevSpec::parse("net:/vmlinux").unwrap();
+        assert_eq!(spec.filename(), Some("/vmlinux"));
+
+        assert_eq!(DevSpec::parse("/").unwrap(), DevSpec::default());
+        assert!(DevSpec::parse("").is_err());
+        assert!(DevSpec::parse("/vdevice//v-scsi").is_err());
+        assert!(DevSpec::parse("disk@1").is_err());
+        assert!(DevSpec::parse("disk:2/partition").is_err());
+        assert!(DevSpec::from_path("disk").is_err());
+    }
+
+    #[test]
+    fn devspec_format() {
+        for text in [
+            "/",
+            "disk",
+            "disk:2,\\boot\\vmlinux",
+            "net:,yaboot",
+            "/vdevice/v-scsi@2000/disk@8100000000000000:3",
+            "/pci@800000020000000:probe/scsi@3:1,/etc/yaboot.conf",
+        ] {
+            assert_eq!(DevSpec::parse(text).unwrap().to_string(), text);
+        }
+
+        let mut spec = DevSpec::parse("disk:3").unwrap();
+        spec.set_partition_file(None, Some("\\vmlinux"));
+        assert_eq!(spec.to_open_string(), "disk:,\\vmlinux\0");
+        spec.set_partition_file(Some("2"), Some("\\vmlinux"));
+        assert_eq!(spec.to_string(), "disk:2,\\vmlinux");
+        spec.set_partition_file(Some("2"), None);
+        assert_eq!(spec.to_string(), "disk:2");
+        assert_eq!(spec.device().to_string(), "disk");
+    }
+
+    #[test]
+    fn devspec_aliases() {
+        let prom = PROM::new(mock_entry).unwrap();
+
+        assert_eq!(
+            devspec::lookup_alias(&prom, "disk").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8100000000000000"
+        );
+        assert!(devspec::lookup_alias(&prom, "floppy").is_err());
+
+        let expand = |text: &str| {
+            DevSpec::parse(text)
+                .unwrap()
+                .expand_aliases(&prom)
+                .map(|spec| spec.to_string())
+        };
+        assert_eq!(
+            expand("disk:2,\\boot\\vmlinux").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8100000000000000:2,\\boot\\vmlinux"
+        );
+        assert_eq!(
+            expand("hd:1").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8100000000000000:1"
+        );
+        assert_eq!(
+            expand("cdrom").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8200000000000000:1"
+        );
+        assert_eq!(
+            expand("cdrom:,\\ppc\\bootinfo.txt").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8200000000000000:,\\ppc\\bootinfo.txt"
+        );
+        assert_eq!(
+            expand("cdrom/part@1:x").unwrap(),
+            "/vdevice/v-scsi@2000/disk@8200000000000000:1/part@1:x"
+        );
+        assert_eq!(expand("/vdevice").unwrap(), "/vdevice");
+        assert!(expand("floppy").is_err());
+        assert!(expand("loop").is_err());
+    }
+
+    #[test]
+    fn boot_context_slof() {
+        let prom = PROM::new(mock_entry).unwrap();
+        prom.set_property(prom.chosen, "bootpath\0", b"disk:2,\\boot\\vmlinux\0")
+            .unwrap();
+        prom.set_property(
+            prom.chosen,
+            "bootargs\0",
+            b"root=/dev/sda2 quiet console=\"hvc0 ttyS0\"\0",
+        )
+        .unwrap();
+        prom.set_property(
+            prom.chosen,
+            "linux,initrd-start\0",
+            &0x200_0000u64.to_be_bytes(),
+        )
+        .unwrap();
+        prom.set_property(
+            prom.chosen,
+            "linux,initrd-end\0",
+            &0x280_0000u64.to_be_bytes(),
+        )
+        .unwrap();
+
+        let context = BootContext::from_chosen(&prom).unwrap();
+        let bootpath = context.bootpath.unwrap();
+        assert_eq!(bootpath.alias.as_deref(), Some("disk"));
+        assert_eq!(bootpath.filename(), Some("\\boot\\vmlinux"));
+        assert_eq!(
+            context.bootargs.iter().collect::<Vec<_>>(),
+            vec!["root=/dev/sda2", "quiet", "console=\"hvc0 ttyS0\""]
+        );
+        assert_eq!(context.bootargs.get("root"), Some("/dev/sda2"));
+        assert_eq!(context.bootargs.get("console"), Some("hvc0 ttyS0"));
+        assert!(context.bootargs.contains("quiet"));
+        assert!(!context.bootargs.contains("ro"));
+        let initrd = context.initrd.unwrap();
+        assert_eq!(initrd.start, 0x200_0000);
+        assert_eq!(initrd.size(), 0x80_0000);
+        assert_eq!(
+            context.stdout_path.unwrap().to_string(),
+            "/vdevice/vty@30000000"
+        );
+    }
+
+    #[test]
+    fn boot_context_apple() {
+        let prom = PROM::new(mock_entry).unwrap();
+        prom.set_property(
+            prom.chosen,
+            "bootpath\0",
+            b"/pci@f2000000/mac-io@17/ata-4@1f000/@0:9,\\\\:tbxi\0\0stale",
+        )
+        .unwrap();
+        prom.set_property(prom.chosen, "bootargs\0", b"\0\0\0\0")
+            .unwrap();
+        prom.set_property(prom.chosen, "boot-args\0", b" -v \0")
+            .unwrap();
+        prom.set_property(
+            prom.chosen,
+            "linux,initrd-start\0",
+            &0x100_0000u32.to_be_bytes(),
+        )
+        .unwrap();
+        prom.set_property(
+            prom.chosen,
+            "linux,initrd-end\0",
+            &0x100_1000u32.to_be_bytes(),
+        )
+        .unwrap();
+        prom.set_property(prom.chosen, "stdout-path\0", b"screen\0")
+            .unwrap();
+
+        let context = BootContext::from_chosen(&prom).unwrap();
+        let bootpath = context.bootpath.unwrap();
+        assert_eq!(bootpath.components.len(), 4);
+        assert_eq!(bootpath.components[3].unit_address.as_deref(), Some("0"));
+        assert_eq!(bootpath.partition(), Some("9"));
+        assert_eq!(bootpath.filename(), Some("\\\\:tbxi"));
+        assert_eq!(context.bootargs, BootArgs::new("-v"));
+        assert_eq!(
+            context.initrd,
+            Some(Initrd {
+                start: 0x100_0000,
+                end: 0x100_1000
+            })
+        );
+        assert_eq!(
+            context.stdout_path.unwrap().alias.as_deref(),
+            Some("screen")
+        );
+    }
+
+    #[test]
+    fn boot_context_empty() {
+        let prom = PROM::new(mock_entry).unwrap();
+        prom.set_property(prom.chosen, "linux,initrd-start\0", &[0, 1, 2])
+            .unwrap();
+
+        let context = BootContext::from_chosen(&prom).unwrap();
+        assert_eq!(context.bootpath, None);
+        assert!(context.bootargs.is_empty());
+        assert_eq!(context.bootargs.iter().next(), None);
+        assert_eq!(context.initrd, None);
+    }
+
+    // Disk image where the first 8 bytes of each block hold its index
+    fn tagged_disk(blocks: usize) -> Vec<u8> {
+        let mut image = vec![0u8; blocks * 512];
+        for (lba, block) in image.chunks_mut(512).enumerate() {
+            block[..8].copy_from_slice(&(lba as u64).to_le_bytes());
+        }
+        image
+    }
+
+    fn mbr_entry(sector: &mut [u8], slot: usize, bootable: bool, kind: u8, start: u32, count: u32) {
+        let entry = &mut sector[446 + slot * 16..][..16];
+        entry[0] = if bootable { 0x80 } else { 0 };
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&count.to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+    }
+
+    // PReP, Linux, an extended partition with two logical partitions and one past the end
+    fn build_mbr_disk() -> Vec<u8> {
+        let mut image = tagged_disk(8192);
+        image[440..444].copy_from_slice(&0xc0ffee00u32.to_le_bytes());
+        mbr_entry(&mut image, 0, true, mbr::TYPE_PREP, 64, 64);
+        mbr_entry(&mut image, 1, false, 0x83, 2048, 2048);
+        mbr_entry(&mut image, 2, false, mbr::TYPE_EXTENDED_LBA, 4096, 4096);
+        mbr_entry(&mut image, 3, false, 0x83, 8000, 1000);
+
+        let ebr = &mut image[4096 * 512..][..512];
+        mbr_entry(ebr, 0, false, 0x83, 64, 1000);
+        mbr_entry(ebr, 1, false, mbr::TYPE_EXTENDED, 2048, 2048);
+        let ebr = &mut image[6144 * 512..][..512];
+        mbr_entry(ebr, 0, false, 0x82, 64, 1000);
+        image
+    }
+
+    #[test]
+    fn mbr_partitions() {
+        let image = build_mbr_disk();
+        let mut disk = MemoryDisk::new(&image, 512);
+        let table = Mbr::read(&mut disk).unwrap();
+
+        assert_eq!(table.disk_signature, 0xc0ffee00);
+        assert!(!table.is_protective());
+        assert_eq!(
+            table
+                .partitions
+                .iter()
+                .map(|p| (p.number, p.kind, p.start))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, 0x41, 64),
+                (2, 0x83, 2048),
+                (3, 0x0f, 4096),
+                (4, 0x83, 8000),
+                (5, 0x83, 4160),
+                (6, 0x82, 6208),
+            ]
+        );
+        assert!(table.get(1).unwrap().bootable);
+        assert!(table.get(3).unwrap().is_extended());
+
+        let mut root = table.get(2).unwrap().open(&mut disk).unwrap();
+        assert_eq!(root.block_count(), 2048);
+        let mut block = [0u8; 1024];
+        root.read_blocks(2046, &mut block).unwrap();
+        assert_eq!(&block[..8], &4094u64.to_le_bytes());
+        assert_eq!(&block[512..520], &4095u64.to_le_bytes());
+        assert!(root.read_blocks(2047, &mut block).is_err());
+        assert!(root.read_blocks(0, &mut block[..100]).is_err());
+
+        let mut bytes = [0u8; 8];
+        root.read_at(511 * 512 - 2, &mut bytes[..4]).unwrap();
+        assert_eq!(&bytes[..4], &[0, 0, 0xff, 0x09]);
+        assert!(root.read_at(2048 * 512 - 4, &mut bytes).is_err());
+
+        let mut swap = table.get(6).unwrap().open(&mut disk).unwrap();
+        swap.read_blocks(0, &mut block[..512]).unwrap();
+        assert_eq!(&block[..8], &6208u64.to_le_bytes());
+
+        assert!(table.get(4).unwrap().open(&mut disk).is_err());
+    }
+
+    #[test]
+    fn mbr_errors() {
+        let mut image = build_mbr_disk();
+        // Logical partition chain linking back to itself
+        mbr_entry(&mut image[6144 * 512..][..512], 1, false, 0x05, 2048, 2048);
+        assert!(Mbr::read(&mut MemoryDisk::new(&image, 512)).is_err());
+
+        image[510] = 0;
+        assert!(Mbr::read(&mut MemoryDisk::new(&image, 512)).is_err());
+        assert!(Mbr::parse(&image[..256]).is_err());
+    }
+
+    #[test]
+    fn disk_instance() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let path = "/vdevice/v-scsi@2000/disk@8100000000000000\0";
+        mock_file(path.trim_end_matches('\0'), build_mbr_disk());
+
+        let mut disk = Disk::open(&prom, path).unwrap();
+        assert_eq!(disk.block_size(), 512);
+        assert_eq!(disk.block_count(), 8192);
+
+        let table = Mbr::read(&mut disk).unwrap();
+        assert_eq!(table.partitions.len(), 6);
+        let mut block = [0u8; 512];
+        let mut logical = table.get(5).unwrap().open(&mut disk).unwrap();
+        logical.read_blocks(10, &mut block).unwrap();
+        assert_eq!(&block[..8], &4170u64.to_le_bytes());
+
+        // Disk packages without read-blocks are read through seek and read
+        READ_BLOCKS.set(false);
+        logical.read_blocks(11, &mut block).unwrap();
+        READ_BLOCKS.set(true);
+        assert_eq!(&block[..8], &4171u64.to_le_bytes());
+        assert!(disk.read_blocks(8192, &mut block).is_err());
+    }
+
+    #[test]
+    fn load_files() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let spec = "/vdevice/v-scsi@2000/disk@8100000000000000:2,\\vmlinux";
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+        mock_file(spec, data.clone());
+
+        for method in [&b"size"[..], b"#bytes", b""] {
+            SIZE_METHOD.set(method);
+            let mut reports = Vec::new();
+            let loaded =
+                load::load_file(&prom, spec, |done, total| reports.push((done, total))).unwrap();
+            assert!(loaded == data);
+            let total = (!method.is_empty()).then_some(150_000);
+            if total.is_some() {
+                // The known size is allocated once, with room for the read finding the end
+                assert_eq!(loaded.capacity(), 150_000 + load::CHUNK_SIZE);
+            }
+            assert_eq!(
+                reports,
+                [(65536, total), (131072, total), (150_000, total)],
+                "{:?}",
+                method
+            );
+        }
+        SIZE_METHOD.set(b"size");
+
+        let mut region = ClaimedRegion::claim_at(&prom, 0x400_0000 as *mut u8, 200_000).unwrap();
+        let mut calls = 0;
+        let len = load::load_file_into(&prom, spec, &mut region, |_, _| calls += 1).unwrap();
+        assert_eq!((len, calls), (150_000, 3));
+        assert!(region.as_slice()[..len] == data[..]);
+        drop(region);
+        assert!(CLAIMED.with(|claimed| claimed.borrow().is_empty()));
+
+        // Without a size to check first, the file is found not to fit while reading
+        let mut small = ClaimedRegion::claim_at(&prom, 0x400_0000 as *mut u8, 100_000).unwrap();
+        assert!(load::load_file_into(&prom, spec, &mut small, |_, _| ()).is_err());
+        SIZE_METHOD.set(b"");
+        assert!(load::load_file_into(&prom, spec, &mut small, |_, _| ()).is_err());
+        let mut exact = ClaimedRegion::claim_at(&prom, 0x500_0000 as *mut u8, 150_000).unwrap();
+        assert_eq!(
+            load::load_file_into(&prom, spec, &mut exact, |_, _| ()),
+            Ok(150_000)
+        );
+        SIZE_METHOD.set(b"size");
+        let (base, size) = exact.leak();
+        assert_eq!(size, 150_000);
+        prom.release(base, size);
+
+        // Compressed files are expanded, progress goes by the bytes read from the file
+        let gzip = "/vdevice/v-scsi@2000/disk@8100000000000000:2,\\initrd.gz";
+        let data = compressible(400_000, 7);
+        let stream = host_gzip(&data, &["-n"]);
+        mock_file(gzip, stream.clone());
+        let mut reports = Vec::new();
+        let loaded =
+            load::load_file(&prom, gzip, |done, total| reports.push((done, total))).unwrap();
+        assert!(loaded == data);
+        assert_eq!(reports.len(), 7);
+        assert_eq!(
+            reports.last(),
+            Some(&(stream.len() as u64, Some(stream.len() as u64)))
+        );
+        assert!(reports.windows(2).all(|w| w[0].0 <= w[1].0));
+        let mut region = ClaimedRegion::claim_at(&prom, 0x600_0000 as *mut u8, 400_000).unwrap();
+        assert_eq!(
+            load::load_file_into(&prom, gzip, &mut region, |_, _| ()),
+            Ok(400_000)
+        );
+        assert!(region.as_slice() == &data[..]);
+        let mut small = ClaimedRegion::claim_at(&prom, 0x600_0000 as *mut u8, 399_999).unwrap();
+        assert!(load::load_file_into(&prom, gzip, &mut small, |_, _| ()).is_err());
+
+        assert!(load::load_file(
+            &prom,
+            "/vdevice/v-scsi@2000/disk@8100000000000000:2,\\missing",
+            |_, _| ()
+        )
+        .is_err());
+        assert!(load::load_file(
+            &prom,
+            "/vdevice/v-scsi@2000/disk@8100000000000000:2",
+            |_, _| ()
+        )
+        .is_err());
+    }
+
+    // Block device holding only the blocks that were written, for full size disk layouts
+    struct SparseDisk {
+        blocks: HashMap<u64, Vec<u8>>,
+        count: u64,
+    }
+
+    impl SparseDisk {
+        fn new(count: u64) -> Self {
+            SparseDisk {
+                blocks: HashMap::new(),
+                count,
+            }
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            for (i, byte) in data.iter().enumerate() {
+                let pos = offset + i as u64;
+                let block = self.blocks.entry(pos / 512).or_insert_with(|| vec![0; 512]);
+                block[(pos % 512) as usize] = *byte;
+            }
+        }
+    }
+
+    impl BlockDevice for SparseDisk {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            self.count
+        }
+
+        fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+            if lba + (buf.len() / 512) as u64 > self.count {
+                return Err("Read beyond the end of the device");
+            }
+            for (i, block) in buf.chunks_mut(512).enumerate() {
+                match self.blocks.get(&(lba + i as u64)) {
+                    Some(data) => block.copy_from_slice(data),
+                    None => block.fill(0),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn gpt_header(my_lba: u64, alternate_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; 92];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&4194270u64.to_le_bytes());
+        let disk = Guid::parse("B0FB3AB0-6C25-8D49-97F1-92E9AD879852").unwrap();
+        header[56..72].copy_from_slice(disk.as_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    // The 2GiB disk from the README: a 4MiB PReP partition followed by a Linux filesystem
+    fn build_readme_disk() -> SparseDisk {
+        let mut disk = SparseDisk::new(4194304);
+
+        let mut mbr = vec![0u8; 512];
+        mbr_entry(&mut mbr, 0, false, mbr::TYPE_GPT_PROTECTIVE, 1, 4194303);
+        disk.write(0, &mbr);
+
+        let mut entries = vec![0u8; 128 * 128];
+        let partitions = [
+            (
+                gpt::TYPE_PREP_BOOT,
+                "91A7B5D3-6237-834E-A3F2-6D7D87CB3A57",
+                2048u64,
+                10239u64,
+                "",
+            ),
+            (
+                gpt::TYPE_LINUX_FILESYSTEM,
+                "5C0E2B6A-1D84-4C2B-9E0F-3A7D1B2C4E5F",
+                10240,
+                4194270,
+                "root",
+            ),
+        ];
+        for (entry, (kind, unique, first, last, name)) in entries.chunks_mut(128).zip(partitions) {
+            entry[..16].copy_from_slice(kind.as_bytes());
+            entry[16..32].copy_from_slice(Guid::parse(unique).unwrap().as_bytes());
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (unit, c) in entry[56..].chunks_mut(2).zip(name.encode_utf16()) {
+                unit.copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        disk.write(512, &gpt_header(1, 4194303, 2, &entries));
+        disk.write(2 * 512, &entries);
+        disk.write(4194271 * 512, &entries);
+        disk.write(4194303 * 512, &gpt_header(4194303, 1, 4194271, &entries));
+        disk.write(2048 * 512, b"\x7fELF PReP payload");
+        disk
+    }
+
+    #[test]
+    fn crc32_and_guid() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+
+        let guid = Guid::parse("9e1a2d38-c612-4316-aa26-8b49521e5a8b").unwrap();
+        assert_eq!(guid, gpt::TYPE_PREP_BOOT);
+        assert_eq!(&guid.as_bytes()[..4], &[0x38, 0x2d, 0x1a, 0x9e]);
+        assert_eq!(guid.to_string(), "9E1A2D38-C612-4316-AA26-8B49521E5A8B");
+        assert!(Guid::parse("9E1A2D38-C612-4316-AA26-8B49521E5A8").is_err());
+        assert!(Guid::parse("9E1A2D38+C612-4316-AA26-8B49521E5A8B").is_err());
+        assert!(Guid::parse("9E1A2D38-C612-4316-AA26-8B49521E5A8G").is_err());
+    }
+
+    #[test]
+    fn gpt_readme_layout() {
+        let mut disk = build_readme_disk();
+        assert!(Mbr::read(&mut disk).unwrap().is_protective());
+
+        let table = Gpt::read(&mut disk).unwrap();
+        assert!(table.primary_valid && table.backup_valid);
+        assert_eq!(
+            table.header.disk_guid.to_string(),
+            "B0FB3AB0-6C25-8D49-97F1-92E9AD879852"
+        );
+        assert_eq!(table.partitions.len(), 2);
+
+        let prep = table.prep().unwrap();
+        assert_eq!((prep.number, prep.start, prep.count), (1, 2048, 8192));
+        assert_eq!(
+            prep.unique_guid.to_string(),
+            "91A7B5D3-6237-834E-A3F2-6D7D87CB3A57"
+        );
+        assert_eq!(prep.name, "");
+
+        let root = table.find_by_label("root").unwrap();
+        assert_eq!(root.number, 2);
+        assert_eq!(root.count, 4184031);
+        assert_eq!(table.find_by_type(&gpt::TYPE_LINUX_FILESYSTEM), Some(root));
+        assert_eq!(table.find_by_type(&gpt::TYPE_EFI_SYSTEM), None);
+        assert_eq!(table.get(2), Some(root));
+
+        let mut payload = prep.open(&mut disk).unwrap();
+        let mut block = [0u8; 512];
+        payload.read_blocks(0, &mut block).unwrap();
+        assert_eq!(&block[..17], b"\x7fELF PReP payload");
+        assert!(payload.read_blocks(8192, &mut block).is_err());
+    }
+
+    #[test]
+    fn gpt_backup_fallback() {
+        let mut disk = build_readme_disk();
+        disk.write(512 + 40, &[0xff]);
+        let table = Gpt::read(&mut disk).unwrap();
+        assert!(!table.primary_valid && table.backup_valid);
+        assert_eq!(table.header.my_lba, 4194303);
+        assert_eq!(table.prep().unwrap().start, 2048);
+
+        let mut disk = build_readme_disk();
+        disk.write(2 * 512 + 60, b"x");
+        let table = Gpt::read(&mut disk).unwrap();
+        assert!(!table.primary_valid && table.backup_valid);
+        assert_eq!(table.find_by_label("root").unwrap().number, 2);
+
+        disk.write(4194303 * 512, b"NOT PART");
+        assert!(Gpt::read(&mut disk).is_err());
+        assert!(Gpt::read(&mut SparseDisk::new(64)).is_err());
+    }
+
+    // PowerMac disk with a bootstrap, an HFS volume, a Linux root and free space
+    fn build_apm_disk() -> Vec<u8> {
+        let mut image = tagged_disk(8192);
+
+        let ddr = &mut image[..512];
+        ddr.fill(0);
+        ddr[..2].copy_from_slice(b"ER");
+        ddr[2..4].copy_from_slice(&512u16.to_be_bytes());
+        ddr[4..8].copy_from_slice(&8192u32.to_be_bytes());
+        ddr[16..18].copy_from_slice(&1u16.to_be_bytes());
+        ddr[18..22].copy_from_slice(&64u32.to_be_bytes());
+        ddr[22..24].copy_from_slice(&20u16.to_be_bytes());
+        ddr[24..26].copy_from_slice(&0x701u16.to_be_bytes());
+
+        let entries = [
+            ("Apple", apm::TYPE_PARTITION_MAP, 1u32, 63u32),
+            ("bootstrap", apm::TYPE_BOOTSTRAP, 64, 1600),
+            ("untitled", apm::TYPE_HFS, 1664, 2048),
+            ("Linux root", apm::TYPE_UNIX_SVR2, 3712, 4096),
+            ("Extra", apm::TYPE_FREE, 7808, 384),
+        ];
+        for (i, (name, kind, start, count)) in entries.iter().enumerate() {
+            let entry = &mut image[(i + 1) * 512..][..512];
+            entry.fill(0);
+            entry[..2].copy_from_slice(b"PM");
+            entry[4..8].copy_from_slice(&(entries.len() as u32).to_be_bytes());
+            entry[8..12].copy_from_slice(&start.to_be_bytes());
+            entry[12..16].copy_from_slice(&count.to_be_bytes());
+            entry[16..16 + name.len()].copy_from_slice(name.as_bytes());
+            entry[48..48 + kind.len()].copy_from_slice(kind.as_bytes());
+            entry[84..88].copy_from_slice(&count.to_be_bytes());
+            entry[88..92].copy_from_slice(&0x37u32.to_be_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn apm_partitions() {
+        let image = build_apm_disk();
+        let mut disk = MemoryDisk::new(&image, 512);
+        let map = Apm::read(&mut disk).unwrap();
+
+        assert_eq!((map.block_size, map.block_count), (512, 8192));
+        assert_eq!(
+            map.drivers,
+            vec![apm::Driver {
+                block: 64,
+                size: 20,
+                kind: 0x701
+            }]
+        );
+        assert_eq!(map.partitions.len(), 5);
+        assert_eq!(map.get(1).unwrap().kind, apm::TYPE_PARTITION_MAP);
+
+        let bootstrap = map.find_by_type(apm::TYPE_BOOTSTRAP).unwrap();
+        assert_eq!((bootstrap.number, bootstrap.start), (2, 64));
+        let root = map.find_by_name("Linux root").unwrap();
+        assert_eq!(root.kind, apm::TYPE_UNIX_SVR2);
+        assert_eq!((root.number, root.count, root.data_count), (4, 4096, 4096));
+        assert_eq!(map.get(3).unwrap().kind, apm::TYPE_HFS);
+
+        let mut hfs = map.get(3).unwrap().open(&mut disk).unwrap();
+        let mut block = [0u8; 512];
+        hfs.read_blocks(2047, &mut block).unwrap();
+        assert_eq!(&block[..8], &3711u64.to_le_bytes());
+        assert!(hfs.read_blocks(2048, &mut block).is_err());
+    }
+
+    #[test]
+    fn apm_cdrom_blocks() {
+        // Map blocks stay at 512 bytes on a CD with 2048 byte sectors
+        let image = build_apm_disk();
+        let mut cdrom = MemoryDisk::new(&image, 2048);
+        let map = Apm::read(&mut cdrom).unwrap();
+        assert_eq!(map.partitions.len(), 5);
+
+        let mut bootstrap = map.get(2).unwrap().open(&mut cdrom).unwrap();
+        assert_eq!(bootstrap.block_count(), 400);
+        let mut block = [0u8; 2048];
+        bootstrap.read_blocks(1, &mut block).unwrap();
+        assert_eq!(&block[..8], &68u64.to_le_bytes());
+        assert!(map.get(1).unwrap().open(&mut cdrom).is_err());
+
+        let mut broken = image.clone();
+        broken[3 * 512] = b'X';
+        assert!(Apm::read(&mut MemoryDisk::new(&broken, 512)).is_err());
+        let mut no_map = image.clone();
+        no_map[512..1024].fill(0);
+        assert!(Apm::read(&mut MemoryDisk::new(&no_map, 512)).is_err());
+    }
+
+    // 16 cylinders of 16 heads and 32 sectors with root, swap, backup and a Linux slice
+    fn build_sun_disk() -> Vec<u8> {
+        let mut image = tagged_disk(8192);
+        let label = &mut image[..512];
+        label.fill(0);
+        label[..24].copy_from_slice(b"QEMU HARDDISK cyl 16 alt");
+
+        let vtoc = &mut label[128..];
+        vtoc[..4].copy_from_slice(&1u32.to_be_bytes());
+        vtoc[4..9].copy_from_slice(b"sparc");
+        vtoc[12..14].copy_from_slice(&8u16.to_be_bytes());
+        vtoc[60..64].copy_from_slice(&0x600ddeedu32.to_be_bytes());
+
+        let slices = [
+            (0, sun::TAG_ROOT, 0, 0u32, 2048u32),
+            (1, sun::TAG_SWAP, sun::FLAG_UNMOUNTABLE, 4, 1024),
+            (2, sun::TAG_BACKUP, sun::FLAG_UNMOUNTABLE, 0, 8192),
+            (7, sun::TAG_LINUX_NATIVE, 0, 6, 5120),
+        ];
+        for (number, tag, flags, cylinder, count) in slices {
+            label[128 + 14 + number * 4..][..2].copy_from_slice(&tag.to_be_bytes());
+            label[128 + 16 + number * 4..][..2].copy_from_slice(&flags.to_be_bytes());
+            label[444 + number * 8..][..4].copy_from_slice(&cylinder.to_be_bytes());
+            label[448 + number * 8..][..4].copy_from_slice(&count.to_be_bytes());
+        }
+
+        label[432..434].copy_from_slice(&16u16.to_be_bytes());
+        label[434..436].copy_from_slice(&2u16.to_be_bytes());
+        label[436..438].copy_from_slice(&16u16.to_be_bytes());
+        label[438..440].copy_from_slice(&32u16.to_be_bytes());
+        label[508..510].copy_from_slice(&0xdabeu16.to_be_bytes());
+        let sum = sun::checksum(label);
+        label[510..512].copy_from_slice(&sum.to_be_bytes());
+        image
+    }
+
+    #[test]
+    fn sun_label() {
+        let image = build_sun_disk();
+        let mut disk = MemoryDisk::new(&image, 512);
+        let label = SunLabel::read(&mut disk).unwrap();
+
+        assert!(label.info.starts_with("QEMU HARDDISK"));
+        assert_eq!(label.volume.as_deref(), Some("sparc"));
+        assert_eq!(
+            (label.cylinders, label.alternate_cylinders, label.heads),
+            (16, 2, 16)
+        );
+        assert_eq!(
+            label
+                .slices
+                .iter()
+                .map(|s| (s.letter(), s.tag, s.start, s.count))
+                .collect::<Vec<_>>(),
+            vec![
+                ('a', sun::TAG_ROOT, 0, 2048),
+                ('b', sun::TAG_SWAP, 2048, 1024),
+                ('c', sun::TAG_BACKUP, 0, 8192),
+                ('h', sun::TAG_LINUX_NATIVE, 3072, 5120),
+            ]
+        );
+        assert_eq!(label.get(1).unwrap().flags, sun::FLAG_UNMOUNTABLE);
+        assert_eq!(
+            label.find_by_tag(sun::TAG_LINUX_NATIVE),
+            label.get_by_letter('h')
+        );
+        assert_eq!(label.get(3), None);
+
+        let mut linux = label.get_by_letter('h').unwrap().open(&mut disk).unwrap();
+        let mut block = [0u8; 512];
+        linux.read_blocks(5119, &mut block).unwrap();
+        assert_eq!(&block[..8], &8191u64.to_le_bytes());
+        assert!(linux.read_blocks(5120, &mut block).is_err());
+    }
+
+    #[test]
+    fn sun_label_errors() {
+        let mut image = build_sun_disk();
+        image[100] ^= 1;
+        assert!(SunLabel::parse(&image[..512]).is_err());
+
+        // Labels without a VTOC still describe the slices
+        let mut image = build_sun_disk();
+        image[128 + 60..128 + 64].fill(0);
+        let sum = sun::checksum(&image[..512]);
+        image[510..512].copy_from_slice(&sum.to_be_bytes());
+        let label = SunLabel::parse(&image[..512]).unwrap();
+        assert_eq!(label.volume, None);
+        assert_eq!(label.slices.len(), 4);
+        assert!(label.slices.iter().all(|s| s.tag == 0));
+
+        // Slices past the end of the disk cannot be opened
+        image[448 + 7 * 8..][..4].copy_from_slice(&5121u32.to_be_bytes());
+        let sum = sun::checksum(&image[..512]);
+        image[510..512].copy_from_slice(&sum.to_be_bytes());
+        let label = SunLabel::parse(&image[..512]).unwrap();
+        assert!(label
+            .get(7)
+            .unwrap()
+            .open(MemoryDisk::new(&image, 512))
+            .is_err());
+        assert!(SunLabel::read(&mut MemoryDisk::new(&image[512..], 512)).is_err());
+    }
+
+    enum FatNode {
+        File(&'static str, Vec<u8>),
+        Dir(&'static str, Vec<FatNode>),
+    }
+
+    impl FatNode {
+        fn name(&self) -> &'static str {
+            match self {
+                FatNode::File(name, _) | FatNode::Dir(name, _) => name,
+            }
+        }
+    }
+
+    // 8.3 name of a file, its case flags and whether it needs long name entries
+    fn fat_short_name(name: &str, index: usize) -> ([u8; 11], u8, bool) {
+        let (base, ext) = match name.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (base, ext),
+            _ => (name, ""),
+        };
+        let valid = |part: &str, max: usize| {
+            part.len() <= max && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        let single_case = |part: &str| {
+            !part.chars().any(|c| c.is_ascii_lowercase())
+                || !part.chars().any(|c| c.is_ascii_uppercase())
+        };
+        let lower = |part: &str| part.chars().any(|c| c.is_ascii_lowercase());
+
+        let mut short = [b' '; 11];
+        let fits = !base.is_empty() && valid(base, 8) && valid(ext, 3);
+        let plain = fits && single_case(base) && single_case(ext);
+        let (base, case) = match plain {
+            true => {
+                let case = (lower(base) as u8) << 3 | (lower(ext) as u8) << 4;
+                (base.to_ascii_uppercase(), case)
+            }
+            false => {
+                let tail = format!("~{}", index + 1);
+                let stem: String = base
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric())
+                    .take(8 - tail.len())
+                    .collect();
+                (stem.to_ascii_uppercase() + &tail, 0)
+            }
+        };
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        let ext: String = ext.chars().take(3).collect::<String>().to_ascii_uppercase();
+        short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        (short, case, !plain)
+    }
+
+    fn fat_entry(short: &[u8; 11], attributes: u8, case: u8, cluster: u32, size: u32) -> Vec<u8> {
+        let mut entry = vec![0u8; 32];
+        entry[..11].copy_from_slice(short);
+        entry[11] = attributes;
+        entry[12] = case;
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    // Long name entries in the order they are stored, the last part first
+    fn fat_lfn_entries(name: &str, checksum: u8) -> Vec<u8> {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        if !units.len().is_multiple_of(13) {
+            units.push(0);
+        }
+        units.resize(units.len().div_ceil(13) * 13, 0xffff);
+
+        let count = units.len() / 13;
+        let mut entries = Vec::new();
+        for sequence in (1..=count).rev() {
+            let mut entry = vec![0u8; 32];
+            entry[0] = sequence as u8 | if sequence == count { 0x40 } else { 0 };
+            entry[11] = fat::ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (unit, offset) in units[(sequence - 1) * 13..sequence * 13]
+                .iter()
+                .zip(offsets)
+            {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entries.extend(entry);
+        }
+        entries
+    }
+
+    fn fat_slots(name: &str) -> usize {
+        match fat_short_name(name, 0).2 {
+            true => name.encode_utf16().count().div_ceil(13) + 1,
+            false => 1,
+        }
+    }
+
+    // FAT image with the boot sector fields mkfs.fat writes, clusters are handed
+    // out in order, skipping every third one when fragmenting
+    struct FatBuilder {
+        image: Vec<u8>,
+        cluster_size: usize,
+        data_offset: usize,
+        fat: Vec<u32>,
+        end: u32,
+        next: u32,
+        fragment: bool,
+    }
+
+    impl FatBuilder {
+        fn new(kind: FatType, sectors: usize, sectors_per_cluster: usize, fragment: bool) -> Self {
+            let (reserved, root_entries, bits, end) = match kind {
+                FatType::Fat12 => (1, 224, 12, 0xfff),
+                FatType::Fat16 => (4, 512, 16, 0xffff),
+                FatType::Fat32 => (32, 0, 32, 0x0fff_ffff),
+            };
+            let fat_size = ((sectors / sectors_per_cluster + 2) * bits).div_ceil(8 * 512);
+            let root_sectors = root_entries * 32 / 512;
+            let data_sector = reserved + 2 * fat_size + root_sectors;
+            let clusters = (sectors - data_sector) / sectors_per_cluster;
+
+            let mut image = vec![0u8; sectors * 512];
+            let boot = &mut image[..512];
+            boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+            boot[3..11].copy_from_slice(b"mkfs.fat");
+            boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+            boot[13] = sectors_per_cluster as u8;
+            boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+            boot[16] = 2;
+            boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+            match sectors < 65536 && kind != FatType::Fat32 {
+                true => boot[19..21].copy_from_slice(&(sectors as u16).to_le_bytes()),
+                false => boot[32..36].copy_from_slice(&(sectors as u32).to_le_bytes()),
+            }
+            boot[21] = 0xf8;
+            boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+            boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+            let extended = match kind {
+                FatType::Fat32 => {
+                    boot[36..40].copy_from_slice(&(fat_size as u32).to_le_bytes());
+                    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+                    boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+                    boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+                    64
+                }
+                _ => {
+                    boot[22..24].copy_from_slice(&(fat_size as u16).to_le_bytes());
+                    36
+                }
+            };
+            boot[extended] = 0x80;
+            boot[extended + 2] = 0x29;
+            boot[extended + 3..extended + 7].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+            boot[extended + 7..extended + 18].copy_from_slice(b"NO NAME    ");
+            let fs_type: &[u8; 8] = match kind {
+                FatType::Fat12 => b"FAT12   ",
+                FatType::Fat16 => b"FAT16   ",
+                FatType::Fat32 => b"FAT32   ",
+            };
+            boot[extended + 18..extended + 26].copy_from_slice(fs_type);
+            boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+            let mut fat = vec![0u32; clusters + 2];
+            fat[0] = 0x0fff_fff8 & end;
+            fat[1] = end;
+            FatBuilder {
+                image,
+                cluster_size: sectors_per_cluster * 512,
+                data_offset: data_sector * 512,
+                fat,
+                end,
+                next: 2,
+                fragment,
+            }
+        }
+
+        fn alloc(&mut self, count: usize) -> u32 {
+            let mut chain: Vec<u32> = Vec::new();
+            while chain.len() < count.max(1) {
+                if !(self.fragment && self.next.is_multiple_of(3)) {
+                    chain.push(self.next);
+                }
+                self.next += 1;
+            }
+            for pair in chain.windows(2) {
+                self.fat[pair[0] as usize] = pair[1];
+            }
+            self.fat[*chain.last().unwrap() as usize] = self.end;
+            chain[0]
+        }
+
+        fn write_chain(&mut self, mut cluster: u32, data: &[u8]) {
+            for chunk in data.chunks(self.cluster_size) {
+                let offset = self.data_offset + (cluster as usize - 2) * self.cluster_size;
+                self.image[offset..offset + chunk.len()].copy_from_slice(chunk);
+                cluster = self.fat[cluster as usize];
+            }
+        }
+
+        fn dir_clusters(&self, children: &[FatNode]) -> usize {
+            let slots: usize = children.iter().map(|c| fat_slots(c.name())).sum();
+            ((slots + 2) * 32).div_ceil(self.cluster_size)
+        }
+
+        // Contents of a directory, parent is None for the root directory
+        fn dir(&mut self, nodes: &[FatNode], own: u32, parent: Option<u32>) -> Vec<u8> {
+            let mut out = Vec::new();
+            if let Some(parent) = parent {
+                out.extend(fat_entry(b".          ", fat::ATTR_DIRECTORY, 0, own, 0));
+                out.extend(fat_entry(b"..         ", fat::ATTR_DIRECTORY, 0, parent, 0));
+            }
+            for (index, node) in nodes.iter().enumerate() {
+                let (attributes, cluster, size) = match node {
+                    FatNode::File(_, data) if data.is_empty() => (fat::ATTR_ARCHIVE, 0, 0),
+                    FatNode::File(_, data) => {
+                        let cluster = self.alloc(data.len().div_ceil(self.cluster_size));
+                        self.write_chain(cluster, data);
+                        (fat::ATTR_ARCHIVE, cluster, data.len())
+                    }
+                    FatNode::Dir(_, children) => {
+                        let cluster = self.alloc(self.dir_clusters(children));
+                        let data = self.dir(children, cluster, Some(parent.map_or(0, |_| own)));
+                        self.write_chain(cluster, &data);
+                        (fat::ATTR_DIRECTORY, cluster, 0)
+                    }
+                };
+                let (short, case, long) = fat_short_name(node.name(), index);
+                if long {
+                    out.extend(fat_lfn_entries(node.name(), fat::lfn_checksum(&short)));
+                }
+                out.extend(fat_entry(&short, attributes, case, cluster, size as u32));
+            }
+            out
+        }
+
+        fn build(mut self, label: Option<&str>, tree: &[FatNode]) -> Vec<u8> {
+            let boot = self.image[..512].to_vec();
+            let reserved = u16::from_le_bytes([boot[14], boot[15]]) as usize;
+            let fat32 = &boot[82..87] == b"FAT32";
+            let fat_size = match fat32 {
+                true => u32::from_le_bytes(boot[36..40].try_into().unwrap()) as usize,
+                false => u16::from_le_bytes([boot[22], boot[23]]) as usize,
+            };
+
+            let mut root = Vec::new();
+            if let Some(label) = label {
+                let mut name = [b' '; 11];
+                name[..label.len()].copy_from_slice(label.as_bytes());
+                root.extend(fat_entry(&name, fat::ATTR_VOLUME_ID, 0, 0, 0));
+                let offset = if fat32 { 71 } else { 43 };
+                self.image[offset..offset + 11].copy_from_slice(&name);
+            }
+            match fat32 {
+                true => {
+                    let cluster = self.alloc(self.dir_clusters(tree));
+                    root.extend(self.dir(tree, cluster, None));
+                    self.write_chain(cluster, &root);
+                }
+                false => {
+                    root.extend(self.dir(tree, 0, None));
+                    let offset = (reserved + 2 * fat_size) * 512;
+                    self.image[offset..offset + root.len()].copy_from_slice(&root);
+                }
+            }
+
+            for copy in 0..2 {
+                let base = (reserved + copy * fat_size) * 512;
+                for (cluster, value) in self.fat.iter().enumerate() {
+                    match self.end {
+                        0xfff => {
+                            let offset = base + cluster + cluster / 2;
+                            let pair =
+                                u16::from_le_bytes([self.image[offset], self.image[offset + 1]]);
+                            let pair = match cluster & 1 {
+                                0 => (pair & 0xf000) | *value as u16,
+                                _ => (pair & 0x000f) | (*value as u16) << 4,
+                            };
+                            self.image[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+                        }
+                        0xffff => {
+                            let offset = base + cluster * 2;
+                            self.image[offset..offset + 2]
+                                .copy_from_slice(&(*value as u16).to_le_bytes());
+                        }
+                        _ => {
+                            let offset = base + cluster * 4;
+                            self.image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            self.image
+        }
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    fn read_all<R: Read>(file: &mut R, chunk: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; chunk];
+        loop {
+            match file.read(&mut buf).unwrap() {
+                0 => return data,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn fat_boot_tree() -> Vec<FatNode> {
+        vec![
+            FatNode::File("README.TXT", b"FAT12 boot floppy\n".to_vec()),
+            FatNode::File("ofboot.b", b"<CHRP-BOOT>".to_vec()),
+            FatNode::File("yaboot.conf", pattern(700, 1)),
+            FatNode::File("A long file name.text", pattern(1500, 2)),
+            FatNode::File("empty", Vec::new()),
+            FatNode::Dir(
+                "boot",
+                vec![FatNode::Dir(
+                    "grub",
+                    vec![FatNode::File("grub.cfg", b"set timeout=5\n".to_vec())],
+                )],
+            ),
+        ]
+    }
+
+    #[test]
+    fn fat12_volume() {
+        let image =
+            FatBuilder::new(FatType::Fat12, 2880, 1, false).build(Some("OFBOOT"), &fat_boot_tree());
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat12);
+        assert_eq!(fs.label(), Some("OFBOOT"));
+        assert_eq!(fs.cluster_size(), 512);
+
+        let root = fs.root();
+        let entries = fs.read_dir(&root).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "README.TXT",
+                "ofboot.b",
+                "yaboot.conf",
+                "A long file name.text",
+                "empty",
+                "boot"
+            ]
+        );
+        // Lower case 8.3 names only set the case flags
+        assert_eq!(entries[1].short_name, "ofboot.b");
+        assert_eq!(entries[2].short_name, "YABOOT~3.CON");
+        assert_eq!(entries[3].short_name, "ALONGF~4.TEX");
+        assert_eq!(entries[3].size, 1500);
+        assert!(entries[5].is_dir());
+
+        let entry = fs.lookup("\\boot\\grub\\GRUB.CFG").unwrap();
+        assert_eq!(entry.name, "grub.cfg");
+        let mut file = fs.open_entry(&entry).unwrap();
+        assert_eq!(read_all(&mut file, 4), b"set timeout=5\n");
+
+        // Long and short names both match, ignoring case
+        let mut file = fs.open("/a long FILE name.text").unwrap();
+        assert_eq!(read_all(&mut file, 4096), pattern(1500, 2));
+        let file = fs.open("alongf~4.tex").unwrap();
+        assert_eq!(file.size(), 1500);
+        let mut file = fs.open("./yaboot.conf").unwrap();
+        assert_eq!(read_all(&mut file, 100), pattern(700, 1));
+
+        let mut file = fs.open("empty").unwrap();
+        assert_eq!(file.read(&mut [0u8; 16]).unwrap(), 0);
+        let boot = fs.lookup("boot").unwrap();
+        assert_eq!(fs.read_dir(&boot).unwrap().len(), 1);
+        assert_eq!(fs.lookup("").unwrap(), root);
+        assert!(fs.open("missing.txt").is_err());
+        assert!(fs.open("boot").is_err());
+        assert!(fs.lookup("README.TXT/file").is_err());
+    }
+
+    #[test]
+    fn fat16_fragmented_file() {
+        let kernel = pattern(50000, 3);
+        let tree = vec![FatNode::Dir(
+            "ppc",
+            vec![FatNode::File("vmlinux", kernel.clone())],
+        )];
+        let image = FatBuilder::new(FatType::Fat16, 32768, 4, true).build(None, &tree);
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat16);
+        assert_eq!(fs.label(), None);
+
+        let mut file = fs.open("ppc/vmlinux").unwrap();
+        assert_eq!(file.size(), 50000);
+        // Reads stop where the clusters stop being contiguous
+        let mut buf = vec![0u8; 50000];
+        let n = file.read(&mut buf).unwrap();
+        assert_eq!(n, 4096);
+        file.seek(0).unwrap();
+        assert_eq!(read_all(&mut file, 3000), kernel);
+        assert_eq!(read_all(&mut file, 77), b"");
+
+        file.seek(40000).unwrap();
+        let mut buf = [0u8; 100];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &kernel[40000..40100]);
+        file.seek(5).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &kernel[5..105]);
+        file.seek(50000).unwrap();
+        assert!(file.read_exact(&mut buf).is_err());
+        assert!(file.seek(50001).is_err());
+    }
+
+    #[test]
+    fn fat32_volume() {
+        let mut tree: Vec<FatNode> = (0..30)
+            .map(|i| {
+                FatNode::File(
+                    Box::leak(format!("Module number {}.ko", i).into_boxed_str()),
+                    pattern(600, i),
+                )
+            })
+            .collect();
+        tree.push(FatNode::Dir(
+            "EFI",
+            vec![FatNode::Dir(
+                "BOOT",
+                vec![FatNode::File("BOOTPPC.EFI", pattern(5000, 9))],
+            )],
+        ));
+        let image = FatBuilder::new(FatType::Fat32, 66600, 1, false).build(Some("ESP"), &tree);
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat32);
+        assert_eq!(fs.label(), Some("ESP"));
+
+        // The root directory spans several clusters
+        let root = fs.root();
+        assert_eq!(root.cluster, 2);
+        let entries = fs.read_dir(&root).unwrap();
+        assert_eq!(entries.len(), 31);
+        assert_eq!(entries[29].name, "Module number 29.ko");
+
+        let mut file = fs.open("module NUMBER 17.ko").unwrap();
+        assert_eq!(read_all(&mut file, 512), pattern(600, 17));
+        let mut file = fs.open("efi\\boot\\bootppc.efi").unwrap();
+        assert_eq!(read_all(&mut file, 8192), pattern(5000, 9));
+    }
+
+    #[test]
+    fn fat_errors() {
+        let mut dir = Vec::new();
+        // Long name with a checksum that does not match its short entry
+        dir.extend(fat_lfn_entries("Orphan name", 0x42));
+        dir.extend(fat_entry(b"ORPHAN     ", fat::ATTR_ARCHIVE, 0, 0, 0));
+        // Deleted entries and names starting with 0xE5
+        let mut deleted = fat_entry(b"GONE    TXT", fat::ATTR_ARCHIVE, 0, 0, 0);
+        deleted[0] = 0xe5;
+        dir.extend(deleted);
+        let mut kanji = fat_entry(b"XABC    TXT", fat::ATTR_ARCHIVE, 0x10, 0, 0);
+        kanji[0] = 0x05;
+        dir.extend(kanji);
+        dir.extend(fat_entry(b".          ", fat::ATTR_DIRECTORY, 0, 5, 0));
+        dir.extend(vec![0u8; 32]);
+        dir.extend(fat_entry(b"HIDDEN     ", fat::ATTR_ARCHIVE, 0, 0, 0));
+
+        let entries = fat::parse_dir(&dir);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["ORPHAN", "\u{e5}ABC.txt"]);
+
+        assert!(FatFs::new(MemoryDisk::new(&[0u8; 4096], 512)).is_err());
+        let image = FatBuilder::new(FatType::Fat12, 2880, 1, false).build(None, &fat_boot_tree());
+        assert!(FatFs::new(MemoryDisk::new(&image[..1024 * 512], 512)).is_err());
+
+        // Clusters marked bad end the read with an error
+        let mut image = image;
+        let cluster = {
+            let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+            fs.lookup("A long file name.text").unwrap().cluster as usize
+        };
+        let offset = 512 + cluster + cluster / 2;
+        let pair = u16::from_le_bytes([image[offset], image[offset + 1]]);
+        let pair = match cluster & 1 {
+            0 => (pair & 0xf000) | 0xff7,
+            _ => (pair & 0x000f) | 0xff70,
+        };
+        image[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        let mut file = fs.open("A long file name.text").unwrap();
+        let mut buf = [0u8; 1500];
+        assert!(file.read_exact(&mut buf).is_err());
+    }
+
+    // Filesystem image built by mkfs.fat and filled by mcopy from a directory tree
+    //
+    // The tests using it are ignored unless dosfstools and mtools are
+    // installed and they are run with ```cargo test -- --ignored```.
+    fn mkfs_fat_image(
+        name: &str,
+        options: &[&str],
+        size_kb: u32,
+        populate: impl Fn(&Path),
+    ) -> Vec<u8> {
+        let base = std::env::temp_dir().join(format!("ieee1275-{}-{}", name, std::process::id()));
+        let root = base.join("root");
+        let image = base.join("image");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&root).unwrap();
+        populate(&root);
+
+        // Top level entries are copied in name order, mcopy recurses into directories
+        let mut entries: Vec<_> = std::fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        let status = Command::new("mkfs.fat")
+            .args(["-C", "-i", "1234abcd"])
+            .args(options)
+            .arg(&image)
+            .arg(size_kb.to_string())
+            .stdout(Stdio::null())
+            .status()
+            .expect("mkfs.fat from dosfstools is needed to build the FAT fixtures");
+        assert!(status.success());
+        let status = Command::new("mcopy")
+            .env("MTOOLS_SKIP_CHECK", "1")
+            .arg("-i")
+            .arg(&image)
+            .args(["-s", "-Q"])
+            .args(&entries)
+            .arg("::/")
+            .status()
+            .expect("mcopy from mtools is needed to build the FAT fixtures");
+        assert!(status.success());
+
+        let data = std::fs::read(&image).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+        data
+    }
+
+    fn populate_fat(root: &Path) {
+        std::fs::write(root.join("README.TXT"), "FAT boot volume\n").unwrap();
+        std::fs::write(root.join("ofboot.b"), "<CHRP-BOOT>").unwrap();
+        std::fs::write(root.join("A long file name.text"), pattern(70_000, 2)).unwrap();
+        std::fs::write(root.join("empty"), "").unwrap();
+        std::fs::create_dir_all(root.join("boot/grub")).unwrap();
+        std::fs::write(root.join("boot/grub/grub.cfg"), "set timeout=5\n").unwrap();
+        std::fs::create_dir(root.join("modules")).unwrap();
+        for i in 0..40 {
+            let name = format!("modules/Module number {}.ko", i);
+            std::fs::write(root.join(name), pattern(600 + i, i as u8)).unwrap();
+        }
+    }
+
+    fn check_fat_tree<D: BlockDevice>(fs: &mut FatFs<D>) {
+        let root = fs.root();
+        let entries = fs.read_dir(&root).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "A long file name.text",
+                "README.TXT",
+                "boot",
+                "empty",
+                "modules",
+                "ofboot.b"
+            ]
+        );
+        assert_eq!(entries[0].size, 70_000);
+        assert!(entries[2].is_dir());
+        assert!(entries[4].is_dir());
+
+        // Long names span several slots, the directory several clusters
+        let modules = fs.lookup("modules").unwrap();
+        let mut modules: Vec<String> = fs
+            .read_dir(&modules)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        modules.sort();
+        let mut expected: Vec<String> =
+            (0..40).map(|i| format!("Module number {}.ko", i)).collect();
+        expected.sort();
+        assert_eq!(modules, expected);
+        let mut file = fs.open("MODULES/module NUMBER 17.ko").unwrap();
+        assert_eq!(read_all(&mut file, 512), pattern(617, 17));
+
+        // Long and short names both match, ignoring case
+        let entry = fs.lookup("\\boot\\grub\\GRUB.CFG").unwrap();
+        assert_eq!(entry.name, "grub.cfg");
+        let mut file = fs.open_entry(&entry).unwrap();
+        assert_eq!(read_all(&mut file, 4), b"set timeout=5\n");
+        let entry = fs.lookup("/a long FILE name.text").unwrap();
+        let short = entry.short_name.clone();
+        assert_ne!(short, entry.name);
+        assert_eq!(fs.lookup(&short).unwrap(), entry);
+        assert_eq!(
+            read_all(&mut fs.open("./ofboot.b").unwrap(), 100),
+            b"<CHRP-BOOT>"
+        );
+
+        // Streaming across cluster boundaries, and seeking back
+        let mut file = fs.open("a long file name.text").unwrap();
+        assert_eq!(file.size(), 70_000);
+        assert_eq!(read_all(&mut file, 3000), pattern(70_000, 2));
+        assert_eq!(read_all(&mut file, 77), b"");
+        file.seek(40_000).unwrap();
+        let mut buf = [0u8; 1000];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(70_000, 2)[40_000..41_000]);
+        file.seek(5).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(70_000, 2)[5..1005]);
+        assert!(file.seek(70_001).is_err());
+
+        let mut file = fs.open("empty").unwrap();
+        assert_eq!(file.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(fs.open("missing.txt").is_err());
+        assert!(fs.open("boot").is_err());
+        assert!(fs.lookup("README.TXT/file").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs mkfs.fat and mcopy, run with cargo test -- --ignored"]
+    fn fat12_mkfs_volume() {
+        let options = ["-F", "12", "-s", "1", "-n", "OFBOOT"];
+        let image = mkfs_fat_image("fat12", &options, 1440, populate_fat);
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat12);
+        assert_eq!(fs.label(), Some("OFBOOT"));
+        assert_eq!(fs.cluster_size(), 512);
+        check_fat_tree(&mut fs);
+    }
+
+    #[test]
+    #[ignore = "needs mkfs.fat and mcopy, run with cargo test -- --ignored"]
+    fn fat16_mkfs_volume() {
+        let options = ["-F", "16", "-s", "4"];
+        let image = mkfs_fat_image("fat16", &options, 32768, populate_fat);
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat16);
+        assert_eq!(fs.label(), None);
+        assert_eq!(fs.cluster_size(), 2048);
+        check_fat_tree(&mut fs);
+    }
+
+    #[test]
+    #[ignore = "needs mkfs.fat and mcopy, run with cargo test -- --ignored"]
+    fn fat32_mkfs_volume() {
+        let options = ["-F", "32", "-s", "1", "-n", "ESP"];
+        let image = mkfs_fat_image("fat32", &options, 65536, |root| {
+            populate_fat(root);
+            std::fs::create_dir_all(root.join("boot/EFI/BOOT")).unwrap();
+            std::fs::write(root.join("boot/EFI/BOOT/BOOTPPC.EFI"), pattern(5000, 9)).unwrap();
+        });
+        let mut fs = FatFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat32);
+        assert_eq!(fs.label(), Some("ESP"));
+        assert_eq!(fs.root().cluster, 2);
+
+        check_fat_tree(&mut fs);
+        let mut file = fs.open("boot\\efi\\boot\\bootppc.efi").unwrap();
+        assert_eq!(read_all(&mut file, 8192), pattern(5000, 9));
+    }
+
+    // Filesystem image built by mke2fs from a directory tree
+    fn mke2fs_image(name: &str, options: &[&str], size: &str, populate: impl Fn(&Path)) -> Vec<u8> {
+        let base = std::env::temp_dir().join(format!("ieee1275-{}-{}", name, std::process::id()));
+        let root = base.join("root");
+        let image = base.join("image");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&root).unwrap();
+        populate(&root);
+
+        let status = Command::new("mke2fs")
+            .args([
+                "-q",
+                "-F",
+                "-L",
+                name,
+                "-U",
+                "6a1e1e2a-0f6e-4c5b-9d0a-5e2a3b4c5d6e",
+            ])
+            .args(options)
+            .arg("-d")
+            .arg(&root)
+            .arg(&image)
+            .arg(size)
+            .stdout(Stdio::null())
+            .status()
+            .expect("mke2fs from e2fsprogs is needed to build the ext fixtures");
+        assert!(status.success());
+
+        let data = std::fs::read(&image).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+        data
+    }
+
+    fn populate_boot(root: &Path) {
+        std::fs::create_dir_all(root.join("boot/grub")).unwrap();
+        std::fs::write(root.join("boot/vmlinux"), pattern(300 * 1024, 5)).unwrap();
+        std::fs::write(root.join("boot/grub/grub.cfg"), "set timeout=5\n").unwrap();
+        symlink("boot/vmlinux", root.join("vmlinuz")).unwrap();
+        symlink("/boot/grub", root.join("boot/grub2")).unwrap();
+        symlink(
+            "../boot/./grub/grub.cfg",
+            root.join("boot/a-rather-long-link-name"),
+        )
+        .unwrap();
+        let long = format!("/boot/{}vmlinux", "./".repeat(35));
+        symlink(&long, root.join("long")).unwrap();
+        symlink("loop-b", root.join("loop-a")).unwrap();
+        symlink("loop-a", root.join("loop-b")).unwrap();
+
+        // Data chunks separated by holes, one extent each
+        let mut sparse = std::fs::File::create(root.join("sparse")).unwrap();
+        for i in 0..10u64 {
+            sparse.seek(SeekFrom::Start(i * 65536)).unwrap();
+            sparse.write_all(&pattern(4096, i as u8)).unwrap();
+        }
+        std::fs::create_dir(root.join("many")).unwrap();
+        for i in 0..200 {
+            std::fs::write(root.join(format!("many/file-{:03}", i)), format!("{}", i)).unwrap();
+        }
+    }
+
+    fn check_boot_tree<D: BlockDevice>(fs: &mut ExtFs<D>) {
+        let root = fs.read_inode(ext::ROOT_INODE).unwrap();
+        let mut names: Vec<(String, FileType)> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.kind))
+            .filter(|(name, _)| name != "lost+found")
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            names,
+            [
+                ("boot".to_string(), FileType::Directory),
+                ("long".to_string(), FileType::Symlink),
+                ("loop-a".to_string(), FileType::Symlink),
+                ("loop-b".to_string(), FileType::Symlink),
+                ("many".to_string(), FileType::Directory),
+                ("sparse".to_string(), FileType::Regular),
+                ("vmlinuz".to_string(), FileType::Symlink),
+            ]
+        );
+
+        let mut file = fs.open("/boot/vmlinux").unwrap();
+        assert_eq!(file.size(), 300 * 1024);
+        assert_eq!(read_all(&mut file, 5000), pattern(300 * 1024, 5));
+        file.seek(290 * 1024 + 3).unwrap();
+        let mut buf = [0u8; 100];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(300 * 1024, 5)[290 * 1024 + 3..][..100]);
+        assert!(file.seek(300 * 1024 + 1).is_err());
+
+        // Relative, absolute and slow symbolic links, also in the middle of paths
+        let kernel = fs.lookup("boot/vmlinux").unwrap();
+        assert_eq!(fs.lookup("vmlinuz").unwrap(), kernel);
+        assert_eq!(fs.lookup("long").unwrap(), kernel);
+        let link = fs.lookup_link("long").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(
+            fs.read_link(&link).unwrap(),
+            format!("/boot/{}vmlinux", "./".repeat(35))
+        );
+        assert_eq!(
+            read_all(&mut fs.open("boot/grub2/grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+        assert_eq!(
+            read_all(&mut fs.open("boot\\a-rather-long-link-name").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+        assert!(fs.lookup("loop-a").is_err());
+        assert!(fs.lookup_link("loop-a").unwrap().is_symlink());
+
+        let mut sparse = fs.open("sparse").unwrap();
+        let data = read_all(&mut sparse, 30000);
+        assert_eq!(data.len(), 9 * 65536 + 4096);
+        for i in 0..10 {
+            let chunk = &data[i * 65536..];
+            assert_eq!(&chunk[..4096], &pattern(4096, i as u8)[..]);
+            if i < 9 {
+                assert!(chunk[4096..65536].iter().all(|b| *b == 0));
+            }
+        }
+
+        let many = fs.lookup("many").unwrap();
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 200);
+        assert_eq!(read_all(&mut fs.open("many/file-137").unwrap(), 8), b"137");
+
+        assert!(fs.open("boot").is_err());
+        assert!(fs.open("boot/missing").is_err());
+        assert!(fs.lookup("boot/vmlinux/x").is_err());
+        assert!(fs.read_link(&kernel).is_err());
+    }
+
+    #[test]
+    fn ext4_volume() {
+        let image = mke2fs_image("ext4", &["-t", "ext4", "-b", "4096"], "16M", populate_boot);
+        let mut fs = ExtFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.label(), Some("ext4"));
+        assert_eq!(fs.block_size(), 4096);
+        assert!(fs.has_metadata_csum());
+        assert_eq!(fs.uuid()[..4], [0x6a, 0x1e, 0x1e, 0x2a]);
+        check_boot_tree(&mut fs);
+
+        let sparse = fs.lookup("sparse").unwrap();
+        assert_eq!(fs.map_block(&sparse, 17).unwrap(), (None, 15));
+    }
+
+    #[test]
+    fn ext2_volume() {
+        let image = mke2fs_image("ext2", &["-t", "ext2", "-b", "1024"], "8M", populate_boot);
+        let mut fs = ExtFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.block_size(), 1024);
+        assert!(!fs.has_metadata_csum());
+        check_boot_tree(&mut fs);
+    }
+
+    #[test]
+    fn ext4_checksum_errors() {
+        let options = ["-t", "ext4", "-b", "1024", "-O", "64bit"];
+        let image = mke2fs_image("csum", &options, "8M", |root| {
+            std::fs::write(root.join("file"), pattern(1000, 1)).unwrap();
+        });
+        let mut fs = ExtFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        let inode = fs.lookup("file").unwrap();
+        assert_eq!(
+            read_all(&mut fs.open("file").unwrap(), 4096),
+            pattern(1000, 1)
+        );
+
+        // Find the inode through its unique size and corrupt it
+        let offset = (0..image.len() - 256)
+            .step_by(256)
+            .find(|o| image[o + 4..o + 8] == 1000u32.to_le_bytes() && image[*o] == 0xa4)
+            .unwrap();
+        let mut corrupted = image.clone();
+        corrupted[offset + 0x1a] ^= 1;
+        let mut fs = ExtFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.read_inode(inode.number).is_err());
+        assert!(fs.open("file").is_err());
+
+        let mut corrupted = image.clone();
+        corrupted[1024 + 0x78] ^= 1;
+        assert!(ExtFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        // Incompatible features the driver does not know about
+        let mut corrupted = image.clone();
+        corrupted[1024 + 96 + 1] |= 0x80;
+        let sum = !crc32c(&corrupted[1024..1024 + 0x3fc]);
+        corrupted[1024 + 0x3fc..1024 + 0x400].copy_from_slice(&sum.to_le_bytes());
+        assert!(ExtFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        assert!(ExtFs::new(MemoryDisk::new(&image[..4 * 1024 * 1024], 512)).is_err());
+        assert!(ExtFs::new(MemoryDisk::new(&vec![0u8; 4096], 512)).is_err());
+    }
+
+    enum IsoNode {
+        File(&'static str, Vec<u8>),
+        Dir(&'static str, Vec<IsoNode>),
+        Link(&'static str, &'static str),
+    }
+
+    fn iso_record(id: &[u8], extent: (u32, u32), flags: u8, system_use: &[u8]) -> Vec<u8> {
+        let mut record = vec![0u8; 33 + id.len() + (1 - id.len() % 2)];
+        record.extend_from_slice(system_use);
+        if record.len() % 2 == 1 {
+            record.push(0);
+        }
+        record[0] = record.len() as u8;
+        record[2..6].copy_from_slice(&extent.0.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.0.to_be_bytes());
+        record[10..14].copy_from_slice(&extent.1.to_le_bytes());
+        record[14..18].copy_from_slice(&extent.1.to_be_bytes());
+        record[18..21].copy_from_slice(&[121, 1, 1]);
+        record[25] = flags;
+        record[28..32].copy_from_slice(&[1, 0, 0, 1]);
+        record[32] = id.len() as u8;
+        record[33..33 + id.len()].copy_from_slice(id);
+        record
+    }
+
+    fn rr_px(mode: u32) -> Vec<u8> {
+        let mut entry = vec![b'P', b'X', 36, 1];
+        for value in [mode, 1, 0, 0] {
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry.extend_from_slice(&value.to_be_bytes());
+        }
+        entry
+    }
+
+    fn rr_sl(target: &str) -> Vec<u8> {
+        let mut entry = vec![b'S', b'L', 0, 1, 0];
+        if target.starts_with('/') {
+            entry.extend_from_slice(&[0x08, 0]);
+        }
+        for component in target.split('/').filter(|c| !c.is_empty()) {
+            match component {
+                "." => entry.extend_from_slice(&[0x02, 0]),
+                ".." => entry.extend_from_slice(&[0x04, 0]),
+                text => {
+                    entry.extend_from_slice(&[0, text.len() as u8]);
+                    entry.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        entry[2] = entry.len() as u8;
+        entry
+    }
+
+    // ISO 9660 image with an optional Rock Ridge primary tree and Joliet tree,
+    // files larger than max_extent are split into extents with a gap between them
+    struct IsoBuilder {
+        image: Vec<u8>,
+        rock_ridge: bool,
+        max_extent: usize,
+        /// Extents of the files written so far, shared by both trees
+        files: HashMap<String, Vec<(u32, u32)>>,
+    }
+
+    impl IsoBuilder {
+        fn new(rock_ridge: bool, max_extent: usize) -> Self {
+            IsoBuilder {
+                image: vec![0u8; 19 * 2048],
+                rock_ridge,
+                max_extent,
+                files: HashMap::new(),
+            }
+        }
+
+        fn alloc(&mut self, data: &[u8]) -> u32 {
+            let lba = (self.image.len() / 2048) as u32;
+            self.image.extend_from_slice(data);
+            self.image.resize(self.image.len().div_ceil(2048) * 2048, 0);
+            lba
+        }
+
+        fn file_extents(&mut self, path: &str, data: &[u8]) -> Vec<(u32, u32)> {
+            if let Some(extents) = self.files.get(path) {
+                return extents.clone();
+            }
+            let mut extents = Vec::new();
+            for chunk in data.chunks(self.max_extent) {
+                if data.len() > self.max_extent {
+                    self.alloc(&[0xee; 2048]);
+                }
+                extents.push((self.alloc(chunk), chunk.len() as u32));
+            }
+            if extents.is_empty() {
+                extents.push((0, 0));
+            }
+            self.files.insert(path.to_string(), extents.clone());
+            extents
+        }
+
+        // Identifiers are truncated to the 64 Joliet and 30 ISO 9660 level 2 characters
+        fn id(name: &str, joliet: bool, file: bool) -> Vec<u8> {
+            let limit = if joliet { 64 } else { 30 };
+            let mut name: String = name.chars().take(limit - 2 * file as usize).collect();
+            if file {
+                name.push_str(";1");
+            }
+            match joliet {
+                true => name.encode_utf16().flat_map(|c| c.to_be_bytes()).collect(),
+                false => name
+                    .to_ascii_uppercase()
+                    .replace(
+                        |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != ';',
+                        "_",
+                    )
+                    .into_bytes(),
+            }
+        }
+
+        // Records of a node, the system use area goes to a continuation area when too long
+        fn records(
+            &mut self,
+            node: &IsoNode,
+            path: &str,
+            joliet: bool,
+            dirs: &HashMap<String, (u32, u32)>,
+            dry: bool,
+        ) -> Vec<Vec<u8>> {
+            let rock_ridge = self.rock_ridge && !joliet;
+            let (name, extents, flags, mut system_use) = match node {
+                IsoNode::File(name, data) => {
+                    let extents = match dry {
+                        true => vec![(0, 0); data.len().div_ceil(self.max_extent).max(1)],
+                        false => self.file_extents(&format!("{}/{}", path, name), data),
+                    };
+                    (name, extents, 0, rr_px(0o100644))
+                }
+                IsoNode::Dir(name, _) => {
+                    let extent = dirs
+                        .get(&format!("{}/{}", path, name))
+                        .copied()
+                        .unwrap_or((0, 2048));
+                    (name, vec![extent], 2, rr_px(0o040755))
+                }
+                IsoNode::Link(_, _) if !rock_ridge => return Vec::new(),
+                IsoNode::Link(name, target) => {
+                    let mut system_use = rr_px(0o120777);
+                    system_use.extend(rr_sl(target));
+                    (name, vec![(0, 0)], 0, system_use)
+                }
+            };
+
+            let id = Self::id(name, joliet, !matches!(node, IsoNode::Dir(_, _)));
+            if rock_ridge {
+                system_use.extend_from_slice(&[b'N', b'M', 5 + name.len() as u8, 1, 0]);
+                system_use.extend_from_slice(name.as_bytes());
+                if 34 + id.len() + system_use.len() > 254 {
+                    let lba = if dry { 0 } else { self.alloc(&system_use) };
+                    let mut ce = vec![b'C', b'E', 28, 1];
+                    for value in [lba, 0, system_use.len() as u32] {
+                        ce.extend_from_slice(&value.to_le_bytes());
+                        ce.extend_from_slice(&value.to_be_bytes());
+                    }
+                    system_use = ce;
+                }
+            } else {
+                system_use.clear();
+            }
+
+            let count = extents.len();
+            extents
+                .into_iter()
+                .enumerate()
+                .map(|(i, extent)| {
+                    let more = if i + 1 < count { 0x80 } else { 0 };
+                    iso_record(&id, extent, flags | more, &system_use)
+                })
+                .collect()
+        }
+
+        fn pack(records: &[Vec<u8>]) -> Vec<u8> {
+            let mut data = Vec::new();
+            for record in records {
+                if data.len() % 2048 + record.len() > 2048 {
+                    data.resize(data.len().div_ceil(2048) * 2048, 0);
+                }
+                data.extend_from_slice(record);
+            }
+            data.resize(data.len().div_ceil(2048) * 2048, 0);
+            data
+        }
+
+        fn dir(
+            &mut self,
+            nodes: &[IsoNode],
+            path: &str,
+            parent: Option<(u32, u32)>,
+            joliet: bool,
+        ) -> (u32, u32) {
+            let rock_ridge = self.rock_ridge && !joliet;
+            let dot = |root: bool| {
+                let mut system_use = Vec::new();
+                if rock_ridge {
+                    if root {
+                        system_use.extend_from_slice(&[b'S', b'P', 7, 1, 0xbe, 0xef, 0]);
+                    }
+                    system_use.extend(rr_px(0o040755));
+                }
+                system_use
+            };
+
+            let mut dirs = HashMap::new();
+            let mut records = vec![
+                iso_record(&[0], (0, 0), 2, &dot(parent.is_none())),
+                iso_record(&[1], (0, 0), 2, &dot(false)),
+            ];
+            for node in nodes {
+                records.extend(self.records(node, path, joliet, &dirs, true));
+            }
+            let size = Self::pack(&records).len();
+            let own = (self.alloc(&vec![0u8; size]), size as u32);
+
+            for node in nodes {
+                if let IsoNode::Dir(name, children) = node {
+                    let child = format!("{}/{}", path, name);
+                    let extent = self.dir(children, &child, Some(own), joliet);
+                    dirs.insert(child, extent);
+                }
+            }
+
+            let mut records = vec![
+                iso_record(&[0], own, 2, &dot(parent.is_none())),
+                iso_record(&[1], parent.unwrap_or(own), 2, &dot(false)),
+            ];
+            for node in nodes {
+                records.extend(self.records(node, path, joliet, &dirs, false));
+            }
+            let data = Self::pack(&records);
+            let offset = own.0 as usize * 2048;
+            self.image[offset..offset + data.len()].copy_from_slice(&data);
+            own
+        }
+
+        fn build(mut self, volume_id: &str, tree: &[IsoNode], joliet: bool) -> Vec<u8> {
+            let root = self.dir(tree, "", None, false);
+            let joliet_root = joliet.then(|| self.dir(tree, "", None, true));
+            let sectors = (self.image.len() / 2048) as u32;
+
+            let mut descriptor = |sector: usize, kind: u8, root: Option<(u32, u32)>, id: &[u8]| {
+                let d = &mut self.image[sector * 2048..(sector + 1) * 2048];
+                d[0] = kind;
+                d[1..7].copy_from_slice(b"CD001\x01");
+                if let Some(root) = root {
+                    // Joliet pads with UCS-2 spaces
+                    for pad in d[8..72].chunks_mut(2) {
+                        pad.copy_from_slice(if kind == 2 { b"\0 " } else { b"  " });
+                    }
+                    d[40..40 + id.len()].copy_from_slice(id);
+                    d[80..84].copy_from_slice(&sectors.to_le_bytes());
+                    d[84..88].copy_from_slice(&sectors.to_be_bytes());
+                    d[120..124].copy_from_slice(&[1, 0, 0, 1]);
+                    d[124..128].copy_from_slice(&[1, 0, 0, 1]);
+                    d[128..132].copy_from_slice(&[0, 8, 8, 0]);
+                    d[156..190].copy_from_slice(&iso_record(&[0], root, 2, &[]));
+                }
+                if kind == 2 {
+                    d[88..91].copy_from_slice(b"%/E");
+                }
+            };
+            descriptor(16, 1, Some(root), volume_id.as_bytes());
+            match joliet_root {
+                Some(root) => {
+                    let id: Vec<u8> = volume_id
+                        .encode_utf16()
+                        .flat_map(|c| c.to_be_bytes())
+                        .collect();
+                    descriptor(17, 2, Some(root), &id);
+                    descriptor(18, 255, None, &[]);
+                }
+                None => descriptor(17, 255, None, &[]),
+            }
+            self.image
+        }
+    }
+
+    fn iso_install_tree() -> Vec<IsoNode> {
+        vec![
+            IsoNode::Dir(
+                "ppc",
+                vec![
+                    IsoNode::File("bootinfo.txt", b"<chrp-boot>\n".to_vec()),
+                    IsoNode::Dir(
+                        "ppc64",
+                        vec![
+                            IsoNode::File("vmlinuz", pattern(10000, 7)),
+                            IsoNode::File("initrd.img", pattern(3000, 8)),
+                        ],
+                    ),
+                    IsoNode::Link("latest", "/ppc/ppc64/vmlinuz"),
+                ],
+            ),
+            IsoNode::Link("boot", "ppc/./ppc64"),
+            IsoNode::Link("up", "ppc/ppc64/../../ppc/bootinfo.txt"),
+            IsoNode::Link("loop", "loop"),
+            IsoNode::File("Release-Notes-For-This-Installer-Media-With-A-Name-Long-Enough-For-A-Continuation-Area-Of-The-System-Use-Field-In-Rock-Ridge-Images.txt", b"notes".to_vec()),
+            IsoNode::File("empty", Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn iso9660_rock_ridge() {
+        let image = IsoBuilder::new(true, 4096).build("INSTALL", &iso_install_tree(), true);
+        let mut fs = IsoFs::new(MemoryDisk::new(&image, 2048)).unwrap();
+        assert_eq!(fs.naming(), iso9660::Naming::RockRidge);
+        assert_eq!(fs.volume_id(), "INSTALL");
+
+        let root = fs.root();
+        let names: Vec<String> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names.len(), 6);
+        assert_eq!(names[..4], ["ppc", "boot", "up", "loop"]);
+        assert!(names[4].starts_with("Release-Notes-For") && names[4].ends_with("Images.txt"));
+
+        // Three extents with gaps in between
+        let kernel = fs.lookup("ppc/ppc64/vmlinuz").unwrap();
+        assert_eq!(kernel.extents.len(), 3);
+        assert_eq!(kernel.size(), 10000);
+        let mut file = fs.open_entry(&kernel).unwrap();
+        assert_eq!(read_all(&mut file, 3000), pattern(10000, 7));
+        file.seek(4000).unwrap();
+        let mut buf = [0u8; 200];
+        assert_eq!(file.read(&mut buf).unwrap(), 96);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(10000, 7)[4096..4296]);
+        assert!(file.seek(10001).is_err());
+
+        assert_eq!(fs.lookup("boot/vmlinuz").unwrap(), kernel);
+        assert_eq!(fs.lookup("ppc/latest").unwrap(), kernel);
+        assert_eq!(read_all(&mut fs.open("up").unwrap(), 64), b"<chrp-boot>\n");
+        let link = fs.lookup_link("ppc/latest").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(link.symlink.as_deref(), Some("/ppc/ppc64/vmlinuz"));
+        assert!(fs.lookup("loop").is_err());
+        assert!(fs.lookup("PPC/bootinfo.txt").is_err());
+        assert_eq!(read_all(&mut fs.open(&names[4]).unwrap(), 64), b"notes");
+        assert_eq!(read_all(&mut fs.open("empty").unwrap(), 64), b"");
+    }
+
+    #[test]
+    fn iso9660_joliet_and_plain() {
+        let image = IsoBuilder::new(false, 4096).build("INSTALL", &iso_install_tree(), true);
+        let mut fs = IsoFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.naming(), iso9660::Naming::Joliet);
+        assert_eq!(fs.volume_id(), "INSTALL");
+        let root = fs.root();
+        let names: Vec<String> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names[0], "ppc");
+        assert_eq!(names.len(), 3);
+        let mut file = fs.open("\\PPC\\ppc64\\VMLINUZ").unwrap();
+        assert_eq!(read_all(&mut file, 8192), pattern(10000, 7));
+        assert!(fs.lookup("boot").is_err());
+
+        let image = IsoBuilder::new(false, 1 << 20).build("INSTALL", &iso_install_tree(), false);
+        let mut fs = IsoFs::new(MemoryDisk::new(&image, 2048)).unwrap();
+        assert_eq!(fs.naming(), iso9660::Naming::Iso9660);
+        let ppc = fs.lookup("ppc").unwrap();
+        let names: Vec<String> = fs
+            .read_dir(&ppc)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["BOOTINFO.TXT", "PPC64"]);
+        assert_eq!(
+            read_all(&mut fs.open("ppc\\bootinfo.txt").unwrap(), 64),
+            b"<chrp-boot>\n"
+        );
+        assert_eq!(fs.lookup("ppc/ppc64/vmlinuz").unwrap().extents.len(), 1);
+        assert!(fs.open("ppc").is_err());
+        let file = fs.lookup("ppc/bootinfo.txt").unwrap();
+        assert!(fs.read_dir(&file).is_err());
+
+        assert!(IsoFs::new(MemoryDisk::new(&image[..16 * 2048], 2048)).is_err());
+        let mut broken = image.clone();
+        broken[16 * 2048 + 1] = b'X';
+        assert!(IsoFs::new(MemoryDisk::new(&broken, 2048)).is_err());
+    }
+
+    enum XfsNode {
+        File(String, Vec<u8>),
+        /// File with one extent per block and a free block between extents
+        Fragmented(String, Vec<u8>),
+        /// File size and (block, seed, unwritten) single block extents, holes elsewhere
+        Sparse(String, u64, Vec<(u64, u8, bool)>),
+        Dir(String, Vec<XfsNode>),
+        Link(String, String),
+    }
+
+    const XFS_AG_BLOCKS: u64 = 400;
+    const XFS_AG_COUNT: u64 = 3;
+    const XFS_AG_BLOCK_LOG: u32 = 9;
+    /// Allocation group header blocks and the inode chunks
+    const XFS_RESERVED: u64 = 68;
+    const XFS_INODE_BLOCK: u64 = 4;
+
+    struct XfsBuilder {
+        image: Vec<u8>,
+        /// v5 metadata with CRCs, or v4 without
+        crc: bool,
+        /// Next free block, counted across allocation groups
+        next_block: u64,
+        next_inode: u64,
+        /// Records per extent tree leaf, kept low to get several leaves
+        leaf_records: usize,
+    }
+
+    fn xfs_crc(data: &mut [u8], offset: usize) {
+        data[offset..offset + 4].fill(0);
+        let crc = crc32c(data);
+        data[offset..offset + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn xfs_extent(offset: u64, block: u64, count: u64, unwritten: bool) -> [u8; 16] {
+        let high = (unwritten as u64) << 63 | offset << 9 | block >> 43;
+        let low = block << 21 | count;
+        let mut record = [0u8; 16];
+        record[..8].copy_from_slice(&high.to_be_bytes());
+        record[8..].copy_from_slice(&low.to_be_bytes());
+        record
+    }
+
+    impl XfsBuilder {
+        fn new(crc: bool, leaf_records: usize) -> Self {
+            XfsBuilder {
+                image: vec![0u8; (XFS_AG_COUNT * XFS_AG_BLOCKS * 4096) as usize],
+                crc,
+                next_block: XFS_RESERVED,
+                next_inode: XFS_INODE_BLOCK << if crc { 3 } else { 4 },
+                leaf_records,
+            }
+        }
+
+        fn inode_size(&self) -> usize {
+            if self.crc {
+                512
+            } else {
+                256
+            }
+        }
+
+        fn inodes_per_block_log(&self) -> u32 {
+            12 - self.inode_size().trailing_zeros()
+        }
+
+        fn fork_size(&self) -> usize {
+            self.inode_size() - if self.crc { 176 } else { 100 }
+        }
+
+        // Block number split by allocation group, as stored in extents
+        fn ag_block(block: u64) -> u64 {
+            ((block / XFS_AG_BLOCKS) << XFS_AG_BLOCK_LOG) | (block % XFS_AG_BLOCKS)
+        }
+
+        fn block_mut(&mut self, ag_block: u64) -> &mut [u8] {
+            let block = (ag_block >> XFS_AG_BLOCK_LOG) * XFS_AG_BLOCKS
+                + (ag_block & ((1 << XFS_AG_BLOCK_LOG) - 1));
+            let start = (block * 4096) as usize;
+            &mut self.image[start..start + 4096]
+        }
+
+        /// Allocates runs of blocks, split where allocation groups end
+        fn alloc(&mut self, mut count: u64) -> Vec<(u64, u64)> {
+            let mut runs = Vec::new();
+            while count > 0 {
+                if self.next_block.is_multiple_of(XFS_AG_BLOCKS) {
+                    self.next_block += XFS_RESERVED;
+                }
+                let len = count.min(XFS_AG_BLOCKS - self.next_block % XFS_AG_BLOCKS);
+                runs.push((Self::ag_block(self.next_block), len));
+                self.next_block += len;
+                count -= len;
+            }
+            runs
+        }
+
+        fn write_runs(&mut self, runs: &[(u64, u64)], data: &[u8]) {
+            let mut chunks = data.chunks(4096);
+            for (block, count) in runs {
+                for i in 0..*count {
+                    if let Some(chunk) = chunks.next() {
+                        self.block_mut(block + i)[..chunk.len()].copy_from_slice(chunk);
+                    }
+                }
+            }
+        }
+
+        fn write_inode(&mut self, number: u64, mode: u16, size: u64, extents: &[[u8; 16]]) {
+            let fork: Vec<u8> = extents.concat();
+            match fork.len() <= self.fork_size() {
+                true => self.write_raw_inode(number, mode, size, 2, extents.len() as u32, &fork),
+                false => self.write_btree_inode(number, mode, size, extents),
+            }
+        }
+
+        fn write_btree_inode(&mut self, number: u64, mode: u16, size: u64, extents: &[[u8; 16]]) {
+            let leaves: Vec<&[[u8; 16]]> = extents.chunks(self.leaf_records).collect();
+            let blocks = self.alloc(leaves.len() as u64);
+            let blocks: Vec<u64> = blocks
+                .iter()
+                .flat_map(|(start, count)| *start..start + count)
+                .collect();
+            let header = if self.crc { 72 } else { 24 };
+            for (i, leaf) in leaves.iter().enumerate() {
+                let crc = self.crc;
+                let block = self.block_mut(blocks[i]);
+                block[..4].copy_from_slice(if crc { b"BMA3" } else { b"BMAP" });
+                block[6..8].copy_from_slice(&(leaf.len() as u16).to_be_bytes());
+                let left = if i > 0 { blocks[i - 1] } else { !0 };
+                let right = blocks.get(i + 1).copied().unwrap_or(!0);
+                block[8..16].copy_from_slice(&left.to_be_bytes());
+                block[16..24].copy_from_slice(&right.to_be_bytes());
+                if crc {
+                    block[56..64].copy_from_slice(&number.to_be_bytes());
+                }
+                block[header..header + leaf.len() * 16].copy_from_slice(&leaf.concat());
+                if crc {
+                    xfs_crc(block, 64);
+                }
+            }
+
+            let fork_size = self.fork_size();
+            let max_records = (fork_size - 4) / 16;
+            let mut fork = vec![0u8; fork_size];
+            fork[..2].copy_from_slice(&1u16.to_be_bytes());
+            fork[2..4].copy_from_slice(&(leaves.len() as u16).to_be_bytes());
+            for (i, leaf) in leaves.iter().enumerate() {
+                fork[4 + i * 8..12 + i * 8].copy_from_slice(&leaf[0][..8]);
+                fork[4 + i * 8] &= 0x7f;
+                let pointer = 4 + max_records * 8 + i * 8;
+                fork[pointer..pointer + 8].copy_from_slice(&blocks[i].to_be_bytes());
+            }
+            self.write_raw_inode(number, mode, size, 3, extents.len() as u32, &fork);
+        }
+
+        fn write_raw_inode(
+            &mut self,
+            number: u64,
+            mode: u16,
+            size: u64,
+            format: u8,
+            extents: u32,
+            fork: &[u8],
+        ) {
+            let inode_size = self.inode_size();
+            let crc = self.crc;
+            let core = if crc { 176 } else { 100 };
+            let log = self.inodes_per_block_log();
+            let offset = ((number & ((1 << log) - 1)) as usize) * inode_size;
+            let block = self.block_mut(number >> log);
+            let raw = &mut block[offset..offset + inode_size];
+            raw[..2].copy_from_slice(b"IN");
+            raw[2..4].copy_from_slice(&mode.to_be_bytes());
+            raw[4] = if crc { 3 } else { 2 };
+            raw[5] = format;
+            raw[16..20].copy_from_slice(&1u32.to_be_bytes());
+            raw[56..64].copy_from_slice(&size.to_be_bytes());
+            raw[76..80].copy_from_slice(&extents.to_be_bytes());
+            raw[96..100].copy_from_slice(&[0xff; 4]);
+            raw[core..core + fork.len()].copy_from_slice(fork);
+            if crc {
+                raw[152..160].copy_from_slice(&number.to_be_bytes());
+                xfs_crc(raw, 100);
+            }
+        }
+
+        fn data_entry(number: u64, name: &str, kind: u8, tag: usize) -> Vec<u8> {
+            let mut entry = number.to_be_bytes().to_vec();
+            entry.push(name.len() as u8);
+            entry.extend_from_slice(name.as_bytes());
+            entry.push(kind);
+            entry.resize((entry.len() + 2).next_multiple_of(8), 0);
+            let len = entry.len();
+            entry[len - 2..].copy_from_slice(&(tag as u16).to_be_bytes());
+            entry
+        }
+
+        fn fill_free(block: &mut [u8], start: usize, end: usize) {
+            if end > start {
+                block[start..start + 2].copy_from_slice(&[0xff, 0xff]);
+                block[start + 2..start + 4].copy_from_slice(&((end - start) as u16).to_be_bytes());
+                block[end - 2..end].copy_from_slice(&(start as u16).to_be_bytes());
+            }
+        }
+
+        fn write_dir(&mut self, number: u64, parent: u64, entries: &[(String, u64, u8)]) {
+            let sf_size = 6 + entries.iter().map(|e| e.0.len() + 8).sum::<usize>();
+            if sf_size <= self.fork_size() {
+                let mut fork = vec![entries.len() as u8, 0];
+                fork.extend_from_slice(&(parent as u32).to_be_bytes());
+                for (i, (name, inode, kind)) in entries.iter().enumerate() {
+                    fork.push(name.len() as u8);
+                    fork.extend_from_slice(&(64 + i as u16 * 16).to_be_bytes());
+                    fork.extend_from_slice(name.as_bytes());
+                    fork.push(*kind);
+                    fork.extend_from_slice(&(*inode as u32).to_be_bytes());
+                }
+                self.write_raw_inode(number, 0o40755, fork.len() as u64, 1, 0, &fork);
+                return;
+            }
+
+            let mut all = vec![(".".to_string(), number, 2), ("..".to_string(), parent, 2)];
+            all.extend_from_slice(entries);
+            let header = if self.crc { 64 } else { 16 };
+            let total: usize = all
+                .iter()
+                .map(|e| (e.0.len() + 12).next_multiple_of(8))
+                .sum();
+            let single = header + total + all.len() * 8 + 8 <= 4096;
+
+            // Pack the entries into data blocks
+            let mut blocks: Vec<Vec<u8>> = Vec::new();
+            let mut offset = 4096;
+            let end = if single {
+                4096 - all.len() * 8 - 8
+            } else {
+                4096
+            };
+            for (name, inode, kind) in &all {
+                let len = (name.len() + 12).next_multiple_of(8);
+                if offset + len > end {
+                    if let Some(block) = blocks.last_mut() {
+                        Self::fill_free(block, offset, end);
+                    }
+                    blocks.push(vec![0u8; 4096]);
+                    offset = header;
+                }
+                let entry = Self::data_entry(*inode, name, *kind, offset);
+                blocks.last_mut().unwrap()[offset..offset + len].copy_from_slice(&entry);
+                offset += len;
+            }
+            Self::fill_free(blocks.last_mut().unwrap(), offset, end);
+
+            let runs = self.alloc(blocks.len() as u64);
+            let physical: Vec<u64> = runs
+                .iter()
+                .flat_map(|(start, count)| *start..start + count)
+                .collect();
+            for (block, data) in blocks.iter_mut().enumerate() {
+                let magic: &[u8; 4] = match (single, self.crc) {
+                    (true, true) => b"XDB3",
+                    (false, true) => b"XDD3",
+                    (true, false) => b"XD2B",
+                    (false, false) => b"XD2D",
+                };
+                data[..4].copy_from_slice(magic);
+                if single {
+                    data[4088..4092].copy_from_slice(&(all.len() as u32).to_be_bytes());
+                }
+                if self.crc {
+                    data[40..48].copy_from_slice(&number.to_be_bytes());
+                    xfs_crc(data, 4);
+                }
+                self.block_mut(physical[block]).copy_from_slice(data);
+            }
+
+            let mut extents: Vec<[u8; 16]> = Vec::new();
+            let mut logical = 0;
+            for (start, count) in runs {
+                extents.push(xfs_extent(logical, start, count, false));
+                logical += count;
+            }
+            if !single {
+                // Hash index past the leaf offset, never read by the driver
+                let (leaf, _) = self.alloc(1)[0];
+                self.block_mut(leaf).fill(0xd2);
+                extents.push(xfs_extent(1 << 23, leaf, 1, false));
+            }
+            let size = blocks.len() as u64 * 4096;
+            self.write_inode(number, 0o40755, size, &extents);
+        }
+
+        fn write_link(&mut self, number: u64, target: &str) {
+            if target.len() <= self.fork_size() {
+                let fork = target.as_bytes();
+                self.write_raw_inode(number, 0o120777, fork.len() as u64, 1, 0, fork);
+                return;
+            }
+            let header = if self.crc { 56 } else { 0 };
+            let chunks: Vec<&[u8]> = target.as_bytes().chunks(4096 - header).collect();
+            let runs = self.alloc(chunks.len() as u64);
+            let (block, _) = runs[0];
+            for (i, chunk) in chunks.iter().enumerate() {
+                let crc = self.crc;
+                let data = self.block_mut(block + i as u64);
+                data[header..header + chunk.len()].copy_from_slice(chunk);
+                if crc {
+                    data[..4].copy_from_slice(b"XSLM");
+                    data[4..8].copy_from_slice(&((i * (4096 - header)) as u32).to_be_bytes());
+                    data[8..12].copy_from_slice(&(chunk.len() as u32).to_be_bytes());
+                    data[32..40].copy_from_slice(&number.to_be_bytes());
+                    xfs_crc(data, 12);
+                }
+            }
+            let extent = xfs_extent(0, block, chunks.len() as u64, false);
+            self.write_inode(number, 0o120777, target.len() as u64, &[extent]);
+        }
+
+        fn node(&mut self, node: &XfsNode, parent: u64) -> (String, u64, u8) {
+            let number = self.next_inode;
+            self.next_inode += 1;
+            let (name, kind) = match node {
+                XfsNode::File(name, data) => {
+                    let runs = self.alloc(data.len().div_ceil(4096) as u64);
+                    self.write_runs(&runs, data);
+                    let mut extents = Vec::new();
+                    let mut logical = 0;
+                    for (start, count) in runs {
+                        extents.push(xfs_extent(logical, start, count, false));
+                        logical += count;
+                    }
+                    self.write_inode(number, 0o100644, data.len() as u64, &extents);
+                    (name, 1)
+                }
+                XfsNode::Fragmented(name, data) => {
+                    let mut extents = Vec::new();
+                    for (i, chunk) in data.chunks(4096).enumerate() {
+                        let (block, _) = self.alloc(2)[0];
+                        self.block_mut(block)[..chunk.len()].copy_from_slice(chunk);
+                        extents.push(xfs_extent(i as u64, block, 1, false));
+                    }
+                    self.write_inode(number, 0o100644, data.len() as u64, &extents);
+                    (name, 1)
+                }
+                XfsNode::Sparse(name, size, chunks) => {
+                    let mut extents = Vec::new();
+                    for (logical, seed, unwritten) in chunks {
+                        let (block, _) = self.alloc(1)[0];
+                        self.block_mut(block).copy_from_slice(&pattern(4096, *seed));
+                        extents.push(xfs_extent(*logical, block, 1, *unwritten));
+                    }
+                    self.write_inode(number, 0o100644, *size, &extents);
+                    (name, 1)
+                }
+                XfsNode::Dir(name, children) => {
+                    let entries: Vec<_> = children.iter().map(|c| self.node(c, number)).collect();
+                    self.write_dir(number, parent, &entries);
+                    (name, 2)
+                }
+                XfsNode::Link(name, target) => {
+                    self.write_link(number, target);
+                    (name, 7)
+                }
+            };
+            (name.clone(), number, kind)
+        }
+
+        fn build(mut self, label: &str, tree: Vec<XfsNode>) -> Vec<u8> {
+            let root = self.next_inode;
+            self.node(&XfsNode::Dir(String::new(), tree), root);
+
+            let crc = self.crc;
+            let inode_size = self.inode_size() as u16;
+            let sb = &mut self.image[..512];
+            sb[..4].copy_from_slice(b"XFSB");
+            sb[4..8].copy_from_slice(&4096u32.to_be_bytes());
+            sb[8..16].copy_from_slice(&(XFS_AG_COUNT * XFS_AG_BLOCKS).to_be_bytes());
+            sb[32..48].copy_from_slice(&[0x5a; 16]);
+            sb[56..64].copy_from_slice(&root.to_be_bytes());
+            sb[84..88].copy_from_slice(&(XFS_AG_BLOCKS as u32).to_be_bytes());
+            sb[88..92].copy_from_slice(&(XFS_AG_COUNT as u32).to_be_bytes());
+            let version: u16 = if crc { 0xb4a5 } else { 0xb4a4 };
+            sb[100..102].copy_from_slice(&version.to_be_bytes());
+            sb[102..104].copy_from_slice(&512u16.to_be_bytes());
+            sb[104..106].copy_from_slice(&inode_size.to_be_bytes());
+            sb[106..108].copy_from_slice(&(4096 / inode_size).to_be_bytes());
+            sb[108..108 + label.len()].copy_from_slice(label.as_bytes());
+            let inode_log = inode_size.trailing_zeros() as u8;
+            sb[120..125].copy_from_slice(&[12, 9, inode_log, 12 - inode_log, 9]);
+            match crc {
+                true => {
+                    sb[216..220].copy_from_slice(&1u32.to_be_bytes());
+                    xfs_crc(sb, 224);
+                }
+                false => sb[200..204].copy_from_slice(&0x200u32.to_be_bytes()),
+            }
+            self.image
+        }
+    }
+
+    fn xfs_boot_tree() -> Vec<XfsNode> {
+        let mut boot = vec![
+            XfsNode::File("config-6.1".into(), b"CONFIG_PPC64=y\n".to_vec()),
+            XfsNode::File(
+                "System.map-6.1".into(),
+                b"c000000000000000 T _stext\n".to_vec(),
+            ),
+        ];
+        for i in 0..20 {
+            let initramfs = format!("initramfs {}", i).into_bytes();
+            boot.push(XfsNode::File(format!("initramfs-{:02}.img", i), initramfs));
+        }
+        boot.push(XfsNode::File("vmlinux".into(), pattern(1536 * 1024, 5)));
+        boot.push(XfsNode::Dir(
+            "grub".into(),
+            vec![XfsNode::File(
+                "grub.cfg".into(),
+                b"set timeout=5\n".to_vec(),
+            )],
+        ));
+        boot.push(XfsNode::Link("grub2".into(), "/boot/grub".into()));
+
+        let many = (0..300)
+            .map(|i| XfsNode::File(format!("file-{:03}", i), i.to_string().into_bytes()))
+            .collect();
+        vec![
+            XfsNode::Dir("boot".into(), boot),
+            XfsNode::Link("vmlinuz".into(), "boot/vmlinux".into()),
+            XfsNode::Link("long".into(), format!("/boot/{}vmlinux", "./".repeat(200))),
+            XfsNode::Link("loop-a".into(), "loop-b".into()),
+            XfsNode::Link("loop-b".into(), "loop-a".into()),
+            XfsNode::Fragmented("fragmented".into(), pattern(40 * 4096 - 100, 9)),
+            XfsNode::Sparse(
+                "sparse".into(),
+                10 * 4096 + 100,
+                vec![(0, 1, false), (3, 2, false), (5, 3, true), (8, 4, false)],
+            ),
+            XfsNode::Dir("many".into(), many),
+        ]
+    }
+
+    fn check_xfs_tree<D: BlockDevice>(fs: &mut XfsFs<D>) {
+        let root = fs.root().unwrap();
+        let mut names: Vec<(String, FileType)> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.kind))
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            names,
+            [
+                ("boot".to_string(), FileType::Directory),
+                ("fragmented".to_string(), FileType::Regular),
+                ("long".to_string(), FileType::Symlink),
+                ("loop-a".to_string(), FileType::Symlink),
+                ("loop-b".to_string(), FileType::Symlink),
+                ("many".to_string(), FileType::Directory),
+                ("sparse".to_string(), FileType::Regular),
+                ("vmlinuz".to_string(), FileType::Symlink),
+            ]
+        );
+
+        // Block directory, and a file crossing into the second allocation group
+        let boot = fs.lookup("boot").unwrap();
+        assert_eq!(fs.read_dir(&boot).unwrap().len(), 25);
+        let kernel = fs.lookup("boot/vmlinux").unwrap();
+        assert_eq!(fs.extents(&kernel).unwrap().len(), 2);
+        let mut file = fs.open("/boot/vmlinux").unwrap();
+        assert_eq!(read_all(&mut file, 5000), pattern(1536 * 1024, 5));
+        file.seek(1500 * 1024 + 3).unwrap();
+        let mut buf = [0u8; 100];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(1536 * 1024, 5)[1500 * 1024 + 3..][..100]);
+        assert!(file.seek(1536 * 1024 + 1).is_err());
+        assert_eq!(
+            read_all(&mut fs.open("boot/initramfs-13.img").unwrap(), 64),
+            b"initramfs 13"
+        );
+
+        // Local and remote symbolic links, also in the middle of paths
+        assert_eq!(fs.lookup("vmlinuz").unwrap(), kernel);
+        assert_eq!(fs.lookup("long").unwrap(), kernel);
+        let link = fs.lookup_link("long").unwrap();
+        assert_eq!(
+            fs.read_link(&link).unwrap(),
+            format!("/boot/{}vmlinux", "./".repeat(200))
+        );
+        assert_eq!(
+            read_all(&mut fs.open("boot/grub2/../grub/grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+        assert!(fs.lookup("loop-a").is_err());
+        assert!(fs.lookup_link("loop-a").unwrap().is_symlink());
+
+        // Extent tree with several leaves
+        let fragmented = fs.lookup("fragmented").unwrap();
+        assert_eq!(fs.extents(&fragmented).unwrap().len(), 40);
+        assert_eq!(
+            read_all(&mut fs.open("fragmented").unwrap(), 10000),
+            pattern(40 * 4096 - 100, 9)
+        );
+
+        let data = read_all(&mut fs.open("sparse").unwrap(), 30000);
+        assert_eq!(data.len(), 10 * 4096 + 100);
+        for (block, seed) in [(0, 1), (3, 2), (8, 4)] {
+            assert_eq!(&data[block * 4096..][..4096], &pattern(4096, seed)[..]);
+        }
+        for block in [1, 2, 4, 5, 6, 7, 9, 10] {
+            assert!(data[block * 4096..].iter().take(4096).all(|b| *b == 0));
+        }
+
+        // Leaf directory spread over several data blocks
+        let many = fs.lookup("many").unwrap();
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 300);
+        assert_eq!(read_all(&mut fs.open("many\\file-237").unwrap(), 8), b"237");
+
+        assert!(fs.open("boot").is_err());
+        assert!(fs.open("boot/missing").is_err());
+        assert!(fs.lookup("boot/vmlinux/x").is_err());
+        assert!(fs.read_link(&kernel).is_err());
+        assert!(fs.read_dir(&kernel).is_err());
+    }
+
+    #[test]
+    fn xfs_v5_volume() {
+        let image = XfsBuilder::new(true, 16).build("xfsboot", xfs_boot_tree());
+        let mut fs = XfsFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.label(), Some("xfsboot"));
+        assert_eq!(fs.block_size(), 4096);
+        assert_eq!(fs.uuid(), &[0x5a; 16]);
+        assert!(fs.has_crc());
+        check_xfs_tree(&mut fs);
+    }
+
+    #[test]
+    fn xfs_v4_volume() {
+        let image = XfsBuilder::new(false, 12).build("", xfs_boot_tree());
+        let mut fs = XfsFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.label(), None);
+        assert!(!fs.has_crc());
+        check_xfs_tree(&mut fs);
+    }
+
+    #[test]
+    fn xfs_checksum_errors() {
+        let image = XfsBuilder::new(true, 16).build("csum", xfs_boot_tree());
+        let grub = XfsFs::new(MemoryDisk::new(&image, 512))
+            .unwrap()
+            .lookup("boot/grub/grub.cfg")
+            .unwrap();
+
+        // Inodes are found through their number
+        let offset = (grub.number >> 3) as usize * 4096 + (grub.number & 7) as usize * 512;
+        let mut corrupted = image.clone();
+        corrupted[offset + 56] ^= 1;
+        let mut fs = XfsFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.read_inode(grub.number).is_err());
+        assert!(fs.open("boot/grub/grub.cfg").is_err());
+        assert!(fs.open("vmlinuz").is_ok());
+
+        // Directory data blocks hold the file names
+        let name = b"file-150";
+        let offset = image.windows(name.len()).position(|w| w == name).unwrap();
+        let mut corrupted = image.clone();
+        corrupted[offset] = b'F';
+        let mut fs = XfsFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.open("many/file-001").is_err());
+        assert!(fs.open("boot/vmlinux").is_ok());
+
+        let mut corrupted = image.clone();
+        corrupted[110] ^= 1;
+        assert!(XfsFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        // Incompatible features the driver does not know about
+        let mut corrupted = image.clone();
+        corrupted[219] |= 0x10;
+        xfs_crc(&mut corrupted[..512], 224);
+        assert!(XfsFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        assert!(XfsFs::new(MemoryDisk::new(&image[..1024 * 1024], 512)).is_err());
+        assert!(XfsFs::new(MemoryDisk::new(&vec![0u8; 4096], 512)).is_err());
+    }
+
+    // Protofile lines describing the contents of a directory, in name order
+    fn xfs_proto(dir: &Path, proto: &mut String) {
+        let mut entries: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        for path in entries {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let kind = std::fs::symlink_metadata(&path).unwrap().file_type();
+            if kind.is_dir() {
+                proto.push_str(&format!("{} d--755 0 0\n", name));
+                xfs_proto(&path, proto);
+                proto.push_str("$\n");
+            } else if kind.is_symlink() {
+                let target = std::fs::read_link(&path).unwrap();
+                proto.push_str(&format!("{} l--777 0 0 {}\n", name, target.display()));
+            } else {
+                proto.push_str(&format!("{} ---644 0 0 {}\n", name, path.display()));
+            }
+        }
+    }
+
+    // Filesystem image built by mkfs.xfs from a protofile describing a directory tree
+    //
+    // The tests using it are ignored unless xfsprogs is installed and they
+    // are run with ```cargo test -- --ignored```.
+    fn mkfs_xfs_image(name: &str, options: &[&str], populate: impl Fn(&Path)) -> SparseDisk {
+        // The smallest filesystem mkfs.xfs accepts, mostly holes
+        const SIZE: u64 = 300 << 20;
+        let base = std::env::temp_dir().join(format!("ieee1275-{}-{}", name, std::process::id()));
+        let root = base.join("root");
+        let image = base.join("image");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&root).unwrap();
+        populate(&root);
+
+        let mut proto = String::from("/dev/null\n0 0\nd--755 0 0\n");
+        xfs_proto(&root, &mut proto);
+        proto.push_str("$\n");
+        std::fs::write(base.join("proto"), proto).unwrap();
+        std::fs::File::create(&image)
+            .unwrap()
+            .set_len(SIZE)
+            .unwrap();
+
+        let status = Command::new("mkfs.xfs")
+            .args(["-f", "-q", "-L", name])
+            .args(["-m", "uuid=6a1e1e2a-0f6e-4c5b-9d0a-5e2a3b4c5d6e"])
+            .args(options)
+            .arg("-p")
+            .arg(base.join("proto"))
+            .arg(&image)
+            .stdout(Stdio::null())
+            .status()
+            .expect("mkfs.xfs from xfsprogs is needed to build the XFS fixtures");
+        assert!(status.success());
+
+        let mut disk = SparseDisk::new(SIZE / 512);
+        let mut file = std::fs::File::open(&image).unwrap();
+        let mut chunk = vec![0u8; 1 << 20];
+        for offset in (0..SIZE).step_by(chunk.len()) {
+            std::io::Read::read_exact(&mut file, &mut chunk).unwrap();
+            for (i, block) in chunk.chunks(512).enumerate() {
+                if block.iter().any(|b| *b != 0) {
+                    disk.blocks.insert(offset / 512 + i as u64, block.to_vec());
+                }
+            }
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+        disk
+    }
+
+    fn populate_xfs(root: &Path) {
+        std::fs::create_dir_all(root.join("boot/grub")).unwrap();
+        std::fs::write(root.join("boot/vmlinux"), pattern(1536 * 1024, 5)).unwrap();
+        std::fs::write(root.join("boot/config-6.1"), "CONFIG_PPC64=y\n").unwrap();
+        std::fs::write(
+            root.join("boot/System.map-6.1"),
+            "c000000000000000 T _stext\n",
+        )
+        .unwrap();
+        for i in 0..12 {
+            let name = root.join(format!("boot/initramfs-6.1.0-{:02}.img", i));
+            std::fs::write(name, format!("initramfs {}", i)).unwrap();
+        }
+        std::fs::write(root.join("boot/grub/grub.cfg"), "set timeout=5\n").unwrap();
+        symlink("/boot/grub", root.join("boot/grub2")).unwrap();
+        symlink("boot/vmlinux", root.join("vmlinuz")).unwrap();
+        let long = format!("/boot/{}vmlinux", "./".repeat(200));
+        symlink(&long, root.join("long")).unwrap();
+        symlink("loop-b", root.join("loop-a")).unwrap();
+        symlink("loop-a", root.join("loop-b")).unwrap();
+
+        // Data blocks separated by holes
+        let mut fragmented = std::fs::File::create(root.join("fragmented")).unwrap();
+        for i in 0..40u64 {
+            fragmented.seek(SeekFrom::Start(i * 8192)).unwrap();
+            fragmented.write_all(&pattern(4096, i as u8)).unwrap();
+        }
+
+        // Every file takes a block, so the directory blocks end up apart
+        std::fs::create_dir(root.join("many")).unwrap();
+        for i in 0..300 {
+            std::fs::write(root.join(format!("many/file-{:03}", i)), i.to_string()).unwrap();
+        }
+        std::fs::create_dir(root.join("huge")).unwrap();
+        for i in 0..4000 {
+            std::fs::write(root.join(format!("huge/module-{:04}.ko", i)), i.to_string()).unwrap();
+        }
+    }
+
+    // Checks a tree from populate_xfs, inline_extents is the number of extents
+    // that fit in an inode before the data fork turns into a tree
+    fn check_mkfs_xfs_tree<D: BlockDevice>(fs: &mut XfsFs<D>, inline_extents: usize) {
+        let root = fs.root().unwrap();
+        let mut names: Vec<(String, FileType)> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.kind))
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            names,
+            [
+                ("boot".to_string(), FileType::Directory),
+                ("fragmented".to_string(), FileType::Regular),
+                ("huge".to_string(), FileType::Directory),
+                ("long".to_string(), FileType::Symlink),
+                ("loop-a".to_string(), FileType::Symlink),
+                ("loop-b".to_string(), FileType::Symlink),
+                ("many".to_string(), FileType::Directory),
+                ("vmlinuz".to_string(), FileType::Symlink),
+            ]
+        );
+        let leaf_block = (1 << 35) / fs.block_size();
+
+        // Short form directories live in the inode, without extents
+        assert!(fs.extents(&root).is_err());
+        let grub = fs.lookup("boot/grub").unwrap();
+        assert!(fs.extents(&grub).is_err());
+        assert_eq!(fs.read_dir(&grub).unwrap().len(), 1);
+        assert_eq!(
+            read_all(&mut fs.open("boot/grub2/../grub/grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+
+        // Block directory, a single block holding entries and hash index
+        let boot = fs.lookup("boot").unwrap();
+        let extents = fs.extents(&boot).unwrap();
+        assert_eq!((extents.len(), extents[0].offset), (1, 0));
+        assert_eq!(fs.read_dir(&boot).unwrap().len(), 16);
+        assert_eq!(
+            read_all(&mut fs.open("boot/initramfs-6.1.0-07.img").unwrap(), 64),
+            b"initramfs 7"
+        );
+
+        // Leaf directory, data blocks and a single leaf block
+        let many = fs.lookup("many").unwrap();
+        let extents = fs.extents(&many).unwrap();
+        assert!(extents.iter().any(|e| e.offset == leaf_block));
+        assert!(extents.iter().all(|e| e.offset < 2 * leaf_block));
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 300);
+        assert_eq!(read_all(&mut fs.open("many\\file-237").unwrap(), 8), b"237");
+
+        // Node directory with a free index, its extents in a tree
+        let huge = fs.lookup("huge").unwrap();
+        let extents = fs.extents(&huge).unwrap();
+        assert!(extents.iter().any(|e| e.offset >= 2 * leaf_block));
+        assert!(extents.len() > inline_extents);
+        let mut entries: Vec<String> = fs
+            .read_dir(&huge)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        entries.sort();
+        let expected: Vec<String> = (0..4000).map(|i| format!("module-{:04}.ko", i)).collect();
+        assert_eq!(entries, expected);
+        assert_eq!(
+            read_all(&mut fs.open("huge/module-3999.ko").unwrap(), 8),
+            b"3999"
+        );
+
+        // Extent list file
+        let kernel = fs.lookup("boot/vmlinux").unwrap();
+        assert!(fs.extents(&kernel).unwrap().len() <= inline_extents);
+        let mut file = fs.open("/boot/vmlinux").unwrap();
+        assert_eq!(read_all(&mut file, 5000), pattern(1536 * 1024, 5));
+        file.seek(1500 * 1024 + 3).unwrap();
+        let mut buf = [0u8; 100];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(1536 * 1024, 5)[1500 * 1024 + 3..][..100]);
+        assert!(file.seek(1536 * 1024 + 1).is_err());
+
+        // Older mkfs.xfs fills the holes in, newer ones leave them
+        let data = read_all(&mut fs.open("fragmented").unwrap(), 10000);
+        assert_eq!(data.len(), 39 * 8192 + 4096);
+        for (i, chunk) in data.chunks(8192).enumerate() {
+            assert_eq!(&chunk[..4096], &pattern(4096, i as u8)[..]);
+            assert!(chunk[4096..].iter().all(|b| *b == 0));
+        }
+
+        // Local and remote symbolic links
+        assert_eq!(fs.lookup("vmlinuz").unwrap(), kernel);
+        assert_eq!(fs.lookup("long").unwrap(), kernel);
+        let link = fs.lookup_link("long").unwrap();
+        assert_eq!(
+            fs.read_link(&link).unwrap(),
+            format!("/boot/{}vmlinux", "./".repeat(200))
+        );
+        assert!(fs.lookup("loop-a").is_err());
+        assert!(fs.lookup_link("loop-a").unwrap().is_symlink());
+
+        assert!(fs.open("huge").is_err());
+        assert!(fs.open("boot/missing").is_err());
+        assert!(fs.lookup("boot/vmlinux/x").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs mkfs.xfs, run with cargo test -- --ignored"]
+    fn xfs_mkfs_v5_volume() {
+        let options = ["-m", "crc=1", "-b", "size=4096", "-i", "size=512"];
+        let disk = mkfs_xfs_image("xfsboot", &options, populate_xfs);
+        let mut fs = XfsFs::new(disk).unwrap();
+        assert_eq!(fs.label(), Some("xfsboot"));
+        assert_eq!(fs.block_size(), 4096);
+        assert_eq!(fs.uuid()[..4], [0x6a, 0x1e, 0x1e, 0x2a]);
+        assert!(fs.has_crc());
+        // 512 byte inodes leave 336 bytes after the v3 core
+        check_mkfs_xfs_tree(&mut fs, 21);
+    }
+
+    #[test]
+    #[ignore = "needs mkfs.xfs, run with cargo test -- --ignored"]
+    fn xfs_mkfs_v4_volume() {
+        let options = [
+            "-m",
+            "crc=0",
+            "-n",
+            "ftype=0",
+            "-b",
+            "size=4096",
+            "-i",
+            "size=256",
+        ];
+        let disk = mkfs_xfs_image("xfsv4", &options, populate_xfs);
+        let mut fs = XfsFs::new(disk).unwrap();
+        assert_eq!(fs.label(), Some("xfsv4"));
+        assert!(!fs.has_crc());
+        // 256 byte inodes leave 156 bytes after the v2 core
+        check_mkfs_xfs_tree(&mut fs, 9);
+    }
+
+    /// Compresses with the host gzip, returning the raw deflate stream inside
+    fn host_deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let stream = host_gzip(data, &["-n", &format!("-{}", level)]);
+        stream[10..stream.len() - 8].to_vec()
+    }
+
+    /// Compresses standard input with the host gzip
+    fn host_gzip(data: &[u8], options: &[&str]) -> Vec<u8> {
+        host_compress("gzip", data, options)
+    }
+
+    /// Compresses standard input with a host tool taking gzip like options
+    fn host_compress(tool: &str, data: &[u8], options: &[&str]) -> Vec<u8> {
+        let mut child = Command::new(tool)
+            .arg("-c")
+            .args(options)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|_| panic!("{} is needed to build the compressed fixtures", tool));
+        let mut stdin = child.stdin.take().unwrap();
+        let input = data.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&input).unwrap());
+        let output = child.wait_with_output().unwrap();
+        writer.join().unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    fn zlib_wrap(deflate: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut adler = zlib::Adler32::new();
+        adler.update(data);
+        let mut stream = vec![0x78, 0x9c];
+        stream.extend_from_slice(deflate);
+        stream.extend_from_slice(&adler.finish().to_be_bytes());
+        stream
+    }
+
+    /// Text-like data with repeats near and far, compressible but not trivially
+    fn compressible(len: usize, seed: u32) -> Vec<u8> {
+        let words = [
+            "kernel",
+            "initrd",
+            "console=hvc0",
+            "root=",
+            "/boot/",
+            "ppc64le",
+            "\n",
+        ];
+        let mut state = seed;
+        let mut data = Vec::with_capacity(len + 16);
+        while data.len() < len {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            match state >> 28 {
+                0..=9 => {
+                    data.extend_from_slice(words[(state >> 8) as usize % words.len()].as_bytes())
+                }
+                _ => data.push((state >> 16) as u8),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn inflate_host_streams() {
+        let inputs = [
+            Vec::new(),
+            b"a".to_vec(),
+            compressible(200_000, 1),
+            pattern(100_000, 3),
+            vec![0u8; 300_000],
+        ];
+        for data in &inputs {
+            for level in [1, 6, 9] {
+                let deflate = host_deflate(data, level);
+                let mut inflate = Inflate::new(Cursor::new(&deflate));
+                assert_eq!(&read_all(&mut inflate, 1000), data);
+                assert!(inflate.is_done());
+                assert_eq!(inflate.total_out(), data.len() as u64);
+
+                let stream = zlib_wrap(&deflate, data);
+                let mut zlib = Zlib::new(Cursor::new(&stream)).unwrap();
+                assert_eq!(&read_all(&mut zlib, 77777), data);
+            }
+        }
+
+        // Stored blocks
+        let data = pattern(5000, 1);
+        let mut stored = vec![0x00, 0x88, 0x13, 0x77, 0xec];
+        stored.extend_from_slice(&data);
+        stored.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(
+            read_all(&mut Inflate::new(Cursor::new(&stored)), 4096),
+            data
+        );
+    }
+
+    #[test]
+    fn inflate_errors() {
+        let data = compressible(50_000, 2);
+        let deflate = host_deflate(&data, 9);
+        let mut truncated = Inflate::new(Cursor::new(&deflate[..deflate.len() / 2]));
+        let mut buf = vec![0u8; data.len()];
+        assert!(truncated.read_exact(&mut buf).is_err());
+
+        let mut stream = zlib_wrap(&deflate, &data);
+        let len = stream.len();
+        stream[len - 1] ^= 1;
+        let mut zlib = Zlib::new(Cursor::new(&stream)).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        zlib.read_exact(&mut buf).unwrap();
+        assert!(zlib.read(&mut buf).is_err());
+
+        assert!(Zlib::new(Cursor::new(&[0x78, 0x9d])).is_err());
+        assert!(Zlib::new(Cursor::new(&[0x78, 0xbb])).is_err());
+        let invalid_block = [0x07];
+        assert!(Inflate::new(Cursor::new(&invalid_block))
+            .read(&mut buf)
+            .is_err());
+        // Fixed block starting with a match, before any output
+        let far = [0x03, 0x02, 0x00];
+        assert!(Inflate::new(Cursor::new(&far)).read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn gzip_host_streams() {
+        let inputs = [Vec::new(), compressible(300_000, 4), vec![0u8; 200_000]];
+        for data in &inputs {
+            for options in [&["-1"][..], &["-9", "-n"]] {
+                let stream = host_gzip(data, options);
+                let mut gzip = Gzip::new(Cursor::new(&stream)).unwrap();
+                assert_eq!(&read_all(&mut gzip, 65536), data);
+                assert_eq!(gzip.name(), None);
+            }
+        }
+
+        // Name and time stored by gzip for files
+        let dir = std::env::temp_dir().join(format!("ieee1275-gzip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("initrd.img");
+        let data = compressible(70_000, 5);
+        std::fs::write(&path, &data).unwrap();
+        let status = Command::new("touch")
+            .args(["-d", "@1600000000"])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new("gzip")
+            .args(["-c", "-N"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut gzip = Gzip::new(Cursor::new(&output.stdout)).unwrap();
+        assert_eq!(gzip.name(), Some(&b"initrd.img"[..]));
+        assert_eq!(gzip.mtime(), 1_600_000_000);
+        assert_eq!(read_all(&mut gzip, 1000), data);
+
+        // Optional header fields gzip does not write
+        let deflate = host_deflate(&data, 6);
+        let mut stream = vec![
+            0x1f, 0x8b, 8, 0x1e, 0, 0, 0, 0, 0, 3, 3, 0, b'x', b'y', b'z',
+        ];
+        stream.extend_from_slice(b"vmlinux\0built by hand\0");
+        let hcrc = crc32(&stream) as u16;
+        stream.extend_from_slice(&hcrc.to_le_bytes());
+        stream.extend_from_slice(&deflate);
+        stream.extend_from_slice(&crc32(&data).to_le_bytes());
+        stream.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let mut gzip = Gzip::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(gzip.name(), Some(&b"vmlinux"[..]));
+        assert_eq!(read_all(&mut gzip, 4096), data);
+
+        // Concatenated members, as left by cat a.gz b.gz, and padding after the last one
+        let (first, second) = (compressible(100_000, 27), noise(20_000, 28));
+        let mut members = host_gzip(&first, &["-n"]);
+        members.extend_from_slice(&host_gzip(&[], &["-1"]));
+        members.extend_from_slice(&output.stdout);
+        members.extend_from_slice(&host_gzip(&second, &["-9"]));
+        let expected = [&first[..], &data, &second].concat();
+        let mut gzip = Gzip::new(Cursor::new(&members)).unwrap();
+        assert_eq!(gzip.name(), None);
+        assert_eq!(read_all(&mut gzip, 7000), expected);
+        members.extend_from_slice(&[0; 512]);
+        let (format, mut reader) = compress::decompress(Cursor::new(&members)).unwrap();
+        assert_eq!(format, Some(Format::Gzip));
+        assert_eq!(read_all(&mut reader, 65536), expected);
+
+        // Files are decompressed when their magic is known
+        let (format, mut reader) = compress::decompress(Cursor::new(&stream)).unwrap();
+        assert_eq!(format, Some(Format::Gzip));
+        assert_eq!(read_all(&mut reader, 3000), data);
+        for plain in [&b""[..], b"\x1f", b"plain text"] {
+            let (format, mut reader) = compress::decompress(Cursor::new(plain)).unwrap();
+            assert_eq!(format, None);
+            assert_eq!(read_all(&mut reader, 3), plain);
+        }
+    }
+
+    #[test]
+    fn gzip_errors() {
+        let data = compressible(50_000, 6);
+        let stream = host_gzip(&data, &["-n"]);
+        let len = stream.len();
+
+        // Checksum and size in the trailer
+        for offset in [len - 8, len - 1] {
+            let mut corrupted = stream.clone();
+            corrupted[offset] ^= 1;
+            let mut gzip = Gzip::new(Cursor::new(&corrupted)).unwrap();
+            let mut buf = vec![0u8; data.len()];
+            gzip.read_exact(&mut buf).unwrap();
+            assert!(gzip.read(&mut buf).is_err());
+        }
+        let mut gzip = Gzip::new(Cursor::new(&stream[..len - 4])).unwrap();
+        let mut buf = vec![0u8; data.len() + 1];
+        assert!(gzip.read_exact(&mut buf).is_err());
+
+        assert!(Gzip::new(Cursor::new(&stream[..8])).is_err());
+        for (offset, value) in [(0, 0x1e), (2, 7), (3, 0x20)] {
+            let mut corrupted = stream.clone();
+            corrupted[offset] = value;
+            assert!(Gzip::new(Cursor::new(&corrupted)).is_err());
+        }
+        let mut header = stream[..10].to_vec();
+        header[3] = 0x02;
+        header.extend_from_slice(&[0x12, 0x34]);
+        assert!(Gzip::new(Cursor::new(&header)).is_err());
+        header[3] = 0x08;
+        assert!(Gzip::new(Cursor::new(&header)).is_err());
+
+        // Members after the first are checked the same way
+        let mut members = stream.clone();
+        members.extend_from_slice(&stream);
+        let decode = |stream: &[u8]| try_read_all(&mut Gzip::new(Cursor::new(stream))?);
+        assert_eq!(decode(&members).unwrap().len(), 2 * data.len());
+        let mut corrupted = members.clone();
+        corrupted[2 * len - 6] ^= 1;
+        assert!(decode(&corrupted).is_err());
+        let mut corrupted = members.clone();
+        corrupted[len + 2] = 7;
+        assert!(decode(&corrupted).is_err());
+        for end in [len + 2, len + 9, len + len / 2] {
+            assert!(decode(&members[..end]).is_err(), "{}", end);
+        }
+    }
+
+    /// Bytes that do not compress
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Reads a stream to its end, keeping the error
+    fn try_read_all<R: Read>(reader: &mut R) -> Result<Vec<u8>, &'static str> {
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; 10_000];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(data),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    /// Machine code like data, with relative branches the PowerPC filter rewrites
+    fn powerpc_code(len: usize) -> Vec<u8> {
+        let mut code = compressible(len, 8);
+        for (i, word) in code.chunks_exact_mut(4).enumerate() {
+            if i % 5 == 0 {
+                let offset = ((i * 37 % 4096) as u32 * 4).wrapping_sub(8192);
+                word.copy_from_slice(&(0x4800_0001 | (offset & 0x03ff_fffc)).to_be_bytes());
+            }
+        }
+        code
+    }
+
+    #[test]
+    fn xz_host_streams() {
+        let inputs = [
+            Vec::new(),
+            compressible(300_000, 9),
+            noise(150_000, 10),
+            [compressible(70_000, 11), noise(70_000, 12)].concat(),
+        ];
+        let options: [&[&str]; 5] = [
+            &["-0"],
+            &["-6", "--check=crc32"],
+            &["-9", "--check=none"],
+            &["--check=crc64", "--block-size=65536"],
+            &["--lzma2=preset=6,lc=1,lp=3,pb=0"],
+        ];
+        for data in &inputs {
+            for options in options {
+                let stream = host_compress("xz", data, options);
+                let mut xz = Xz::new(Cursor::new(&stream)).unwrap();
+                assert_eq!(&read_all(&mut xz, 77777), data, "{:?}", options);
+            }
+        }
+
+        // Kernel images go through the branch filter
+        let code = powerpc_code(200_000);
+        let stream = host_compress("xz", &code, &["--powerpc", "--lzma2", "--check=crc32"]);
+        assert_eq!(
+            read_all(&mut Xz::new(Cursor::new(&stream)).unwrap(), 4093),
+            code
+        );
+        let stream = host_compress("xz", &code[..4099], &["--powerpc=start=4096", "--lzma2"]);
+        assert_eq!(
+            read_all(&mut Xz::new(Cursor::new(&stream)).unwrap(), 1),
+            &code[..4099]
+        );
+
+        // Concatenated streams with stream padding
+        let (first, second) = (compressible(5000, 13), noise(3000, 14));
+        let mut stream = host_compress("xz", &first, &[]);
+        stream.extend_from_slice(&[0; 8]);
+        stream.extend_from_slice(&host_compress("xz", &second, &["--check=crc32"]));
+        stream.extend_from_slice(&[0; 4]);
+        let mut xz = Xz::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(read_all(&mut xz, 1000), [first, second].concat());
+
+        // Raw LZMA2
+        let data = inputs[3].clone();
+        let raw = host_compress("xz", &data, &["--format=raw", "--lzma2=dict=64KiB"]);
+        let mut lzma2 = Lzma2::new(Cursor::new(&raw), 64 * 1024);
+        assert_eq!(read_all(&mut lzma2, 5000), data);
+
+        let (format, mut reader) = compress::decompress(Cursor::new(&stream)).unwrap();
+        assert_eq!(format, Some(Format::Xz));
+        assert_eq!(read_all(&mut reader, 3000).len(), 8000);
+    }
+
+    #[test]
+    fn xz_errors() {
+        let data = compressible(100_000, 15);
+        let stream = host_compress("xz", &data, &["--check=crc64"]);
+        let len = stream.len();
+        let decode = |stream: &[u8]| try_read_all(&mut Xz::new(Cursor::new(stream))?);
+        assert_eq!(decode(&stream), Ok(data.clone()));
+
+        // Compressed data, check, index and footer
+        for offset in [len / 2, len - 40, len - 20, len - 10, len - 2] {
+            let mut corrupted = stream.clone();
+            corrupted[offset] ^= 0x10;
+            assert!(decode(&corrupted).is_err(), "{}", offset);
+        }
+        for end in [6, 12, len / 2, len - 12, len - 1] {
+            assert!(decode(&stream[..end]).is_err(), "{}", end);
+        }
+        let mut padded = stream.clone();
+        padded.extend_from_slice(&[0, 0]);
+        assert!(decode(&padded).is_err());
+        let mut corrupted = stream.clone();
+        corrupted[7] = 0x0a;
+        assert!(decode(&corrupted).is_err());
+
+        let stream = host_compress("xz", &data, &["--check=sha256"]);
+        assert!(Xz::new(Cursor::new(&stream)).is_err());
+        let stream = host_compress("xz", &data, &["--x86", "--lzma2"]);
+        assert!(decode(&stream).is_err());
+    }
+
+    #[test]
+    fn zstd_host_streams() {
+        let inputs = [
+            Vec::new(),
+            compressible(300_000, 16),
+            noise(150_000, 17),
+            [
+                compressible(70_000, 18),
+                noise(70_000, 19),
+                compressible(9, 20),
+            ]
+            .concat(),
+        ];
+        let options: [&[&str]; 5] = [
+            &["-1"],
+            &["-19"],
+            &["-3", "--no-check"],
+            &["--fast=3"],
+            &["--ultra", "-22", "--no-content-size"],
+        ];
+        for data in &inputs {
+            for options in options {
+                let stream = host_compress("zstd", data, options);
+                let mut zstd = Zstd::new(Cursor::new(&stream)).unwrap();
+                assert_eq!(&read_all(&mut zstd, 77777), data, "{:?}", options);
+            }
+        }
+
+        // Small windows keep the history trimmed while matches reach back
+        let data = [compressible(600_000, 21), noise(50_000, 22)].concat();
+        let stream = host_compress("zstd", &data, &["--zstd=wlog=10"]);
+        assert_eq!(
+            read_all(&mut Zstd::new(Cursor::new(&stream)).unwrap(), 4096),
+            data
+        );
+
+        // Concatenated frames with a skippable frame in between
+        let (first, second) = (compressible(5000, 23), noise(3000, 24));
+        let mut stream = host_compress("zstd", &first, &[]);
+        stream.extend_from_slice(&0x184d_2a5au32.to_le_bytes());
+        stream.extend_from_slice(&3u32.to_le_bytes());
+        stream.extend_from_slice(b"abc");
+        stream.extend_from_slice(&host_compress("zstd", &second, &["--no-check"]));
+        let mut zstd = Zstd::new(Cursor::new(&stream)).unwrap();
+        assert_eq!(read_all(&mut zstd, 1000), [first, second].concat());
+
+        let (format, mut reader) = compress::decompress(Cursor::new(&stream)).unwrap();
+        assert_eq!(format, Some(Format::Zstd));
+        assert_eq!(read_all(&mut reader, 3000).len(), 8000);
+
+        let mut hash = Xxh64::new(0);
+        assert_eq!(hash.finish(), 0xef46_db37_51d8_e999);
+        hash.update(b"abc");
+        assert_eq!(hash.finish(), 0x44bc_2cf5_ad77_0999);
+    }
+
+    #[test]
+    fn zstd_errors() {
+        let data = compressible(100_000, 25);
+        let stream = host_compress("zstd", &data, &[]);
+        let len = stream.len();
+        let decode = |stream: &[u8]| try_read_all(&mut Zstd::new(Cursor::new(stream))?);
+        assert_eq!(decode(&stream), Ok(data.clone()));
+
+        for end in [3, 6, len / 2, len - 4, len - 1] {
+            assert!(decode(&stream[..end]).is_err(), "{}", end);
+        }
+        let mut corrupted = stream.clone();
+        corrupted[len - 2] ^= 0x01;
+        assert!(decode(&corrupted).is_err());
+        let mut corrupted = stream.clone();
+        corrupted[len / 2] ^= 0x10;
+        assert!(decode(&corrupted).is_err());
+        // Reserved header bit
+        let mut corrupted = stream.clone();
+        corrupted[4] |= 0x08;
+        assert!(decode(&corrupted).is_err());
+        let mut padded = stream.clone();
+        padded.extend_from_slice(&[0, 0]);
+        assert!(decode(&padded).is_err());
+
+        // Dictionaries are not supported
+        let mut with_dict = 0xfd2f_b528u32.to_le_bytes().to_vec();
+        with_dict.extend_from_slice(&[0x21, 0x50, 0x07]);
+        assert!(Zstd::new(Cursor::new(&with_dict)).is_err());
+    }
+
+    /// Archive written by the host bsdcpio with the given paths, in order
+    fn host_cpio(name: &str, paths: &[&str], populate: impl Fn(&Path)) -> Vec<u8> {
+        let root = std::env::temp_dir().join(format!("ieee1275-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        populate(&root);
+        let mut child = Command::new("bsdcpio")
+            .args(["-o", "--quiet", "-H", "newc"])
+            .current_dir(&root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("bsdcpio from libarchive is needed to build the cpio fixtures");
+        let list = paths.join("\n") + "\n";
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(list.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        std::fs::remove_dir_all(&root).unwrap();
+        output.stdout
+    }
+
+    #[test]
+    fn cpio_archives() {
+        let firmware = pattern(70_001, 4);
+        let archive = host_cpio(
+            "cpio",
+            &["etc", "etc/hostname", "lib", "lib/firmware.bin", "init"],
+            |root| {
+                std::fs::create_dir(root.join("etc")).unwrap();
+                std::fs::write(root.join("etc/hostname"), "ppc64le\n").unwrap();
+                std::fs::create_dir(root.join("lib")).unwrap();
+                std::fs::write(root.join("lib/firmware.bin"), &firmware).unwrap();
+                symlink("usr/lib/systemd/systemd", root.join("init")).unwrap();
+            },
+        );
+        let entries: Vec<cpio::Entry> = cpio::entries(&archive).map(Result::unwrap).collect();
+        let names: Vec<&str> = entries.iter().map(|e| e.name).collect();
+        assert_eq!(
+            names,
+            ["etc", "etc/hostname", "lib", "lib/firmware.bin", "init"]
+        );
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[1].kind(), FileType::Regular);
+        assert_eq!(entries[1].data, b"ppc64le\n");
+        assert_eq!(entries[3].data, &firmware[..]);
+        assert!(entries[4].is_symlink());
+        assert_eq!(entries[4].data, b"usr/lib/systemd/systemd");
+
+        // Extra configuration appended for the kernel to unpack after the original
+        let mut writer = cpio::Writer::with_crc();
+        writer.set_mtime(1_600_000_000);
+        writer.dir("etc/cmdline.d", 0o755).unwrap();
+        writer
+            .file("etc/cmdline.d/50-boot.conf", 0o644, b"rd.break\n")
+            .unwrap();
+        writer
+            .symlink("etc/localtime", "../usr/share/zoneinfo/UTC")
+            .unwrap();
+        assert!(writer.file("", 0o644, b"").is_err());
+        assert!(writer.file("a\0b", 0o644, b"").is_err());
+        let extra = writer.finish();
+        assert_eq!(&extra[..6], cpio::MAGIC_CRC);
+        assert_eq!(extra.len() % 4, 0);
+
+        let prom = PROM::new(mock_entry).unwrap();
+        let initrd = &archive[..archive.len() - 2];
+        let region = cpio::concat(&prom, initrd, &extra).unwrap();
+        assert_eq!(region.size(), archive.len() + extra.len());
+        let mut all = cpio::entries(region.as_slice());
+        let appended: Vec<cpio::Entry> = all.by_ref().skip(5).map(Result::unwrap).collect();
+        assert_eq!(all.position(), region.size());
+        assert_eq!(appended.len(), 3);
+        assert_eq!(appended[0].name, "etc/cmdline.d");
+        assert_eq!(appended[0].mode, 0o40755);
+        assert_eq!(appended[1].mode, 0o100644);
+        assert_eq!(appended[1].data, b"rd.break\n");
+        assert_eq!(appended[1].mtime, 1_600_000_000);
+        assert_eq!((appended[1].uid, appended[1].ino), (0, 2));
+        assert_eq!(appended[2].data, b"../usr/share/zoneinfo/UTC");
+        drop(region);
+
+        // The host cpio unpacks what the writer produces
+        let mut writer = cpio::Writer::new();
+        writer.dir("keys", 0o700).unwrap();
+        writer.file("keys/boot.pem", 0o600, &firmware).unwrap();
+        let extra = writer.finish();
+        let dir = std::env::temp_dir().join(format!("ieee1275-cpio-out-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut child = Command::new("bsdcpio")
+            .args(["-i", "--quiet", "-d"])
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&extra).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(std::fs::read(dir.join("keys/boot.pem")).unwrap(), firmware);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cpio_errors() {
+        let mut writer = cpio::Writer::with_crc();
+        writer.file("init", 0o755, b"#!/bin/sh\n").unwrap();
+        let archive = writer.finish();
+        let entries = |data: &[u8]| {
+            cpio::entries(data)
+                .collect::<Result<Vec<_>, _>>()
+                .map(|e| e.len())
+        };
+        assert_eq!(entries(&archive), Ok(1));
+        assert_eq!(entries(&[0; 16]), Ok(0));
+
+        for end in [50, 112, 120, 125] {
+            assert!(entries(&archive[..end]).is_err(), "{}", end);
+        }
+        let mut corrupted = archive.clone();
+        corrupted[120] ^= 1;
+        assert_eq!(entries(&corrupted), Err("cpio file checksum mismatch"));
+        let mut corrupted = archive.clone();
+        corrupted[20] = b'g';
+        assert!(entries(&corrupted).is_err());
+        let mut corrupted = archive.clone();
+        corrupted[114] = b'!';
+        assert!(entries(&corrupted).is_err());
+
+        // Compressed segments are not expanded, iteration stops there
+        let mut initrd = archive.clone();
+        initrd.extend_from_slice(&host_gzip(&archive, &["-n"]));
+        let mut iter = cpio::entries(&initrd);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    enum BtrfsNode {
+        /// File stored inline when small, in one extent otherwise
+        File(String, Vec<u8>),
+        /// File stored in zlib compressed extents of up to 128 KiB
+        Compressed(String, Vec<u8>),
+        /// Same as ```Compressed``` with zstd
+        Zstd(String, Vec<u8>),
+        /// File size and (offset, data, preallocated) extents, holes elsewhere
+        Sparse(String, u64, Vec<(u64, Vec<u8>, bool)>),
+        Dir(String, Vec<BtrfsNode>),
+        Link(String, String),
+        Subvolume(String, Vec<BtrfsNode>),
+    }
+
+    type BtrfsKey = (u64, u8, u64);
+    type BtrfsItems = BTreeMap<BtrfsKey, Vec<u8>>;
+
+    const BTRFS_SYSTEM_CHUNK: (u64, u64, u64) = (0x10_0000, 0x10_0000, 0x10_0000);
+    const BTRFS_DATA_CHUNK: (u64, u64, u64) = (0x400_0000, 0x60_0000, 0x20_0000);
+    const BTRFS_FSID: [u8; 16] = [0x42; 16];
+
+    fn btrfs_key(key: BtrfsKey) -> Vec<u8> {
+        let mut raw = key.0.to_le_bytes().to_vec();
+        raw.push(key.1);
+        raw.extend_from_slice(&key.2.to_le_bytes());
+        raw
+    }
+
+    /// CRC-32C register seeded with ~1 and without the final inversion, bit by bit
+    fn btrfs_name_hash(name: &str) -> u64 {
+        let mut state = !1u32;
+        for byte in name.bytes() {
+            state ^= byte as u32;
+            for _ in 0..8 {
+                state = match state & 1 {
+                    1 => (state >> 1) ^ 0x82f6_3b78,
+                    _ => state >> 1,
+                };
+            }
+        }
+        state as u64
+    }
+
+    fn btrfs_chunk(length: u64, kind: u64, physical: u64) -> Vec<u8> {
+        let mut chunk = vec![0u8; 48 + 32];
+        chunk[..8].copy_from_slice(&length.to_le_bytes());
+        chunk[8..16].copy_from_slice(&2u64.to_le_bytes());
+        chunk[16..24].copy_from_slice(&0x1_0000u64.to_le_bytes());
+        chunk[24..32].copy_from_slice(&kind.to_le_bytes());
+        chunk[32..36].copy_from_slice(&4096u32.to_le_bytes());
+        chunk[36..40].copy_from_slice(&4096u32.to_le_bytes());
+        chunk[40..44].copy_from_slice(&4096u32.to_le_bytes());
+        chunk[44..46].copy_from_slice(&1u16.to_le_bytes());
+        chunk[48..56].copy_from_slice(&1u64.to_le_bytes());
+        chunk[56..64].copy_from_slice(&physical.to_le_bytes());
+        chunk
+    }
+
+    fn btrfs_inode(size: u64, mode: u32) -> Vec<u8> {
+        let mut inode = vec![0u8; 160];
+        inode[..8].copy_from_slice(&1u64.to_le_bytes());
+        inode[16..24].copy_from_slice(&size.to_le_bytes());
+        inode[40..44].copy_from_slice(&1u32.to_le_bytes());
+        inode[52..56].copy_from_slice(&mode.to_le_bytes());
+        inode
+    }
+
+    fn btrfs_dir_item(location: BtrfsKey, kind: u8, name: &str) -> Vec<u8> {
+        let mut item = btrfs_key(location);
+        item.extend_from_slice(&1u64.to_le_bytes());
+        item.extend_from_slice(&0u16.to_le_bytes());
+        item.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        item.push(kind);
+        item.extend_from_slice(name.as_bytes());
+        item
+    }
+
+    /// File extent item: (compression, inline data) or (compression, disk, ram size, offset, len)
+    fn btrfs_extent(compression: u8, kind: u8, ram: u64, tail: &[u8]) -> Vec<u8> {
+        let mut item = 1u64.to_le_bytes().to_vec();
+        item.extend_from_slice(&ram.to_le_bytes());
+        item.extend_from_slice(&[compression, 0, 0, 0, kind]);
+        item.extend_from_slice(tail);
+        item
+    }
+
+    fn btrfs_disk_extent(start: u64, disk_len: u64, offset: u64, len: u64) -> Vec<u8> {
+        [start, disk_len, offset, len]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
+
+    struct BtrfsBuilder {
+        image: Vec<u8>,
+        next_system: u64,
+        next_data: u64,
+        next_subvolume: u64,
+        root_items: BtrfsItems,
+        /// Trees of the subvolumes, the top level one included
+        subvolumes: Vec<(u64, BtrfsItems)>,
+    }
+
+    impl BtrfsBuilder {
+        fn new() -> Self {
+            BtrfsBuilder {
+                image: vec![0u8; (BTRFS_DATA_CHUNK.2 + BTRFS_DATA_CHUNK.1) as usize],
+                next_system: BTRFS_SYSTEM_CHUNK.0,
+                next_data: BTRFS_DATA_CHUNK.0,
+                next_subvolume: 256,
+                root_items: BTreeMap::new(),
+                subvolumes: Vec::new(),
+            }
+        }
+
+        fn physical(logical: u64) -> usize {
+            for (start, len, physical) in [BTRFS_SYSTEM_CHUNK, BTRFS_DATA_CHUNK] {
+                if (start..start + len).contains(&logical) {
+                    return (physical + logical - start) as usize;
+                }
+            }
+            panic!("unmapped logical address {:#x}", logical);
+        }
+
+        fn alloc(&mut self, data: &[u8]) -> u64 {
+            let logical = self.next_data;
+            self.next_data += (data.len() as u64).next_multiple_of(4096);
+            let physical = Self::physical(logical);
+            self.image[physical..physical + data.len()].copy_from_slice(data);
+            logical
+        }
+
+        fn write_block(&mut self, system: bool, owner: u64, level: u8, body: &[u8]) -> u64 {
+            let logical = match system {
+                true => &mut self.next_system,
+                false => &mut self.next_data,
+            };
+            let address = *logical;
+            *logical += 4096;
+            let mut block = vec![0u8; 4096];
+            block[32..48].copy_from_slice(&BTRFS_FSID);
+            block[48..56].copy_from_slice(&address.to_le_bytes());
+            block[56..64].copy_from_slice(&1u64.to_le_bytes());
+            block[80..88].copy_from_slice(&1u64.to_le_bytes());
+            block[88..96].copy_from_slice(&owner.to_le_bytes());
+            block[100] = level;
+            block[101..101 + body.len() - 4].copy_from_slice(&body[4..]);
+            block[96..100].copy_from_slice(&body[..4]);
+            let crc = crc32c(&block[32..]);
+            block[..4].copy_from_slice(&crc.to_le_bytes());
+            let physical = Self::physical(address);
+            self.image[physical..physical + 4096].copy_from_slice(&block);
+            address
+        }
+
+        /// Packs items into leaves and nodes, returning the root block and level
+        fn write_tree(&mut self, owner: u64, items: &BtrfsItems, system: bool) -> (u64, u8) {
+            let mut level_items: Vec<(BtrfsKey, u64)> = Vec::new();
+            let mut leaf: Vec<(&BtrfsKey, &Vec<u8>)> = Vec::new();
+            let mut used = 0;
+            let mut flush = |builder: &mut Self, leaf: &mut Vec<(&BtrfsKey, &Vec<u8>)>| {
+                let mut body = (leaf.len() as u32).to_le_bytes().to_vec();
+                let mut data_end = 4096 - 101;
+                let mut data = vec![0u8; 4096 - 101];
+                for (key, value) in leaf.iter() {
+                    data_end -= value.len();
+                    data[data_end..data_end + value.len()].copy_from_slice(value);
+                    body.extend_from_slice(&btrfs_key(**key));
+                    body.extend_from_slice(&(data_end as u32).to_le_bytes());
+                    body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                }
+                let items_end = body.len() - 4;
+                body.extend_from_slice(&data[items_end..]);
+                let address = builder.write_block(system, owner, 0, &body);
+                level_items.push((*leaf[0].0, address));
+                leaf.clear();
+            };
+            for (key, value) in items {
+                if used + 25 + value.len() > 4096 - 101 {
+                    flush(self, &mut leaf);
+                    used = 0;
+                }
+                leaf.push((key, value));
+                used += 25 + value.len();
+            }
+            flush(self, &mut leaf);
+
+            let mut level = 0;
+            while level_items.len() > 1 {
+                level += 1;
+                let mut parents = Vec::new();
+                for children in level_items.chunks(121) {
+                    let mut body = (children.len() as u32).to_le_bytes().to_vec();
+                    for (key, address) in children {
+                        body.extend_from_slice(&btrfs_key(*key));
+                        body.extend_from_slice(&address.to_le_bytes());
+                        body.extend_from_slice(&1u64.to_le_bytes());
+                    }
+                    parents.push((children[0].0, self.write_block(system, owner, level, &body)));
+                }
+                level_items = parents;
+            }
+            (level_items[0].1, level)
+        }
+
+        fn file(&mut self, items: &mut BtrfsItems, ino: u64, data: &[u8], compression: u8) {
+            let compress = |data: &[u8]| match compression {
+                1 => zlib_wrap(&host_deflate(data, 6), data),
+                3 => host_compress("zstd", data, &["--no-check"]),
+                _ => data.to_vec(),
+            };
+            if data.len() < 2048 {
+                let extent = btrfs_extent(compression, 0, data.len() as u64, &compress(data));
+                items.insert((ino, 108, 0), extent);
+                return;
+            }
+            if compression == 0 {
+                let start = self.alloc(data);
+                let len = (data.len() as u64).next_multiple_of(4096);
+                let disk = btrfs_disk_extent(start, len, 0, len);
+                items.insert((ino, 108, 0), btrfs_extent(0, 1, len, &disk));
+                return;
+            }
+            for (i, chunk) in data.chunks(128 * 1024).enumerate() {
+                let mut padded = chunk.to_vec();
+                padded.resize(chunk.len().next_multiple_of(4096), 0);
+                let stream = compress(&padded);
+                let start = self.alloc(&stream);
+                let disk_len = (stream.len() as u64).next_multiple_of(4096);
+                let ram = padded.len() as u64;
+                let offset = i as u64 * 128 * 1024;
+                // Split the first extent in two items, as partial overwrites leave them
+                let parts = match i == 0 && ram > 8192 {
+                    true => vec![(0, 8192), (8192, ram - 8192)],
+                    false => vec![(0, ram)],
+                };
+                for (within, len) in parts {
+                    let disk = btrfs_disk_extent(start, disk_len, within, len);
+                    let extent = btrfs_extent(compression, 1, ram, &disk);
+                    items.insert((ino, 108, offset + within), extent);
+                }
+            }
+        }
+
+        fn dir(
+            &mut self,
+            items: &mut BtrfsItems,
+            subvolume: u64,
+            ino: u64,
+            parent: u64,
+            children: &[BtrfsNode],
+        ) {
+            let mut next_ino = items.keys().map(|k| k.0).max().unwrap_or(256).max(ino) + 1;
+            let mut size = 0;
+            for (index, child) in children.iter().enumerate() {
+                let index = index as u64 + 2;
+                let mut child_ino = next_ino;
+                next_ino += 1;
+                let (name, kind, location) = match child {
+                    BtrfsNode::File(name, data)
+                    | BtrfsNode::Compressed(name, data)
+                    | BtrfsNode::Zstd(name, data) => {
+                        let compression = match child {
+                            BtrfsNode::Compressed(..) => 1,
+                            BtrfsNode::Zstd(..) => 3,
+                            _ => 0,
+                        };
+                        items.insert((child_ino, 1, 0), btrfs_inode(data.len() as u64, 0o100644));
+                        self.file(items, child_ino, data, compression);
+                        (name, 1, (child_ino, 1, 0))
+                    }
+                    BtrfsNode::Sparse(name, file_size, extents) => {
+                        items.insert((child_ino, 1, 0), btrfs_inode(*file_size, 0o100644));
+                        for (offset, data, prealloc) in extents {
+                            let start = self.alloc(data);
+                            let len = data.len() as u64;
+                            let kind = if *prealloc { 2 } else { 1 };
+                            let disk = btrfs_disk_extent(start, len, 0, len);
+                            items.insert(
+                                (child_ino, 108, *offset),
+                                btrfs_extent(0, kind, len, &disk),
+                            );
+                        }
+                        // An explicit hole extent, as written without no-holes
+                        let disk = btrfs_disk_extent(0, 0, 0, 4096);
+                        items.insert((child_ino, 108, 4096), btrfs_extent(0, 1, 4096, &disk));
+                        (name, 1, (child_ino, 1, 0))
+                    }
+                    BtrfsNode::Link(name, target) => {
+                        items.insert(
+                            (child_ino, 1, 0),
+                            btrfs_inode(target.len() as u64, 0o120777),
+                        );
+                        let extent = btrfs_extent(0, 0, target.len() as u64, target.as_bytes());
+                        items.insert((child_ino, 108, 0), extent);
+                        (name, 7, (child_ino, 1, 0))
+                    }
+                    BtrfsNode::Dir(name, grandchildren) => {
+                        self.dir(items, subvolume, child_ino, ino, grandchildren);
+                        next_ino = items.keys().map(|k| k.0).max().unwrap() + 1;
+                        (name, 2, (child_ino, 1, 0))
+                    }
+                    BtrfsNode::Subvolume(name, grandchildren) => {
+                        let id = self.next_subvolume;
+                        self.next_subvolume += 1;
+                        let mut tree = BTreeMap::new();
+                        self.dir(&mut tree, id, 256, 256, grandchildren);
+                        self.subvolumes.push((id, tree));
+                        let mut root_ref = ino.to_le_bytes().to_vec();
+                        root_ref.extend_from_slice(&index.to_le_bytes());
+                        root_ref.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                        root_ref.extend_from_slice(name.as_bytes());
+                        self.root_items
+                            .insert((subvolume, 156, id), root_ref.clone());
+                        self.root_items.insert((id, 144, subvolume), root_ref);
+                        next_ino -= 1;
+                        child_ino = 256;
+                        (name, 2, (id, 132, u64::MAX))
+                    }
+                };
+                let mut inode_ref = index.to_le_bytes().to_vec();
+                inode_ref.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                inode_ref.extend_from_slice(name.as_bytes());
+                if location.1 == 1 {
+                    items.insert((child_ino, 12, ino), inode_ref);
+                }
+                let entry = btrfs_dir_item(location, kind, name);
+                items
+                    .entry((ino, 84, btrfs_name_hash(name)))
+                    .or_default()
+                    .extend_from_slice(&entry);
+                items.insert((ino, 96, index), entry);
+                size += 2 * name.len() as u64;
+            }
+            items.insert((ino, 1, 0), btrfs_inode(size, 0o40755));
+            if ino == 256 {
+                let mut inode_ref = 0u64.to_le_bytes().to_vec();
+                inode_ref.extend_from_slice(&2u16.to_le_bytes());
+                inode_ref.extend_from_slice(b"..");
+                items.insert((256, 12, parent), inode_ref);
+            }
+        }
+
+        fn build(mut self, label: &str, tree: &[BtrfsNode], default: u64) -> Vec<u8> {
+            let mut top = BTreeMap::new();
+            self.dir(&mut top, 5, 256, 256, tree);
+            self.subvolumes.push((5, top));
+
+            for (id, items) in std::mem::take(&mut self.subvolumes) {
+                let (bytenr, level) = self.write_tree(id, &items, false);
+                let mut root_item = btrfs_inode(0, 0o40755);
+                root_item.resize(439, 0);
+                root_item[160..168].copy_from_slice(&1u64.to_le_bytes());
+                root_item[168..176].copy_from_slice(&256u64.to_le_bytes());
+                root_item[176..184].copy_from_slice(&bytenr.to_le_bytes());
+                root_item[216..220].copy_from_slice(&1u32.to_le_bytes());
+                root_item[238] = level;
+                self.root_items.insert((id, 132, 0), root_item);
+            }
+            let entry = btrfs_dir_item((default, 132, u64::MAX), 2, "default");
+            self.root_items
+                .insert((6, 84, btrfs_name_hash("default")), entry);
+            let root_items = std::mem::take(&mut self.root_items);
+            let root = self.write_tree(1, &root_items, false);
+
+            let mut chunk_items = BTreeMap::new();
+            let system = btrfs_chunk(BTRFS_SYSTEM_CHUNK.1, 2, BTRFS_SYSTEM_CHUNK.2);
+            chunk_items.insert((256, 228, BTRFS_SYSTEM_CHUNK.0), system.clone());
+            let data = btrfs_chunk(BTRFS_DATA_CHUNK.1, 1 | 4, BTRFS_DATA_CHUNK.2);
+            chunk_items.insert((256, 228, BTRFS_DATA_CHUNK.0), data);
+            let chunk_root = self.write_tree(3, &chunk_items, true);
+
+            let size = self.image.len() as u64;
+            let sb = &mut self.image[0x10000..0x11000];
+            sb[0x20..0x30].copy_from_slice(&BTRFS_FSID);
+            sb[0x30..0x38].copy_from_slice(&0x10000u64.to_le_bytes());
+            sb[0x40..0x48].copy_from_slice(b"_BHRfS_M");
+            sb[0x48..0x50].copy_from_slice(&1u64.to_le_bytes());
+            sb[0x50..0x58].copy_from_slice(&root.0.to_le_bytes());
+            sb[0x58..0x60].copy_from_slice(&chunk_root.0.to_le_bytes());
+            sb[0x70..0x78].copy_from_slice(&size.to_le_bytes());
+            sb[0x80..0x88].copy_from_slice(&6u64.to_le_bytes());
+            sb[0x88..0x90].copy_from_slice(&1u64.to_le_bytes());
+            for offset in [0x90, 0x94, 0x98, 0x9c] {
+                sb[offset..offset + 4].copy_from_slice(&4096u32.to_le_bytes());
+            }
+            let mut array = btrfs_key((256, 228, BTRFS_SYSTEM_CHUNK.0));
+            array.extend_from_slice(&system);
+            sb[0xa0..0xa4].copy_from_slice(&(array.len() as u32).to_le_bytes());
+            sb[0xbc..0xc4].copy_from_slice(&0x363u64.to_le_bytes());
+            sb[0xc6] = root.1;
+            sb[0xc7] = chunk_root.1;
+            sb[0xc9..0xd1].copy_from_slice(&1u64.to_le_bytes());
+            sb[0xd1..0xd9].copy_from_slice(&size.to_le_bytes());
+            sb[0x12b..0x12b + label.len()].copy_from_slice(label.as_bytes());
+            sb[0x32b..0x32b + array.len()].copy_from_slice(&array);
+            let crc = crc32c(&sb[32..]);
+            sb[..4].copy_from_slice(&crc.to_le_bytes());
+            self.image
+        }
+    }
+
+    fn btrfs_fedora_tree() -> Vec<BtrfsNode> {
+        let many = (0..300)
+            .map(|i| BtrfsNode::File(format!("entry-{:03}", i), i.to_string().into_bytes()))
+            .collect();
+        let boot = vec![
+            BtrfsNode::Compressed("vmlinuz-6.1".into(), compressible(400_000, 7)),
+            BtrfsNode::File("initramfs-6.1.img".into(), pattern(150_000, 3)),
+            BtrfsNode::Compressed("config-6.1".into(), b"CONFIG_PPC64=y\n".repeat(40)),
+            BtrfsNode::Zstd("System.map-6.1".into(), compressible(300_000, 26)),
+            BtrfsNode::Zstd("loader.conf".into(), b"timeout 5\n".repeat(30)),
+            BtrfsNode::Dir(
+                "grub2".into(),
+                vec![BtrfsNode::File(
+                    "grub.cfg".into(),
+                    b"set timeout=5\n".to_vec(),
+                )],
+            ),
+            BtrfsNode::Link("vmlinuz".into(), "vmlinuz-6.1".into()),
+        ];
+        let root = vec![
+            BtrfsNode::Dir("boot".into(), boot),
+            BtrfsNode::Link("grub".into(), "/boot/grub2".into()),
+            BtrfsNode::Link("kernel".into(), "boot/../boot/vmlinuz".into()),
+            BtrfsNode::Sparse(
+                "sparse".into(),
+                5 * 4096 + 10,
+                vec![(0, pattern(4096, 1), false), (8192, pattern(4096, 2), true)],
+            ),
+            BtrfsNode::Dir("many".into(), many),
+            BtrfsNode::Subvolume(
+                "machines".into(),
+                vec![BtrfsNode::File(
+                    "os-release".into(),
+                    b"ID=fedora\n".to_vec(),
+                )],
+            ),
+        ];
+        vec![
+            BtrfsNode::Subvolume("root".into(), root),
+            BtrfsNode::Subvolume(
+                "home".into(),
+                vec![BtrfsNode::File("notes".into(), b"home".to_vec())],
+            ),
+            BtrfsNode::File("top-level".into(), b"top".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn btrfs_subvolumes() {
+        let image = BtrfsBuilder::new().build("fedora", &btrfs_fedora_tree(), 256);
+        let mut fs = BtrfsFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.label(), Some("fedora"));
+        assert_eq!(fs.uuid(), &BTRFS_FSID);
+        assert_eq!(fs.sector_size(), 4096);
+        assert_eq!(fs.node_size(), 4096);
+
+        // The default subvolume is the Fedora root one
+        assert_eq!(fs.subvolume(), 256);
+        let root = fs.root().unwrap();
+        let names: Vec<String> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(
+            names,
+            ["boot", "grub", "kernel", "sparse", "many", "machines"]
+        );
+        let mut subvolumes: Vec<(u64, u64, String)> = fs
+            .subvolumes()
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.id, s.parent, s.name))
+            .collect();
+        subvolumes.sort();
+        assert_eq!(
+            subvolumes,
+            [
+                (256, 5, "root".to_string()),
+                (257, 256, "machines".to_string()),
+                (258, 5, "home".to_string()),
+            ]
+        );
+
+        // Nested subvolumes are crossed like directories
+        let machines = fs.lookup("machines").unwrap();
+        assert_eq!(
+            (machines.subvolume, machines.number),
+            (257, btrfs::ROOT_DIR_INODE)
+        );
+        assert_eq!(
+            read_all(&mut fs.open("machines/os-release").unwrap(), 64),
+            b"ID=fedora\n"
+        );
+        assert_eq!(
+            read_all(&mut fs.open("machines/../boot/grub2/grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+
+        fs.set_subvolume("/home").unwrap();
+        assert_eq!(fs.subvolume(), 258);
+        assert_eq!(read_all(&mut fs.open("notes").unwrap(), 64), b"home");
+        assert!(fs.open("boot/grub2/grub.cfg").is_err());
+        fs.set_subvolume_id(5).unwrap();
+        assert_eq!(
+            read_all(&mut fs.open("root/machines/os-release").unwrap(), 64),
+            b"ID=fedora\n"
+        );
+        assert_eq!(read_all(&mut fs.open("top-level").unwrap(), 64), b"top");
+        assert!(fs.set_subvolume("top-level").is_err());
+        assert!(fs.set_subvolume("missing").is_err());
+        assert_eq!(fs.subvolume(), 5);
+        assert!(fs.set_subvolume_id(300).is_err());
+
+        // Many directory entries spread the tree over several levels
+        fs.set_subvolume("root").unwrap();
+        let many = fs.lookup("many").unwrap();
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 300);
+        assert_eq!(read_all(&mut fs.open("many/entry-299").unwrap(), 8), b"299");
+        assert!(fs.open("many").is_err());
+        assert!(fs.open("many/entry-300").is_err());
+        assert!(fs.lookup("many/entry-001/x").is_err());
+    }
+
+    #[test]
+    fn btrfs_extents() {
+        let image = BtrfsBuilder::new().build("", &btrfs_fedora_tree(), 256);
+        let mut fs = BtrfsFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.label(), None);
+
+        // zlib compressed extents, one of them referenced by two items
+        let kernel = compressible(400_000, 7);
+        let mut file = fs.open("kernel").unwrap();
+        assert_eq!(file.size(), 400_000);
+        assert_eq!(read_all(&mut file, 3000), kernel);
+        file.seek(131_000).unwrap();
+        let mut buf = [0u8; 2000];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &kernel[131_000..133_000]);
+        assert!(file.seek(400_001).is_err());
+
+        assert_eq!(
+            read_all(&mut fs.open("boot/initramfs-6.1.img").unwrap(), 10000),
+            pattern(150_000, 3)
+        );
+        // Inline extents, compressed and not
+        assert_eq!(
+            read_all(&mut fs.open("boot/config-6.1").unwrap(), 100),
+            b"CONFIG_PPC64=y\n".repeat(40)
+        );
+        // zstd compressed extents
+        let map = compressible(300_000, 26);
+        let mut file = fs.open("boot/System.map-6.1").unwrap();
+        assert_eq!(read_all(&mut file, 7000), map);
+        file.seek(140_000).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &map[140_000..142_000]);
+        assert_eq!(
+            read_all(&mut fs.open("boot/loader.conf").unwrap(), 100),
+            b"timeout 5\n".repeat(30)
+        );
+        assert_eq!(
+            read_all(&mut fs.open("grub/grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+        let link = fs.lookup_link("grub").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(fs.read_link(&link).unwrap(), "/boot/grub2");
+        let kernel = fs.lookup("kernel").unwrap();
+        assert!(fs.read_link(&kernel).is_err());
+
+        // Explicit and implicit holes, and a preallocated extent
+        let data = read_all(&mut fs.open("sparse").unwrap(), 3000);
+        assert_eq!(data.len(), 5 * 4096 + 10);
+        assert_eq!(&data[..4096], &pattern(4096, 1)[..]);
+        assert!(data[4096..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn btrfs_errors() {
+        let tree = vec![BtrfsNode::File("file".into(), pattern(10000, 4))];
+        let image = BtrfsBuilder::new().build("errors", &tree, 5);
+        let mut fs = BtrfsFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.subvolume(), 5);
+        assert_eq!(
+            read_all(&mut fs.open("file").unwrap(), 4096),
+            pattern(10000, 4)
+        );
+
+        let mut corrupted = image.clone();
+        corrupted[0x10000 + 0x12b] = b'E';
+        assert!(BtrfsFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        // Unknown incompatible features
+        let mut corrupted = image.clone();
+        corrupted[0x10000 + 0xbd] |= 0x10;
+        let crc = crc32c(&corrupted[0x10020..0x11000]);
+        corrupted[0x10000..0x10004].copy_from_slice(&crc.to_le_bytes());
+        assert!(BtrfsFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+
+        // Find the leaf of the top level tree through its owner
+        let leaf = (0..image.len())
+            .step_by(4096)
+            .find(|o| image[o + 32..o + 48] == BTRFS_FSID && image[o + 88] == 5)
+            .unwrap();
+        let mut corrupted = image.clone();
+        corrupted[leaf + 200] ^= 1;
+        let mut fs = BtrfsFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.open("file").is_err());
+
+        assert!(BtrfsFs::new(MemoryDisk::new(&image[..0x10800], 512)).is_err());
+        assert!(BtrfsFs::new(MemoryDisk::new(&image[..0x40_0000], 512)).is_err());
+    }
+
+    enum HfsNode {
+        File(String, Vec<u8>),
+        /// File with a Finder type code
+        Typed(String, [u8; 4], Vec<u8>),
+        /// File with one extent per block and a free block between extents
+        Fragmented(String, Vec<u8>),
+        Dir(String, Vec<HfsNode>),
+        /// Symbolic link, HFS+ only
+        Link(String, String),
+        /// Name of a hard linked inode, HFS+ only
+        HardLink(String, u32, Vec<u8>),
+    }
+
+    /// Catalog or extents record, with the (id, folded name, name) it sorts by
+    type HfsRecord = ((u32, String, String), Vec<u8>, Vec<u8>);
+    /// (file id, first block, extents) record of the extents overflow file
+    type HfsOverflow = (u32, u32, Vec<(u32, u32)>);
+
+    const HFS_PRIVATE_DIR: &str = "\0\0\0\0HFS+ Private Data";
+
+    struct HfsBuilder {
+        image: Vec<u8>,
+        /// HFS+ records, or the original HFS ones
+        plus: bool,
+        hfsx: bool,
+        /// Offset of allocation block 0
+        base: usize,
+        block_size: usize,
+        next_block: u32,
+        next_id: u32,
+        catalog: Vec<HfsRecord>,
+        overflow: Vec<HfsOverflow>,
+        private_dir: Option<u32>,
+        inodes: Vec<u32>,
+        bless: String,
+        blessed: u32,
+    }
+
+    fn hfs_mac_roman(name: &str) -> Vec<u8> {
+        name.chars()
+            .map(|c| match c {
+                'è' => 0x8f,
+                c => c as u8,
+            })
+            .collect()
+    }
+
+    fn hfs_node(kind: u8, height: u8, records: &[Vec<u8>], size: usize) -> Vec<u8> {
+        let mut node = vec![0u8; size];
+        node[8] = kind;
+        node[9] = height;
+        node[10..12].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        let mut offset = 14;
+        for (i, record) in records.iter().enumerate() {
+            node[size - 2 * (i + 1)..][..2].copy_from_slice(&(offset as u16).to_be_bytes());
+            node[offset..offset + record.len()].copy_from_slice(record);
+            offset += record.len();
+        }
+        let end = size - 2 * (records.len() + 1);
+        node[end..end + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+        node
+    }
+
+    /// Packs records into B-tree nodes, returning the nodes and the (depth, root) of the tree
+    fn hfs_btree(
+        records: Vec<Vec<u8>>,
+        key_len: impl Fn(&[u8]) -> usize,
+        index_key: impl Fn(&[u8]) -> Vec<u8>,
+        size: usize,
+    ) -> (Vec<Vec<u8>>, u16, u32) {
+        let mut nodes = vec![vec![0u8; size]];
+        let mut level = records;
+        let mut height = 0;
+        while !level.is_empty() {
+            height += 1;
+            let mut groups: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+            for record in level {
+                let group = groups.last().unwrap();
+                let used: usize = group.iter().map(Vec::len).sum();
+                if 14 + used + record.len() + 2 * (group.len() + 2) > size {
+                    groups.push(Vec::new());
+                }
+                groups.last_mut().unwrap().push(record);
+            }
+
+            let first = nodes.len();
+            let kind = if height == 1 { 0xff } else { 0 };
+            let mut next = Vec::new();
+            for (i, group) in groups.iter().enumerate() {
+                let number = (first + i) as u32;
+                let mut node = hfs_node(kind, height, group, size);
+                if i + 1 < groups.len() {
+                    node[0..4].copy_from_slice(&(number + 1).to_be_bytes());
+                }
+                if i > 0 {
+                    node[4..8].copy_from_slice(&(number - 1).to_be_bytes());
+                }
+                nodes.push(node);
+                let mut record = index_key(&group[0][..key_len(&group[0])]);
+                record.extend_from_slice(&number.to_be_bytes());
+                next.push(record);
+            }
+            if next.len() == 1 {
+                return (nodes, height as u16, first as u32);
+            }
+            level = next;
+        }
+        (nodes, 0, 0)
+    }
+
+    impl HfsBuilder {
+        fn new(plus: bool, hfsx: bool) -> Self {
+            let (base, block_size, size) = match plus {
+                true => (0, 4096, 8 << 20),
+                false => (4096, 512, 4 << 20),
+            };
+            HfsBuilder {
+                image: vec![0u8; size],
+                plus,
+                hfsx,
+                base,
+                block_size,
+                next_block: plus as u32,
+                next_id: 16,
+                catalog: Vec::new(),
+                overflow: Vec::new(),
+                private_dir: None,
+                inodes: Vec::new(),
+                bless: String::new(),
+                blessed: 0,
+            }
+        }
+
+        fn alloc(&mut self, data: &[u8], fragmented: bool) -> Vec<(u32, u32)> {
+            let mut extents = Vec::new();
+            for chunk in data.chunks(if fragmented {
+                self.block_size
+            } else {
+                data.len().max(1)
+            }) {
+                let blocks = chunk.len().div_ceil(self.block_size) as u32;
+                let offset = self.base + self.next_block as usize * self.block_size;
+                self.image[offset..offset + chunk.len()].copy_from_slice(chunk);
+                extents.push((self.next_block, blocks));
+                self.next_block += blocks + fragmented as u32;
+            }
+            extents
+        }
+
+        // Extents beyond the ones a catalog record holds go to the overflow file
+        fn split_extents(&mut self, id: u32, extents: &[(u32, u32)]) -> Vec<(u32, u32)> {
+            let per = if self.plus { 8 } else { 3 };
+            let mut start: u32 = extents.iter().take(per).map(|e| e.1).sum();
+            for chunk in extents[extents.len().min(per)..].chunks(per) {
+                self.overflow.push((id, start, chunk.to_vec()));
+                start += chunk.iter().map(|e| e.1).sum::<u32>();
+            }
+            extents.iter().take(per).copied().collect()
+        }
+
+        fn key(&self, parent: u32, name: &str) -> Vec<u8> {
+            let mut key = Vec::new();
+            if self.plus {
+                let units: Vec<u16> = name.encode_utf16().collect();
+                key.extend_from_slice(&(6 + 2 * units.len() as u16).to_be_bytes());
+                key.extend_from_slice(&parent.to_be_bytes());
+                key.extend_from_slice(&(units.len() as u16).to_be_bytes());
+                units
+                    .iter()
+                    .for_each(|u| key.extend_from_slice(&u.to_be_bytes()));
+            } else {
+                let name = hfs_mac_roman(name);
+                key.extend_from_slice(&[6 + name.len() as u8, 0]);
+                key.extend_from_slice(&parent.to_be_bytes());
+                key.push(name.len() as u8);
+                key.extend_from_slice(&name);
+                if key.len() % 2 == 1 {
+                    key.push(0);
+                }
+            }
+            key
+        }
+
+        fn record(&mut self, parent: u32, name: &str, data: Vec<u8>) {
+            let key = self.key(parent, name);
+            let sort = (parent, name.to_lowercase(), name.to_string());
+            self.catalog.push((sort, key, data));
+        }
+
+        fn thread(&mut self, id: u32, parent: u32, name: &str, folder: bool) {
+            let kind = if folder { 3 } else { 4 };
+            let mut data = Vec::new();
+            if self.plus {
+                data.extend_from_slice(&[0, kind, 0, 0]);
+                data.extend_from_slice(&parent.to_be_bytes());
+                let units: Vec<u16> = name.encode_utf16().collect();
+                data.extend_from_slice(&(units.len() as u16).to_be_bytes());
+                units
+                    .iter()
+                    .for_each(|u| data.extend_from_slice(&u.to_be_bytes()));
+            } else {
+                data.resize(46, 0);
+                data[0] = kind;
+                data[10..14].copy_from_slice(&parent.to_be_bytes());
+                let name = hfs_mac_roman(name);
+                data[14] = name.len() as u8;
+                data[15..15 + name.len()].copy_from_slice(&name);
+            }
+            self.record(id, "", data);
+        }
+
+        fn folder(&mut self, id: u32, parent: u32, name: &str, valence: usize) {
+            let mut data = vec![0u8; if self.plus { 88 } else { 70 }];
+            if self.plus {
+                data[1] = 1;
+                data[4..8].copy_from_slice(&(valence as u32).to_be_bytes());
+                data[8..12].copy_from_slice(&id.to_be_bytes());
+                data[42..44].copy_from_slice(&0o40755u16.to_be_bytes());
+            } else {
+                data[0] = 1;
+                data[4..6].copy_from_slice(&(valence as u16).to_be_bytes());
+                data[6..10].copy_from_slice(&id.to_be_bytes());
+            }
+            self.record(parent, name, data);
+            self.thread(id, parent, name, true);
+        }
+
+        fn file(
+            &mut self,
+            parent: u32,
+            name: &str,
+            content: &[u8],
+            fragmented: bool,
+            finder: &[u8; 8],
+            special: u32,
+        ) {
+            let id = self.next_id;
+            self.next_id += 1;
+            let extents = self.alloc(content, fragmented);
+            let blocks: u32 = extents.iter().map(|e| e.1).sum();
+            let listed = self.split_extents(id, &extents);
+
+            let mut data = vec![0u8; if self.plus { 248 } else { 102 }];
+            if self.plus {
+                let mode: u16 = match finder {
+                    b"slnkrhap" => 0o120755,
+                    _ => 0o100644,
+                };
+                data[1] = 2;
+                data[8..12].copy_from_slice(&id.to_be_bytes());
+                data[42..44].copy_from_slice(&mode.to_be_bytes());
+                data[44..48].copy_from_slice(&special.to_be_bytes());
+                data[48..56].copy_from_slice(finder);
+                data[88..96].copy_from_slice(&(content.len() as u64).to_be_bytes());
+                data[100..104].copy_from_slice(&blocks.to_be_bytes());
+                for (i, (start, count)) in listed.iter().enumerate() {
+                    data[104 + i * 8..][..4].copy_from_slice(&start.to_be_bytes());
+                    data[108 + i * 8..][..4].copy_from_slice(&count.to_be_bytes());
+                }
+            } else {
+                data[0] = 2;
+                data[4..12].copy_from_slice(finder);
+                data[20..24].copy_from_slice(&id.to_be_bytes());
+                data[26..30].copy_from_slice(&(content.len() as u32).to_be_bytes());
+                let physical = blocks * self.block_size as u32;
+                data[30..34].copy_from_slice(&physical.to_be_bytes());
+                for (i, (start, count)) in listed.iter().enumerate() {
+                    data[74 + i * 4..][..2].copy_from_slice(&(*start as u16).to_be_bytes());
+                    data[76 + i * 4..][..2].copy_from_slice(&(*count as u16).to_be_bytes());
+                }
+            }
+            self.record(parent, name, data);
+            self.thread(id, parent, name, false);
+        }
+
+        fn add(&mut self, parent: u32, nodes: &[HfsNode]) {
+            for node in nodes {
+                match node {
+                    HfsNode::File(name, data) => self.file(parent, name, data, false, &[0; 8], 0),
+                    HfsNode::Typed(name, kind, data) => {
+                        let mut finder = *b"....UNIX";
+                        finder[..4].copy_from_slice(kind);
+                        self.file(parent, name, data, false, &finder, 0);
+                    }
+                    HfsNode::Fragmented(name, data) => {
+                        self.file(parent, name, data, true, &[0; 8], 0)
+                    }
+                    HfsNode::Dir(name, children) => {
+                        let id = self.next_id;
+                        self.next_id += 1;
+                        if *name == self.bless {
+                            self.blessed = id;
+                        }
+                        self.folder(id, parent, name, children.len());
+                        self.add(id, children);
+                    }
+                    HfsNode::Link(name, target) => {
+                        self.file(parent, name, target.as_bytes(), false, b"slnkrhap", 0)
+                    }
+                    HfsNode::HardLink(name, link, data) => {
+                        let private_dir = *self.private_dir.get_or_insert(self.next_id);
+                        if private_dir == self.next_id {
+                            self.next_id += 1;
+                            self.folder(private_dir, 2, HFS_PRIVATE_DIR, 0);
+                        }
+                        if !self.inodes.contains(link) {
+                            self.inodes.push(*link);
+                            let inode = format!("iNode{}", link);
+                            self.file(private_dir, &inode, data, false, &[0; 8], 0);
+                        }
+                        self.file(parent, name, &[], false, b"hlnkhfs+", *link);
+                    }
+                }
+            }
+        }
+
+        // Writes the nodes of a B-tree file, returning its fork extents
+        fn write_tree(
+            &mut self,
+            records: Vec<Vec<u8>>,
+            max_key: u16,
+            attributes: u32,
+            fragmented: bool,
+        ) -> (u32, Vec<(u32, u32)>) {
+            let plus = self.plus;
+            let key_len = move |r: &[u8]| match plus {
+                true => 2 + u16::from_be_bytes([r[0], r[1]]) as usize,
+                false => (1 + r[0] as usize).next_multiple_of(2),
+            };
+            // HFS index keys are padded to the maximum key length
+            let index_key = move |key: &[u8]| {
+                let mut key = key.to_vec();
+                if !plus {
+                    key.resize(1 + max_key as usize, 0);
+                    key[0] = max_key as u8;
+                }
+                key
+            };
+            let node_size = if plus { 4096 } else { 512 };
+            let leaf_records = records.len() as u32;
+            let (mut nodes, depth, root) = hfs_btree(records, key_len, index_key, node_size);
+
+            let mut header = vec![0u8; 106];
+            header[0..2].copy_from_slice(&depth.to_be_bytes());
+            header[2..6].copy_from_slice(&root.to_be_bytes());
+            header[6..10].copy_from_slice(&leaf_records.to_be_bytes());
+            let leaves = nodes.iter().filter(|n| n[8] == 0xff).count() as u32;
+            header[10..14].copy_from_slice(&(leaves.min(1)).to_be_bytes());
+            header[14..18].copy_from_slice(&leaves.to_be_bytes());
+            header[18..20].copy_from_slice(&(node_size as u16).to_be_bytes());
+            header[20..22].copy_from_slice(&max_key.to_be_bytes());
+            header[22..26].copy_from_slice(&(nodes.len() as u32).to_be_bytes());
+            header[37] = if self.hfsx { 0xbc } else { 0xcf };
+            header[38..42].copy_from_slice(&attributes.to_be_bytes());
+            let map = vec![0xffu8; node_size - 14 - 106 - 128 - 8];
+            nodes[0] = hfs_node(1, 0, &[header, vec![0u8; 128], map], node_size);
+
+            let data = nodes.concat();
+            let extents = self.alloc(&data, fragmented);
+            (data.len() as u32, extents)
+        }
+
+        fn write_fork(&mut self, offset: usize, size: u32, extents: &[(u32, u32)]) {
+            let blocks: u32 = extents.iter().map(|e| e.1).sum();
+            if self.plus {
+                let fork = &mut self.image[offset..offset + 80];
+                fork[0..8].copy_from_slice(&(size as u64).to_be_bytes());
+                fork[12..16].copy_from_slice(&blocks.to_be_bytes());
+                for (i, (start, count)) in extents.iter().take(8).enumerate() {
+                    fork[16 + i * 8..][..4].copy_from_slice(&start.to_be_bytes());
+                    fork[20 + i * 8..][..4].copy_from_slice(&count.to_be_bytes());
+                }
+            } else {
+                let fork = &mut self.image[offset..offset + 16];
+                fork[0..4].copy_from_slice(&size.to_be_bytes());
+                for (i, (start, count)) in extents.iter().take(3).enumerate() {
+                    fork[4 + i * 4..][..2].copy_from_slice(&(*start as u16).to_be_bytes());
+                    fork[6 + i * 4..][..2].copy_from_slice(&(*count as u16).to_be_bytes());
+                }
+            }
+        }
+
+        fn build(mut self, name: &str, tree: &[HfsNode], bless: &str) -> Vec<u8> {
+            self.bless = bless.to_string();
+            self.blessed = if bless.is_empty() { 2 } else { 0 };
+            self.folder(2, 1, name, tree.len());
+            self.add(2, tree);
+
+            // The catalog gets an extent per node, more than the volume header holds
+            let mut catalog = std::mem::take(&mut self.catalog);
+            catalog.sort_by(|a, b| a.0.cmp(&b.0));
+            let records = catalog
+                .into_iter()
+                .map(|(_, key, data)| [key, data].concat());
+            let (max_key, attributes) = if self.plus { (516, 6) } else { (37, 0) };
+            let catalog = self.write_tree(records.collect(), max_key, attributes, true);
+            self.split_extents(4, &catalog.1);
+
+            let mut overflow = std::mem::take(&mut self.overflow);
+            overflow.sort();
+            let records = overflow.into_iter().map(|(id, start, extents)| {
+                let mut record = Vec::new();
+                if self.plus {
+                    record.extend_from_slice(&[0, 10, 0, 0]);
+                    record.extend_from_slice(&id.to_be_bytes());
+                    record.extend_from_slice(&start.to_be_bytes());
+                    for i in 0..8 {
+                        let (start, count) = extents.get(i).copied().unwrap_or((0, 0));
+                        record.extend_from_slice(&start.to_be_bytes());
+                        record.extend_from_slice(&count.to_be_bytes());
+                    }
+                } else {
+                    record.extend_from_slice(&[7, 0]);
+                    record.extend_from_slice(&id.to_be_bytes());
+                    record.extend_from_slice(&(start as u16).to_be_bytes());
+                    for i in 0..3 {
+                        let (start, count) = extents.get(i).copied().unwrap_or((0, 0));
+                        record.extend_from_slice(&(start as u16).to_be_bytes());
+                        record.extend_from_slice(&(count as u16).to_be_bytes());
+                    }
+                }
+                record
+            });
+            let (max_key, attributes) = if self.plus { (10, 2) } else { (7, 0) };
+            let extents = self.write_tree(records.collect(), max_key, attributes, false);
+
+            let total = ((self.image.len() - self.base) / self.block_size) as u32;
+            let header = &mut self.image[1024..1536];
+            if self.plus {
+                header[0..4].copy_from_slice(if self.hfsx { b"HX\0\x05" } else { b"H+\0\x04" });
+                header[4..8].copy_from_slice(&0x100u32.to_be_bytes());
+                header[8..12].copy_from_slice(b"10.0");
+                header[40..44].copy_from_slice(&(self.block_size as u32).to_be_bytes());
+                header[44..48].copy_from_slice(&total.to_be_bytes());
+                header[64..68].copy_from_slice(&self.next_id.to_be_bytes());
+                header[80..84].copy_from_slice(&self.blessed.to_be_bytes());
+                self.write_fork(1024 + 192, extents.0, &extents.1);
+                self.write_fork(1024 + 272, catalog.0, &catalog.1);
+            } else {
+                header[0..2].copy_from_slice(b"BD");
+                header[18..20].copy_from_slice(&(total as u16).to_be_bytes());
+                header[20..24].copy_from_slice(&(self.block_size as u32).to_be_bytes());
+                header[28..30].copy_from_slice(&((self.base / 512) as u16).to_be_bytes());
+                header[30..34].copy_from_slice(&self.next_id.to_be_bytes());
+                let label = hfs_mac_roman(name);
+                header[36] = label.len() as u8;
+                header[37..37 + label.len()].copy_from_slice(&label);
+                header[92..96].copy_from_slice(&self.blessed.to_be_bytes());
+                self.write_fork(1024 + 130, extents.0, &extents.1);
+                self.write_fork(1024 + 146, catalog.0, &catalog.1);
+            }
+            self.image
+        }
+    }
+
+    /// Embeds an HFS+ volume in an HFS wrapper, as Mac OS 8.1 and later did
+    fn hfs_wrap(volume: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 8192];
+        image.extend_from_slice(volume);
+        let mdb = &mut image[1024..1536];
+        mdb[0..2].copy_from_slice(b"BD");
+        mdb[18..20].copy_from_slice(&((volume.len() / 4096 + 1) as u16).to_be_bytes());
+        mdb[20..24].copy_from_slice(&4096u32.to_be_bytes());
+        mdb[28..30].copy_from_slice(&8u16.to_be_bytes());
+        mdb[36..44].copy_from_slice(b"\x07Wrapper");
+        mdb[124..126].copy_from_slice(b"H+");
+        mdb[126..128].copy_from_slice(&1u16.to_be_bytes());
+        mdb[128..130].copy_from_slice(&((volume.len() / 4096) as u16).to_be_bytes());
+        image
+    }
+
+    fn hfs_boot_tree(plus: bool) -> Vec<HfsNode> {
+        let many = (0..150)
+            .map(|i| HfsNode::File(format!("file-{:03}", i), i.to_string().into_bytes()))
+            .collect();
+        let mut tree = vec![
+            HfsNode::File("yaboot.conf".into(), b"image=/vmlinux\n".to_vec()),
+            HfsNode::Typed("ofboot.b".into(), *b"tbxi", b"<CHRP-BOOT>".to_vec()),
+            HfsNode::Fragmented("yaboot".into(), pattern(80_000, 3)),
+            HfsNode::Dir(
+                "Kernels".into(),
+                vec![HfsNode::File("vmlinux".into(), pattern(600_000, 4))],
+            ),
+            HfsNode::Dir(
+                "Système".into(),
+                vec![
+                    HfsNode::Typed("BootX".into(), *b"tbxi", b"BootX".to_vec()),
+                    HfsNode::File("Finder".into(), Vec::new()),
+                ],
+            ),
+            HfsNode::Dir("many".into(), many),
+        ];
+        if plus {
+            tree.push(HfsNode::Link("vmlinux".into(), "Kernels/vmlinux".into()));
+            tree.push(HfsNode::Link("loop".into(), "/loop".into()));
+            tree.push(HfsNode::HardLink("initrd".into(), 7, pattern(100_000, 5)));
+            tree.push(HfsNode::Dir(
+                "boot".into(),
+                vec![HfsNode::HardLink("initrd.img".into(), 7, Vec::new())],
+            ));
+        }
+        tree
+    }
+
+    fn check_hfs_tree<D: BlockDevice>(fs: &mut HfsPlusFs<D>) {
+        let root = fs.root();
+        let names: Vec<String> = fs
+            .read_dir(&root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert!(names.iter().all(|n| !n.starts_with('\0')));
+        assert!(names.contains(&"Système".to_string()));
+
+        assert_eq!(
+            read_all(&mut fs.open("yaboot.conf").unwrap(), 64),
+            b"image=/vmlinux\n"
+        );
+        // Extents found in the overflow file
+        let mut file = fs.open("/yaboot").unwrap();
+        assert_eq!(file.size(), 80_000);
+        assert_eq!(read_all(&mut file, 3000), pattern(80_000, 3));
+        file.seek(70_001).unwrap();
+        let mut buf = [0u8; 2000];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pattern(80_000, 3)[70_001..72_001]);
+        assert!(file.seek(80_001).is_err());
+        assert_eq!(
+            read_all(&mut fs.open("Kernels/vmlinux").unwrap(), 5000),
+            pattern(600_000, 4)
+        );
+        assert!(read_all(&mut fs.open("Système/Finder").unwrap(), 16).is_empty());
+
+        // Open Firmware boot files are found by type in the blessed folder
+        let root_boot = fs.find_by_type(&root, b"tbxi").unwrap();
+        assert_eq!(root_boot.name, "ofboot.b");
+        assert_eq!(&root_boot.creator, b"UNIX");
+        let blessed = fs.blessed().unwrap();
+        let bootx = fs.find_by_type(&blessed, b"tbxi").unwrap();
+        assert_eq!(fs.entry_by_id(bootx.id).unwrap(), bootx);
+
+        // Catalog with several index levels
+        let many = fs.lookup("many").unwrap();
+        assert_eq!(fs.read_dir(&many).unwrap().len(), 150);
+        assert_eq!(read_all(&mut fs.open("many/file-123").unwrap(), 8), b"123");
+        assert_eq!(fs.entry_by_id(many.id).unwrap(), many);
+
+        assert!(fs.open("Kernels").is_err());
+        assert!(fs.open("Kernels/missing").is_err());
+        assert!(fs.lookup("yaboot/x").is_err());
+        assert!(fs.read_dir(&root_boot).is_err());
+        assert!(fs.read_link(&root_boot).is_err());
+    }
+
+    #[test]
+    fn hfsplus_volume() {
+        let image =
+            HfsBuilder::new(true, false).build("Macintosh HD", &hfs_boot_tree(true), "Système");
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.flavor(), Flavor::HfsPlus);
+        assert_eq!(fs.volume_name(), "Macintosh HD");
+        assert_eq!(fs.block_size(), 4096);
+        check_hfs_tree(&mut fs);
+        assert_eq!(fs.blessed().unwrap().name, "Système");
+
+        // Names are case insensitive
+        assert_eq!(
+            read_all(&mut fs.open("YABOOT.CONF").unwrap(), 64),
+            b"image=/vmlinux\n"
+        );
+        assert_eq!(fs.lookup("système/bootx").unwrap().name, "BootX");
+
+        let link = fs.lookup_link("vmlinux").unwrap();
+        assert!(link.is_symlink());
+        assert_eq!(fs.read_link(&link).unwrap(), "Kernels/vmlinux");
+        assert_eq!(
+            fs.lookup("vmlinux").unwrap(),
+            fs.lookup("Kernels/vmlinux").unwrap()
+        );
+        assert!(fs.lookup("loop").is_err());
+
+        // Hard links share the inode in the private folder
+        let initrd = fs.lookup("initrd").unwrap();
+        assert_eq!(initrd.name, "initrd");
+        assert_eq!(initrd.size, 100_000);
+        assert_eq!(fs.lookup("boot/initrd.img").unwrap().id, initrd.id);
+        assert_eq!(
+            read_all(&mut fs.open("boot/../boot/initrd.img").unwrap(), 4096),
+            pattern(100_000, 5)
+        );
+    }
+
+    #[test]
+    fn hfsx_volume() {
+        let tree = vec![
+            HfsNode::File("README".into(), b"upper".to_vec()),
+            HfsNode::File("readme".into(), b"lower".to_vec()),
+        ];
+        let image = HfsBuilder::new(true, true).build("hfsx", &tree, "");
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.flavor(), Flavor::Hfsx);
+        assert_eq!(read_all(&mut fs.open("README").unwrap(), 16), b"upper");
+        assert_eq!(read_all(&mut fs.open("readme").unwrap(), 16), b"lower");
+        assert!(fs.open("ReadMe").is_err());
+        assert_eq!(fs.blessed().unwrap(), fs.root());
+    }
+
+    #[test]
+    fn hfs_volumes() {
+        // Apple_Bootstrap style volume, blessed at the root
+        let image = HfsBuilder::new(false, false).build("bootstrap", &hfs_boot_tree(false), "");
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.flavor(), Flavor::Hfs);
+        assert_eq!(fs.volume_name(), "bootstrap");
+        assert_eq!(fs.block_size(), 512);
+        check_hfs_tree(&mut fs);
+        assert_eq!(fs.blessed().unwrap(), fs.root());
+        assert_eq!(fs.lookup("SYSTÈME").unwrap().name, "Système");
+
+        // HFS+ volume inside an HFS wrapper
+        let volume = HfsBuilder::new(true, false).build("Wrapped", &hfs_boot_tree(true), "Système");
+        let image = hfs_wrap(&volume);
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&image, 512)).unwrap();
+        assert_eq!(fs.flavor(), Flavor::HfsPlus);
+        assert_eq!(fs.volume_name(), "Wrapped");
+        check_hfs_tree(&mut fs);
+    }
+
+    #[test]
+    fn hfs_errors() {
+        let tree = vec![HfsNode::Fragmented("file".into(), pattern(40_000, 6))];
+        let image = HfsBuilder::new(true, false).build("errors", &tree, "");
+        assert!(HfsPlusFs::new(MemoryDisk::new(&image, 512)).is_ok());
+
+        let mut corrupted = image.clone();
+        corrupted[1024] = b'X';
+        assert!(HfsPlusFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+        let mut corrupted = image.clone();
+        corrupted[1024 + 3] = 5;
+        assert!(HfsPlusFs::new(MemoryDisk::new(&corrupted, 512)).is_err());
+        assert!(HfsPlusFs::new(MemoryDisk::new(&image[..1 << 20], 512)).is_err());
+
+        // Overflow extents that do not cover the fork
+        let extents_start = u32::from_be_bytes(image[1024 + 208..1024 + 212].try_into().unwrap());
+        let leaf = extents_start as usize * 4096 + 4096;
+        let mut corrupted = image.clone();
+        corrupted[leaf + 14 + 8..leaf + 14 + 12].copy_from_slice(&1u32.to_be_bytes());
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.open("file").is_err());
+
+        // Record offsets beyond the node
+        let mut corrupted = image.clone();
+        corrupted[leaf + 4094..leaf + 4096].copy_from_slice(&5000u16.to_be_bytes());
+        let mut fs = HfsPlusFs::new(MemoryDisk::new(&corrupted, 512)).unwrap();
+        assert!(fs.open("file").is_err());
+    }
+
+    /// Image, format, a file and its contents, and a symbolic link and its target
+    type ProbeCase<'a> = (
+        &'a [u8],
+        FsType,
+        &'a str,
+        &'a [u8],
+        Option<(&'a str, &'a str)>,
+    );
+
+    #[test]
+    fn fs_probe() {
+        let ext = mke2fs_image("probe", &["-t", "ext2", "-b", "1024"], "8M", populate_boot);
+        let fat = FatBuilder::new(FatType::Fat12, 2880, 1, false).build(None, &fat_boot_tree());
+        let iso = IsoBuilder::new(true, 4096).build("INSTALL", &iso_install_tree(), true);
+        let xfs = XfsBuilder::new(true, 16).build("xfsboot", xfs_boot_tree());
+        let btrfs = BtrfsBuilder::new().build("fedora", &btrfs_fedora_tree(), 256);
+        let hfs = HfsBuilder::new(true, false).build("Mac", &hfs_boot_tree(true), "");
+        let grub = b"set timeout=5\n";
+
+        let cases: [ProbeCase; 6] = [
+            (
+                &ext,
+                FsType::Ext,
+                "boot/grub/grub.cfg",
+                grub,
+                Some(("vmlinuz", "boot/vmlinux")),
+            ),
+            (&fat, FsType::Fat, "\\boot\\grub\\grub.cfg", grub, None),
+            (
+                &iso,
+                FsType::Iso9660,
+                "ppc/bootinfo.txt",
+                b"<chrp-boot>\n",
+                Some(("boot", "ppc/./ppc64")),
+            ),
+            (
+                &xfs,
+                FsType::Xfs,
+                "boot/grub/grub.cfg",
+                grub,
+                Some(("vmlinuz", "boot/vmlinux")),
+            ),
+            (
+                &btrfs,
+                FsType::Btrfs,
+                "grub/grub.cfg",
+                grub,
+                Some(("grub", "/boot/grub2")),
+            ),
+            (
+                &hfs,
+                FsType::Hfs,
+                "yaboot.conf",
+                b"image=/vmlinux\n",
+                Some(("vmlinux", "Kernels/vmlinux")),
+            ),
+        ];
+        for (image, kind, path, contents, link) in cases {
+            assert_eq!(fs::detect(&mut MemoryDisk::new(image, 512)), Some(kind));
+            let mut fs = fs::probe(MemoryDisk::new(image, 512)).unwrap();
+            assert_eq!(fs.fs_type(), kind);
+
+            let metadata = fs.metadata(path).unwrap();
+            assert_eq!(metadata.kind, FileType::Regular);
+            assert_eq!(metadata.size, contents.len() as u64);
+            let mut file = fs.open(path).unwrap();
+            assert_eq!(file.size(), contents.len() as u64);
+            assert_eq!(read_all(&mut file, 5), contents);
+            drop(file);
+            assert!(fs.read_link(path).is_err());
+
+            let first = path.split(['/', '\\']).find(|c| !c.is_empty()).unwrap();
+            let root = fs.read_dir("/").unwrap();
+            assert!(root.iter().any(|e| e.name == first));
+            assert!(root.iter().all(|e| e.name != "." && e.name != ".."));
+            assert_eq!(fs.metadata("").unwrap().kind, FileType::Directory);
+            assert!(fs.open("/").is_err());
+            assert!(fs.read_dir(path).is_err());
+            assert!(fs.metadata("missing").is_err());
+
+            if let Some((link, target)) = link {
+                assert_eq!(fs.read_link(link).unwrap(), target);
+            }
+        }
+
+        assert_eq!(fs::detect(&mut MemoryDisk::new(&[0u8; 65536], 512)), None);
+        assert!(fs::probe(MemoryDisk::new(&[0u8; 65536], 512)).is_err());
+        let mut broken = xfs.clone();
+        broken[4] ^= 0xff;
+        assert!(fs::probe(MemoryDisk::new(&broken, 512)).is_err());
+    }
+
+    // MBR disk with an empty PReP partition, a FAT floppy image and an HFS+ volume
+    fn build_fs_disk() -> Vec<u8> {
+        let fat = FatBuilder::new(FatType::Fat12, 2880, 1, false).build(None, &fat_boot_tree());
+        let hfs = HfsBuilder::new(true, false).build("Mac", &hfs_boot_tree(true), "Système");
+        let mut image = vec![0u8; (8192 + hfs.len() / 512) * 512];
+        mbr_entry(&mut image, 0, true, mbr::TYPE_PREP, 64, 64);
+        mbr_entry(&mut image, 1, false, 0x0c, 2048, 2880);
+        mbr_entry(&mut image, 2, false, 0xaf, 8192, (hfs.len() / 512) as u32);
+        image[2048 * 512..][..fat.len()].copy_from_slice(&fat);
+        image[8192 * 512..].copy_from_slice(&hfs);
+        image
+    }
+
+    #[test]
+    fn fs_mount_partitions() {
+        let image = build_fs_disk();
+        let disk = || MemoryDisk::new(&image, 512);
+        assert_eq!(fs::detect(&mut disk()), None);
+
+        // The first partition with a filesystem is used by default
+        let mut fs = fs::mount(disk(), None).unwrap();
+        assert_eq!(fs.fs_type(), FsType::Fat);
+        assert_eq!(
+            read_all(&mut fs.open("ofboot.b").unwrap(), 64),
+            b"<CHRP-BOOT>"
+        );
+        let mut fs = fs::mount(disk(), Some("3")).unwrap();
+        assert_eq!(fs.fs_type(), FsType::Hfs);
+        assert_eq!(fs.metadata("Kernels/vmlinux").unwrap().size, 600_000);
+
+        assert!(fs::mount(disk(), Some("1")).is_err());
+        assert!(fs::mount(disk(), Some("4")).is_err());
+        assert!(fs::mount(disk(), Some("b")).is_err());
+
+        // Filesystems covering the whole device, with no partition table
+        let iso = IsoBuilder::new(false, 4096).build("INSTALL", &iso_install_tree(), false);
+        let fs = fs::mount(MemoryDisk::new(&iso, 512), None).unwrap();
+        assert_eq!(fs.fs_type(), FsType::Iso9660);
+        let fat = FatBuilder::new(FatType::Fat12, 2880, 1, false).build(None, &fat_boot_tree());
+        let fs = fs::mount(MemoryDisk::new(&fat, 512), None).unwrap();
+        assert_eq!(fs.fs_type(), FsType::Fat);
+        assert!(fs::mount(MemoryDisk::new(&[0u8; 65536], 512), None).is_err());
+
+        let table = Table::read(&mut disk()).unwrap();
+        assert!(matches!(table, Table::Mbr(_)));
+        assert_eq!(table.numbers(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn vfs_specs() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let path = "/vdevice/v-scsi@2000/disk@8100000000000000";
+        mock_file(path, build_fs_disk());
+
+        let mut vfs = Vfs::new(&prom);
+        assert_eq!(
+            read_all(&mut vfs.open("disk:2,\\boot\\grub\\grub.cfg").unwrap(), 64),
+            b"set timeout=5\n"
+        );
+        let spec = format!("{}:3,\\Kernels\\vmlinux", path);
+        assert_eq!(
+            read_all(&mut vfs.open(&spec).unwrap(), 4096),
+            pattern(600_000, 4)
+        );
+        assert_eq!(vfs.read_link("hd:3,/vmlinux").unwrap(), "Kernels/vmlinux");
+        assert_eq!(vfs.metadata("disk:3,yaboot.conf").unwrap().size, 15);
+        let root = vfs.read_dir("disk:3").unwrap();
+        assert!(root
+            .iter()
+            .any(|e| e.name == "Système" && e.kind == FileType::Directory));
+        assert!(vfs.open("disk:3,\\missing").is_err());
+        assert!(vfs.open("disk:1,\\ofboot.b").is_err());
+        assert!(vfs.open("floppy:,\\ofboot.b").is_err());
+
+        // Mounted filesystems are kept once the disk has been read
+        FILES.with(|files| files.borrow_mut().remove(path.as_bytes()));
+        assert!(vfs.metadata("disk:2,ofboot.b").is_ok());
+        assert!(Vfs::new(&prom).metadata("disk:2,ofboot.b").is_err());
+        vfs.unmount_all();
+        assert!(vfs.metadata("disk:2,ofboot.b").is_err());
+
+        // Devices the firmware does not know about
+        let ramdisk: &'static [u8] = Box::leak(build_fs_disk().into_boxed_slice());
+        vfs.mount("ramdisk", Some("3"), MemoryDisk::new(ramdisk, 512))
+            .unwrap();
+        assert_eq!(
+            read_all(&mut vfs.open("ramdisk:3,Syst\u{e8}me/BootX").unwrap(), 64),
+            b"BootX"
+        );
+        assert!(vfs.open("ramdisk:2,ofboot.b").is_err());
+    }
+
+    // Ethernet frame with an ARP packet, ```op``` 1 for requests and 2 for replies
+    fn arp_frame(op: u16, sender: ([u8; 6], [u8; 4]), target: ([u8; 6], [u8; 4])) -> Vec<u8> {
+        let destination = if op == 1 { [0xff; 6] } else { target.0 };
+        let mut frame = [&destination[..], &sender.0, &[0x08, 0x06]].concat();
+        frame.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        frame.extend_from_slice(&op.to_be_bytes());
+        for (mac, ip) in [sender, target] {
+            frame.extend_from_slice(&mac);
+            frame.extend_from_slice(&ip);
+        }
+        frame
+    }
+
+    #[test]
+    fn net_interfaces() {
+        let prom = PROM::new(mock_entry).unwrap();
+        let path = "/vdevice/l-lan@30000002";
+        assert_eq!(net::find_interfaces(&prom).unwrap(), [path]);
+
+        // A server at 10.0.0.1 answering ARP, other frames are dropped
+        let server = ([0x02, 0, 0, 0, 0, 1], [10, 0, 0, 1]);
+        mock_nic(move |frame| match frame.get(12..14) {
+            Some([0x08, 0x06]) if frame[38..42] == server.1 => {
+                let client = (
+                    frame[22..28].try_into().unwrap(),
+                    frame[28..32].try_into().unwrap(),
+                );
+                vec![arp_frame(2, server, client)]
+            }
+            _ => Vec::new(),
+        });
+
+        let mut nic = Interface::open(&prom, &format!("{}:speed=auto\0", path)).unwrap();
+        assert_eq!(nic.mac_address(), LAN_MAC);
+        assert_eq!(nic.mtu(), 1500);
+        assert_eq!(nic.max_frame_size(), 1514);
+        let mut buf = vec![0u8; nic.max_frame_size()];
+        assert_eq!(nic.receive(&mut buf), Ok(None));
+
+        let client = (LAN_MAC, [10, 0, 0, 2]);
+        nic.send(&arp_frame(1, client, ([0; 6], [10, 0, 0, 9])))
+            .unwrap();
+        assert_eq!(nic.receive(&mut buf), Ok(None));
+        nic.send(&arp_frame(1, client, ([0; 6], server.1))).unwrap();
+        let len = nic.receive(&mut buf).unwrap().unwrap();
+        assert_eq!(&buf[..len], arp_frame(2, server, client));
+        assert_eq!(&buf[6..12], &server.0);
+        assert_eq!(nic.receive(&mut buf), Ok(None));
+
+        assert!(nic.send(&[0; 13]).is_err());
+        assert!(nic.send(&[0; 1515]).is_err());
+        nic.send(&[0; 1514]).unwrap();
+        drop(nic);
+        assert!(NIC_INSTANCES.with(|nics| nics.borrow().is_empty()));
+
+        // The address in use and jumbo frames
+        let in_use = [0x52, 0x54, 0, 0xab, 0xcd, 0xef];
+        prom.set_property(LAN_PHANDLE as *const PHandle, "mac-address\0", &in_use)
+            .unwrap();
+        let jumbo = cells(&[9014]);
+        prom.set_property(LAN_PHANDLE as *const PHandle, "max-frame-size\0", &jumbo)
+            .unwrap();
+        let nic = Interface::open(&prom, &format!("{}\0", path)).unwrap();
+        assert_eq!(nic.mac_address(), in_use);
+        assert_eq!(nic.mtu(), 9000);
+        prom.set_property(
+            LAN_PHANDLE as *const PHandle,
+            "max-frame-size\0",
+            &cells(&[10]),
+        )
+        .unwrap();
+        assert!(Interface::open(&prom, &format!("{}\0", path)).is_err());
+
+        // Other devices are refused
+        assert!(Interface::open(&prom, "disk\0").is_err());
+        assert!(Interface::open(&prom, "/vdevice/l-lan@30000003\0").is_err());
+    }
+
     #[test]
     fn read() {}
 